@@ -0,0 +1,107 @@
+use axum::{
+    Json,
+    body::{self, HttpBody},
+    extract::Request,
+    http::{HeaderValue, header, header::HeaderName},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use std::time::Instant;
+use tracing::Instrument;
+use uuid::Uuid;
+
+use crate::error::ApiError;
+
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+// Plain-text error bodies up to this size are wrapped into the JSON envelope
+const MAX_ERROR_TEXT: usize = 64 * 1024;
+
+/// Give every request an id (the caller's `X-Request-Id` when it sends a sane one),
+/// run it inside a span carrying that id, echo the id back, and write one access log
+/// line with method, path, status and duration.
+pub async fn trace_request(request: Request, next: Next) -> Response {
+    let id = request
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .filter(|value| {
+            !value.is_empty()
+                && value.len() <= 64
+                && value
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        })
+        .map(str::to_string)
+        .unwrap_or_else(|| Uuid::new_v4().to_string());
+    let method = request.method().clone();
+    let path = request.uri().path().to_string();
+
+    let span = tracing::info_span!("request", request_id = %id);
+    let started = Instant::now();
+    let mut response = next.run(request).instrument(span).await;
+    let status = response.status();
+
+    if status.is_client_error() || status.is_server_error() {
+        response = envelope(response, &id).await;
+    }
+    if let Ok(value) = HeaderValue::from_str(&id) {
+        response
+            .headers_mut()
+            .insert(HeaderName::from_static(REQUEST_ID_HEADER), value);
+    }
+
+    tracing::info!(
+        target: "access",
+        request_id = %id,
+        method = %method,
+        path = %path,
+        status = status.as_u16(),
+        duration_ms = started.elapsed().as_millis() as u64,
+    );
+    response
+}
+
+/// Render an error response as the ApiError envelope with the request id, so clients
+/// can quote it when reporting the error. Bare statuses and plain-text bodies, such as
+/// axum's extractor rejections, are wrapped with their text as the message.
+async fn envelope(response: Response, id: &str) -> Response {
+    let (mut parts, body) = response.into_parts();
+    let error = match parts.extensions.remove::<ApiError>() {
+        Some(error) => error,
+        None => {
+            let is_json = parts
+                .headers
+                .get(header::CONTENT_TYPE)
+                .is_some_and(|value| value.as_bytes().starts_with(b"application/json"));
+            let small = body
+                .size_hint()
+                .upper()
+                .is_some_and(|size| size <= MAX_ERROR_TEXT as u64);
+            if is_json || !small {
+                return Response::from_parts(parts, body);
+            }
+            let text = body::to_bytes(body, MAX_ERROR_TEXT)
+                .await
+                .map(|bytes| String::from_utf8_lossy(&bytes).trim().to_string())
+                .unwrap_or_default();
+            let message = if text.is_empty() {
+                parts
+                    .status
+                    .canonical_reason()
+                    .unwrap_or("Error")
+                    .to_string()
+            } else {
+                text
+            };
+            ApiError::status(parts.status, message)
+        }
+    };
+    let mut response = (parts.status, Json(error.body(Some(id)))).into_response();
+    // Keep headers like Retry-After, but not ones describing the old body
+    for (name, value) in &parts.headers {
+        if name != header::CONTENT_TYPE && name != header::CONTENT_LENGTH {
+            response.headers_mut().append(name.clone(), value.clone());
+        }
+    }
+    response
+}
@@ -0,0 +1,220 @@
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+use crate::approvals::ApprovalKind;
+use crate::command_tools::ToolResult;
+use crate::config::AgentConfig;
+use crate::model::{GenerationControl, GenerationStats, PromptInput, Turn};
+use crate::server::AppState;
+use crate::tools::{self, ToolCall};
+
+// Agent mode: the model calls command tools and reads their output until it answers
+
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct AgentOptions {
+    /// Model turns allowed before the run fails; capped by `agent.max_steps` in the config
+    pub max_steps: Option<usize>,
+    /// Tool runs allowed; capped by `agent.max_tool_calls`
+    pub max_tool_calls: Option<usize>,
+    /// Prompt plus generated tokens allowed over all steps; capped by `agent.max_tokens`
+    pub max_tokens: Option<usize>,
+    /// Name of an `agent.profiles` entry; defaults to `agent.default_profile`
+    pub profile: Option<String>,
+}
+
+/// Limits one agent run is held to, resolved from its request and the config.
+#[derive(Debug, Clone)]
+pub struct Guardrails {
+    pub max_steps: usize,
+    pub max_tool_calls: usize,
+    pub max_tokens: usize,
+    /// Tools, or `*`, that wait for a human's approval before they run
+    pub require_approval: Vec<String>,
+    pub approval_timeout: Duration,
+}
+
+impl Guardrails {
+    /// Apply the config's caps and profile to `options`, refusing the run if it offers
+    /// a tool the profile denies.
+    pub fn resolve(
+        options: &AgentOptions,
+        config: &AgentConfig,
+        offered: &[&str],
+    ) -> Result<Self, String> {
+        let profile = match options.profile.as_ref().or(config.default_profile.as_ref()) {
+            Some(name) => Some(
+                config
+                    .profiles
+                    .get(name)
+                    .ok_or_else(|| format!("Unknown agent profile: {}", name))?,
+            ),
+            None => None,
+        };
+        if let Some(profile) = profile
+            && let Some(denied) = offered
+                .iter()
+                .find(|name| profile.deny_tools.iter().any(|d| d == *name))
+        {
+            return Err(format!("Tool {} is denied by the agent profile", denied));
+        }
+        let cap = |requested: Option<usize>, max: usize| requested.unwrap_or(max).clamp(1, max);
+        Ok(Self {
+            max_steps: cap(options.max_steps, config.max_steps),
+            max_tool_calls: cap(options.max_tool_calls, config.max_tool_calls),
+            max_tokens: cap(options.max_tokens, config.max_tokens),
+            require_approval: profile
+                .map(|p| p.require_approval.clone())
+                .unwrap_or_default(),
+            approval_timeout: Duration::from_secs(config.approval_timeout_secs),
+        })
+    }
+
+    fn needs_approval(&self, tool: &str) -> bool {
+        self.require_approval.iter().any(|t| t == "*" || t == tool)
+    }
+}
+
+/// One model turn of an agent run and the tools it called.
+#[derive(Serialize, Debug, Clone)]
+pub struct AgentStep {
+    /// Counting from 1
+    pub step: usize,
+    pub content: String,
+    pub tool_calls: Vec<ToolCall>,
+    pub tool_results: Vec<ToolResult>,
+    pub prompt_tokens: usize,
+    pub completion_tokens: usize,
+}
+
+/// Run execution `id` as an agent within `guardrails`, offering the `offered` command
+/// tools and recording every step on the execution as it finishes. Returns the step
+/// that answered without calling a command tool, and the generation stats summed over
+/// all steps.
+///
+/// A step that calls a tool the client defined ends the run too, since only the
+/// client can answer it.
+pub async fn run(
+    state: &AppState,
+    id: &str,
+    input: &PromptInput,
+    control: &GenerationControl,
+    offered: &[String],
+    guardrails: &Guardrails,
+) -> Result<(AgentStep, GenerationStats), String> {
+    let mut input = input.clone();
+    let mut total = GenerationStats::default();
+    let mut tool_runs = 0;
+    for step in 1..=guardrails.max_steps {
+        if total.prompt_tokens + total.completion_tokens >= guardrails.max_tokens {
+            return Err(format!(
+                "Agent used up its budget of {} tokens",
+                guardrails.max_tokens
+            ));
+        }
+        // The model is free for other requests while tools run or a human decides
+        let (output, stats) = state
+            .model
+            .lock()
+            .await
+            .run_prompt_with_stats(&input, control)
+            .await?;
+        total.prompt_tokens += stats.prompt_tokens;
+        total.completion_tokens += stats.completion_tokens;
+        total.prompt_eval_ms += stats.prompt_eval_ms;
+        total.generation_ms += stats.generation_ms;
+        total.finish_reason = stats.finish_reason;
+
+        let (content, tool_calls) = tools::parse_tool_calls(&output);
+        let runnable: Vec<ToolCall> = tool_calls
+            .iter()
+            .filter(|call| offered.contains(&call.function.name))
+            .cloned()
+            .collect();
+        tool_runs += runnable.len();
+        if tool_runs > guardrails.max_tool_calls {
+            return Err(format!(
+                "Agent reached its limit of {} tool calls",
+                guardrails.max_tool_calls
+            ));
+        }
+        let gated: Vec<ToolCall> = runnable
+            .iter()
+            .filter(|call| guardrails.needs_approval(&call.function.name))
+            .cloned()
+            .collect();
+        let tool_results = if gated.is_empty()
+            || wait_for_approval(state, id, gated, guardrails.approval_timeout).await?
+        {
+            state
+                .command_tools
+                .run_all(&runnable, offered, &state.audit)
+                .await
+        } else {
+            runnable.iter().map(rejected).collect()
+        };
+
+        let finished = runnable.is_empty() || runnable.len() < tool_calls.len();
+        let record = AgentStep {
+            step,
+            content,
+            tool_calls,
+            tool_results,
+            prompt_tokens: stats.prompt_tokens,
+            completion_tokens: stats.completion_tokens,
+        };
+        state
+            .execution_manager
+            .lock()
+            .await
+            .push_step(id, record.clone());
+        if finished {
+            return Ok((record, total));
+        }
+
+        input.turns.push(Turn::Assistant(output));
+        for result in &record.tool_results {
+            input.turns.push(Turn::ToolResponse(match &result.error {
+                Some(error) => format!("{} failed: {}", result.name, error),
+                None => result.output.clone(),
+            }));
+        }
+    }
+    Err(format!(
+        "Agent reached its step limit of {} without a final answer",
+        guardrails.max_steps
+    ))
+}
+
+/// Hold execution `id` in PendingApproval until its approval is answered.
+async fn wait_for_approval(
+    state: &AppState,
+    id: &str,
+    calls: Vec<ToolCall>,
+    timeout: Duration,
+) -> Result<bool, String> {
+    let answer =
+        state
+            .execution_manager
+            .lock()
+            .await
+            .await_approval(id, ApprovalKind::ToolCalls, calls);
+    match tokio::time::timeout(timeout, answer).await {
+        Ok(Ok(approved)) => Ok(approved),
+        Ok(Err(_)) => Err("Approval request was dropped".to_string()),
+        Err(_) => Err(format!(
+            "No approval within {}s; tools were not run",
+            timeout.as_secs()
+        )),
+    }
+}
+
+fn rejected(call: &ToolCall) -> ToolResult {
+    ToolResult {
+        tool_call_id: call.id.clone(),
+        name: call.function.name.clone(),
+        output: String::new(),
+        exit_code: None,
+        error: Some("Rejected by the operator".to_string()),
+        duration_ms: 0.0,
+    }
+}
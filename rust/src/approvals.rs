@@ -0,0 +1,230 @@
+use axum::{
+    Router,
+    extract::{
+        Path, State,
+        ws::{Message, WebSocketUpgrade},
+    },
+    http::StatusCode,
+    response::{IntoResponse, Json},
+    routing::{get, post},
+};
+use chrono::Utc;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::{broadcast, oneshot};
+use uuid::Uuid;
+
+use crate::error::ApiError;
+use crate::server::AppState;
+use crate::tools::ToolCall;
+
+// Longest prompt excerpt shown on an execution approval
+const PROMPT_PREVIEW_CHARS: usize = 200;
+
+/// What a human is asked to allow.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ApprovalKind {
+    /// An execution started while `approve_executions` is set, or with `require_approval`
+    Execution,
+    /// Agent tool calls gated by the profile's `require_approval`
+    ToolCalls,
+}
+
+impl ApprovalKind {
+    /// Audit log event recording the answer.
+    fn audit_event(self) -> &'static str {
+        match self {
+            Self::Execution => "execution_approval",
+            Self::ToolCalls => "tool_approval",
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Approval {
+    pub id: String,
+    pub execution_id: String,
+    pub kind: ApprovalKind,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub prompt: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub tool_calls: Vec<ToolCall>,
+    pub requested_at: String,
+}
+
+/// Pushed to /ws/approvals subscribers.
+#[derive(Serialize, Debug, Clone)]
+pub struct ApprovalEvent {
+    /// `requested`, `approved`, `denied`, or `withdrawn` when the execution stopped
+    /// waiting on its own
+    pub event: &'static str,
+    pub approval: Approval,
+}
+
+// Open approvals, each answered through the oneshot its execution waits on
+pub struct ApprovalQueue {
+    pending: HashMap<String, (Approval, oneshot::Sender<bool>)>,
+    tx: broadcast::Sender<ApprovalEvent>,
+}
+
+impl ApprovalQueue {
+    pub fn new() -> Self {
+        let (tx, _) = broadcast::channel(100);
+        Self {
+            pending: HashMap::new(),
+            tx,
+        }
+    }
+
+    /// Open an approval for `execution_id`; the receiver yields the answer.
+    pub fn request(
+        &mut self,
+        execution_id: &str,
+        kind: ApprovalKind,
+        prompt: Option<&str>,
+        tool_calls: Vec<ToolCall>,
+    ) -> (Approval, oneshot::Receiver<bool>) {
+        let approval = Approval {
+            id: Uuid::new_v4().to_string(),
+            execution_id: execution_id.to_string(),
+            kind,
+            prompt: prompt.map(|p| p.chars().take(PROMPT_PREVIEW_CHARS).collect()),
+            tool_calls,
+            requested_at: Utc::now().to_rfc3339(),
+        };
+        let (answer_tx, answer_rx) = oneshot::channel();
+        self.pending
+            .insert(approval.id.clone(), (approval.clone(), answer_tx));
+        self.notify("requested", &approval);
+        (approval, answer_rx)
+    }
+
+    pub fn answer(&mut self, id: &str, approved: bool) -> Result<Approval, String> {
+        let (approval, answer_tx) = self.pending.remove(id).ok_or("Approval not found")?;
+        let _ = answer_tx.send(approved);
+        self.notify(if approved { "approved" } else { "denied" }, &approval);
+        Ok(approval)
+    }
+
+    /// Drop whatever `execution_id` is waiting on, e.g. after it was cancelled.
+    pub fn withdraw(&mut self, execution_id: &str) {
+        let ids: Vec<String> = self
+            .pending
+            .values()
+            .filter(|(approval, _)| approval.execution_id == execution_id)
+            .map(|(approval, _)| approval.id.clone())
+            .collect();
+        for id in ids {
+            if let Some((approval, _)) = self.pending.remove(&id) {
+                self.notify("withdrawn", &approval);
+            }
+        }
+    }
+
+    /// The open approval of `execution_id`, if it has one.
+    pub fn for_execution(&self, execution_id: &str) -> Option<&Approval> {
+        self.pending
+            .values()
+            .map(|(approval, _)| approval)
+            .find(|approval| approval.execution_id == execution_id)
+    }
+
+    /// Oldest first.
+    pub fn list(&self) -> Vec<Approval> {
+        let mut list: Vec<Approval> = self
+            .pending
+            .values()
+            .map(|(approval, _)| approval.clone())
+            .collect();
+        list.sort_by(|a, b| a.requested_at.cmp(&b.requested_at));
+        list
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<ApprovalEvent> {
+        self.tx.subscribe()
+    }
+
+    fn notify(&self, event: &'static str, approval: &Approval) {
+        let _ = self.tx.send(ApprovalEvent {
+            event,
+            approval: approval.clone(),
+        });
+    }
+}
+
+pub fn routes() -> Router<Arc<AppState>> {
+    Router::new()
+        .route("/approvals", get(list_approvals))
+        .route("/approvals/:id/approve", post(approve))
+        .route("/approvals/:id/deny", post(deny))
+        .route("/ws/approvals", get(approvals_ws))
+}
+
+async fn list_approvals(State(state): State<Arc<AppState>>) -> Json<Vec<Approval>> {
+    Json(state.execution_manager.lock().await.approvals().list())
+}
+
+async fn approve(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+) -> Result<StatusCode, ApiError> {
+    answer(&state, &id, true).await
+}
+
+async fn deny(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+) -> Result<StatusCode, ApiError> {
+    answer(&state, &id, false).await
+}
+
+/// Resolve approval `id` and record the answer in the audit log.
+pub async fn answer(state: &AppState, id: &str, approved: bool) -> Result<StatusCode, ApiError> {
+    let approval = state
+        .execution_manager
+        .lock()
+        .await
+        .answer_approval(id, approved)
+        .map_err(ApiError::not_found)?;
+    state
+        .audit
+        .record(
+            approval.kind.audit_event(),
+            serde_json::json!({
+                "approval": approval.id,
+                "execution": approval.execution_id,
+                "approved": approved,
+                "tools": approval
+                    .tool_calls
+                    .iter()
+                    .map(|c| &c.function.name)
+                    .collect::<Vec<_>>(),
+            }),
+        )
+        .await;
+    Ok(StatusCode::OK)
+}
+
+async fn approvals_ws(
+    ws: WebSocketUpgrade,
+    State(state): State<Arc<AppState>>,
+) -> impl IntoResponse {
+    let mut rx = state.execution_manager.lock().await.approvals().subscribe();
+    ws.on_upgrade(move |mut socket| async move {
+        loop {
+            let event = match rx.recv().await {
+                Ok(event) => event,
+                Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => break,
+            };
+            let Ok(msg) = serde_json::to_string(&event) else {
+                continue;
+            };
+            if socket.send(Message::Text(msg)).await.is_err() {
+                break;
+            }
+        }
+    })
+}
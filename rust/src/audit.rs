@@ -0,0 +1,43 @@
+use chrono::Utc;
+use serde_json::Value;
+use std::path::PathBuf;
+use tokio::io::AsyncWriteExt;
+
+// Security-relevant events, appended as one JSON object per line
+pub struct AuditLog {
+    path: PathBuf,
+}
+
+impl AuditLog {
+    pub fn new(path: PathBuf) -> Self {
+        Self { path }
+    }
+
+    /// Append `event` with its details. Failures are logged, never surfaced to the caller.
+    pub async fn record(&self, event: &str, details: Value) {
+        let mut entry = serde_json::json!({
+            "timestamp": Utc::now().to_rfc3339(),
+            "event": event,
+        });
+        if let (Some(entry), Value::Object(details)) = (entry.as_object_mut(), details) {
+            entry.extend(details);
+        }
+        let mut line = entry.to_string();
+        line.push('\n');
+        if let Err(e) = self.append(line.as_bytes()).await {
+            tracing::error!("Failed to write audit log {}: {}", self.path.display(), e);
+        }
+    }
+
+    async fn append(&self, line: &[u8]) -> std::io::Result<()> {
+        if let Some(parent) = self.path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        let mut file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .await?;
+        file.write_all(line).await
+    }
+}
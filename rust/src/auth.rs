@@ -0,0 +1,300 @@
+use axum::{
+    body::Body,
+    extract::{Request, State},
+    http::{Method, StatusCode, header},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use chrono::Utc;
+use sha2::{Digest, Sha256, digest::Output};
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+use crate::error::{ApiError, ErrorCode};
+use crate::security::{self, SecurityEventKind};
+use crate::server::AppState;
+
+pub const TIMESTAMP_HEADER: &str = "x-kekahyde-timestamp";
+pub const SIGNATURE_HEADER: &str = "x-kekahyde-signature";
+// Signed bodies are buffered to be hashed
+const MAX_SIGNED_BODY: usize = 32 * 1024 * 1024;
+
+/// The configured API key a request was authorized with, added to its extensions.
+#[derive(Clone)]
+pub struct ApiKey(pub String);
+
+/// Signatures seen within the replay window; a signed request is accepted once.
+pub struct ReplayGuard {
+    window_secs: i64,
+    seen: HashMap<String, i64>,
+}
+
+impl ReplayGuard {
+    pub fn new(window_secs: u64) -> Self {
+        Self {
+            window_secs: window_secs as i64,
+            seen: HashMap::new(),
+        }
+    }
+
+    fn check(&mut self, signature: &str, timestamp: i64) -> Result<(), StatusCode> {
+        let now = Utc::now().timestamp();
+        if (now - timestamp).abs() > self.window_secs {
+            return Err(StatusCode::UNAUTHORIZED);
+        }
+        // Anything older is rejected by its timestamp alone
+        self.seen
+            .retain(|_, seen| (now - *seen).abs() <= self.window_secs);
+        if self.seen.insert(signature.to_string(), timestamp).is_some() {
+            return Err(StatusCode::UNAUTHORIZED);
+        }
+        Ok(())
+    }
+}
+
+/// Require `Authorization: Bearer <key>` on mutating requests when API keys are configured.
+/// Reads (status polling, execution status, WebSocket upgrades) stay open.
+pub async fn require_api_key(
+    State(state): State<Arc<AppState>>,
+    request: Request,
+    next: Next,
+) -> Result<Response, ApiError> {
+    if state.api_keys.is_empty()
+        || matches!(
+            *request.method(),
+            Method::GET | Method::HEAD | Method::OPTIONS
+        )
+    {
+        return Ok(next.run(request).await);
+    }
+    authorize(&state, request, next).await
+}
+
+/// Layered on the routes whose reads expose prompts and outputs (exports, transcripts,
+/// conversations), so their `GET`s need a key too. Other methods were already checked by
+/// [`require_api_key`].
+pub async fn require_api_key_on_reads(
+    State(state): State<Arc<AppState>>,
+    request: Request,
+    next: Next,
+) -> Result<Response, ApiError> {
+    if state.api_keys.is_empty() || !matches!(*request.method(), Method::GET | Method::HEAD) {
+        return Ok(next.run(request).await);
+    }
+    authorize(&state, request, next).await
+}
+
+async fn authorize(
+    state: &AppState,
+    mut request: Request,
+    next: Next,
+) -> Result<Response, ApiError> {
+    let token = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(str::trim);
+    let client = security::client_address(&request);
+    let Some(key) = token.and_then(|token| {
+        state
+            .api_keys
+            .iter()
+            .find(|key| constant_time_eq(key, token))
+    }) else {
+        state.security.lock().await.record(
+            SecurityEventKind::AuthFailure,
+            client,
+            format!("Missing or unknown API key for {}", request.uri().path()),
+        );
+        return Err(ApiError::new(
+            StatusCode::UNAUTHORIZED,
+            ErrorCode::Unauthorized,
+            "Missing or unknown API key",
+        ));
+    };
+    request.extensions_mut().insert(ApiKey(key.clone()));
+    let path = request.uri().path().to_string();
+    let request = match &state.replay_guard {
+        Some(guard) => match verify_signature(guard, key, request).await {
+            Ok(request) => request,
+            Err(status) => {
+                let (kind, detail) = if status == StatusCode::PAYLOAD_TOO_LARGE {
+                    (SecurityEventKind::OversizedRequest, "Signed body too large")
+                } else {
+                    (
+                        SecurityEventKind::AuthFailure,
+                        "Invalid, stale or replayed signature",
+                    )
+                };
+                state.security.lock().await.record(
+                    kind,
+                    client,
+                    format!("{} for {}", detail, path),
+                );
+                return Err(ApiError::status(status, detail));
+            }
+        },
+        None => request,
+    };
+    Ok(next.run(request).await)
+}
+
+/// Check `X-Kekahyde-Signature`: hex HMAC-SHA256, keyed with the API key, of
+/// "<timestamp>\n<METHOD>\n<path?query>\n<hex SHA-256 of the body>", where the
+/// timestamp is the `X-Kekahyde-Timestamp` header in Unix seconds.
+async fn verify_signature(
+    guard: &Mutex<ReplayGuard>,
+    key: &str,
+    request: Request,
+) -> Result<Request, StatusCode> {
+    let (timestamp, signature) = {
+        let header = |name: &str| {
+            request
+                .headers()
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(|value| value.trim().to_ascii_lowercase())
+        };
+        (
+            header(TIMESTAMP_HEADER).and_then(|value| value.parse::<i64>().ok()),
+            header(SIGNATURE_HEADER),
+        )
+    };
+    let (Some(timestamp), Some(signature)) = (timestamp, signature) else {
+        return Err(StatusCode::UNAUTHORIZED);
+    };
+
+    let (parts, body) = request.into_parts();
+    let body = axum::body::to_bytes(body, MAX_SIGNED_BODY)
+        .await
+        .map_err(|_| StatusCode::PAYLOAD_TOO_LARGE)?;
+    let target = parts.uri.path_and_query().map_or("/", |p| p.as_str());
+    let message = signing_message(timestamp, parts.method.as_str(), target, &body);
+    let expected = format!("{:x}", hmac_sha256(key.as_bytes(), message.as_bytes()));
+    if !constant_time_eq(&expected, &signature) {
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+    guard.lock().await.check(&signature, timestamp)?;
+    Ok(Request::from_parts(parts, Body::from(body)))
+}
+
+/// Timestamp and signature headers for a request this daemon sends to a peer, in the
+/// form verify_signature checks.
+pub fn sign(key: &str, method: &str, target: &str, body: &[u8]) -> (String, String) {
+    let timestamp = Utc::now().timestamp();
+    let message = signing_message(timestamp, method, target, body);
+    let signature = format!("{:x}", hmac_sha256(key.as_bytes(), message.as_bytes()));
+    (timestamp.to_string(), signature)
+}
+
+fn signing_message(timestamp: i64, method: &str, target: &str, body: &[u8]) -> String {
+    format!(
+        "{}\n{}\n{}\n{:x}",
+        timestamp,
+        method,
+        target,
+        Sha256::digest(body)
+    )
+}
+
+pub fn hmac_sha256(key: &[u8], message: &[u8]) -> Output<Sha256> {
+    const BLOCK: usize = 64;
+    let mut block = [0u8; BLOCK];
+    if key.len() > BLOCK {
+        block[..32].copy_from_slice(&Sha256::digest(key));
+    } else {
+        block[..key.len()].copy_from_slice(key);
+    }
+    let mut inner = Sha256::new();
+    inner.update(block.map(|b| b ^ 0x36));
+    inner.update(message);
+    let mut outer = Sha256::new();
+    outer.update(block.map(|b| b ^ 0x5c));
+    outer.update(inner.finalize());
+    outer.finalize()
+}
+
+/// In read-only mode only reads get through; everything that would submit work or
+/// change state is refused with 403.
+pub async fn enforce_read_only(
+    State(state): State<Arc<AppState>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    if state.read_only
+        && !matches!(
+            *request.method(),
+            Method::GET | Method::HEAD | Method::OPTIONS
+        )
+    {
+        return ApiError::new(
+            StatusCode::FORBIDDEN,
+            ErrorCode::ReadOnly,
+            "Daemon is in read-only mode",
+        )
+        .into_response();
+    }
+    next.run(request).await
+}
+
+// Don't leak how much of a key matched through response timing
+pub fn constant_time_eq(a: impl AsRef<[u8]>, b: impl AsRef<[u8]>) -> bool {
+    let (a, b) = (a.as_ref(), b.as_ref());
+    a.len() == b.len()
+        && a.iter()
+            .zip(b.iter())
+            .fold(0u8, |diff, (x, y)| diff | (x ^ y))
+            == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hmac_sha256_matches_rfc_4231() {
+        let cases: [(&[u8], &[u8], &str); 4] = [
+            (
+                &[0x0b; 20],
+                b"Hi There",
+                "b0344c61d8db38535ca8afceaf0bf12b881dc200c9833da726e9376c2e32cff7",
+            ),
+            (
+                b"Jefe",
+                b"what do ya want for nothing?",
+                "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843",
+            ),
+            // Keys longer than a block are hashed first
+            (
+                &[0xaa; 131],
+                b"Test Using Larger Than Block-Size Key - Hash Key First",
+                "60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54",
+            ),
+            (
+                &[0xaa; 131],
+                b"This is a test using a larger than block-size key and a larger than \
+                  block-size data. The key needs to be hashed before being used by the \
+                  HMAC algorithm.",
+                "9b09ffa71b942fcb27635fbcd5b0e944bfdc63644f0713938a7f51535c3a35e2",
+            ),
+        ];
+        for (key, message, expected) in cases {
+            assert_eq!(format!("{:x}", hmac_sha256(key, message)), expected);
+        }
+    }
+
+    #[test]
+    fn replay_guard_rejects_stale_and_replayed_signatures() {
+        let mut guard = ReplayGuard::new(300);
+        let now = Utc::now().timestamp();
+        assert_eq!(guard.check("a", now - 301), Err(StatusCode::UNAUTHORIZED));
+        assert_eq!(guard.check("b", now + 301), Err(StatusCode::UNAUTHORIZED));
+        assert_eq!(guard.check("c", now - 10), Ok(()));
+        assert_eq!(guard.check("c", now - 10), Err(StatusCode::UNAUTHORIZED));
+        // Another timestamp doesn't make a used signature fresh
+        assert_eq!(guard.check("c", now), Err(StatusCode::UNAUTHORIZED));
+        assert_eq!(guard.check("d", now), Ok(()));
+    }
+}
@@ -0,0 +1,243 @@
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Component, Path, PathBuf};
+
+// Archive layout: MAGIC, manifest length (u64 LE), manifest JSON, then every
+// file's bytes back to back in manifest order.
+const MAGIC: &[u8; 8] = b"KEKABAK1";
+// The manifest is read into memory; real ones are a few KiB
+const MAX_MANIFEST_BYTES: u64 = 64 * 1024 * 1024;
+
+/// What a backup can hold, as (section, location under the data dir).
+const SECTIONS: &[(&str, &str)] = &[
+    ("config", "config.json"),
+    ("personas", "personas.json"),
+    ("templates", "templates.json"),
+    ("memories", "memories.json"),
+    ("conversations", "conversations.json"),
+    ("migrations", "migrations.json"),
+    ("voices", "voices"),
+    ("models", "models"),
+];
+
+#[derive(Serialize, Deserialize)]
+struct Manifest {
+    version: String,
+    created_at: String,
+    files: Vec<ManifestEntry>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ManifestEntry {
+    section: String,
+    // Relative to the data dir, '/'-separated
+    path: String,
+    size: u64,
+    sha256: String,
+}
+
+/// Write an archive of the data dir to `out`. Models are large, so they are opt-in.
+/// Returns the number of files archived.
+pub fn backup(data_dir: &Path, out: &Path, include_models: bool) -> Result<usize, String> {
+    let mut files = Vec::new();
+    for (section, location) in SECTIONS {
+        if *section == "models" && !include_models {
+            continue;
+        }
+        let mut paths = Vec::new();
+        collect_files(&data_dir.join(location), &mut paths)?;
+        for path in paths {
+            let relative = path
+                .strip_prefix(data_dir)
+                .map_err(|e| e.to_string())?
+                .components()
+                .map(|c| c.as_os_str().to_string_lossy())
+                .collect::<Vec<_>>()
+                .join("/");
+            let (size, sha256) = hash_file(&path)?;
+            files.push(ManifestEntry {
+                section: section.to_string(),
+                path: relative,
+                size,
+                sha256,
+            });
+        }
+    }
+
+    let manifest = Manifest {
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        created_at: Utc::now().to_rfc3339(),
+        files,
+    };
+    let manifest_json = serde_json::to_vec(&manifest).map_err(|e| e.to_string())?;
+    let archive =
+        File::create(out).map_err(|e| format!("Failed to create {}: {}", out.display(), e))?;
+    let mut writer = BufWriter::new(archive);
+    let write_err = |e: io::Error| format!("Failed to write {}: {}", out.display(), e);
+    writer.write_all(MAGIC).map_err(write_err)?;
+    writer
+        .write_all(&(manifest_json.len() as u64).to_le_bytes())
+        .map_err(write_err)?;
+    writer.write_all(&manifest_json).map_err(write_err)?;
+    for entry in &manifest.files {
+        let path = data_dir.join(&entry.path);
+        let file =
+            File::open(&path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        // A file that changed since it was hashed would fail verification on restore
+        let copied = io::copy(&mut file.take(entry.size), &mut writer).map_err(write_err)?;
+        if copied != entry.size {
+            return Err(format!("{} changed during backup", path.display()));
+        }
+    }
+    writer.flush().map_err(write_err)?;
+    Ok(manifest.files.len())
+}
+
+/// Restore an archive into the data dir, optionally only the listed sections.
+/// Every file is checked against its manifest hash before any of them replaces the
+/// current one. Returns the number of files restored.
+pub fn restore(data_dir: &Path, archive: &Path, only: Option<&[String]>) -> Result<usize, String> {
+    if let Some(only) = only
+        && let Some(unknown) = only
+            .iter()
+            .find(|s| !SECTIONS.iter().any(|(name, _)| name == s))
+    {
+        return Err(format!("Unknown backup section: {}", unknown));
+    }
+
+    let file =
+        File::open(archive).map_err(|e| format!("Failed to open {}: {}", archive.display(), e))?;
+    let mut reader = BufReader::new(file);
+    let read_err = |e: io::Error| format!("Failed to read {}: {}", archive.display(), e);
+    let mut magic = [0u8; 8];
+    reader.read_exact(&mut magic).map_err(read_err)?;
+    if &magic != MAGIC {
+        return Err(format!("{} is not a kekahyde backup", archive.display()));
+    }
+    let mut len = [0u8; 8];
+    reader.read_exact(&mut len).map_err(read_err)?;
+    let len = u64::from_le_bytes(len);
+    if len > MAX_MANIFEST_BYTES {
+        return Err(format!(
+            "Backup manifest of {} bytes is over the {} byte limit",
+            len, MAX_MANIFEST_BYTES
+        ));
+    }
+    let mut manifest_json = vec![0u8; len as usize];
+    reader.read_exact(&mut manifest_json).map_err(read_err)?;
+    let manifest: Manifest = serde_json::from_slice(&manifest_json)
+        .map_err(|e| format!("Invalid backup manifest: {}", e))?;
+    for entry in &manifest.files {
+        check_entry(entry)?;
+    }
+
+    // Files staged so far, with where each goes; removed if a later one fails
+    let mut staged = Vec::new();
+    let result = stage_files(
+        data_dir,
+        &manifest,
+        only,
+        &mut reader,
+        &mut staged,
+        read_err,
+    );
+    if let Err(e) = result {
+        for (staged, _) in &staged {
+            let _ = std::fs::remove_file(staged);
+        }
+        return Err(e);
+    }
+    for (staged, dest) in &staged {
+        std::fs::rename(staged, dest).map_err(|e| e.to_string())?;
+    }
+    Ok(staged.len())
+}
+
+/// Refuse entries that would land outside their section's location in the data dir.
+fn check_entry(entry: &ManifestEntry) -> Result<(), String> {
+    let relative = Path::new(&entry.path);
+    if !relative
+        .components()
+        .all(|c| matches!(c, Component::Normal(_)))
+    {
+        return Err(format!("Refusing to restore unsafe path {}", entry.path));
+    }
+    let Some((_, location)) = SECTIONS.iter().find(|(name, _)| *name == entry.section) else {
+        return Err(format!("Unknown backup section: {}", entry.section));
+    };
+    if !relative.starts_with(location) {
+        return Err(format!(
+            "Refusing to restore {} outside the {} section",
+            entry.path, entry.section
+        ));
+    }
+    Ok(())
+}
+
+// Staged next to each destination, so nothing is replaced until every file checks out
+fn stage_files(
+    data_dir: &Path,
+    manifest: &Manifest,
+    only: Option<&[String]>,
+    reader: &mut impl Read,
+    staged: &mut Vec<(PathBuf, PathBuf)>,
+    read_err: impl Fn(io::Error) -> String,
+) -> Result<(), String> {
+    for entry in &manifest.files {
+        let mut contents = (&mut *reader).take(entry.size);
+        let selected = only.is_none_or(|only| only.contains(&entry.section));
+        if !selected {
+            io::copy(&mut contents, &mut io::sink()).map_err(&read_err)?;
+            continue;
+        }
+        let dest = data_dir.join(&entry.path);
+        if let Some(parent) = dest.parent() {
+            std::fs::create_dir_all(parent).map_err(|e| e.to_string())?;
+        }
+        let mut path = dest.clone().into_os_string();
+        path.push(".restore");
+        let path = PathBuf::from(path);
+        let mut hasher = Sha256::new();
+        let mut out = BufWriter::new(File::create(&path).map_err(|e| e.to_string())?);
+        staged.push((path, dest));
+        let mut buf = vec![0u8; 64 * 1024];
+        loop {
+            let n = contents.read(&mut buf).map_err(&read_err)?;
+            if n == 0 {
+                break;
+            }
+            hasher.update(&buf[..n]);
+            out.write_all(&buf[..n]).map_err(|e| e.to_string())?;
+        }
+        out.flush().map_err(|e| e.to_string())?;
+        if format!("{:x}", hasher.finalize()) != entry.sha256 {
+            return Err(format!("Checksum mismatch for {}", entry.path));
+        }
+    }
+    Ok(())
+}
+
+fn collect_files(path: &Path, files: &mut Vec<PathBuf>) -> Result<(), String> {
+    if path.is_file() {
+        files.push(path.to_path_buf());
+    } else if path.is_dir() {
+        let entries = std::fs::read_dir(path)
+            .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        for entry in entries {
+            collect_files(&entry.map_err(|e| e.to_string())?.path(), files)?;
+        }
+    }
+    Ok(())
+}
+
+fn hash_file(path: &Path) -> Result<(u64, String), String> {
+    let mut file =
+        File::open(path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+    let mut hasher = Sha256::new();
+    let size = io::copy(&mut file, &mut hasher)
+        .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+    Ok((size, format!("{:x}", hasher.finalize())))
+}
@@ -0,0 +1,147 @@
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+use crate::config::CacheConfig;
+use crate::model::{LiveParams, PromptInput, Turn};
+
+#[derive(Serialize, Debug, Clone, Copy)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub entries: usize,
+}
+
+struct CacheEntry {
+    response: String,
+    stored_at: Instant,
+}
+
+// LRU cache of completions keyed by a hash of the prompt and sampling params
+pub struct ResponseCache {
+    capacity: usize,
+    ttl: Duration,
+    entries: HashMap<String, CacheEntry>,
+    // Least recently used key first
+    order: VecDeque<String>,
+    hits: u64,
+    misses: u64,
+}
+
+impl ResponseCache {
+    pub fn new(config: &CacheConfig) -> Self {
+        Self {
+            capacity: config.capacity.max(1),
+            ttl: Duration::from_secs(config.ttl_secs),
+            entries: HashMap::new(),
+            order: VecDeque::new(),
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    pub fn key(input: &PromptInput, params: &LiveParams) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(input.system.as_deref().unwrap_or_default().as_bytes());
+        hasher.update([0]);
+        hasher.update(input.user.as_bytes());
+        hasher.update([0]);
+        for image in &input.images {
+            hasher.update(Sha256::digest(image));
+        }
+        if !input.tools.is_empty() {
+            hasher.update(serde_json::to_vec(&input.tools).unwrap_or_default());
+        }
+        for memory in &input.memories {
+            hasher.update(memory.as_bytes());
+            hasher.update([0]);
+        }
+        for turn in &input.turns {
+            let (role, content) = match turn {
+                Turn::Assistant(content) => (b'a', content),
+                Turn::ToolResponse(content) => (b't', content),
+            };
+            hasher.update([role]);
+            hasher.update(content.as_bytes());
+            hasher.update([0]);
+        }
+        hasher.update(params.temperature.to_bits().to_le_bytes());
+        hasher.update((params.max_tokens as u64).to_le_bytes());
+        hasher.update(params.seed.to_le_bytes());
+        format!("{:x}", hasher.finalize())
+    }
+
+    pub fn get(&mut self, key: &str) -> Option<String> {
+        let fresh = match self.entries.get(key) {
+            Some(entry) => entry.stored_at.elapsed() < self.ttl,
+            None => {
+                self.misses += 1;
+                return None;
+            }
+        };
+        if !fresh {
+            self.remove(key);
+            self.misses += 1;
+            return None;
+        }
+        self.touch(key);
+        self.hits += 1;
+        self.entries.get(key).map(|entry| entry.response.clone())
+    }
+
+    pub fn insert(&mut self, key: String, response: String) {
+        if self.entries.contains_key(&key) {
+            self.touch(&key);
+        } else {
+            self.order.push_back(key.clone());
+        }
+        self.entries.insert(
+            key,
+            CacheEntry {
+                response,
+                stored_at: Instant::now(),
+            },
+        );
+        while self.entries.len() > self.capacity {
+            let Some(oldest) = self.order.pop_front() else {
+                break;
+            };
+            self.entries.remove(&oldest);
+        }
+    }
+
+    /// Drop entries past their TTL. Returns how many were removed.
+    pub fn purge_expired(&mut self) -> usize {
+        let expired: Vec<String> = self
+            .entries
+            .iter()
+            .filter(|(_, entry)| entry.stored_at.elapsed() >= self.ttl)
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in &expired {
+            self.remove(key);
+        }
+        expired.len()
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits,
+            misses: self.misses,
+            entries: self.entries.len(),
+        }
+    }
+
+    fn touch(&mut self, key: &str) {
+        if let Some(pos) = self.order.iter().position(|k| k == key) {
+            let key = self.order.remove(pos).unwrap();
+            self.order.push_back(key);
+        }
+    }
+
+    fn remove(&mut self, key: &str) {
+        self.entries.remove(key);
+        self.order.retain(|k| k != key);
+    }
+}
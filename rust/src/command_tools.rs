@@ -0,0 +1,256 @@
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::time::{Duration, Instant};
+use tokio::io::AsyncReadExt;
+use tokio::process::Command;
+
+use crate::audit::AuditLog;
+use crate::config::{CommandToolConfig, ToolSandboxConfig};
+use crate::tools::{FunctionDefinition, ToolCall, ToolDefinition};
+
+// Programs run without a shell, so only these reach them from the environment
+const TOOL_PATH: &str = "/usr/local/bin:/usr/bin:/bin";
+
+/// Output of a command tool the daemon ran for a tool call.
+#[derive(Serialize, Debug, Clone)]
+pub struct ToolResult {
+    pub tool_call_id: String,
+    pub name: String,
+    /// Captured stdout
+    pub output: String,
+    /// Absent when the tool didn't start, timed out or was killed
+    pub exit_code: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    pub duration_ms: f64,
+}
+
+// Command tools declared in the config, with the sandbox policy they run under
+pub struct CommandTools {
+    tools: HashMap<String, CommandToolConfig>,
+    sandbox: ToolSandboxConfig,
+    work_dir: PathBuf,
+}
+
+impl CommandTools {
+    /// Check every tool against the policy. `work_dir` is the scratch directory tools
+    /// run in.
+    pub fn new(
+        tools: Vec<CommandToolConfig>,
+        sandbox: ToolSandboxConfig,
+        work_dir: PathBuf,
+    ) -> Result<Self, String> {
+        if !tools.is_empty() && !sandbox.allow_network && !cfg!(target_os = "linux") {
+            return Err(
+                "Command tools need Linux to run without network access; set tool_sandbox.allow_network to run them here"
+                    .to_string(),
+            );
+        }
+        let mut by_name = HashMap::new();
+        for tool in tools {
+            if tool.name.is_empty()
+                || !tool
+                    .name
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+            {
+                return Err(format!("Invalid command tool name {:?}", tool.name));
+            }
+            if !Path::new(&tool.binary).is_absolute() {
+                return Err(format!(
+                    "Command tool {} must use an absolute binary path",
+                    tool.name
+                ));
+            }
+            if !sandbox.allowed_binaries.contains(&tool.binary) {
+                return Err(format!(
+                    "Command tool {} runs {}, which is not in tool_sandbox.allowed_binaries",
+                    tool.name, tool.binary
+                ));
+            }
+            if by_name.contains_key(&tool.name) {
+                return Err(format!("Duplicate command tool {}", tool.name));
+            }
+            by_name.insert(tool.name.clone(), tool);
+        }
+        Ok(Self {
+            tools: by_name,
+            sandbox,
+            work_dir,
+        })
+    }
+
+    /// Function definitions of the named tools, to offer the model.
+    pub fn definitions(&self, names: &[String]) -> Result<Vec<ToolDefinition>, String> {
+        names
+            .iter()
+            .map(|name| {
+                let tool = self
+                    .tools
+                    .get(name)
+                    .ok_or_else(|| format!("Unknown command tool: {}", name))?;
+                Ok(ToolDefinition {
+                    kind: "function".to_string(),
+                    function: FunctionDefinition {
+                        name: tool.name.clone(),
+                        description: tool.description.clone(),
+                        parameters: tool.parameters.clone(),
+                    },
+                })
+            })
+            .collect()
+    }
+
+    /// Run every call that names one of the `offered` command tools, auditing each
+    /// one. Calls to tools the client defined itself are left for the client.
+    pub async fn run_all(
+        &self,
+        calls: &[ToolCall],
+        offered: &[String],
+        audit: &AuditLog,
+    ) -> Vec<ToolResult> {
+        let mut results = Vec::new();
+        for call in calls {
+            let Some(tool) = self
+                .tools
+                .get(&call.function.name)
+                .filter(|tool| offered.contains(&tool.name))
+            else {
+                continue;
+            };
+            let result = self.run(tool, call).await;
+            audit
+                .record(
+                    "tool_call",
+                    serde_json::json!({
+                        "tool": tool.name,
+                        "binary": tool.binary,
+                        "arguments": call.function.arguments,
+                        "exit_code": result.exit_code,
+                        "error": result.error,
+                        "output_bytes": result.output.len(),
+                        "duration_ms": result.duration_ms,
+                    }),
+                )
+                .await;
+            results.push(result);
+        }
+        results
+    }
+
+    async fn run(&self, tool: &CommandToolConfig, call: &ToolCall) -> ToolResult {
+        let started = Instant::now();
+        let outcome = match render_args(tool, &call.function.arguments) {
+            Ok(args) => self.execute(tool, &args).await,
+            Err(e) => Err(e),
+        };
+        let (output, exit_code, error) = match outcome {
+            Ok((output, exit_code)) => (output, exit_code, None),
+            Err(e) => {
+                tracing::warn!("Command tool {} failed: {}", tool.name, e);
+                (String::new(), None, Some(e))
+            }
+        };
+        ToolResult {
+            tool_call_id: call.id.clone(),
+            name: tool.name.clone(),
+            output,
+            exit_code,
+            error,
+            duration_ms: started.elapsed().as_secs_f64() * 1000.0,
+        }
+    }
+
+    async fn execute(
+        &self,
+        tool: &CommandToolConfig,
+        args: &[String],
+    ) -> Result<(String, Option<i32>), String> {
+        tokio::fs::create_dir_all(&self.work_dir)
+            .await
+            .map_err(|e| format!("Failed to create {}: {}", self.work_dir.display(), e))?;
+        let mut command = if self.sandbox.allow_network {
+            Command::new(&tool.binary)
+        } else {
+            // A fresh user and network namespace: only a down loopback interface
+            let mut command = Command::new("unshare");
+            command
+                .args(["--user", "--map-root-user", "--net", "--"])
+                .arg(&tool.binary);
+            command
+        };
+        let mut child = command
+            .args(args)
+            .env_clear()
+            .env("PATH", TOOL_PATH)
+            .current_dir(&self.work_dir)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .kill_on_drop(true)
+            .spawn()
+            .map_err(|e| format!("Failed to start {}: {}", tool.binary, e))?;
+        let mut stdout = child.stdout.take().ok_or("No stdout")?;
+
+        let limit = self.sandbox.max_output_bytes;
+        let run = async {
+            let mut output = Vec::new();
+            (&mut stdout)
+                .take(limit as u64 + 1)
+                .read_to_end(&mut output)
+                .await
+                .map_err(|e| format!("Failed to read output: {}", e))?;
+            if output.len() > limit {
+                output.truncate(limit);
+                let _ = child.start_kill();
+            }
+            let status = child.wait().await.map_err(|e| e.to_string())?;
+            Ok::<_, String>((output, status))
+        };
+        let timeout = Duration::from_secs(tool.timeout_secs.unwrap_or(self.sandbox.timeout_secs));
+        // Dropping the child on timeout kills it
+        let (output, status) = tokio::time::timeout(timeout, run)
+            .await
+            .map_err(|_| format!("Timed out after {}s", timeout.as_secs()))??;
+        Ok((String::from_utf8_lossy(&output).into_owned(), status.code()))
+    }
+}
+
+/// `tool.args` with each `{name}` replaced by the call's argument of that name.
+fn render_args(tool: &CommandToolConfig, arguments: &str) -> Result<Vec<String>, String> {
+    let arguments: Value =
+        serde_json::from_str(arguments).map_err(|e| format!("Invalid arguments: {}", e))?;
+    let mut rendered = Vec::with_capacity(tool.args.len());
+    for template in &tool.args {
+        let mut arg = String::new();
+        let mut rest = template.as_str();
+        while let Some(start) = rest.find('{') {
+            let Some(len) = rest[start..].find('}') else {
+                break;
+            };
+            let name = &rest[start + 1..start + len];
+            let value = match arguments.get(name) {
+                Some(Value::String(s)) => s.clone(),
+                Some(value @ (Value::Number(_) | Value::Bool(_))) => value.to_string(),
+                Some(_) => return Err(format!("Argument {} must be a string or number", name)),
+                None => return Err(format!("Missing argument {}", name)),
+            };
+            // Keep the model from slipping in options the config didn't declare
+            if value.starts_with('-') || value.contains('\0') {
+                return Err(format!(
+                    "Argument {} may not start with '-' or contain NUL",
+                    name
+                ));
+            }
+            arg.push_str(&rest[..start]);
+            arg.push_str(&value);
+            rest = &rest[start + len + 1..];
+        }
+        arg.push_str(rest);
+        rendered.push(arg);
+    }
+    Ok(rendered)
+}
@@ -0,0 +1,641 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::env;
+use std::path::PathBuf;
+
+use llama_cpp_2::context::params::RopeScalingType;
+use llama_cpp_2::llama_backend::NumaStrategy;
+
+use crate::model::DEFAULT_SYSTEM_PROMPT;
+
+// Daemon configuration, read from $KEKAHYDE_CONFIG or <data dir>/config.json.
+// Every field has a default so a missing file means "stock behaviour".
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct Config {
+    /// Address the HTTP API binds to. Use 0.0.0.0:3000 (with `tls`) to serve the LAN.
+    pub listen: String,
+    /// Serve HTTPS/WSS instead of plaintext.
+    pub tls: Option<TlsConfig>,
+    /// Bearer tokens accepted on mutating routes. Empty leaves the API open.
+    pub api_keys: Vec<String>,
+    /// Also require mutating requests to be signed with their API key (needs `api_keys`).
+    pub request_signing: Option<SigningConfig>,
+    /// Source-IP allow/deny lists for the user API and the /admin routes.
+    pub ip_filter: Option<IpFilterConfig>,
+    /// Serve reads only (status, executions, personas, memories); reject everything else.
+    pub read_only: bool,
+    /// Per-client limits; clients are told when to retry via Retry-After.
+    pub rate_limit: Option<RateLimitConfig>,
+    /// Executions allowed to wait behind the running ones before /execution/start returns 503.
+    pub queue_depth: usize,
+    /// Executions run side by side. Local generations still take turns on the model, so
+    /// the extra slots go to executions offloaded to peers or waiting on tool commands.
+    pub max_concurrent_executions: usize,
+    /// Hold every /execution/start in PendingApproval until it is approved.
+    pub approve_executions: bool,
+    /// Keep finished executions in SQLite under the data dir so they survive a restart.
+    pub persist_executions: bool,
+    /// What startup does with saved executions that were running when the daemon died.
+    pub interrupted_executions: InterruptedExecutions,
+    /// How many finished executions are kept, and for how long.
+    pub retention: RetentionConfig,
+    /// Seconds a SIGINT/SIGTERM waits for in-flight generations before cancelling them.
+    pub shutdown_grace_secs: u64,
+    /// How long generation routes may take before answering 504.
+    pub timeouts: TimeoutConfig,
+    /// Largest request body accepted, in bytes.
+    pub max_body_bytes: usize,
+    /// Language of API error messages for requests without Accept-Language
+    /// (`en`, `es`, `fr` or `de`).
+    pub locale: Option<String>,
+    /// Built web UI served at `/`. Relative paths resolve against the working directory.
+    pub frontend_dir: String,
+    /// Local-time windows in which the queue is paused for housekeeping.
+    pub maintenance: Vec<MaintenanceWindow>,
+    pub model: ModelConfig,
+    /// Optional cross-encoder GGUF (e.g. bge-reranker) used by /v1/rerank.
+    pub reranker_path: Option<String>,
+    /// Optional embedding GGUF (e.g. nomic-embed) so embeddings don't run on the chat model.
+    pub embedding_path: Option<String>,
+    /// Optional piper text-to-speech stage for GET /execution/:id/audio.
+    pub tts: Option<TtsConfig>,
+    /// Optional LRU cache of completions for repeated prompts.
+    pub cache: Option<CacheConfig>,
+    /// Label generated output with model and version metadata.
+    pub provenance: Option<ProvenanceMode>,
+    /// Opt-in: remember facts about the user across prompts.
+    pub memory: Option<MemoryConfig>,
+    /// External programs the model may call as tools, run under `tool_sandbox`.
+    pub command_tools: Vec<CommandToolConfig>,
+    pub tool_sandbox: ToolSandboxConfig,
+    /// Limits for executions started in agent mode.
+    pub agent: AgentConfig,
+    /// Read-only conversation sharing with other kekahyde nodes on the LAN.
+    pub sharing: SharingConfig,
+    /// Opt-in: keep personas in step with other devices through a shared folder.
+    pub sync: Option<SyncConfig>,
+    /// Opt-in: a daily self-check, reported at GET /admin/last-selfcheck.
+    pub selfcheck: Option<SelfcheckConfig>,
+    /// Peers that prompts with `allow_hybrid_compute` are offloaded to.
+    pub hybrid: HybridConfig,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct ModelConfig {
+    /// Map the model file into memory instead of reading it up front.
+    pub use_mmap: bool,
+    /// Pin the model weights in RAM so the OS can't swap them out.
+    pub use_mlock: bool,
+    pub numa: NumaMode,
+    /// Inference threads; defaults to the number of logical CPUs.
+    pub threads: Option<usize>,
+    /// Layers to offload to the GPU when built with a GPU backend.
+    pub n_gpu_layers: Option<u32>,
+    /// Context window in tokens. Raise together with `rope` on models that support it.
+    pub context_size: u32,
+    /// Longest rendered prompt accepted, in tokens. Defaults to leaving a quarter of the
+    /// context for the reply.
+    pub max_prompt_tokens: Option<usize>,
+    pub rope: RopeConfig,
+    /// Multimodal projector (mmproj GGUF) for LLaVA-style vision models.
+    pub mmproj_path: Option<String>,
+    /// System prompt used when neither the request, persona nor session sets one.
+    pub system_prompt: String,
+    /// Prompt-eval batch size for interactive requests. Smaller batches trade
+    /// throughput for an earlier first token; unset evaluates in full-size batches.
+    pub latency_batch: Option<u32>,
+}
+
+impl Default for ModelConfig {
+    fn default() -> Self {
+        Self {
+            use_mmap: true,
+            use_mlock: false,
+            numa: NumaMode::Disabled,
+            threads: None,
+            n_gpu_layers: None,
+            context_size: 2048,
+            max_prompt_tokens: None,
+            rope: RopeConfig::default(),
+            mmproj_path: None,
+            system_prompt: DEFAULT_SYSTEM_PROMPT.to_string(),
+            latency_batch: None,
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            listen: "127.0.0.1:3000".to_string(),
+            tls: None,
+            api_keys: Vec::new(),
+            request_signing: None,
+            ip_filter: None,
+            read_only: false,
+            rate_limit: None,
+            queue_depth: 16,
+            max_concurrent_executions: 1,
+            approve_executions: false,
+            persist_executions: true,
+            interrupted_executions: InterruptedExecutions::Fail,
+            retention: RetentionConfig::default(),
+            shutdown_grace_secs: 30,
+            timeouts: TimeoutConfig::default(),
+            max_body_bytes: 8 * 1024 * 1024,
+            locale: None,
+            frontend_dir: "frontend/out".to_string(),
+            maintenance: Vec::new(),
+            model: ModelConfig::default(),
+            reranker_path: None,
+            embedding_path: None,
+            tts: None,
+            cache: None,
+            provenance: None,
+            memory: None,
+            command_tools: Vec::new(),
+            tool_sandbox: ToolSandboxConfig::default(),
+            agent: AgentConfig::default(),
+            sharing: SharingConfig::default(),
+            sync: None,
+            selfcheck: None,
+            hybrid: HybridConfig::default(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct RateLimitConfig {
+    /// Mutating requests per client per minute.
+    pub requests_per_minute: Option<u32>,
+    /// Generation budget per client per minute, charged as the requested max_tokens.
+    pub tokens_per_minute: Option<u64>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct MemoryConfig {
+    /// Most memories added to a single prompt.
+    pub max_injected: usize,
+}
+
+impl Default for MemoryConfig {
+    fn default() -> Self {
+        Self { max_injected: 5 }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct CommandToolConfig {
+    /// Function name the model calls the tool by.
+    pub name: String,
+    #[serde(default)]
+    pub description: String,
+    /// JSON Schema of the arguments object.
+    #[serde(default)]
+    pub parameters: Value,
+    /// Absolute path of the program; must be listed in `tool_sandbox.allowed_binaries`.
+    pub binary: String,
+    /// Arguments passed as-is (no shell), with `{name}` replaced by that argument's value.
+    #[serde(default)]
+    pub args: Vec<String>,
+    /// Overrides `tool_sandbox.timeout_secs`.
+    pub timeout_secs: Option<u64>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct ToolSandboxConfig {
+    /// Programs command tools may run. A tool whose binary isn't listed fails startup.
+    pub allowed_binaries: Vec<String>,
+    pub timeout_secs: u64,
+    /// Stdout beyond this many bytes is cut off and the tool killed.
+    pub max_output_bytes: usize,
+    /// Let tools reach the network. Off, they run in an empty network namespace
+    /// (Linux only, via `unshare`).
+    pub allow_network: bool,
+}
+
+impl Default for ToolSandboxConfig {
+    fn default() -> Self {
+        Self {
+            allowed_binaries: Vec::new(),
+            timeout_secs: 10,
+            max_output_bytes: 64 * 1024,
+            allow_network: false,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct AgentConfig {
+    /// Most model turns one agent run may take; also the default.
+    pub max_steps: usize,
+    /// Most tool runs one agent run may make; also the default.
+    pub max_tool_calls: usize,
+    /// Most prompt plus generated tokens one agent run may use over all its steps;
+    /// also the default.
+    pub max_tokens: usize,
+    /// Seconds a run waits for a human to approve its tool calls before failing.
+    pub approval_timeout_secs: u64,
+    /// Profile applied when the request doesn't name one.
+    pub default_profile: Option<String>,
+    pub profiles: HashMap<String, AgentProfile>,
+}
+
+impl Default for AgentConfig {
+    fn default() -> Self {
+        Self {
+            max_steps: 8,
+            max_tool_calls: 16,
+            max_tokens: 32768,
+            approval_timeout_secs: 900,
+            default_profile: None,
+            profiles: HashMap::new(),
+        }
+    }
+}
+
+/// Which tools agent runs under this profile may use unattended.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct AgentProfile {
+    /// Tools that may not be offered to the model at all.
+    pub deny_tools: Vec<String>,
+    /// Tools that only run once a human approves the call; `*` covers every tool.
+    pub require_approval: Vec<String>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct SharingConfig {
+    /// How this node is named on the nodes it shares with.
+    pub node_name: String,
+    /// Take conversations shared by other nodes; only with `api_keys` set, so a
+    /// sender has to hold one.
+    pub accept: bool,
+    /// Nodes conversations can be shared to.
+    pub peers: Vec<SharingPeer>,
+    /// Nodes conversations are accepted from, each known by the one of `api_keys` it
+    /// shares with.
+    pub senders: Vec<SharingSender>,
+}
+
+impl Default for SharingConfig {
+    fn default() -> Self {
+        Self {
+            node_name: "kekahyde".to_string(),
+            accept: false,
+            peers: Vec::new(),
+            senders: Vec::new(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct SharingSender {
+    /// Shown as `shared_from` on the conversations it shares.
+    pub name: String,
+    /// The one of `api_keys` this node sends.
+    pub api_key: String,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct SharingPeer {
+    pub name: String,
+    /// Base URL of the peer's API, e.g. `http://192.168.1.20:3000`.
+    pub url: String,
+    /// One of the peer's `api_keys`; shares are also signed with it.
+    pub api_key: String,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct SyncConfig {
+    /// Folder every device can read and write, e.g. a mounted WebDAV share.
+    pub folder: String,
+    /// How this device is named in the sync file.
+    #[serde(default = "default_device_name")]
+    pub device_name: String,
+    /// Sync on this interval as well as on POST /sync; 0 syncs only on request.
+    #[serde(default)]
+    pub interval_secs: u64,
+}
+
+fn default_device_name() -> String {
+    "kekahyde".to_string()
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct SelfcheckConfig {
+    /// Local time the check runs at each day, "HH:MM".
+    pub at: String,
+    /// Free space on the data dir's volume below which the disk check fails.
+    pub min_free_disk_mb: u64,
+    /// Size at which audit.log or usage.log fails the log check.
+    pub max_log_mb: u64,
+}
+
+impl Default for SelfcheckConfig {
+    fn default() -> Self {
+        Self {
+            at: "04:00".to_string(),
+            min_free_disk_mb: 1024,
+            max_log_mb: 100,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct HybridConfig {
+    /// `host:port` of each machine running `kekahyde peer`.
+    pub peers: Vec<String>,
+    /// Address `kekahyde peer` listens on; use `0.0.0.0:8081` to take prompts from
+    /// other machines.
+    pub listen: String,
+    /// How the peer for each offloaded prompt is picked.
+    pub selection: PeerSelection,
+    /// How long each peer gets to answer before the next one is tried.
+    pub peer_timeout_secs: u64,
+    /// Pre-shared key the coordinator and its peers prove to each other on every
+    /// connection. A peer with a key set only answers coordinators holding it.
+    pub key: Option<String>,
+    /// Public keys of peers by address, as `kekahyde peer` prints them. Results from
+    /// a peer listed here are only trusted with its signature.
+    pub peer_keys: HashMap<String, String>,
+}
+
+impl Default for HybridConfig {
+    fn default() -> Self {
+        Self {
+            peers: vec!["127.0.0.1:8081".to_string()],
+            listen: "127.0.0.1:8081".to_string(),
+            selection: PeerSelection::First,
+            peer_timeout_secs: 120,
+            key: None,
+            peer_keys: HashMap::new(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum PeerSelection {
+    /// Always the first peer listed
+    First,
+    /// Each peer in turn
+    RoundRobin,
+    /// The peer with the fewest offloads from this daemon in flight
+    LeastLoaded,
+    /// The peer that answered fastest lately; peers not heard from yet are tried first
+    LowestLatency,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct TimeoutConfig {
+    pub run_prompt_secs: u64,
+    /// Applies to /v1/embeddings and /v1/rerank.
+    pub embeddings_secs: u64,
+    pub benchmark_secs: u64,
+    /// Text-to-speech rendering for /execution/:id/audio.
+    pub audio_secs: u64,
+}
+
+impl Default for TimeoutConfig {
+    fn default() -> Self {
+        Self {
+            run_prompt_secs: 120,
+            embeddings_secs: 60,
+            benchmark_secs: 600,
+            audio_secs: 120,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct RetentionConfig {
+    /// Finished executions kept at most; the oldest go first. 0 keeps any number.
+    pub max_count: usize,
+    /// Hours after finishing that an execution is forgotten. 0 keeps them indefinitely.
+    pub max_age_hours: u64,
+    /// How often the collector runs; 0 leaves it to maintenance windows and DELETE.
+    pub gc_interval_secs: u64,
+}
+
+impl Default for RetentionConfig {
+    fn default() -> Self {
+        Self {
+            max_count: 1000,
+            max_age_hours: 24,
+            gc_interval_secs: 300,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MaintenanceWindow {
+    /// Local start time, "HH:MM".
+    pub start: String,
+    #[serde(default = "default_maintenance_minutes")]
+    pub duration_mins: u32,
+    /// Weekdays the window applies on ("mon", "tue", ...). Empty means every day.
+    #[serde(default)]
+    pub days: Vec<String>,
+}
+
+fn default_maintenance_minutes() -> u32 {
+    30
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct IpFilterConfig {
+    pub user: IpListConfig,
+    pub admin: IpListConfig,
+}
+
+/// Addresses or CIDR blocks. Deny wins; an empty allow list admits everyone else.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct IpListConfig {
+    pub allow: Vec<String>,
+    pub deny: Vec<String>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct SigningConfig {
+    /// How far a request's timestamp may drift from the daemon's clock, in seconds.
+    /// Signatures are remembered this long so each is accepted once.
+    pub window_secs: u64,
+}
+
+impl Default for SigningConfig {
+    fn default() -> Self {
+        Self { window_secs: 300 }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct TlsConfig {
+    /// PEM certificate chain.
+    pub cert_path: String,
+    /// PEM private key.
+    pub key_path: String,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct TtsConfig {
+    /// piper executable, looked up on PATH unless absolute.
+    pub piper_path: String,
+    /// Default voice, installed as <data dir>/voices/<voice>.onnx.
+    pub voice: String,
+    /// Where to fetch the default voice from when it isn't installed.
+    pub voice_url: Option<String>,
+}
+
+impl Default for TtsConfig {
+    fn default() -> Self {
+        Self {
+            piper_path: "piper".to_string(),
+            voice: "en_US-lessac-medium".to_string(),
+            voice_url: Some(
+                "https://huggingface.co/rhasspy/piper-voices/resolve/main/en/en_US/lessac/medium/en_US-lessac-medium.onnx"
+                    .to_string(),
+            ),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct CacheConfig {
+    /// Maximum number of cached completions.
+    pub capacity: usize,
+    /// Seconds a cached completion stays valid.
+    pub ttl_secs: u64,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            capacity: 128,
+            ttl_secs: 600,
+        }
+    }
+}
+
+/// RoPE overrides. Anything left unset falls back to the values stored in the GGUF.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct RopeConfig {
+    pub scaling: Option<RopeScaling>,
+    pub freq_base: Option<f32>,
+    pub freq_scale: Option<f32>,
+    pub yarn_ext_factor: Option<f32>,
+    pub yarn_attn_factor: Option<f32>,
+    pub yarn_beta_fast: Option<f32>,
+    pub yarn_beta_slow: Option<f32>,
+    pub yarn_orig_ctx: Option<u32>,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum RopeScaling {
+    None,
+    Linear,
+    Yarn,
+}
+
+impl From<RopeScaling> for RopeScalingType {
+    fn from(scaling: RopeScaling) -> Self {
+        match scaling {
+            RopeScaling::None => RopeScalingType::None,
+            RopeScaling::Linear => RopeScalingType::Linear,
+            RopeScaling::Yarn => RopeScalingType::Yarn,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum NumaMode {
+    Disabled,
+    Distribute,
+    Isolate,
+    Numactl,
+    Mirror,
+}
+
+impl From<NumaMode> for NumaStrategy {
+    fn from(mode: NumaMode) -> Self {
+        match mode {
+            NumaMode::Disabled => NumaStrategy::DISABLED,
+            NumaMode::Distribute => NumaStrategy::DISTRIBUTE,
+            NumaMode::Isolate => NumaStrategy::ISOLATE,
+            NumaMode::Numactl => NumaStrategy::NUMACTL,
+            NumaMode::Mirror => NumaStrategy::MIRROR,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum InterruptedExecutions {
+    /// End them `Failed` with "interrupted"
+    Fail,
+    /// Queue plain text executions again; the rest still fail
+    Requeue,
+}
+
+/// Where provenance metadata is attached to generated output.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ProvenanceMode {
+    /// `X-Kekahyde-Provenance` header on /run_prompt, `provenance` field on execution status
+    Header,
+    /// Machine-readable footer appended to the response text
+    Footer,
+    Both,
+}
+
+pub fn data_dir() -> PathBuf {
+    let home = env::var("HOME")
+        .or_else(|_| env::var("USERPROFILE"))
+        .expect("HOME or USERPROFILE not set");
+    PathBuf::from(home).join(".local/share/com.kekahyde.dev")
+}
+
+/// Where downloaded GGUF models are kept.
+pub fn models_dir() -> PathBuf {
+    data_dir().join("models")
+}
+
+/// `KEKAHYDE_CONFIG` when set, otherwise config.json in the data dir.
+pub fn config_path() -> PathBuf {
+    env::var("KEKAHYDE_CONFIG")
+        .map(PathBuf::from)
+        .unwrap_or_else(|_| data_dir().join("config.json"))
+}
+
+impl Config {
+    pub fn load() -> Result<Self, String> {
+        let path = config_path();
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        let data = std::fs::read_to_string(&path)
+            .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        serde_json::from_str(&data).map_err(|e| format!("Invalid config {}: {}", path.display(), e))
+    }
+}
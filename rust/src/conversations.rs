@@ -0,0 +1,583 @@
+use axum::{
+    Router,
+    extract::{Extension, Path, Query, State},
+    http::{StatusCode, header},
+    response::{IntoResponse, Json, Response},
+    routing::{get, post},
+};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use uuid::Uuid;
+
+use crate::auth::{self, SIGNATURE_HEADER, TIMESTAMP_HEADER};
+use crate::deprecation::API_PREFIX;
+use crate::error::{ApiError, ErrorCode};
+use crate::server::AppState;
+
+const ROLES: [&str; 4] = ["system", "user", "assistant", "tool"];
+const SHARE_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Message {
+    /// `system`, `user`, `assistant` or `tool`
+    pub role: String,
+    pub content: String,
+    #[serde(default)]
+    pub created_at: String,
+}
+
+/// A named chat history shared by every client of the daemon.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Conversation {
+    pub id: String,
+    pub name: String,
+    pub created_at: String,
+    pub updated_at: String,
+    /// Node that shared this read-only copy; unset for conversations made here
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub shared_from: Option<String>,
+    pub messages: Vec<Message>,
+}
+
+/// A conversation without its messages, for listings.
+#[derive(Serialize)]
+struct ConversationSummary {
+    id: String,
+    name: String,
+    created_at: String,
+    updated_at: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    shared_from: Option<String>,
+    message_count: usize,
+}
+
+#[derive(Deserialize)]
+struct CreateConversationRequest {
+    name: String,
+    #[serde(default)]
+    messages: Vec<Message>,
+}
+
+#[derive(Deserialize)]
+struct ShareRequest {
+    /// Name of a `sharing.peers` entry
+    peer: String,
+}
+
+#[derive(Deserialize)]
+struct ExportQuery {
+    /// `md` (the default) or `html`
+    format: Option<String>,
+}
+
+/// Body a node sends to POST /conversations/shared on the node it shares with.
+#[derive(Serialize, Deserialize)]
+struct SharedConversation {
+    from: String,
+    conversation: Conversation,
+}
+
+// Conversations persisted as a JSON array under the data dir
+pub struct ConversationStore {
+    path: PathBuf,
+    conversations: HashMap<String, Conversation>,
+}
+
+impl ConversationStore {
+    pub fn load(path: PathBuf) -> Result<Self, String> {
+        let conversations = if path.exists() {
+            let data = std::fs::read_to_string(&path)
+                .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+            let list: Vec<Conversation> = serde_json::from_str(&data)
+                .map_err(|e| format!("Invalid conversations file {}: {}", path.display(), e))?;
+            list.into_iter().map(|c| (c.id.clone(), c)).collect()
+        } else {
+            HashMap::new()
+        };
+        Ok(Self {
+            path,
+            conversations,
+        })
+    }
+
+    fn save(&self) -> Result<(), String> {
+        if let Some(parent) = self.path.parent() {
+            std::fs::create_dir_all(parent).map_err(|e| e.to_string())?;
+        }
+        let list: Vec<&Conversation> = self.conversations.values().collect();
+        let data = serde_json::to_vec_pretty(&list).map_err(|e| e.to_string())?;
+        std::fs::write(&self.path, data).map_err(|e| e.to_string())
+    }
+
+    pub fn get(&self, id: &str) -> Option<&Conversation> {
+        self.conversations.get(id)
+    }
+
+    /// Most recently updated first.
+    fn list(&self) -> Vec<ConversationSummary> {
+        let mut list: Vec<&Conversation> = self.conversations.values().collect();
+        list.sort_by(|a, b| b.updated_at.cmp(&a.updated_at));
+        list.into_iter()
+            .map(|c| ConversationSummary {
+                id: c.id.clone(),
+                name: c.name.clone(),
+                created_at: c.created_at.clone(),
+                updated_at: c.updated_at.clone(),
+                shared_from: c.shared_from.clone(),
+                message_count: c.messages.len(),
+            })
+            .collect()
+    }
+
+    fn create(&mut self, name: String, messages: Vec<Message>) -> Result<Conversation, String> {
+        let now = Utc::now().to_rfc3339();
+        let conversation = Conversation {
+            id: Uuid::new_v4().to_string(),
+            name,
+            created_at: now.clone(),
+            updated_at: now.clone(),
+            shared_from: None,
+            messages: messages.into_iter().map(|m| stamped(m, &now)).collect(),
+        };
+        self.conversations
+            .insert(conversation.id.clone(), conversation.clone());
+        self.save()?;
+        Ok(conversation)
+    }
+
+    /// Append `message` to conversation `id`, which must have been made here.
+    fn append(&mut self, id: &str, message: Message) -> Result<Message, ApiError> {
+        let conversation = self
+            .conversations
+            .get_mut(id)
+            .ok_or_else(|| ApiError::not_found("Conversation not found"))?;
+        if conversation.shared_from.is_some() {
+            return Err(ApiError::conflict("Shared conversations are read-only"));
+        }
+        let now = Utc::now().to_rfc3339();
+        let message = stamped(message, &now);
+        conversation.messages.push(message.clone());
+        conversation.updated_at = now;
+        self.save().map_err(|e| {
+            tracing::error!("Failed to save conversation: {}", e);
+            ApiError::internal(e)
+        })?;
+        Ok(message)
+    }
+
+    /// Store, or refresh, the read-only copy `from` shared. A conversation made here
+    /// is never overwritten.
+    fn receive(&mut self, from: &str, mut conversation: Conversation) -> Result<(), ApiError> {
+        if self
+            .conversations
+            .get(&conversation.id)
+            .is_some_and(|existing| existing.shared_from.as_deref() != Some(from))
+        {
+            return Err(ApiError::conflict(
+                "A different conversation with this id already exists",
+            ));
+        }
+        conversation.shared_from = Some(from.to_string());
+        self.conversations
+            .insert(conversation.id.clone(), conversation);
+        self.save().map_err(|e| {
+            tracing::error!("Failed to save shared conversation: {}", e);
+            ApiError::internal(e)
+        })
+    }
+
+    fn remove(&mut self, id: &str) -> Result<bool, String> {
+        let removed = self.conversations.remove(id).is_some();
+        if removed {
+            self.save()?;
+        }
+        Ok(removed)
+    }
+}
+
+fn stamped(mut message: Message, now: &str) -> Message {
+    if message.created_at.is_empty() {
+        message.created_at = now.to_string();
+    }
+    message
+}
+
+fn check_roles(messages: &[Message]) -> Result<(), ApiError> {
+    match messages.iter().find(|m| !ROLES.contains(&m.role.as_str())) {
+        Some(message) => Err(ApiError::bad_request(format!(
+            "Unknown role {:?}, expected one of {}",
+            message.role,
+            ROLES.join(", ")
+        ))),
+        None => Ok(()),
+    }
+}
+
+pub fn routes() -> Router<Arc<AppState>> {
+    Router::new()
+        .route(
+            "/conversations",
+            get(list_conversations).post(create_conversation),
+        )
+        .route(
+            "/conversations/:id",
+            get(get_conversation).delete(delete_conversation),
+        )
+        .route("/conversations/:id/messages", post(append_message))
+        .route("/conversations/:id/export", get(export_conversation))
+        .route("/conversations/:id/share", post(share_conversation))
+        .route("/conversations/shared", post(receive_conversation))
+}
+
+async fn list_conversations(State(state): State<Arc<AppState>>) -> Json<Vec<ConversationSummary>> {
+    Json(state.conversations.lock().await.list())
+}
+
+async fn get_conversation(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+) -> Result<Json<Conversation>, ApiError> {
+    let conversations = state.conversations.lock().await;
+    conversations
+        .get(&id)
+        .cloned()
+        .map(Json)
+        .ok_or_else(|| ApiError::not_found("Conversation not found"))
+}
+
+/// Conversation `id` as a document to save or share: Markdown, or a standalone HTML
+/// page. Message contents are written as they were sent, so their code fences survive.
+async fn export_conversation(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+    Query(query): Query<ExportQuery>,
+) -> Result<Response, ApiError> {
+    let html = match query.format.as_deref() {
+        None | Some("md") => false,
+        Some("html") => true,
+        Some(format) => {
+            return Err(ApiError::bad_request(format!(
+                "Unknown format {:?}, expected md or html",
+                format
+            )));
+        }
+    };
+    let conversation = state
+        .conversations
+        .lock()
+        .await
+        .get(&id)
+        .cloned()
+        .ok_or_else(|| ApiError::not_found("Conversation not found"))?;
+    let (content_type, extension, body) = if html {
+        ("text/html; charset=utf-8", "html", to_html(&conversation))
+    } else {
+        (
+            "text/markdown; charset=utf-8",
+            "md",
+            to_markdown(&conversation),
+        )
+    };
+    Ok((
+        [
+            (header::CONTENT_TYPE, content_type.to_string()),
+            (
+                header::CONTENT_DISPOSITION,
+                format!(
+                    "attachment; filename=\"conversation-{}.{}\"",
+                    conversation.id, extension
+                ),
+            ),
+        ],
+        body,
+    )
+        .into_response())
+}
+
+fn to_markdown(conversation: &Conversation) -> String {
+    let mut out = format!("# {}\n\n", conversation.name);
+    if let Some(from) = &conversation.shared_from {
+        out.push_str(&format!("_Shared from {}_\n\n", from));
+    }
+    for message in &conversation.messages {
+        out.push_str(&format!(
+            "## {} · {}\n\n{}\n\n",
+            message.role, message.created_at, message.content
+        ));
+    }
+    out
+}
+
+fn to_html(conversation: &Conversation) -> String {
+    let mut out = format!(
+        r#"<!doctype html>
+<html lang="en">
+<head><meta charset="utf-8"><title>{name}</title>
+<style>body{{font-family:sans-serif;max-width:48rem;margin:auto}}pre{{background:#f4f4f4;padding:.75rem;overflow-x:auto}}</style>
+</head>
+<body>
+<h1>{name}</h1>
+"#,
+        name = escape_html(&conversation.name)
+    );
+    if let Some(from) = &conversation.shared_from {
+        out.push_str(&format!(
+            "<p><em>Shared from {}</em></p>\n",
+            escape_html(from)
+        ));
+    }
+    for message in &conversation.messages {
+        out.push_str(&format!(
+            "<h2>{} <small>{}</small></h2>\n{}",
+            escape_html(&message.role),
+            escape_html(&message.created_at),
+            content_html(&message.content)
+        ));
+    }
+    out.push_str("</body>\n</html>\n");
+    out
+}
+
+/// Fenced code blocks become `<pre><code>`; the text between them becomes paragraphs.
+fn content_html(content: &str) -> String {
+    let mut out = String::new();
+    let mut paragraph: Vec<&str> = Vec::new();
+    let mut code: Option<Vec<&str>> = None;
+    for line in content.lines() {
+        let fence = line.trim_start().strip_prefix("```");
+        match (&mut code, fence) {
+            (Some(lines), Some(_)) => {
+                out.push_str(&format!(
+                    "{}</code></pre>\n",
+                    escape_html(&lines.join("\n"))
+                ));
+                code = None;
+            }
+            (Some(lines), None) => lines.push(line),
+            (None, Some(language)) => {
+                push_paragraph(&mut paragraph, &mut out);
+                match language.trim() {
+                    "" => out.push_str("<pre><code>"),
+                    language => out.push_str(&format!(
+                        "<pre><code class=\"language-{}\">",
+                        escape_html(language)
+                    )),
+                }
+                code = Some(Vec::new());
+            }
+            (None, None) if line.trim().is_empty() => push_paragraph(&mut paragraph, &mut out),
+            (None, None) => paragraph.push(line),
+        }
+    }
+    // An unclosed fence runs to the end of the message
+    if let Some(lines) = code {
+        out.push_str(&format!(
+            "{}</code></pre>\n",
+            escape_html(&lines.join("\n"))
+        ));
+    }
+    push_paragraph(&mut paragraph, &mut out);
+    out
+}
+
+fn push_paragraph(paragraph: &mut Vec<&str>, out: &mut String) {
+    if !paragraph.is_empty() {
+        let lines: Vec<String> = paragraph.iter().map(|line| escape_html(line)).collect();
+        out.push_str(&format!("<p>{}</p>\n", lines.join("<br>\n")));
+        paragraph.clear();
+    }
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+async fn create_conversation(
+    State(state): State<Arc<AppState>>,
+    Json(req): Json<CreateConversationRequest>,
+) -> Result<Json<Conversation>, ApiError> {
+    check_roles(&req.messages)?;
+    let mut conversations = state.conversations.lock().await;
+    conversations
+        .create(req.name, req.messages)
+        .map(Json)
+        .map_err(|e| {
+            tracing::error!("Failed to save conversation: {}", e);
+            ApiError::internal(e)
+        })
+}
+
+async fn append_message(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+    Json(message): Json<Message>,
+) -> Result<Json<Message>, ApiError> {
+    check_roles(std::slice::from_ref(&message))?;
+    let mut conversations = state.conversations.lock().await;
+    conversations.append(&id, message).map(Json)
+}
+
+async fn delete_conversation(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+) -> Result<StatusCode, ApiError> {
+    let mut conversations = state.conversations.lock().await;
+    match conversations.remove(&id) {
+        Ok(true) => Ok(StatusCode::OK),
+        Ok(false) => Err(ApiError::not_found("Conversation not found")),
+        Err(e) => {
+            tracing::error!("Failed to delete conversation: {}", e);
+            Err(ApiError::internal(e))
+        }
+    }
+}
+
+/// Send a read-only copy of conversation `id` to a peer from `sharing.peers`. Sharing
+/// again replaces the peer's copy.
+async fn share_conversation(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+    Json(req): Json<ShareRequest>,
+) -> Result<StatusCode, ApiError> {
+    let peer = state
+        .sharing
+        .peers
+        .iter()
+        .find(|peer| peer.name == req.peer)
+        .ok_or_else(|| ApiError::bad_request(format!("Unknown sharing peer: {}", req.peer)))?;
+    let conversation = state
+        .conversations
+        .lock()
+        .await
+        .get(&id)
+        .cloned()
+        .ok_or_else(|| ApiError::not_found("Conversation not found"))?;
+    if conversation.shared_from.is_some() {
+        return Err(ApiError::conflict(
+            "Conversations shared with this node can't be shared on",
+        ));
+    }
+    let body = serde_json::to_vec(&SharedConversation {
+        from: state.sharing.node_name.clone(),
+        conversation,
+    })
+    .map_err(|e| ApiError::internal(e.to_string()))?;
+
+    let path = format!("{}/conversations/shared", API_PREFIX);
+    let (timestamp, signature) = auth::sign(&peer.api_key, "POST", &path, &body);
+    let client = reqwest::Client::builder()
+        .timeout(SHARE_TIMEOUT)
+        .build()
+        .map_err(|e| ApiError::internal(e.to_string()))?;
+    let upstream = |e: reqwest::Error| {
+        tracing::warn!(
+            "Sharing conversation {} with {} failed: {}",
+            id,
+            peer.name,
+            e
+        );
+        ApiError::new(
+            StatusCode::BAD_GATEWAY,
+            ErrorCode::Upstream,
+            format!("Sharing with {} failed: {}", peer.name, e),
+        )
+    };
+    client
+        .post(format!("{}{}", peer.url.trim_end_matches('/'), path))
+        .bearer_auth(&peer.api_key)
+        .header(TIMESTAMP_HEADER, timestamp)
+        .header(SIGNATURE_HEADER, signature)
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .body(body)
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(upstream)?;
+    state
+        .audit
+        .record(
+            "conversation_shared",
+            serde_json::json!({"conversation": id, "peer": peer.name}),
+        )
+        .await;
+    Ok(StatusCode::OK)
+}
+
+/// Take a conversation another node shared. The API key middleware has already
+/// checked the sender's key, so this is refused when no keys are configured. The
+/// sender is the `sharing.senders` entry for that key; the `from` it claims is ignored.
+async fn receive_conversation(
+    State(state): State<Arc<AppState>>,
+    key: Option<Extension<auth::ApiKey>>,
+    Json(shared): Json<SharedConversation>,
+) -> Result<StatusCode, ApiError> {
+    if !state.sharing.accept || state.api_keys.is_empty() {
+        return Err(ApiError::new(
+            StatusCode::FORBIDDEN,
+            ErrorCode::Forbidden,
+            "This node doesn't accept shared conversations",
+        ));
+    }
+    let sender = key
+        .and_then(|Extension(auth::ApiKey(key))| {
+            state
+                .sharing
+                .senders
+                .iter()
+                .find(|sender| auth::constant_time_eq(&sender.api_key, &key))
+        })
+        .ok_or_else(|| {
+            ApiError::new(
+                StatusCode::FORBIDDEN,
+                ErrorCode::Forbidden,
+                "This API key isn't a sharing sender",
+            )
+        })?;
+    check_roles(&shared.conversation.messages)?;
+    let id = shared.conversation.id.clone();
+    state
+        .conversations
+        .lock()
+        .await
+        .receive(&sender.name, shared.conversation)?;
+    state
+        .audit
+        .record(
+            "conversation_received",
+            serde_json::json!({"conversation": id, "from": sender.name}),
+        )
+        .await;
+    Ok(StatusCode::OK)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn html_export_escapes_message_text() {
+        let html = content_html("<script>alert(1)</script> & \"quotes\"");
+        assert_eq!(
+            html,
+            "<p>&lt;script&gt;alert(1)&lt;/script&gt; &amp; &quot;quotes&quot;</p>\n"
+        );
+    }
+
+    #[test]
+    fn html_export_keeps_code_blocks() {
+        let html = content_html("Run this:\n\n```rust\nlet a = 1 < 2;\n```\nDone\nfor now");
+        assert_eq!(
+            html,
+            "<p>Run this:</p>\n\
+             <pre><code class=\"language-rust\">let a = 1 &lt; 2;</code></pre>\n\
+             <p>Done<br>\nfor now</p>\n"
+        );
+    }
+}
@@ -0,0 +1,35 @@
+use axum::{
+    extract::Request,
+    http::{HeaderValue, header, header::HeaderName},
+    middleware::Next,
+    response::Response,
+};
+
+/// Prefix of the current API version.
+pub const API_PREFIX: &str = "/v1";
+// Last day the unversioned paths are served
+const SUNSET: &str = "Wed, 14 Apr 2027 00:00:00 GMT";
+
+/// Flag responses on the old unversioned paths as deprecated, with a `Link` to the
+/// /v1 route that replaces them.
+pub async fn mark_deprecated(request: Request, next: Next) -> Response {
+    let successor = format!(
+        "<{}{}>; rel=\"successor-version\"",
+        API_PREFIX,
+        request.uri().path()
+    );
+    let mut response = next.run(request).await;
+    let headers = response.headers_mut();
+    headers.insert(
+        HeaderName::from_static("deprecation"),
+        HeaderValue::from_static("true"),
+    );
+    headers.insert(
+        HeaderName::from_static("sunset"),
+        HeaderValue::from_static(SUNSET),
+    );
+    if let Ok(link) = HeaderValue::from_str(&successor) {
+        headers.insert(header::LINK, link);
+    }
+    response
+}
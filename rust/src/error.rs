@@ -0,0 +1,137 @@
+use axum::{
+    http::StatusCode,
+    response::{IntoResponse, Json, Response},
+};
+use serde::Serialize;
+use serde_json::Value;
+
+/// Machine-readable reason returned as `code` in every error body.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    InvalidRequest,
+    PolicyViolation,
+    Unauthorized,
+    Forbidden,
+    ReadOnly,
+    NotFound,
+    MethodNotAllowed,
+    /// The execution is in the wrong state for the operation
+    Conflict,
+    PayloadTooLarge,
+    PromptTooLong,
+    UnsupportedMediaType,
+    RateLimited,
+    /// Stopped with POST /stop before it finished
+    Cancelled,
+    Internal,
+    /// A peer or download source failed
+    Upstream,
+    Unavailable,
+    QueueFull,
+    Timeout,
+}
+
+impl ErrorCode {
+    /// Code for an error response that only set a status.
+    pub fn for_status(status: StatusCode) -> Self {
+        match status.as_u16() {
+            401 => Self::Unauthorized,
+            403 => Self::Forbidden,
+            404 => Self::NotFound,
+            405 => Self::MethodNotAllowed,
+            409 => Self::Conflict,
+            413 => Self::PayloadTooLarge,
+            415 => Self::UnsupportedMediaType,
+            429 => Self::RateLimited,
+            499 => Self::Cancelled,
+            502 => Self::Upstream,
+            503 => Self::Unavailable,
+            504 => Self::Timeout,
+            400..=499 => Self::InvalidRequest,
+            _ => Self::Internal,
+        }
+    }
+}
+
+/// Error returned by handlers and middleware. Renders as
+/// `{"error": message, "code": code, "request_id": id}`; the request id is filled in
+/// by access_log::trace_request.
+#[derive(Debug, Clone)]
+pub struct ApiError {
+    pub status: StatusCode,
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+impl ApiError {
+    pub fn new(status: StatusCode, code: ErrorCode, message: impl Into<String>) -> Self {
+        Self {
+            status,
+            code,
+            message: message.into(),
+        }
+    }
+
+    /// An error for `status` with the code it implies.
+    pub fn status(status: StatusCode, message: impl Into<String>) -> Self {
+        Self::new(status, ErrorCode::for_status(status), message)
+    }
+
+    pub fn bad_request(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::BAD_REQUEST, ErrorCode::InvalidRequest, message)
+    }
+
+    pub fn not_found(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::NOT_FOUND, ErrorCode::NotFound, message)
+    }
+
+    pub fn conflict(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::CONFLICT, ErrorCode::Conflict, message)
+    }
+
+    pub fn internal(message: impl Into<String>) -> Self {
+        Self::new(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            ErrorCode::Internal,
+            message,
+        )
+    }
+
+    /// 499: the generation was stopped with POST /stop before it finished.
+    pub fn cancelled() -> Self {
+        Self::new(
+            StatusCode::from_u16(499).unwrap(),
+            ErrorCode::Cancelled,
+            "Generation stopped",
+        )
+    }
+
+    pub fn unavailable(message: impl Into<String>) -> Self {
+        Self::new(
+            StatusCode::SERVICE_UNAVAILABLE,
+            ErrorCode::Unavailable,
+            message,
+        )
+    }
+
+    pub fn body(&self, request_id: Option<&str>) -> Value {
+        let mut body = serde_json::json!({
+            "error": self.message,
+            "code": self.code,
+        });
+        if let Some(id) = request_id {
+            body["request_id"] = Value::from(id);
+        }
+        body
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let mut response = (self.status, Json(self.body(None))).into_response();
+        // Picked up by trace_request to add the request id
+        response.extensions_mut().insert(self);
+        response
+    }
+}
@@ -0,0 +1,193 @@
+use serde::Serialize;
+use std::fs::File;
+use std::io::{BufReader, Read, Seek};
+use std::path::Path;
+
+// Reads the metadata header of a GGUF file without loading its tensors.
+// Layout: https://github.com/ggml-org/ggml/blob/master/docs/gguf.md
+
+const MAGIC: &[u8; 4] = b"GGUF";
+// Keeps a corrupt file from making us allocate gigabytes for one string
+const MAX_STRING_LEN: u64 = 1 << 20;
+
+/// Metadata from a GGUF header. Anything the file doesn't declare is unset.
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct GgufInfo {
+    pub architecture: Option<String>,
+    pub name: Option<String>,
+    /// Weight type from `general.file_type`, e.g. `Q4_K_M`
+    pub quantization: Option<String>,
+    /// Context length the model was trained for
+    pub context_length: Option<u64>,
+}
+
+#[derive(Debug)]
+enum MetaValue {
+    Uint(u64),
+    Str(String),
+    Other,
+}
+
+pub fn read_info(path: &Path) -> Result<GgufInfo, String> {
+    let file = File::open(path).map_err(|e| format!("Failed to open {}: {}", path.display(), e))?;
+    let mut reader = BufReader::new(file);
+    parse(&mut reader).map_err(|e| format!("Invalid GGUF file {}: {}", path.display(), e))
+}
+
+fn parse<R: Read + Seek>(reader: &mut R) -> Result<GgufInfo, String> {
+    let mut magic = [0u8; 4];
+    read_exact(reader, &mut magic)?;
+    if &magic != MAGIC {
+        return Err("missing GGUF magic".to_string());
+    }
+    let version = read_u32(reader)?;
+    if version < 2 {
+        return Err(format!("unsupported version {}", version));
+    }
+    let _tensor_count = read_u64(reader)?;
+    let kv_count = read_u64(reader)?;
+
+    let mut info = GgufInfo::default();
+    let mut file_type = None;
+    // `<arch>.context_length` can come before `general.architecture`
+    let mut context_lengths = Vec::new();
+    for _ in 0..kv_count {
+        let key = read_string(reader)?;
+        let kind = read_u32(reader)?;
+        let value = read_value(reader, kind)?;
+        match (key.as_str(), value) {
+            ("general.architecture", MetaValue::Str(s)) => info.architecture = Some(s),
+            ("general.name", MetaValue::Str(s)) => info.name = Some(s),
+            ("general.file_type", MetaValue::Uint(n)) => file_type = Some(n),
+            (key, MetaValue::Uint(n)) if key.ends_with(".context_length") => {
+                context_lengths.push((key.to_string(), n));
+            }
+            _ => {}
+        }
+    }
+    info.quantization = file_type.and_then(file_type_name).map(str::to_string);
+    info.context_length = match &info.architecture {
+        Some(arch) => {
+            let key = format!("{}.context_length", arch);
+            context_lengths
+                .iter()
+                .find(|(k, _)| *k == key)
+                .map(|(_, n)| *n)
+        }
+        None => context_lengths.first().map(|(_, n)| *n),
+    };
+    Ok(info)
+}
+
+fn read_value<R: Read + Seek>(reader: &mut R, kind: u32) -> Result<MetaValue, String> {
+    Ok(match kind {
+        0 | 1 | 7 => MetaValue::Uint(read_n::<1, _>(reader)?[0] as u64),
+        2 | 3 => MetaValue::Uint(u16::from_le_bytes(read_n(reader)?) as u64),
+        4 => MetaValue::Uint(read_u32(reader)? as u64),
+        5 => MetaValue::Uint(i32::from_le_bytes(read_n(reader)?).max(0) as u64),
+        10 => MetaValue::Uint(read_u64(reader)?),
+        11 => MetaValue::Uint(i64::from_le_bytes(read_n(reader)?).max(0) as u64),
+        6 => skip(reader, 4).map(|_| MetaValue::Other)?,
+        12 => skip(reader, 8).map(|_| MetaValue::Other)?,
+        8 => MetaValue::Str(read_string(reader)?),
+        9 => {
+            // Arrays (mostly the tokenizer's vocabulary) are skipped without decoding
+            let item_kind = read_u32(reader)?;
+            let len = read_u64(reader)?;
+            match fixed_size(item_kind) {
+                Some(size) => skip(reader, size.checked_mul(len).ok_or("array too large")?)?,
+                None => {
+                    for _ in 0..len {
+                        read_value(reader, item_kind)?;
+                    }
+                }
+            }
+            MetaValue::Other
+        }
+        other => return Err(format!("unknown metadata type {}", other)),
+    })
+}
+
+fn fixed_size(kind: u32) -> Option<u64> {
+    match kind {
+        0 | 1 | 7 => Some(1),
+        2 | 3 => Some(2),
+        4..=6 => Some(4),
+        10..=12 => Some(8),
+        _ => None,
+    }
+}
+
+/// llama.cpp's name for a `general.file_type` value.
+fn file_type_name(file_type: u64) -> Option<&'static str> {
+    Some(match file_type {
+        0 => "F32",
+        1 => "F16",
+        2 => "Q4_0",
+        3 => "Q4_1",
+        7 => "Q8_0",
+        8 => "Q5_0",
+        9 => "Q5_1",
+        10 => "Q2_K",
+        11 => "Q3_K_S",
+        12 => "Q3_K_M",
+        13 => "Q3_K_L",
+        14 => "Q4_K_S",
+        15 => "Q4_K_M",
+        16 => "Q5_K_S",
+        17 => "Q5_K_M",
+        18 => "Q6_K",
+        19 => "IQ2_XXS",
+        20 => "IQ2_XS",
+        21 => "Q2_K_S",
+        22 => "IQ3_XS",
+        23 => "IQ3_XXS",
+        24 => "IQ1_S",
+        25 => "IQ4_NL",
+        26 => "IQ3_S",
+        27 => "IQ3_M",
+        28 => "IQ2_S",
+        29 => "IQ2_M",
+        30 => "IQ4_XS",
+        31 => "IQ1_M",
+        32 => "BF16",
+        _ => return None,
+    })
+}
+
+fn read_exact<R: Read>(reader: &mut R, buf: &mut [u8]) -> Result<(), String> {
+    reader
+        .read_exact(buf)
+        .map_err(|e| format!("truncated header: {}", e))
+}
+
+fn read_n<const N: usize, R: Read>(reader: &mut R) -> Result<[u8; N], String> {
+    let mut buf = [0u8; N];
+    read_exact(reader, &mut buf)?;
+    Ok(buf)
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> Result<u32, String> {
+    Ok(u32::from_le_bytes(read_n(reader)?))
+}
+
+fn read_u64<R: Read>(reader: &mut R) -> Result<u64, String> {
+    Ok(u64::from_le_bytes(read_n(reader)?))
+}
+
+fn read_string<R: Read>(reader: &mut R) -> Result<String, String> {
+    let len = read_u64(reader)?;
+    if len > MAX_STRING_LEN {
+        return Err(format!("string of {} bytes", len));
+    }
+    let mut buf = vec![0u8; len as usize];
+    read_exact(reader, &mut buf)?;
+    Ok(String::from_utf8_lossy(&buf).into_owned())
+}
+
+fn skip<R: Seek>(reader: &mut R, bytes: u64) -> Result<(), String> {
+    let bytes = i64::try_from(bytes).map_err(|_| "array too large".to_string())?;
+    reader
+        .seek_relative(bytes)
+        .map_err(|e| format!("truncated header: {}", e))
+}
@@ -0,0 +1,246 @@
+use chrono::{DateTime, Utc};
+use rusqlite::{Connection, params};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::path::Path;
+use std::sync::mpsc;
+
+use crate::model::GenerationStats;
+
+// Finished executions kept in SQLite so they outlive a restart. Writes go to a
+// thread that owns the connection, so callers holding the execution lock never
+// wait on the disk.
+
+const SCHEMA: &str = "CREATE TABLE IF NOT EXISTS executions (
+    id TEXT PRIMARY KEY,
+    state TEXT NOT NULL,
+    prompt TEXT NOT NULL,
+    system_prompt TEXT,
+    persona TEXT,
+    voice TEXT,
+    policy TEXT NOT NULL,
+    best_of INTEGER NOT NULL,
+    temperature REAL NOT NULL,
+    max_tokens INTEGER NOT NULL,
+    result TEXT,
+    error TEXT,
+    candidates TEXT,
+    tool_calls TEXT,
+    start_time TEXT NOT NULL,
+    finished_at TEXT
+)";
+
+// Applied in order to databases whose `user_version` is below their position
+const MIGRATIONS: &[&str] = &[
+    "ALTER TABLE executions ADD COLUMN attempts TEXT",
+    "ALTER TABLE executions ADD COLUMN tags TEXT",
+    "ALTER TABLE executions ADD COLUMN metadata TEXT",
+    "ALTER TABLE executions ADD COLUMN started_at TEXT",
+    "ALTER TABLE executions ADD COLUMN stats TEXT",
+    "ALTER TABLE executions ADD COLUMN replayable INTEGER NOT NULL DEFAULT 0",
+    "ALTER TABLE executions ADD COLUMN seed INTEGER",
+    "ALTER TABLE executions ADD COLUMN partial_result TEXT",
+    "ALTER TABLE executions ADD COLUMN node TEXT",
+    "ALTER TABLE executions ADD COLUMN scheduled_for TEXT",
+];
+
+/// An execution as saved in the history database, and as exported by
+/// GET /executions/export.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoredExecution {
+    pub id: String,
+    /// `Completed`, `Cancelled` or `Failed`, `Running` for one cut short by a crash, or
+    /// `Scheduled` for one still waiting for its time
+    pub state: String,
+    pub prompt: String,
+    pub system_prompt: Option<String>,
+    pub persona: Option<String>,
+    pub voice: Option<String>,
+    pub policy: Value,
+    pub best_of: usize,
+    pub temperature: f32,
+    pub max_tokens: usize,
+    /// Unset on rows saved before seeds were kept
+    pub seed: Option<u32>,
+    pub result: Option<String>,
+    /// Output of a run that ended, or was cut short, before its result
+    pub partial_result: Option<String>,
+    pub error: Option<String>,
+    pub candidates: Option<Vec<String>>,
+    pub tool_calls: Option<Value>,
+    pub attempts: Option<Value>,
+    /// `local` or the peer that generated the result
+    pub node: Option<String>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    pub metadata: Option<serde_json::Map<String, Value>>,
+    pub start_time: DateTime<Utc>,
+    pub started_at: Option<DateTime<Utc>>,
+    pub finished_at: Option<DateTime<Utc>>,
+    pub scheduled_for: Option<DateTime<Utc>>,
+    pub stats: Option<GenerationStats>,
+    /// The saved prompt is all it takes to run the execution again
+    #[serde(default)]
+    pub replayable: bool,
+}
+
+enum Write {
+    Save(Box<StoredExecution>),
+    Delete(Vec<String>),
+}
+
+pub struct ExecutionHistory {
+    tx: mpsc::Sender<Write>,
+}
+
+impl ExecutionHistory {
+    /// Open, or create, the database at `path`, returning it with every execution
+    /// saved so far.
+    pub fn open(path: &Path) -> Result<(Self, Vec<StoredExecution>), String> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent).map_err(|e| e.to_string())?;
+        }
+        let conn = Connection::open(path)
+            .map_err(|e| format!("Failed to open {}: {}", path.display(), e))?;
+        conn.execute(SCHEMA, [])
+            .map_err(|e| format!("Failed to create the executions table: {}", e))?;
+        migrate(&conn).map_err(|e| format!("Failed to upgrade the executions table: {}", e))?;
+        let stored = load(&conn).map_err(|e| format!("Failed to read executions: {}", e))?;
+
+        let (tx, rx) = mpsc::channel();
+        std::thread::spawn(move || {
+            for write in rx {
+                let outcome = match write {
+                    Write::Save(execution) => save(&conn, &execution),
+                    Write::Delete(ids) => delete(&conn, &ids),
+                };
+                if let Err(e) = outcome {
+                    tracing::error!("Failed to write execution history: {}", e);
+                }
+            }
+        });
+        Ok((Self { tx }, stored))
+    }
+
+    pub fn save(&self, execution: StoredExecution) {
+        let _ = self.tx.send(Write::Save(Box::new(execution)));
+    }
+
+    pub fn delete(&self, ids: Vec<String>) {
+        if !ids.is_empty() {
+            let _ = self.tx.send(Write::Delete(ids));
+        }
+    }
+}
+
+fn migrate(conn: &Connection) -> rusqlite::Result<()> {
+    let version: usize = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+    for migration in MIGRATIONS.iter().skip(version) {
+        conn.execute(migration, [])?;
+    }
+    conn.pragma_update(None, "user_version", MIGRATIONS.len())
+}
+
+fn save(conn: &Connection, e: &StoredExecution) -> rusqlite::Result<()> {
+    conn.execute(
+        "INSERT OR REPLACE INTO executions (id, state, prompt, system_prompt, persona, voice,
+            policy, best_of, temperature, max_tokens, result, error, candidates, tool_calls,
+            start_time, finished_at, attempts, tags, metadata, started_at, stats, replayable,
+            seed, partial_result, node, scheduled_for)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17,
+            ?18, ?19, ?20, ?21, ?22, ?23, ?24, ?25, ?26)",
+        params![
+            e.id,
+            e.state,
+            e.prompt,
+            e.system_prompt,
+            e.persona,
+            e.voice,
+            e.policy.to_string(),
+            e.best_of as i64,
+            e.temperature as f64,
+            e.max_tokens as i64,
+            e.result,
+            e.error,
+            e.candidates
+                .as_ref()
+                .and_then(|c| serde_json::to_string(c).ok()),
+            e.tool_calls.as_ref().map(Value::to_string),
+            e.start_time.to_rfc3339(),
+            e.finished_at.map(|t| t.to_rfc3339()),
+            e.attempts.as_ref().map(Value::to_string),
+            (!e.tags.is_empty())
+                .then(|| serde_json::to_string(&e.tags).ok())
+                .flatten(),
+            e.metadata
+                .as_ref()
+                .and_then(|m| serde_json::to_string(m).ok()),
+            e.started_at.map(|t| t.to_rfc3339()),
+            e.stats.as_ref().and_then(|s| serde_json::to_string(s).ok()),
+            e.replayable,
+            e.seed,
+            e.partial_result,
+            e.node,
+            e.scheduled_for.map(|t| t.to_rfc3339()),
+        ],
+    )?;
+    Ok(())
+}
+
+fn delete(conn: &Connection, ids: &[String]) -> rusqlite::Result<()> {
+    let mut statement = conn.prepare("DELETE FROM executions WHERE id = ?1")?;
+    for id in ids {
+        statement.execute([id])?;
+    }
+    Ok(())
+}
+
+fn load(conn: &Connection) -> rusqlite::Result<Vec<StoredExecution>> {
+    let mut statement = conn.prepare(
+        "SELECT id, state, prompt, system_prompt, persona, voice, policy, best_of,
+            temperature, max_tokens, result, error, candidates, tool_calls, start_time,
+            finished_at, attempts, tags, metadata, started_at, stats, replayable, seed,
+            partial_result, node, scheduled_for
+         FROM executions",
+    )?;
+    let rows = statement.query_map([], |row| {
+        let time = |text: Option<String>| {
+            text.and_then(|t| DateTime::parse_from_rfc3339(&t).ok())
+                .map(|t| t.with_timezone(&Utc))
+        };
+        Ok(StoredExecution {
+            id: row.get(0)?,
+            state: row.get(1)?,
+            prompt: row.get(2)?,
+            system_prompt: row.get(3)?,
+            persona: row.get(4)?,
+            voice: row.get(5)?,
+            policy: json_column(row.get(6)?).unwrap_or_default(),
+            best_of: row.get::<_, i64>(7)? as usize,
+            temperature: row.get::<_, f64>(8)? as f32,
+            max_tokens: row.get::<_, i64>(9)? as usize,
+            result: row.get(10)?,
+            error: row.get(11)?,
+            candidates: json_column(row.get(12)?),
+            tool_calls: json_column(row.get(13)?),
+            start_time: time(row.get(14)?).unwrap_or_default(),
+            finished_at: time(row.get(15)?),
+            attempts: json_column(row.get(16)?),
+            tags: json_column(row.get(17)?).unwrap_or_default(),
+            metadata: json_column(row.get(18)?),
+            started_at: time(row.get(19)?),
+            stats: json_column(row.get(20)?),
+            replayable: row.get(21)?,
+            seed: row.get(22)?,
+            partial_result: row.get(23)?,
+            node: row.get(24)?,
+            scheduled_for: time(row.get(25)?),
+        })
+    })?;
+    rows.collect()
+}
+
+fn json_column<T: DeserializeOwned>(text: Option<String>) -> Option<T> {
+    text.and_then(|t| serde_json::from_str(&t).ok())
+}
@@ -1,10 +1,62 @@
+use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
+use rand_core::{OsRng, RngCore};
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex as StdMutex};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio::net::TcpStream;
+use tokio::sync::{Mutex, Semaphore, mpsc, oneshot};
+
+use crate::auth;
+use crate::config::{HybridConfig, PeerSelection};
+use crate::model::{GenerationControl, Model, PromptInput};
 
 // Define types for hybrid compute
 
+// One-shot frames are type, length (u32 LE) and payload, one prompt per connection
+const PROMPT: u8 = 2;
+const RESULT: u8 = 3;
+// Session frames carry a request id (u32 LE) after the type, so many prompts share one
+// connection and their results can come back in any order
+const SESSION_PROMPT: u8 = 4;
+const SESSION_RESULT: u8 = 5;
+// With `hybrid.key` set every connection opens with a handshake of type and payload
+// frames: the coordinator's nonce, the peer's nonce and proof, the coordinator's proof
+const HELLO: u8 = 6;
+const CHALLENGE: u8 = 7;
+const PROOF: u8 = 8;
+// A session opens with these empty session frames before any prompt is sent, so a peer
+// without sessions hangs up on one and never sees a prompt it would drop
+const SESSION_OPEN: u8 = 9;
+const SESSION_READY: u8 = 10;
+// Prompts of one session a peer runs, or waits on its model for, at a time; it reads
+// no further frames until one finishes
+const MAX_SESSION_PROMPTS: usize = 4;
+const NONCE_LEN: usize = 32;
+// Proofs are keyed by role, so neither side can pass the other's proof back
+const PEER_ROLE: &[u8] = b"kekahyde peer";
+const COORDINATOR_ROLE: &[u8] = b"kekahyde coordinator";
+// After a keyed handshake every frame is followed by an HMAC-SHA256 tag under a key
+// derived from both nonces, so a host relaying the handshake can't inject or rewrite
+// the frames after it
+const SESSION_CONTEXT: &[u8] = b"kekahyde session";
+const FRAME_MAC_LEN: usize = 32;
+// Prefix of what a peer signs, so its signatures can't pass for anything else
+const RESULT_CONTEXT: &str = "kekahyde result";
+// Largest frame payload read from the other side
+const MAX_FRAME_BYTES: usize = 64 * 1024 * 1024;
+// Weight of the newest answer time in a peer's average
+const LATENCY_WEIGHT: f64 = 0.3;
+// Answer time a failed offload counts as, so lowest-latency selection moves away
+const FAILURE_LATENCY_MS: f64 = 60_000.0;
+// How long a failed peer is passed over when failing over to the next one
+const FAILURE_COOLDOWN: Duration = Duration::from_secs(30);
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct HiddenState {
     pub data: Vec<u8>, // Serialized hidden state
@@ -26,6 +78,63 @@ pub struct PeerResponse {
 pub struct InferenceResult {
     pub output: String,
     pub hash: String, // SHA256 of output
+    /// Ed25519 signature over the prompt and `hash`, base64; peers before signing
+    /// leave it out
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<String>,
+    /// Set, with an empty output, when the peer couldn't generate one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// What a peer proves itself with: the pre-shared key and the key it signs results with.
+pub struct PeerIdentity {
+    pub key: Option<String>,
+    pub signing_key: SigningKey,
+}
+
+impl PeerIdentity {
+    /// The key coordinators register for this peer, base64.
+    pub fn public_key(&self) -> String {
+        BASE64.encode(self.signing_key.verifying_key().as_bytes())
+    }
+}
+
+/// The peer's signing key, saved as base64 at `path` and created there on first use.
+pub fn load_signing_key(path: &Path) -> Result<SigningKey, String> {
+    if path.exists() {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        let seed: [u8; 32] = BASE64
+            .decode(text.trim())
+            .ok()
+            .and_then(|bytes| bytes.try_into().ok())
+            .ok_or_else(|| format!("Invalid signing key in {}", path.display()))?;
+        return Ok(SigningKey::from_bytes(&seed));
+    }
+    let signing_key = SigningKey::generate(&mut OsRng);
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent).map_err(|e| e.to_string())?;
+    }
+    std::fs::write(path, BASE64.encode(signing_key.to_bytes()))
+        .map_err(|e| format!("Failed to write {}: {}", path.display(), e))?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))
+            .map_err(|e| e.to_string())?;
+    }
+    Ok(signing_key)
+}
+
+fn result_message(prompt: &str, hash: &str) -> Vec<u8> {
+    format!(
+        "{}\n{:x}\n{}",
+        RESULT_CONTEXT,
+        Sha256::digest(prompt.as_bytes()),
+        hash
+    )
+    .into_bytes()
 }
 
 // Tokenized prompt
@@ -33,21 +142,99 @@ pub struct InferenceResult {
 
 pub struct HybridExecutor {
     pub peers: Vec<Peer>,
+    pub sessions: Arc<PeerSessions>,
+    pub selection: PeerSelection,
+    /// Time each peer gets to answer an offload
+    pub peer_timeout: Duration,
+    // Round-robin position, counted over every selection
+    turn: AtomicUsize,
 }
 
 impl HybridExecutor {
-    pub fn new() -> Self {
-        let mut executor = Self { peers: vec![] };
-        // Add dummy peers for testing
-        executor.add_peer(Peer {
-            id: "peer1".to_string(),
-            address: "127.0.0.1:8081".to_string(),
-        });
-        executor
+    pub fn new(config: &HybridConfig) -> Result<Self, String> {
+        let mut public_keys = HashMap::new();
+        for (address, key) in &config.peer_keys {
+            let key = BASE64
+                .decode(key.trim())
+                .ok()
+                .and_then(|bytes| <[u8; 32]>::try_from(bytes).ok())
+                .and_then(|bytes| VerifyingKey::from_bytes(&bytes).ok())
+                .ok_or_else(|| format!("Invalid public key for peer {}", address))?;
+            public_keys.insert(address.clone(), key);
+        }
+        Ok(Self {
+            peers: config
+                .peers
+                .iter()
+                .enumerate()
+                .map(|(i, address)| Peer {
+                    id: format!("peer{}", i + 1),
+                    address: address.clone(),
+                })
+                .collect(),
+            sessions: Arc::new(PeerSessions {
+                key: config.key.clone(),
+                public_keys,
+                ..Default::default()
+            }),
+            selection: config.selection,
+            peer_timeout: Duration::from_secs(config.peer_timeout_secs),
+            turn: AtomicUsize::new(0),
+        })
     }
 
-    pub fn add_peer(&mut self, peer: Peer) {
-        self.peers.push(peer);
+    /// Peers to try in turn for the next offloaded prompt: the selected one, then the
+    /// peers listed after it, leaving out any that failed lately. Empty when every
+    /// peer is cooling down, so the prompt runs locally without waiting on one.
+    pub fn failover_order(&self) -> Vec<Peer> {
+        let Some(first) = self.choose(&self.peers, true) else {
+            return Vec::new();
+        };
+        let load = self.sessions.load.lock().unwrap();
+        let healthy = |peer: &Peer| {
+            load.get(&peer.address)
+                .and_then(|load| load.failed_at)
+                .is_none_or(|at| at.elapsed() >= FAILURE_COOLDOWN)
+        };
+        let count = self.peers.len();
+        (0..count)
+            .map(|offset| &self.peers[(first + offset) % count])
+            .filter(|peer| healthy(peer))
+            .cloned()
+            .collect()
+    }
+
+    /// Index of the peer in `peers` that `selection` picks. Only an `advance` moves
+    /// round-robin on to the next peer.
+    pub fn choose(&self, peers: &[Peer], advance: bool) -> Option<usize> {
+        if peers.is_empty() {
+            return None;
+        }
+        let load = self.sessions.load.lock().unwrap();
+        let of = |index: usize| load.get(&peers[index].address).copied().unwrap_or_default();
+        // Ties go to the peer listed first
+        let index = match self.selection {
+            PeerSelection::First => 0,
+            PeerSelection::RoundRobin => {
+                let turn = if advance {
+                    self.turn.fetch_add(1, Ordering::Relaxed)
+                } else {
+                    self.turn.load(Ordering::Relaxed)
+                };
+                turn % peers.len()
+            }
+            PeerSelection::LeastLoaded => (0..peers.len())
+                .min_by_key(|&index| of(index).in_flight)
+                .unwrap_or(0),
+            // Peers not heard from yet count as instant, so each gets measured
+            PeerSelection::LowestLatency => (0..peers.len())
+                .min_by(|&a, &b| {
+                    let latency = |index| of(index).latency_ms.unwrap_or(0.0);
+                    latency(a).total_cmp(&latency(b))
+                })
+                .unwrap_or(0),
+        };
+        Some(index)
     }
 
     // Decide if to use hybrid based on policy and availability
@@ -55,17 +242,46 @@ impl HybridExecutor {
         allow_hybrid && !self.peers.is_empty()
     }
 
+    /// What keeps a prompt on this machine, if anything. The peer protocol only carries
+    /// the user's text, and peers sample with the defaults, so image prompts, custom
+    /// system prompts (personas, tools) and custom sampling stay local.
+    pub fn local_only(
+        images: bool,
+        system_prompt: bool,
+        tools: bool,
+        memories: bool,
+        sampling: bool,
+    ) -> Option<&'static str> {
+        [
+            (images, "images"),
+            (system_prompt, "a system prompt"),
+            (tools, "tools"),
+            (memories, "memories"),
+            (sampling, "custom sampling"),
+        ]
+        .into_iter()
+        .find_map(|(set, what)| set.then_some(what))
+    }
+
     // Send prompt to peer and receive result
-    pub async fn send_prompt_to_peer(prompt: &str, peer: &Peer) -> Result<InferenceResult, String> {
+    pub async fn send_prompt_to_peer(
+        prompt: &str,
+        peer: &Peer,
+        key: Option<&str>,
+    ) -> Result<InferenceResult, String> {
         let mut stream = TcpStream::connect(&peer.address)
             .await
             .map_err(|e| format!("Connect failed: {}", e))?;
+        let (mut outgoing, mut incoming) = match key {
+            Some(key) => {
+                let (outgoing, incoming) = authenticate(&mut stream, key).await?;
+                (Some(outgoing), Some(incoming))
+            }
+            None => (None, None),
+        };
 
         // Send message: type 2 for prompt execution, length, prompt
-        let mut message = vec![2u8]; // type
-        let prompt_bytes = prompt.as_bytes();
-        message.extend(&(prompt_bytes.len() as u32).to_le_bytes());
-        message.extend(prompt_bytes);
+        let message = seal(outgoing.as_mut(), frame(PROMPT, prompt.as_bytes()));
         stream
             .write_all(&message)
             .await
@@ -77,45 +293,820 @@ impl HybridExecutor {
             .read_exact(&mut type_buf)
             .await
             .map_err(|e| format!("Read type failed: {}", e))?;
-        if type_buf[0] != 3 {
+        if type_buf[0] != RESULT {
             return Err("Invalid response type".to_string());
         }
-        let mut len_buf = [0u8; 4];
-        stream
-            .read_exact(&mut len_buf)
+        let data = read_payload(&mut stream)
             .await
-            .map_err(|e| format!("Read length failed: {}", e))?;
-        let len = u32::from_le_bytes(len_buf) as usize;
-        let mut data = vec![0u8; len];
-        stream
-            .read_exact(&mut data)
+            .map_err(|e| format!("Read data failed: {}", e))?;
+        check_mac(incoming.as_mut(), &mut stream, &frame(RESULT, &data))
             .await
             .map_err(|e| format!("Read data failed: {}", e))?;
 
-        let result: InferenceResult =
-            serde_json::from_slice(&data).map_err(|e| format!("Deserialize failed: {}", e))?;
+        verify(&data)
+    }
+}
+
+fn verify(data: &[u8]) -> Result<InferenceResult, String> {
+    let result: InferenceResult =
+        serde_json::from_slice(data).map_err(|e| format!("Deserialize failed: {}", e))?;
+    if let Some(error) = &result.error {
+        return Err(format!("Generation failed on the peer: {}", error));
+    }
+
+    // Verify hash
+    let mut hasher = Sha256::new();
+    hasher.update(&result.output);
+    let computed_hash = format!("{:x}", hasher.finalize());
+    if computed_hash != result.hash {
+        return Err("Result hash mismatch".to_string());
+    }
 
-        // Verify hash
-        let mut hasher = Sha256::new();
-        hasher.update(&result.output);
-        let computed_hash = format!("{:x}", hasher.finalize());
-        if computed_hash != result.hash {
-            return Err("Result hash mismatch".to_string());
+    Ok(result)
+}
+
+fn nonce() -> Vec<u8> {
+    let mut nonce = vec![0u8; NONCE_LEN];
+    OsRng.fill_bytes(&mut nonce);
+    nonce
+}
+
+fn proof(key: &str, role: &[u8], coordinator_nonce: &[u8], peer_nonce: &[u8]) -> Vec<u8> {
+    let message = [role, coordinator_nonce, peer_nonce].concat();
+    auth::hmac_sha256(key.as_bytes(), &message).to_vec()
+}
+
+/// Type, length and payload, as one-shot and handshake frames are sent.
+fn frame(kind: u8, payload: &[u8]) -> Vec<u8> {
+    let mut frame = vec![kind];
+    frame.extend(&(payload.len() as u32).to_le_bytes());
+    frame.extend(payload);
+    frame
+}
+
+/// Type, request id, length and payload, as session frames are sent.
+fn session_frame(kind: u8, id: u32, payload: &[u8]) -> Vec<u8> {
+    let mut frame = vec![kind];
+    frame.extend(&id.to_le_bytes());
+    frame.extend(&(payload.len() as u32).to_le_bytes());
+    frame.extend(payload);
+    frame
+}
+
+/// Tags for the frames one side of a keyed connection sends, numbered so they can't
+/// be replayed, dropped or reordered either.
+struct FrameMac {
+    key: Vec<u8>,
+    // The role of the side sending the frames
+    sender: &'static [u8],
+    count: u64,
+}
+
+impl FrameMac {
+    fn new(key: &str, sender: &'static [u8], coordinator_nonce: &[u8], peer_nonce: &[u8]) -> Self {
+        Self {
+            key: proof(key, SESSION_CONTEXT, coordinator_nonce, peer_nonce),
+            sender,
+            count: 0,
         }
+    }
 
-        Ok(result)
+    fn tag(&mut self, frame: &[u8]) -> Vec<u8> {
+        let message = [self.sender, &self.count.to_le_bytes(), frame].concat();
+        self.count += 1;
+        auth::hmac_sha256(&self.key, &message).to_vec()
     }
+}
+
+/// `frame` followed by its tag, when the connection is keyed.
+fn seal(mac: Option<&mut FrameMac>, mut frame: Vec<u8>) -> Vec<u8> {
+    if let Some(mac) = mac {
+        let tag = mac.tag(&frame);
+        frame.extend(tag);
+    }
+    frame
+}
+
+/// Read the tag following `frame` and check it, when the connection is keyed.
+async fn check_mac<R: AsyncRead + Unpin>(
+    mac: Option<&mut FrameMac>,
+    reader: &mut R,
+    frame: &[u8],
+) -> std::io::Result<()> {
+    let Some(mac) = mac else {
+        return Ok(());
+    };
+    let mut tag = [0u8; FRAME_MAC_LEN];
+    reader.read_exact(&mut tag).await?;
+    if !auth::constant_time_eq(mac.tag(frame), tag) {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "Frame MAC mismatch",
+        ));
+    }
+    Ok(())
+}
+
+/// Prove `key` to the peer on a new connection and check that it holds the key too.
+/// Returns the tags for the frames this side sends and for those it receives.
+async fn authenticate<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    key: &str,
+) -> Result<(FrameMac, FrameMac), String> {
+    let failed = |e: &dyn std::fmt::Display| format!("Handshake failed: {}", e);
+    let ours = nonce();
+    stream
+        .write_all(&frame(HELLO, &ours))
+        .await
+        .map_err(|e| failed(&e))?;
+    let mut type_buf = [0u8; 1];
+    stream
+        .read_exact(&mut type_buf)
+        .await
+        .map_err(|e| failed(&e))?;
+    let challenge = read_payload(stream).await.map_err(|e| failed(&e))?;
+    if type_buf[0] != CHALLENGE || challenge.len() != 2 * NONCE_LEN {
+        return Err(failed(&"unexpected reply"));
+    }
+    let (theirs, their_proof) = challenge.split_at(NONCE_LEN);
+    if !auth::constant_time_eq(proof(key, PEER_ROLE, &ours, theirs), their_proof) {
+        return Err(failed(&"the peer doesn't hold the key"));
+    }
+    stream
+        .write_all(&frame(PROOF, &proof(key, COORDINATOR_ROLE, &ours, theirs)))
+        .await
+        .map_err(|e| failed(&e))?;
+    Ok((
+        FrameMac::new(key, COORDINATOR_ROLE, &ours, theirs),
+        FrameMac::new(key, PEER_ROLE, &ours, theirs),
+    ))
+}
 
-    // Run distributed inference by offloading to a peer
-    pub async fn run_distributed_inference(
+/// The peer's side of the handshake, after the HELLO type byte. Returns the tags for
+/// the frames the peer sends and for those it receives.
+async fn accept<S: AsyncRead + AsyncWrite + Unpin>(
+    socket: &mut S,
+    key: &str,
+) -> Result<(FrameMac, FrameMac), String> {
+    let theirs = read_payload(socket).await.map_err(|e| e.to_string())?;
+    if theirs.len() != NONCE_LEN {
+        return Err("Invalid hello".to_string());
+    }
+    let ours = nonce();
+    let challenge = [ours.clone(), proof(key, PEER_ROLE, &theirs, &ours)].concat();
+    socket
+        .write_all(&frame(CHALLENGE, &challenge))
+        .await
+        .map_err(|e| e.to_string())?;
+    let mut type_buf = [0u8; 1];
+    socket
+        .read_exact(&mut type_buf)
+        .await
+        .map_err(|e| e.to_string())?;
+    let their_proof = read_payload(socket).await.map_err(|e| e.to_string())?;
+    if type_buf[0] != PROOF
+        || !auth::constant_time_eq(proof(key, COORDINATOR_ROLE, &theirs, &ours), their_proof)
+    {
+        return Err("The coordinator doesn't hold the key".to_string());
+    }
+    Ok((
+        FrameMac::new(key, PEER_ROLE, &theirs, &ours),
+        FrameMac::new(key, COORDINATOR_ROLE, &theirs, &ours),
+    ))
+}
+
+type Pending = Arc<StdMutex<HashMap<u32, oneshot::Sender<Vec<u8>>>>>;
+
+/// One connection to a peer, shared by every prompt offloaded to it.
+struct Session {
+    frames: mpsc::UnboundedSender<Vec<u8>>,
+    // Prompts sent and not yet answered, by request id
+    pending: Pending,
+    next_id: AtomicU32,
+    // Set when the connection drops; the next offload opens a new one
+    closed: Arc<AtomicBool>,
+}
+
+/// What this daemon has seen of a peer, for selecting one.
+#[derive(Clone, Copy, Default)]
+struct PeerLoad {
+    // Offloads sent and not yet answered
+    in_flight: usize,
+    // Moving average of the time offloads took to answer
+    latency_ms: Option<f64>,
+    // The last offload failed, at this time
+    failed_at: Option<Instant>,
+}
+
+/// Open sessions to peers, one per address.
+#[derive(Default)]
+pub struct PeerSessions {
+    sessions: Mutex<HashMap<String, Arc<Session>>>,
+    // Peers that hung up on SESSION_OPEN; they get a connection per prompt
+    one_shot: StdMutex<HashSet<String>>,
+    load: StdMutex<HashMap<String, PeerLoad>>,
+    // `hybrid.key`, proved to every peer before sending it prompts
+    key: Option<String>,
+    // `hybrid.peer_keys`, by address
+    public_keys: HashMap<String, VerifyingKey>,
+}
+
+// Counts an offload as in flight until it is answered, fails or is dropped
+struct InFlight<'a> {
+    load: &'a StdMutex<HashMap<String, PeerLoad>>,
+    address: &'a str,
+}
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        if let Some(load) = self.load.lock().unwrap().get_mut(self.address) {
+            load.in_flight = load.in_flight.saturating_sub(1);
+        }
+    }
+}
+
+// Drops a prompt's request id once its offload is answered, fails or times out
+struct Waiting<'a> {
+    pending: &'a Pending,
+    id: u32,
+}
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        self.pending.lock().unwrap().remove(&self.id);
+    }
+}
+
+impl PeerSessions {
+    /// Run `prompt` on `peer` over its session, opening one if needed. It fails when
+    /// the peer hasn't answered within `timeout`.
+    pub async fn offload(
+        &self,
+        prompt: &str,
+        peer: &Peer,
+        timeout: Duration,
+    ) -> Result<InferenceResult, String> {
+        self.load
+            .lock()
+            .unwrap()
+            .entry(peer.address.clone())
+            .or_default()
+            .in_flight += 1;
+        let _in_flight = InFlight {
+            load: &self.load,
+            address: &peer.address,
+        };
+        let started = Instant::now();
+        let result = tokio::time::timeout(timeout, self.send(prompt, peer))
+            .await
+            .unwrap_or_else(|_| Err(format!("Timed out after {}s", timeout.as_secs())))
+            .and_then(|result| self.check_signature(prompt, peer, result));
+        let latency_ms = match &result {
+            Ok(_) => started.elapsed().as_secs_f64() * 1000.0,
+            Err(_) => FAILURE_LATENCY_MS,
+        };
+        if let Some(load) = self.load.lock().unwrap().get_mut(&peer.address) {
+            load.latency_ms = Some(match load.latency_ms {
+                Some(average) => average + (latency_ms - average) * LATENCY_WEIGHT,
+                None => latency_ms,
+            });
+            load.failed_at = result.is_err().then(Instant::now);
+        }
+        result
+    }
+
+    /// Only results signed by the peer's registered key are trusted from it; peers
+    /// without one are trusted on the hash alone.
+    fn check_signature(
         &self,
-        _model: &crate::model::Model,
         prompt: &str,
         peer: &Peer,
-    ) -> Result<String, String> {
-        // Send the full prompt to peer for remote execution
-        let result = Self::send_prompt_to_peer(prompt, peer).await?;
-        // Result is already verified
-        Ok(result.output)
+        result: InferenceResult,
+    ) -> Result<InferenceResult, String> {
+        let Some(public_key) = self.public_keys.get(&peer.address) else {
+            return Ok(result);
+        };
+        let signature = result
+            .signature
+            .as_deref()
+            .ok_or_else(|| "Result signature missing".to_string())?;
+        let signature = BASE64
+            .decode(signature)
+            .ok()
+            .and_then(|bytes| Signature::from_slice(&bytes).ok())
+            .ok_or_else(|| "Result signature malformed".to_string())?;
+        public_key
+            .verify_strict(&result_message(prompt, &result.hash), &signature)
+            .map_err(|_| "Result signature doesn't match the peer's key".to_string())?;
+        Ok(result)
+    }
+
+    async fn send(&self, prompt: &str, peer: &Peer) -> Result<InferenceResult, String> {
+        if self.one_shot.lock().unwrap().contains(&peer.address) {
+            return HybridExecutor::send_prompt_to_peer(prompt, peer, self.key.as_deref()).await;
+        }
+        let Some(session) = self.session(peer).await? else {
+            return HybridExecutor::send_prompt_to_peer(prompt, peer, self.key.as_deref()).await;
+        };
+        let id = session.next_id.fetch_add(1, Ordering::Relaxed);
+        let (tx, rx) = oneshot::channel();
+        session.pending.lock().unwrap().insert(id, tx);
+        let _waiting = Waiting {
+            pending: &session.pending,
+            id,
+        };
+        // The reader sets `closed` before it drops what is pending
+        if session.closed.load(Ordering::SeqCst) {
+            return Err("Send failed: peer session closed".to_string());
+        }
+
+        let frame = session_frame(SESSION_PROMPT, id, prompt.as_bytes());
+        if session.frames.send(frame).is_err() {
+            return Err("Send failed: peer session closed".to_string());
+        }
+
+        // The peer may have run the prompt before the connection dropped, so it isn't
+        // sent again; the execution fails over instead
+        match rx.await {
+            Ok(data) => verify(&data),
+            Err(_) => Err("Read data failed: peer session closed".to_string()),
+        }
+    }
+
+    /// The open session to `peer`, opening one if needed, or `None` for a peer without
+    /// sessions.
+    async fn session(&self, peer: &Peer) -> Result<Option<Arc<Session>>, String> {
+        // Held while connecting, so concurrent offloads share the new connection
+        let mut sessions = self.sessions.lock().await;
+        if let Some(session) = sessions.get(&peer.address)
+            && !session.closed.load(Ordering::SeqCst)
+        {
+            return Ok(Some(Arc::clone(session)));
+        }
+        let mut stream = TcpStream::connect(&peer.address)
+            .await
+            .map_err(|e| format!("Connect failed: {}", e))?;
+        let (mut outgoing_mac, mut incoming_mac) = match &self.key {
+            Some(key) => {
+                let (outgoing, incoming) = authenticate(&mut stream, key).await?;
+                (Some(outgoing), Some(incoming))
+            }
+            None => (None, None),
+        };
+        let open = seal(outgoing_mac.as_mut(), session_frame(SESSION_OPEN, 0, &[]));
+        stream
+            .write_all(&open)
+            .await
+            .map_err(|e| format!("Send failed: {}", e))?;
+        match read_session_frame(&mut stream, incoming_mac.as_mut()).await {
+            Ok((SESSION_READY, _, _)) => {}
+            Ok((kind, _, _)) => return Err(format!("Unexpected reply {} to a session", kind)),
+            // Peers from before sessions close the connection on a frame type they don't
+            // know; nothing but SESSION_OPEN was sent on it
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    std::io::ErrorKind::UnexpectedEof
+                        | std::io::ErrorKind::ConnectionReset
+                        | std::io::ErrorKind::ConnectionAborted
+                ) =>
+            {
+                tracing::info!(
+                    "Peer {} doesn't keep sessions, using a connection per prompt",
+                    peer.address
+                );
+                self.one_shot.lock().unwrap().insert(peer.address.clone());
+                return Ok(None);
+            }
+            Err(e) => return Err(format!("Read data failed: {}", e)),
+        }
+        let (mut reader, mut writer) = stream.into_split();
+        let (frames, mut outgoing) = mpsc::unbounded_channel::<Vec<u8>>();
+        let session = Arc::new(Session {
+            frames,
+            pending: Arc::new(StdMutex::new(HashMap::new())),
+            next_id: AtomicU32::new(0),
+            closed: Arc::new(AtomicBool::new(false)),
+        });
+
+        tokio::spawn(async move {
+            while let Some(frame) = outgoing.recv().await {
+                // Tagged in the order they go out, which is the order the peer checks
+                let frame = seal(outgoing_mac.as_mut(), frame);
+                if writer.write_all(&frame).await.is_err() {
+                    break;
+                }
+            }
+        });
+        let (pending, closed) = (Arc::clone(&session.pending), Arc::clone(&session.closed));
+        let address = peer.address.clone();
+        tokio::spawn(async move {
+            loop {
+                match read_session_frame(&mut reader, incoming_mac.as_mut()).await {
+                    Ok((SESSION_RESULT, id, data)) => {
+                        if let Some(tx) = pending.lock().unwrap().remove(&id) {
+                            let _ = tx.send(data);
+                        }
+                    }
+                    Ok((kind, _, _)) => {
+                        tracing::warn!("Peer {} sent an unexpected frame type {}", address, kind);
+                        break;
+                    }
+                    Err(e) => {
+                        if e.kind() == std::io::ErrorKind::InvalidData {
+                            tracing::warn!("Dropped the session to peer {}: {}", address, e);
+                        }
+                        break;
+                    }
+                }
+            }
+            closed.store(true, Ordering::SeqCst);
+            // Dropping the senders fails every offload still waiting
+            pending.lock().unwrap().clear();
+        });
+
+        sessions.insert(peer.address.clone(), Arc::clone(&session));
+        Ok(Some(session))
+    }
+}
+
+/// Type, request id and payload of the next session frame, checked against its tag
+/// on a keyed connection.
+async fn read_session_frame<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    mac: Option<&mut FrameMac>,
+) -> std::io::Result<(u8, u32, Vec<u8>)> {
+    let mut type_buf = [0u8; 1];
+    reader.read_exact(&mut type_buf).await?;
+    let (id, data) = read_tagged_payload(reader).await?;
+    check_mac(mac, reader, &session_frame(type_buf[0], id, &data)).await?;
+    Ok((type_buf[0], id, data))
+}
+
+async fn read_tagged_payload<R: AsyncRead + Unpin>(
+    reader: &mut R,
+) -> std::io::Result<(u32, Vec<u8>)> {
+    let mut id_buf = [0u8; 4];
+    reader.read_exact(&mut id_buf).await?;
+    let data = read_payload(reader).await?;
+    Ok((u32::from_le_bytes(id_buf), data))
+}
+
+async fn read_payload<R: AsyncRead + Unpin>(reader: &mut R) -> std::io::Result<Vec<u8>> {
+    let mut len_buf = [0u8; 4];
+    reader.read_exact(&mut len_buf).await?;
+    let len = u32::from_le_bytes(len_buf) as usize;
+    if len > MAX_FRAME_BYTES {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("Frame of {} bytes is over the limit", len),
+        ));
+    }
+    let mut data = vec![0u8; len];
+    reader.read_exact(&mut data).await?;
+    Ok(data)
+}
+
+/// Answer one connection on the peer side: a single prompt, or a session of them. With
+/// a `key`, only after the coordinator has proved it holds the key.
+pub async fn serve_peer(
+    mut socket: TcpStream,
+    model: Arc<Mutex<Model>>,
+    identity: Arc<PeerIdentity>,
+) {
+    // Read type
+    let mut type_buf = [0u8; 1];
+    if socket.read_exact(&mut type_buf).await.is_err() {
+        return;
+    }
+    let (mut outgoing, mut incoming) = (None, None);
+    if let Some(key) = &identity.key {
+        let accepted = match type_buf[0] {
+            HELLO => accept(&mut socket, key).await,
+            _ => Err("No handshake".to_string()),
+        };
+        match accepted {
+            Ok((sent, received)) => (outgoing, incoming) = (Some(sent), Some(received)),
+            Err(e) => {
+                let from = socket
+                    .peer_addr()
+                    .map(|a| a.to_string())
+                    .unwrap_or_default();
+                tracing::warn!("Refused unauthenticated coordinator {}: {}", from, e);
+                return;
+            }
+        }
+        if socket.read_exact(&mut type_buf).await.is_err() {
+            return;
+        }
+    }
+    match type_buf[0] {
+        PROMPT => {
+            let Ok(prompt) = read_payload(&mut socket).await else {
+                return;
+            };
+            if check_mac(incoming.as_mut(), &mut socket, &frame(PROMPT, &prompt))
+                .await
+                .is_err()
+            {
+                return;
+            }
+            let data = answer(&model, prompt, &identity.signing_key).await;
+            // Send response: type 3, length, data
+            let message = seal(outgoing.as_mut(), frame(RESULT, &data));
+            let _ = socket.write_all(&message).await;
+        }
+        SESSION_OPEN => {
+            let opened = match read_tagged_payload(&mut socket).await {
+                Ok((id, data)) => {
+                    check_mac(
+                        incoming.as_mut(),
+                        &mut socket,
+                        &session_frame(SESSION_OPEN, id, &data),
+                    )
+                    .await
+                }
+                Err(e) => Err(e),
+            };
+            if opened.is_ok() {
+                serve_session(socket, model, identity, outgoing, incoming).await;
+            }
+        }
+        _ => {} // Invalid type
+    }
+}
+
+// Prompts are answered as they finish, each tagged with the id it came with, up to
+// MAX_SESSION_PROMPTS at a time
+async fn serve_session(
+    socket: TcpStream,
+    model: Arc<Mutex<Model>>,
+    identity: Arc<PeerIdentity>,
+    mut outgoing_mac: Option<FrameMac>,
+    mut incoming_mac: Option<FrameMac>,
+) {
+    let (mut reader, mut writer) = socket.into_split();
+    let (results, mut outgoing) = mpsc::unbounded_channel::<Vec<u8>>();
+    let _ = results.send(session_frame(SESSION_READY, 0, &[]));
+    tokio::spawn(async move {
+        while let Some(frame) = outgoing.recv().await {
+            let frame = seal(outgoing_mac.as_mut(), frame);
+            if writer.write_all(&frame).await.is_err() {
+                break;
+            }
+        }
+    });
+    let slots = Arc::new(Semaphore::new(MAX_SESSION_PROMPTS));
+    while let Ok((SESSION_PROMPT, id, prompt)) =
+        read_session_frame(&mut reader, incoming_mac.as_mut()).await
+    {
+        let Ok(slot) = Arc::clone(&slots).acquire_owned().await else {
+            break;
+        };
+        let (model, identity, results) =
+            (Arc::clone(&model), Arc::clone(&identity), results.clone());
+        tokio::spawn(async move {
+            let data = answer(&model, prompt, &identity.signing_key).await;
+            let _ = results.send(session_frame(SESSION_RESULT, id, &data));
+            drop(slot);
+        });
+    }
+}
+
+/// Run `prompt` on the peer's model and encode the hashed and signed result.
+async fn answer(model: &Mutex<Model>, prompt: Vec<u8>, signing_key: &SigningKey) -> Vec<u8> {
+    let prompt = String::from_utf8_lossy(&prompt).into_owned();
+    // Run inference
+    let generated = {
+        let model = model.lock().await;
+        model
+            .run_prompt(
+                &PromptInput::text(prompt.clone()),
+                &GenerationControl::default(),
+            )
+            .await
+    };
+    let (output, error) = match generated {
+        Ok(output) => (output, None),
+        Err(e) => {
+            tracing::warn!("Failed to answer an offloaded prompt: {}", e);
+            (String::new(), Some(e))
+        }
+    };
+
+    // Compute hash
+    let mut hasher = Sha256::new();
+    hasher.update(&output);
+    let hash = format!("{:x}", hasher.finalize());
+
+    let signature = signing_key.sign(&result_message(&prompt, &hash));
+    serde_json::to_vec(&InferenceResult {
+        output,
+        hash,
+        signature: Some(BASE64.encode(signature.to_bytes())),
+        error,
+    })
+    .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::duplex;
+
+    // The peer's side of a connection: its HELLO type byte, then `accept`
+    async fn accept_hello<S: AsyncRead + AsyncWrite + Unpin>(
+        socket: &mut S,
+        key: &str,
+    ) -> Result<(FrameMac, FrameMac), String> {
+        let mut type_buf = [0u8; 1];
+        socket
+            .read_exact(&mut type_buf)
+            .await
+            .map_err(|e| e.to_string())?;
+        assert_eq!(type_buf[0], HELLO);
+        accept(socket, key).await
+    }
+
+    fn macs() -> (FrameMac, FrameMac) {
+        let (coordinator_nonce, peer_nonce) = (nonce(), nonce());
+        (
+            FrameMac::new("key", COORDINATOR_ROLE, &coordinator_nonce, &peer_nonce),
+            FrameMac::new("key", COORDINATOR_ROLE, &coordinator_nonce, &peer_nonce),
+        )
+    }
+
+    async fn check(mac: &mut FrameMac, sealed: &[u8]) -> std::io::Result<()> {
+        let (frame, mut tag) = sealed.split_at(sealed.len() - FRAME_MAC_LEN);
+        check_mac(Some(mac), &mut tag, frame).await
+    }
+
+    #[tokio::test]
+    async fn handshake_keys_both_directions() {
+        let (mut coordinator, mut peer) = duplex(4096);
+        let (coordinator_side, peer_side) = tokio::join!(
+            authenticate(&mut coordinator, "key"),
+            accept_hello(&mut peer, "key"),
+        );
+        let (mut to_peer, mut from_peer) = coordinator_side.unwrap();
+        let (mut to_coordinator, mut from_coordinator) = peer_side.unwrap();
+
+        let prompt = seal(Some(&mut to_peer), frame(PROMPT, b"Hello"));
+        let result = seal(Some(&mut to_coordinator), frame(RESULT, b"Hi"));
+        check(&mut from_coordinator, &prompt).await.unwrap();
+        check(&mut from_peer, &result).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn coordinator_refuses_a_peer_with_another_key() {
+        let (mut coordinator, mut peer) = duplex(4096);
+        // The coordinator hangs up once it has seen the wrong proof
+        let coordinator_side = async move { authenticate(&mut coordinator, "key").await };
+        let (coordinator_side, peer_side) =
+            tokio::join!(coordinator_side, accept_hello(&mut peer, "other"));
+        let e = coordinator_side.err().unwrap();
+        assert!(e.starts_with("Handshake failed"), "{}", e);
+        assert!(peer_side.is_err());
+    }
+
+    #[tokio::test]
+    async fn peer_refuses_a_coordinator_with_another_key() {
+        let (mut coordinator, mut peer) = duplex(4096);
+        let forged = async {
+            let ours = nonce();
+            coordinator.write_all(&frame(HELLO, &ours)).await.unwrap();
+            let mut type_buf = [0u8; 1];
+            coordinator.read_exact(&mut type_buf).await.unwrap();
+            let challenge = read_payload(&mut coordinator).await.unwrap();
+            let theirs = &challenge[..NONCE_LEN];
+            let proof = proof("other", COORDINATOR_ROLE, &ours, theirs);
+            coordinator.write_all(&frame(PROOF, &proof)).await.unwrap();
+        };
+        let ((), peer_side) = tokio::join!(forged, accept_hello(&mut peer, "key"));
+        assert_eq!(
+            peer_side.err().as_deref(),
+            Some("The coordinator doesn't hold the key")
+        );
+    }
+
+    #[tokio::test]
+    async fn tampered_frames_fail_the_mac() {
+        let (mut sender, mut receiver) = macs();
+        let mut sealed = seal(Some(&mut sender), frame(PROMPT, b"Hello"));
+        sealed[5] ^= 1;
+        let e = check(&mut receiver, &sealed).await.unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn replayed_frames_fail_the_mac() {
+        let (mut sender, mut receiver) = macs();
+        let sealed = seal(Some(&mut sender), frame(PROMPT, b"Hello"));
+        check(&mut receiver, &sealed).await.unwrap();
+        let e = check(&mut receiver, &sealed).await.unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn reordered_frames_fail_the_mac() {
+        let (mut sender, mut receiver) = macs();
+        let _first = seal(Some(&mut sender), frame(PROMPT, b"first"));
+        let second = seal(Some(&mut sender), frame(PROMPT, b"second"));
+        let e = check(&mut receiver, &second).await.unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn frames_reflected_to_their_sender_fail_the_mac() {
+        let (coordinator_nonce, peer_nonce) = (nonce(), nonce());
+        let mut sender = FrameMac::new("key", COORDINATOR_ROLE, &coordinator_nonce, &peer_nonce);
+        let mut receiver = FrameMac::new("key", PEER_ROLE, &coordinator_nonce, &peer_nonce);
+        let sealed = seal(Some(&mut sender), frame(PROMPT, b"Hello"));
+        let e = check(&mut receiver, &sealed).await.unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    fn signed_result(prompt: &str, signing_key: &SigningKey) -> InferenceResult {
+        let hash = format!("{:x}", Sha256::digest(b"Hi"));
+        let signature = signing_key.sign(&result_message(prompt, &hash));
+        InferenceResult {
+            output: "Hi".to_string(),
+            hash,
+            signature: Some(BASE64.encode(signature.to_bytes())),
+            error: None,
+        }
+    }
+
+    fn signed_by(peer: &Peer, signing_key: &SigningKey) -> PeerSessions {
+        PeerSessions {
+            public_keys: HashMap::from([(peer.address.clone(), signing_key.verifying_key())]),
+            ..Default::default()
+        }
+    }
+
+    fn peer() -> Peer {
+        Peer {
+            id: "peer".to_string(),
+            address: "10.0.0.2:8081".to_string(),
+        }
+    }
+
+    #[test]
+    fn results_signed_by_the_peer_key_are_trusted() {
+        let (peer, key) = (peer(), SigningKey::generate(&mut OsRng));
+        let result = signed_result("Hello", &key);
+        let checked = signed_by(&peer, &key).check_signature("Hello", &peer, result);
+        assert_eq!(checked.unwrap().output, "Hi");
+    }
+
+    #[test]
+    fn results_signed_by_another_key_are_refused() {
+        let peer = peer();
+        let sessions = signed_by(&peer, &SigningKey::generate(&mut OsRng));
+        let result = signed_result("Hello", &SigningKey::generate(&mut OsRng));
+        assert_eq!(
+            sessions
+                .check_signature("Hello", &peer, result)
+                .err()
+                .as_deref(),
+            Some("Result signature doesn't match the peer's key")
+        );
+    }
+
+    #[test]
+    fn unsigned_results_are_refused_from_peers_with_a_key() {
+        let (peer, key) = (peer(), SigningKey::generate(&mut OsRng));
+        let mut result = signed_result("Hello", &key);
+        result.signature = None;
+        assert_eq!(
+            signed_by(&peer, &key)
+                .check_signature("Hello", &peer, result)
+                .err()
+                .as_deref(),
+            Some("Result signature missing")
+        );
+    }
+
+    #[test]
+    fn results_signed_for_another_prompt_are_refused() {
+        let (peer, key) = (peer(), SigningKey::generate(&mut OsRng));
+        // A genuine answer to one prompt replayed as the answer to another
+        let result = signed_result("Hello", &key);
+        assert_eq!(
+            signed_by(&peer, &key)
+                .check_signature("Goodbye", &peer, result)
+                .err()
+                .as_deref(),
+            Some("Result signature doesn't match the peer's key")
+        );
+    }
+
+    #[test]
+    fn peers_without_a_key_are_trusted_on_the_hash() {
+        let peer = peer();
+        let mut result = signed_result("Hello", &SigningKey::generate(&mut OsRng));
+        result.signature = None;
+        let checked = PeerSessions::default().check_signature("Hello", &peer, result);
+        assert!(checked.is_ok());
     }
 }
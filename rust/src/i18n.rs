@@ -0,0 +1,291 @@
+use axum::{
+    body::{Body, HttpBody},
+    extract::{Request, State},
+    http::{HeaderValue, header},
+    middleware::Next,
+    response::Response,
+};
+use serde_json::Value;
+use std::sync::Arc;
+
+use crate::server::AppState;
+
+/// Languages API errors can be returned in; the catalog columns follow this order.
+pub const LOCALES: [&str; 4] = ["en", "es", "fr", "de"];
+// Error bodies are small; anything bigger is passed through untouched
+const MAX_ERROR_BODY: usize = 64 * 1024;
+
+// English message (with `{}` for interpolated values), then its translations
+const MESSAGES: &[[&str; 4]] = &[
+    [
+        "Daemon is in read-only mode",
+        "El daemon está en modo de solo lectura",
+        "Le démon est en mode lecture seule",
+        "Der Daemon ist im Nur-Lese-Modus",
+    ],
+    [
+        "Address not allowed",
+        "Dirección no permitida",
+        "Adresse non autorisée",
+        "Adresse nicht zulässig",
+    ],
+    [
+        "Execution not found",
+        "Ejecución no encontrada",
+        "Exécution introuvable",
+        "Ausführung nicht gefunden",
+    ],
+    [
+        "Persona not found",
+        "Persona no encontrada",
+        "Persona introuvable",
+        "Persona nicht gefunden",
+    ],
+    [
+        "Session not found",
+        "Sesión no encontrada",
+        "Session introuvable",
+        "Sitzung nicht gefunden",
+    ],
+    [
+        "Memory not found",
+        "Recuerdo no encontrado",
+        "Souvenir introuvable",
+        "Erinnerung nicht gefunden",
+    ],
+    [
+        "Missing or unknown API key",
+        "Clave de API ausente o desconocida",
+        "Clé d'API absente ou inconnue",
+        "API-Schlüssel fehlt oder ist unbekannt",
+    ],
+    [
+        "Rate limit exceeded, retry in {}s",
+        "Límite de solicitudes superado, reintente en {} s",
+        "Limite de débit dépassée, réessayez dans {} s",
+        "Ratenlimit überschritten, erneut versuchen in {} s",
+    ],
+    [
+        "Execution queue is full",
+        "La cola de ejecuciones está llena",
+        "La file d'exécution est pleine",
+        "Die Ausführungswarteschlange ist voll",
+    ],
+    [
+        "Generation stopped",
+        "Generación detenida",
+        "Génération arrêtée",
+        "Generierung gestoppt",
+    ],
+    [
+        "Request timed out after {}s",
+        "La solicitud superó el tiempo límite de {} s",
+        "La requête a expiré après {} s",
+        "Zeitüberschreitung der Anfrage nach {} s",
+    ],
+    [
+        "Request body is {} bytes, the limit is {} bytes",
+        "El cuerpo de la solicitud ocupa {} bytes; el límite es de {} bytes",
+        "Le corps de la requête fait {} octets, la limite est de {} octets",
+        "Der Anfragetext ist {} Bytes groß, das Limit beträgt {} Bytes",
+    ],
+    [
+        "Prompt too long: {} tokens, the limit is {}",
+        "Prompt demasiado largo: {} tokens; el límite es {}",
+        "Prompt trop long : {} jetons, la limite est de {}",
+        "Prompt zu lang: {} Tokens, das Limit beträgt {}",
+    ],
+    // Reasons filled into otherwise empty error responses
+    [
+        "Bad Request",
+        "Solicitud incorrecta",
+        "Requête invalide",
+        "Ungültige Anfrage",
+    ],
+    [
+        "Unauthorized",
+        "No autorizado",
+        "Non autorisé",
+        "Nicht autorisiert",
+    ],
+    ["Forbidden", "Prohibido", "Interdit", "Verboten"],
+    [
+        "Not Found",
+        "No encontrado",
+        "Introuvable",
+        "Nicht gefunden",
+    ],
+    [
+        "Method Not Allowed",
+        "Método no permitido",
+        "Méthode non autorisée",
+        "Methode nicht erlaubt",
+    ],
+    [
+        "Payload Too Large",
+        "Carga demasiado grande",
+        "Contenu trop volumineux",
+        "Anfrage zu groß",
+    ],
+    [
+        "Unsupported Media Type",
+        "Tipo de medio no admitido",
+        "Type de média non pris en charge",
+        "Nicht unterstützter Medientyp",
+    ],
+    [
+        "Unprocessable Entity",
+        "Entidad no procesable",
+        "Entité non traitable",
+        "Nicht verarbeitbare Entität",
+    ],
+    [
+        "Too Many Requests",
+        "Demasiadas solicitudes",
+        "Trop de requêtes",
+        "Zu viele Anfragen",
+    ],
+    [
+        "Internal Server Error",
+        "Error interno del servidor",
+        "Erreur interne du serveur",
+        "Interner Serverfehler",
+    ],
+    [
+        "Service Unavailable",
+        "Servicio no disponible",
+        "Service indisponible",
+        "Dienst nicht verfügbar",
+    ],
+    [
+        "Gateway Timeout",
+        "Tiempo de espera de la puerta de enlace agotado",
+        "Délai d'attente de la passerelle dépassé",
+        "Zeitüberschreitung des Gateways",
+    ],
+];
+
+/// Position of `locale` in LOCALES, by its primary language subtag.
+pub fn locale_index(locale: &str) -> Option<usize> {
+    let language = locale.split(['-', '_']).next()?.trim().to_ascii_lowercase();
+    LOCALES.iter().position(|l| *l == language)
+}
+
+/// The supported language the client ranks highest in its Accept-Language header.
+fn negotiate(accept_language: &str) -> Option<usize> {
+    let mut ranges: Vec<(f32, &str)> = accept_language
+        .split(',')
+        .filter_map(|range| {
+            let mut parts = range.split(';');
+            let tag = parts.next()?.trim();
+            let quality = parts
+                .find_map(|p| p.trim().strip_prefix("q="))
+                .map_or(Some(1.0), |q| q.parse().ok())?;
+            Some((quality, tag))
+        })
+        .filter(|(quality, _)| *quality > 0.0)
+        .collect();
+    ranges.sort_by(|a, b| b.0.total_cmp(&a.0));
+    ranges.into_iter().find_map(|(_, tag)| locale_index(tag))
+}
+
+/// Values `message` fills into `template`'s placeholders, if it is an instance of it.
+fn match_template<'a>(template: &str, message: &'a str) -> Option<Vec<&'a str>> {
+    let mut parts = template.split("{}");
+    let mut rest = message.strip_prefix(parts.next()?)?;
+    let parts: Vec<&str> = parts.collect();
+    let mut args = Vec::new();
+    for (i, part) in parts.iter().enumerate() {
+        let end = if i + 1 == parts.len() {
+            rest.strip_suffix(part)?.len()
+        } else {
+            rest.find(part)?
+        };
+        if end == 0 {
+            return None;
+        }
+        args.push(&rest[..end]);
+        rest = &rest[end + part.len()..];
+    }
+    // Without placeholders the whole message must match
+    if parts.is_empty() && !rest.is_empty() {
+        return None;
+    }
+    Some(args)
+}
+
+fn translate(message: &str, locale: usize) -> Option<String> {
+    MESSAGES.iter().find_map(|row| {
+        let args = match_template(row[0], message)?;
+        let mut out = String::new();
+        for (i, part) in row[locale].split("{}").enumerate() {
+            if i > 0 {
+                out.push_str(args.get(i - 1)?);
+            }
+            out.push_str(part);
+        }
+        Some(out)
+    })
+}
+
+/// Translate the `error` of JSON error responses into the language picked from
+/// Accept-Language, or the configured `locale` when the client doesn't ask for one.
+/// Messages without a translation are left in English.
+pub async fn localize(
+    State(state): State<Arc<AppState>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let locale = request
+        .headers()
+        .get(header::ACCEPT_LANGUAGE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(negotiate)
+        .or(state.locale);
+    let response = next.run(request).await;
+    let Some(locale) = locale.filter(|l| *l != 0) else {
+        return response;
+    };
+    let is_json = response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .is_some_and(|value| value.as_bytes().starts_with(b"application/json"));
+    let small = response
+        .body()
+        .size_hint()
+        .upper()
+        .is_some_and(|size| size <= MAX_ERROR_BODY as u64);
+    if !(response.status().is_client_error() || response.status().is_server_error())
+        || !is_json
+        || !small
+    {
+        return response;
+    }
+
+    let (mut parts, body) = response.into_parts();
+    let Ok(bytes) = axum::body::to_bytes(body, MAX_ERROR_BODY).await else {
+        return Response::from_parts(parts, Body::empty());
+    };
+    let mut value: Value = match serde_json::from_slice(&bytes) {
+        Ok(value) => value,
+        Err(_) => return Response::from_parts(parts, Body::from(bytes)),
+    };
+    let translated = value
+        .get("error")
+        .and_then(Value::as_str)
+        .and_then(|message| translate(message, locale));
+    let Some(translated) = translated else {
+        return Response::from_parts(parts, Body::from(bytes));
+    };
+    value["error"] = Value::from(translated);
+    let body = value.to_string();
+    parts.headers.remove(header::CONTENT_LENGTH);
+    parts.headers.insert(
+        header::CONTENT_LANGUAGE,
+        HeaderValue::from_static(LOCALES[locale]),
+    );
+    parts
+        .headers
+        .append(header::VARY, HeaderValue::from_static("accept-language"));
+    Response::from_parts(parts, Body::from(body))
+}
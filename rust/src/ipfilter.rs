@@ -0,0 +1,156 @@
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    http::StatusCode,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::Arc;
+
+use crate::config::{IpFilterConfig, IpListConfig};
+use crate::deprecation::API_PREFIX;
+use crate::error::{ApiError, ErrorCode};
+use crate::security::SecurityEventKind;
+use crate::server::AppState;
+
+/// Requests under this prefix, versioned or not, are checked against the admin lists.
+const ADMIN_PREFIX: &str = "/admin";
+
+/// A single address or a CIDR block such as 192.168.1.0/24.
+#[derive(Debug, Clone, Copy)]
+struct IpRange {
+    network: IpAddr,
+    prefix: u32,
+}
+
+impl FromStr for IpRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = s.split_once('/').map_or((s, None), |(a, p)| (a, Some(p)));
+        let network: IpAddr = addr
+            .trim()
+            .parse()
+            .map_err(|_| format!("Invalid IP address {:?}", s))?;
+        let max = if network.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(p) => p
+                .trim()
+                .parse()
+                .ok()
+                .filter(|p| *p <= max)
+                .ok_or_else(|| format!("Invalid prefix length in {:?}", s))?,
+            None => max,
+        };
+        Ok(Self { network, prefix })
+    }
+}
+
+impl IpRange {
+    fn contains(&self, ip: IpAddr) -> bool {
+        match (self.network, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+struct IpList {
+    allow: Vec<IpRange>,
+    deny: Vec<IpRange>,
+}
+
+impl IpList {
+    fn new(config: &IpListConfig) -> Result<Self, String> {
+        let parse = |list: &[String]| -> Result<Vec<IpRange>, String> {
+            list.iter().map(|s| s.parse()).collect()
+        };
+        Ok(Self {
+            allow: parse(&config.allow)?,
+            deny: parse(&config.deny)?,
+        })
+    }
+
+    /// Deny wins; a non-empty allow list admits only its own ranges.
+    fn permits(&self, ip: IpAddr) -> bool {
+        !self.deny.iter().any(|range| range.contains(ip))
+            && (self.allow.is_empty() || self.allow.iter().any(|range| range.contains(ip)))
+    }
+}
+
+// Separate lists for the user API and the /admin surface
+pub struct IpFilter {
+    user: IpList,
+    admin: IpList,
+}
+
+impl IpFilter {
+    pub fn new(config: &IpFilterConfig) -> Result<Self, String> {
+        Ok(Self {
+            user: IpList::new(&config.user)?,
+            admin: IpList::new(&config.admin)?,
+        })
+    }
+}
+
+/// Refuse requests from addresses the configured lists don't permit, recording each
+/// refusal in the audit log.
+pub async fn filter_ip(
+    State(state): State<Arc<AppState>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let Some(filter) = &state.ip_filter else {
+        return next.run(request).await;
+    };
+    let Some(ip) = request
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip().to_canonical())
+    else {
+        return next.run(request).await;
+    };
+    let path = request.uri().path().to_string();
+    let unversioned = path.strip_prefix(API_PREFIX).unwrap_or(&path);
+    let (surface, list) = if unversioned.starts_with(ADMIN_PREFIX) {
+        ("admin", &filter.admin)
+    } else {
+        ("user", &filter.user)
+    };
+    if list.permits(ip) {
+        return next.run(request).await;
+    }
+
+    tracing::warn!("Denied {} request from {} to {}", surface, ip, path);
+    state.security.lock().await.record(
+        SecurityEventKind::IpDenied,
+        Some(format!("ip:{}", ip)),
+        format!("Denied {} request to {}", surface, path),
+    );
+    state
+        .audit
+        .record(
+            "ip_denied",
+            serde_json::json!({
+                "ip": ip.to_string(),
+                "surface": surface,
+                "method": request.method().as_str(),
+                "path": path,
+            }),
+        )
+        .await;
+    ApiError::new(
+        StatusCode::FORBIDDEN,
+        ErrorCode::Forbidden,
+        "Address not allowed",
+    )
+    .into_response()
+}
@@ -0,0 +1,53 @@
+use axum::{
+    extract::{Request, State},
+    http::{StatusCode, header},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use std::sync::Arc;
+
+use crate::error::{ApiError, ErrorCode};
+use crate::model::PROMPT_TOO_LONG;
+use crate::ratelimit::ClientId;
+use crate::security::SecurityEventKind;
+use crate::server::AppState;
+
+/// 413 explaining which limit was hit.
+pub fn payload_too_large(message: String) -> Response {
+    let code = if message.starts_with(PROMPT_TOO_LONG) {
+        ErrorCode::PromptTooLong
+    } else {
+        ErrorCode::PayloadTooLarge
+    };
+    ApiError::new(StatusCode::PAYLOAD_TOO_LARGE, code, message).into_response()
+}
+
+/// Refuse bodies declared larger than `max_body_bytes` before reading them. Bodies without
+/// a Content-Length are capped by `DefaultBodyLimit` as they are read.
+pub async fn limit_body(
+    State(state): State<Arc<AppState>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let length = request
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<usize>().ok());
+    match length {
+        Some(length) if length > state.max_body_bytes => {
+            let message = format!(
+                "Request body is {} bytes, the limit is {} bytes",
+                length, state.max_body_bytes
+            );
+            let client = request.extensions().get::<ClientId>().map(|c| c.0.clone());
+            state.security.lock().await.record(
+                SecurityEventKind::OversizedRequest,
+                client,
+                format!("{} on {}", message, request.uri().path()),
+            );
+            payload_too_large(message)
+        }
+        _ => next.run(request).await,
+    }
+}
@@ -0,0 +1,69 @@
+use axum::{
+    Router,
+    extract::State,
+    response::{IntoResponse, Json, Response},
+    routing::get,
+};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tracing_subscriber::{
+    Registry, filter::LevelFilter, fmt, layer::SubscriberExt, reload, util::SubscriberInitExt,
+};
+
+use crate::error::ApiError;
+use crate::server::AppState;
+
+/// Changes the level of the global subscriber while the daemon runs.
+pub type LogLevelHandle = reload::Handle<LevelFilter, Registry>;
+
+/// Install the global subscriber at `info`, writing to stderr when stdout is taken.
+pub fn init(to_stderr: bool) -> LogLevelHandle {
+    let (filter, handle) = reload::Layer::new(LevelFilter::INFO);
+    let registry = tracing_subscriber::registry().with(filter);
+    if to_stderr {
+        registry
+            .with(fmt::layer().with_writer(std::io::stderr))
+            .init();
+    } else {
+        registry.with(fmt::layer()).init();
+    }
+    handle
+}
+
+#[derive(Serialize, Deserialize)]
+struct LogLevel {
+    /// `off`, `error`, `warn`, `info`, `debug` or `trace`
+    level: String,
+}
+
+pub fn routes() -> Router<Arc<AppState>> {
+    Router::new().route("/admin/log_level", get(get_log_level).put(set_log_level))
+}
+
+async fn get_log_level(State(state): State<Arc<AppState>>) -> Response {
+    match state.log_level.with_current(|filter| filter.to_string()) {
+        Ok(level) => Json(LogLevel { level }).into_response(),
+        Err(e) => ApiError::internal(e.to_string()).into_response(),
+    }
+}
+
+async fn set_log_level(State(state): State<Arc<AppState>>, Json(req): Json<LogLevel>) -> Response {
+    let Ok(filter) = req.level.parse::<LevelFilter>() else {
+        return ApiError::bad_request(format!("Unknown log level {:?}", req.level)).into_response();
+    };
+    if let Err(e) = state.log_level.reload(filter) {
+        return ApiError::internal(e.to_string()).into_response();
+    }
+    tracing::warn!("Log level set to {}", filter);
+    state
+        .audit
+        .record(
+            "log_level",
+            serde_json::json!({"level": filter.to_string()}),
+        )
+        .await;
+    Json(LogLevel {
+        level: filter.to_string(),
+    })
+    .into_response()
+}
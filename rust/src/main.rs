@@ -1,29 +1,111 @@
+mod access_log;
+mod agent;
+mod approvals;
+mod audit;
+mod auth;
+mod backup;
+mod cache;
+mod command_tools;
+mod config;
+mod conversations;
+mod deprecation;
+mod error;
+mod etag;
+mod gguf;
+mod history;
 mod hybrid;
+mod i18n;
+mod ipfilter;
+mod limits;
+mod logging;
+mod maintenance;
+mod memories;
+mod migrations;
 mod model;
+mod models;
 mod monitor;
+mod ollama;
+mod personas;
+mod plain;
+mod provenance;
+mod ratelimit;
+mod security;
+mod selfcheck;
 mod server;
+mod sessions;
+mod simulate;
+mod standby;
+mod stdio;
+mod support;
+mod sync;
+mod templates;
+mod tools;
+mod transcript;
+mod tts;
+mod usage;
 
 use axum::serve;
+use axum_server::Handle;
+use axum_server::tls_rustls::RustlsConfig;
 use futures::TryStreamExt;
 use indicatif::{ProgressBar, ProgressStyle};
 use reqwest::Client;
 use std::env;
 use std::fs::File;
+use std::future::IntoFuture;
 use std::io::Write;
+use std::net::SocketAddr;
 use std::path::Path;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
 use tokio::net::TcpListener;
 use tokio::sync::Mutex;
 use tokio_util::io::StreamReader;
+use tokio_util::sync::CancellationToken;
 
+use audit::AuditLog;
+use auth::ReplayGuard;
+use cache::ResponseCache;
+use command_tools::CommandTools;
+use config::Config;
+use conversations::ConversationStore;
+use history::ExecutionHistory;
 use hybrid::HybridExecutor;
+use ipfilter::IpFilter;
+use logging::LogLevelHandle;
+use maintenance::Maintenance;
+use memories::MemoryStore;
 use model::Model;
 use monitor::Monitor;
+use personas::PersonaStore;
+use provenance::ProvenanceStamp;
+use ratelimit::RateLimiter;
+use security::SecurityMonitor;
 use server::{AppState, ExecutionManager, create_router};
+use sessions::SessionStore;
+use standby::UpgradeStatus;
+use templates::TemplateStore;
+use tts::Tts;
+use usage::UsageLog;
+
+// Set under --stdio, where stdout carries JSON-RPC
+static STDIO: AtomicBool = AtomicBool::new(false);
+
+/// println!, except on stderr under --stdio.
+macro_rules! say {
+    ($($arg:tt)*) => {
+        if STDIO.load(Ordering::Relaxed) {
+            eprintln!($($arg)*);
+        } else {
+            println!($($arg)*);
+        }
+    };
+}
 
 async fn download_model(url: &str, path: &Path) -> Result<(), Box<dyn std::error::Error>> {
     if path.exists() {
-        println!("Model already exists at {:?}", path);
+        say!("Model already exists at {:?}", path);
         return Ok(());
     }
 
@@ -32,7 +114,7 @@ async fn download_model(url: &str, path: &Path) -> Result<(), Box<dyn std::error
         std::fs::create_dir_all(parent)?;
     }
 
-    println!("Downloading model from {}...", url);
+    say!("Downloading model from {}...", url);
     let client = Client::new();
     let response = client.get(url).send().await?;
     let total_size = response.content_length().unwrap_or(0);
@@ -63,15 +145,64 @@ async fn main() {
 }
 
 async fn run_server() {
-    tracing_subscriber::fmt().init();
-
     let args: Vec<String> = env::args().collect();
+    let stdio = args.iter().any(|a| a == "--stdio");
+    STDIO.store(stdio, Ordering::Relaxed);
+    let log_level = logging::init(stdio);
+
     if args.len() > 1 && args[1] == "peer" {
         run_as_peer().await;
         return;
     }
+    if args.len() > 1 && args[1] == "--check" {
+        check_migrations();
+        return;
+    }
+    if args.len() > 1 && (args[1] == "backup" || args[1] == "restore") {
+        run_backup_command(&args[1..]);
+        return;
+    }
+    if args.len() > 1 && args[1] == "support-bundle" {
+        run_support_command(&args[1..]).await;
+        return;
+    }
+
+    let shutdown = CancellationToken::new();
+    tokio::spawn({
+        let shutdown = shutdown.clone();
+        async move {
+            shutdown_signal().await;
+            shutdown.cancel();
+        }
+    });
+    let mut previous = None;
+    while let Some(model) = run_daemon(&args, &shutdown, &log_level, previous).await {
+        previous = Some(model);
+    }
+}
 
-    let mut model = Model::new().expect("Failed to create model");
+/// Load the config and models and serve until a shutdown signal or POST /admin/restart.
+/// On restart, returns an unloaded model whose llama backend (which can only be
+/// initialised once per process) the next run reuses.
+async fn run_daemon(
+    args: &[String],
+    shutdown: &CancellationToken,
+    log_level: &LogLevelHandle,
+    previous: Option<Model>,
+) -> Option<Model> {
+    let config = Config::load().expect("Failed to load config");
+    match migrations::run(&config::data_dir()) {
+        Ok(0) => {}
+        Ok(n) => say!("Applied {} data migration(s)", n),
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    }
+    let mut model = match previous {
+        Some(previous) => previous.sibling(config.model.clone()),
+        None => Model::new(config.model.clone()).expect("Failed to create model"),
+    };
 
     // Load model at startup
     let model_path = env::var("MODEL_PATH").unwrap_or_else(|_| {
@@ -83,7 +214,7 @@ async fn run_server() {
             home
         )
     });
-    println!("Model path: {}", model_path);
+    say!("Model path: {}", model_path);
     let model_path_path = Path::new(&model_path);
     if !model_path_path.exists() {
         let url = "https://huggingface.co/Qwen/Qwen2.5-0.5B-Instruct-GGUF/resolve/main/qwen2.5-0.5b-instruct-q4_k_m.gguf";
@@ -91,40 +222,363 @@ async fn run_server() {
             .await
             .expect("Failed to download model");
     }
-    println!("Loading model from: {}", model_path);
+    say!("Loading model from: {}", model_path);
     model
         .load_model(&model_path)
         .await
         .expect("Failed to load model");
 
+    let reranker = match &config.reranker_path {
+        Some(path) => {
+            say!("Loading reranker from: {}", path);
+            let mut reranker = model.sibling(config.model.clone());
+            reranker
+                .load_model(path)
+                .await
+                .expect("Failed to load reranker");
+            Some(Arc::new(Mutex::new(reranker)))
+        }
+        None => None,
+    };
+
+    let embedder = match &config.embedding_path {
+        Some(path) => {
+            say!("Loading embedding model from: {}", path);
+            let mut embedder = model.sibling(config.model.clone());
+            embedder
+                .load_model(path)
+                .await
+                .expect("Failed to load embedding model");
+            Some(Arc::new(Mutex::new(embedder)))
+        }
+        None => None,
+    };
+
+    let tts = match config.tts.clone() {
+        Some(tts_config) => {
+            let tts = Tts::new(tts_config);
+            if let Err(e) = tts.ensure_default_voice().await {
+                eprintln!("Failed to download TTS voice: {}", e);
+            }
+            Some(Arc::new(tts))
+        }
+        None => None,
+    };
+
+    let provenance = match config.provenance {
+        Some(mode) => {
+            let name = model.name().unwrap_or_else(|| {
+                model_path_path
+                    .file_stem()
+                    .map(|stem| stem.to_string_lossy().into_owned())
+                    .unwrap_or_default()
+            });
+            let stamp = ProvenanceStamp::new(mode, name, model_path_path.to_path_buf())
+                .await
+                .expect("Failed to hash model for provenance");
+            Some(Arc::new(stamp))
+        }
+        None => None,
+    };
+
+    let mut execution_manager = ExecutionManager::new(
+        provenance.clone(),
+        config.queue_depth,
+        config.max_concurrent_executions,
+        config.retention.clone(),
+    );
+    let mut requeued = Vec::new();
+    if config.persist_executions {
+        let (history, stored) =
+            ExecutionHistory::open(&config::data_dir().join("executions.sqlite3"))
+                .expect("Failed to open execution history");
+        tracing::info!("Loaded {} saved executions", stored.len());
+        requeued = execution_manager.restore(history, stored, config.interrupted_executions);
+    }
+
     let app_state = Arc::new(AppState {
         model: Arc::new(Mutex::new(model)),
+        reranker,
+        embedder,
         monitor: Arc::new(Mutex::new(Monitor::new())),
         state: Arc::new(Mutex::new("idle".to_string())),
-        execution_manager: Arc::new(Mutex::new(ExecutionManager::new())),
-        hybrid_executor: Arc::new(Mutex::new(HybridExecutor::new())),
+        execution_manager: Arc::new(Mutex::new(execution_manager)),
+        hybrid_executor: Arc::new(Mutex::new(
+            HybridExecutor::new(&config.hybrid).expect("Invalid hybrid config"),
+        )),
+        personas: Arc::new(Mutex::new(
+            PersonaStore::load(config::data_dir().join("personas.json"))
+                .expect("Failed to load personas"),
+        )),
+        templates: Arc::new(Mutex::new(
+            TemplateStore::load(config::data_dir().join("templates.json"))
+                .expect("Failed to load templates"),
+        )),
+        sessions: Arc::new(Mutex::new(SessionStore::new())),
+        conversations: Arc::new(Mutex::new(
+            ConversationStore::load(config::data_dir().join("conversations.json"))
+                .expect("Failed to load conversations"),
+        )),
+        memories: config.memory.clone().map(|memory| {
+            Arc::new(Mutex::new(
+                MemoryStore::load(config::data_dir().join("memories.json"), memory)
+                    .expect("Failed to load memories"),
+            ))
+        }),
+        tts,
+        cache: config
+            .cache
+            .as_ref()
+            .map(|cache| Arc::new(Mutex::new(ResponseCache::new(cache)))),
+        provenance,
+        prompt_cancel: Arc::new(Mutex::new(None)),
+        api_keys: config.api_keys.clone(),
+        replay_guard: config
+            .request_signing
+            .as_ref()
+            .map(|signing| Arc::new(Mutex::new(ReplayGuard::new(signing.window_secs)))),
+        ip_filter: config
+            .ip_filter
+            .as_ref()
+            .map(|filter| IpFilter::new(filter).expect("Invalid ip_filter")),
+        audit: Arc::new(AuditLog::new(config::data_dir().join("audit.log"))),
+        usage: Arc::new(UsageLog::new(config::data_dir().join("usage.log"))),
+        security: Arc::new(Mutex::new(SecurityMonitor::new())),
+        stop: shutdown.child_token(),
+        log_level: log_level.clone(),
+        read_only: config.read_only || args.iter().any(|a| a == "--read-only"),
+        timeouts: config.timeouts.clone(),
+        max_body_bytes: config.max_body_bytes,
+        frontend_dir: config.frontend_dir.clone().into(),
+        locale: config.locale.as_deref().map(|locale| {
+            i18n::locale_index(locale).expect("Unsupported locale, expected en, es, fr or de")
+        }),
+        maintenance: Arc::new(Mutex::new(
+            Maintenance::new(config.maintenance.clone()).expect("Invalid maintenance schedule"),
+        )),
+        rate_limiter: config
+            .rate_limit
+            .clone()
+            .map(|limits| Arc::new(Mutex::new(RateLimiter::new(limits)))),
+        command_tools: Arc::new(
+            CommandTools::new(
+                config.command_tools.clone(),
+                config.tool_sandbox.clone(),
+                config::data_dir().join("tools"),
+            )
+            .expect("Invalid command_tools"),
+        ),
+        agent: config.agent.clone(),
+        approve_executions: config.approve_executions,
+        sharing: config.sharing.clone(),
+        sync: config.sync.clone(),
+        model_upgrade: Arc::new(Mutex::new(UpgradeStatus::new())),
+        selfcheck: config.selfcheck.clone(),
+        last_selfcheck: Arc::new(Mutex::new(None)),
     });
 
-    let app = create_router(app_state);
+    maintenance::spawn_scheduler(Arc::clone(&app_state));
+    maintenance::spawn_collector(Arc::clone(&app_state), config.retention.gc_interval_secs);
+    sync::spawn_scheduler(Arc::clone(&app_state));
+    selfcheck::spawn_scheduler(Arc::clone(&app_state));
+    server::spawn_schedule_runner(Arc::clone(&app_state));
+    server::requeue(&app_state, requeued);
+    let app = create_router(Arc::clone(&app_state));
+    let grace = Duration::from_secs(config.shutdown_grace_secs);
+
+    if STDIO.load(Ordering::Relaxed) {
+        stdio::serve(app, Arc::clone(&app_state)).await;
+        app_state.drain(grace).await;
+        return None;
+    }
 
-    let listener = tokio::net::TcpListener::bind("127.0.0.1:3000").await;
+    let listener = TcpListener::bind(&config.listen).await;
     if let Err(e) = &listener {
         eprintln!(
-            "Failed to bind to port 3000: {}. Please ensure no other process is using port 3000.",
-            e
+            "Failed to bind to {}: {}. Please ensure no other process is using that port.",
+            config.listen, e
         );
         std::process::exit(1);
     }
     let listener = listener.unwrap();
-    println!("Daemon running on http://127.0.0.1:3000");
 
-    axum::serve(listener, app).await.unwrap();
+    let stop = app_state.stop.clone();
+
+    // Both servers stop accepting connections once `stop` fires
+    let mut server = match &config.tls {
+        Some(tls) => {
+            let rustls = RustlsConfig::from_pem_file(&tls.cert_path, &tls.key_path)
+                .await
+                .expect("Failed to load TLS certificate/key");
+            println!("Daemon running on https://{}", config.listen);
+            let handle = Handle::new();
+            tokio::spawn({
+                let handle = handle.clone();
+                let stop = stop.clone();
+                async move {
+                    stop.cancelled().await;
+                    handle.graceful_shutdown(Some(grace));
+                }
+            });
+            tokio::spawn(
+                axum_server::from_tcp_rustls(listener.into_std().unwrap(), rustls)
+                    .handle(handle)
+                    .serve(app.into_make_service_with_connect_info::<SocketAddr>()),
+            )
+        }
+        None => {
+            println!("Daemon running on http://{}", config.listen);
+            tokio::spawn(
+                serve(
+                    listener,
+                    app.into_make_service_with_connect_info::<SocketAddr>(),
+                )
+                .with_graceful_shutdown(stop.clone().cancelled_owned())
+                .into_future(),
+            )
+        }
+    };
+
+    tokio::select! {
+        result = &mut server => {
+            result.expect("Server task panicked").unwrap();
+            return None;
+        }
+        _ = stop.cancelled() => {}
+    }
+
+    let restarting = !shutdown.is_cancelled();
+    println!(
+        "{}, waiting up to {}s for generations to finish",
+        if restarting {
+            "Restarting"
+        } else {
+            "Shutting down"
+        },
+        grace.as_secs()
+    );
+    app_state.drain(grace).await;
+    if tokio::time::timeout(Duration::from_secs(5), &mut server)
+        .await
+        .is_err()
+    {
+        eprintln!("Connections still open, closing them");
+        server.abort();
+    }
+    if !restarting {
+        println!("Daemon stopped");
+        return None;
+    }
+
+    // Free the weights now rather than whenever the last handle to the old state goes
+    for slot in [&app_state.reranker, &app_state.embedder]
+        .into_iter()
+        .flatten()
+    {
+        slot.lock().await.unload();
+    }
+    let mut model = app_state.model.lock().await;
+    model.unload();
+    Some(model.sibling(config.model.clone()))
 }
 
-async fn run_as_peer() {
-    println!("Running as peer server on 127.0.0.1:8081");
+// Report pending data migrations without applying them; exits non-zero if any are pending
+fn check_migrations() {
+    let pending = migrations::pending(&config::data_dir()).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    });
+    if pending.is_empty() {
+        println!("No pending migrations");
+        return;
+    }
+    for migration in &pending {
+        println!(
+            "Pending migration {}: {}",
+            migration.id, migration.description
+        );
+    }
+    std::process::exit(1);
+}
+
+// kekahyde backup <path> [--models]
+// kekahyde restore <path> [--only config,personas,...]
+fn run_backup_command(args: &[String]) {
+    let Some(path) = args.get(1) else {
+        eprintln!("Usage: kekahyde backup <path> [--models] | restore <path> [--only <sections>]");
+        std::process::exit(2);
+    };
+    let data_dir = config::data_dir();
+    let result = if args[0] == "backup" {
+        let include_models = args.iter().any(|a| a == "--models");
+        backup::backup(&data_dir, Path::new(path), include_models)
+            .map(|n| format!("Backed up {} file(s) to {}", n, path))
+    } else {
+        let only: Option<Vec<String>> = args
+            .iter()
+            .position(|a| a == "--only")
+            .and_then(|i| args.get(i + 1))
+            .map(|list| list.split(',').map(|s| s.trim().to_string()).collect());
+        backup::restore(&data_dir, Path::new(path), only.as_deref())
+            .map(|n| format!("Restored {} file(s) from {}", n, path))
+    };
+    match result {
+        Ok(message) => println!("{}", message),
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+// kekahyde support-bundle <path> [--executions N]
+async fn run_support_command(args: &[String]) {
+    let Some(path) = args.get(1) else {
+        eprintln!("Usage: kekahyde support-bundle <path> [--executions N]");
+        std::process::exit(2);
+    };
+    let executions = args
+        .iter()
+        .position(|a| a == "--executions")
+        .and_then(|i| args.get(i + 1))
+        .and_then(|n| n.parse().ok())
+        .unwrap_or(20);
+    match support::bundle(&config::data_dir(), Path::new(path), executions).await {
+        Ok(n) => println!("Wrote {} file(s) to {}", n, path),
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    }
+}
 
-    let mut model = Model::new().expect("Failed to create model");
+// Ctrl+C, or SIGTERM from a service manager
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("Failed to listen for Ctrl+C");
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("Failed to listen for SIGTERM")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {}
+        _ = terminate => {}
+    }
+}
+
+async fn run_as_peer() {
+    let config = Config::load().expect("Failed to load config");
+    println!("Running as peer server on {}", config.hybrid.listen);
+    let mut model = Model::new(config.model).expect("Failed to create model");
     let model_path = env::var("MODEL_PATH").unwrap_or_else(|_| {
         let home = env::var("HOME")
             .or_else(|_| env::var("USERPROFILE"))
@@ -148,64 +602,30 @@ async fn run_as_peer() {
         .expect("Failed to load model");
 
     let model = Arc::new(Mutex::new(model));
+    if config.hybrid.key.is_none() {
+        tracing::warn!("No hybrid.key set; the peer answers any coordinator");
+    }
+    let signing_key = hybrid::load_signing_key(&config::data_dir().join("peer_signing.key"))
+        .expect("Failed to load the peer signing key");
+    let identity = Arc::new(hybrid::PeerIdentity {
+        key: config.hybrid.key.clone(),
+        signing_key,
+    });
+    println!(
+        "Peer public key (for hybrid.peer_keys): {}",
+        identity.public_key()
+    );
 
-    let listener = TcpListener::bind("127.0.0.1:8081").await.unwrap();
+    let listener = TcpListener::bind(&config.hybrid.listen)
+        .await
+        .expect("Failed to bind the peer listen address");
 
     loop {
-        let (mut socket, _) = listener.accept().await.unwrap();
-        let model = Arc::clone(&model);
-
-        tokio::spawn(async move {
-            use hybrid::InferenceResult;
-            use serde_json;
-            use sha2::{Digest, Sha256};
-            use tokio::io::{AsyncReadExt, AsyncWriteExt};
-
-            // Read type
-            let mut type_buf = [0u8; 1];
-            if socket.read_exact(&mut type_buf).await.is_err() {
-                return;
-            }
-            if type_buf[0] != 2 {
-                return; // Invalid type
-            }
-
-            // Read length
-            let mut len_buf = [0u8; 4];
-            if socket.read_exact(&mut len_buf).await.is_err() {
-                return;
-            }
-            let len = u32::from_le_bytes(len_buf) as usize;
-
-            // Read prompt
-            let mut prompt_buf = vec![0u8; len];
-            if socket.read_exact(&mut prompt_buf).await.is_err() {
-                return;
-            }
-            let prompt = String::from_utf8(prompt_buf).unwrap();
-
-            // Run inference
-            let output = {
-                let model = model.lock().await;
-                model
-                    .run_prompt(&prompt)
-                    .await
-                    .unwrap_or_else(|_| "Error".to_string())
-            };
-
-            // Compute hash
-            let mut hasher = Sha256::new();
-            hasher.update(&output);
-            let hash = format!("{:x}", hasher.finalize());
-
-            let result = InferenceResult { output, hash };
-            let data = serde_json::to_vec(&result).unwrap();
-
-            // Send response: type 3, length, data
-            let mut message = vec![3u8];
-            message.extend(&(data.len() as u32).to_le_bytes());
-            message.extend(data);
-            let _ = socket.write_all(&message).await;
-        });
+        let (socket, _) = listener.accept().await.unwrap();
+        tokio::spawn(hybrid::serve_peer(
+            socket,
+            Arc::clone(&model),
+            Arc::clone(&identity),
+        ));
     }
 }
@@ -0,0 +1,219 @@
+use axum::{Router, extract::State, response::Json, routing::get};
+use chrono::{DateTime, Datelike, Duration, Local, NaiveDate, NaiveTime, Utc, Weekday};
+use serde::Serialize;
+use std::sync::Arc;
+
+use crate::config::MaintenanceWindow;
+use crate::server::{self, AppState};
+
+// How often the scheduler checks whether a window has opened or closed
+const POLL_INTERVAL: std::time::Duration = std::time::Duration::from_secs(30);
+
+struct Window {
+    config: MaintenanceWindow,
+    start: NaiveTime,
+    days: Vec<Weekday>,
+}
+
+impl Window {
+    fn parse(config: MaintenanceWindow) -> Result<Self, String> {
+        let start = NaiveTime::parse_from_str(&config.start, "%H:%M").map_err(|_| {
+            format!(
+                "Invalid maintenance start {:?}, expected HH:MM",
+                config.start
+            )
+        })?;
+        let days = config
+            .days
+            .iter()
+            .map(|day| {
+                day.parse::<Weekday>()
+                    .map_err(|_| format!("Invalid maintenance day {:?}", day))
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Self {
+            config,
+            start,
+            days,
+        })
+    }
+
+    /// When the window opens on `date`, if it applies that day.
+    fn start_on(&self, date: NaiveDate) -> Option<DateTime<Local>> {
+        if !self.days.is_empty() && !self.days.contains(&date.weekday()) {
+            return None;
+        }
+        date.and_time(self.start)
+            .and_local_timezone(Local)
+            .earliest()
+    }
+
+    fn is_open(&self, now: DateTime<Local>) -> bool {
+        let today = now.date_naive();
+        // Yesterday's window may run past midnight
+        [today.pred_opt(), Some(today)]
+            .into_iter()
+            .flatten()
+            .filter_map(|date| self.start_on(date))
+            .any(|start| {
+                now >= start && now < start + Duration::minutes(self.config.duration_mins.into())
+            })
+    }
+
+    fn next_start(&self, now: DateTime<Local>) -> Option<DateTime<Local>> {
+        let today = now.date_naive();
+        (0..=7)
+            .filter_map(|offset| today.checked_add_days(chrono::Days::new(offset)))
+            .filter_map(|date| self.start_on(date))
+            .find(|start| *start > now)
+    }
+}
+
+/// What the last housekeeping pass cleaned up.
+#[derive(Serialize, Clone)]
+pub struct HousekeepingReport {
+    pub finished_at: String,
+    pub executions_pruned: usize,
+    pub cache_entries_purged: usize,
+}
+
+#[derive(Serialize)]
+struct MaintenanceStatus {
+    windows: Vec<MaintenanceWindow>,
+    active: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    next_window: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    last_run: Option<HousekeepingReport>,
+}
+
+pub struct Maintenance {
+    windows: Vec<Window>,
+    active: bool,
+    last_run: Option<HousekeepingReport>,
+}
+
+impl Maintenance {
+    pub fn new(windows: Vec<MaintenanceWindow>) -> Result<Self, String> {
+        Ok(Self {
+            windows: windows
+                .into_iter()
+                .map(Window::parse)
+                .collect::<Result<_, _>>()?,
+            active: false,
+            last_run: None,
+        })
+    }
+
+    fn is_scheduled(&self) -> bool {
+        !self.windows.is_empty()
+    }
+
+    fn status(&self) -> MaintenanceStatus {
+        let now = Local::now();
+        MaintenanceStatus {
+            windows: self.windows.iter().map(|w| w.config.clone()).collect(),
+            active: self.active,
+            next_window: self
+                .windows
+                .iter()
+                .filter_map(|w| w.next_start(now))
+                .min()
+                .map(|start| start.to_rfc3339()),
+            last_run: self.last_run.clone(),
+        }
+    }
+}
+
+/// Pause the queue and run housekeeping while a window is open, then resume.
+pub fn spawn_scheduler(state: Arc<AppState>) {
+    tokio::spawn(async move {
+        if !state.maintenance.lock().await.is_scheduled() {
+            return;
+        }
+        let mut interval = tokio::time::interval(POLL_INTERVAL);
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {}
+                _ = state.stop.cancelled() => return,
+            }
+            let now = Local::now();
+            let (open, active) = {
+                let maintenance = state.maintenance.lock().await;
+                (
+                    maintenance.windows.iter().any(|w| w.is_open(now)),
+                    maintenance.active,
+                )
+            };
+            if open && !active {
+                tracing::info!("Maintenance window open, pausing the queue");
+                state.maintenance.lock().await.active = true;
+                state.execution_manager.lock().await.set_paused(true);
+                let report = housekeeping(&state).await;
+                tracing::info!(
+                    "Housekeeping pruned {} executions and {} cache entries",
+                    report.executions_pruned,
+                    report.cache_entries_purged
+                );
+                state.maintenance.lock().await.last_run = Some(report);
+            } else if !open && active {
+                tracing::info!("Maintenance window closed, resuming the queue");
+                state.execution_manager.lock().await.set_paused(false);
+                state.maintenance.lock().await.active = false;
+                server::dispatch_next(&state).await;
+            }
+        }
+    });
+}
+
+/// Apply the execution `retention` every `interval_secs`, outside maintenance windows too.
+pub fn spawn_collector(state: Arc<AppState>, interval_secs: u64) {
+    if interval_secs == 0 {
+        return;
+    }
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(std::time::Duration::from_secs(interval_secs));
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {}
+                _ = state.stop.cancelled() => return,
+            }
+            let pruned = prune_executions(&state).await;
+            if pruned > 0 {
+                tracing::info!("Forgot {} finished executions past retention", pruned);
+            }
+        }
+    });
+}
+
+/// Forget finished executions past retention, with their cached audio.
+async fn prune_executions(state: &AppState) -> usize {
+    let pruned = state.execution_manager.lock().await.prune_finished();
+    if let Some(tts) = &state.tts {
+        for id in &pruned {
+            tts.remove_audio(id).await;
+        }
+    }
+    pruned.len()
+}
+
+async fn housekeeping(state: &AppState) -> HousekeepingReport {
+    let executions_pruned = prune_executions(state).await;
+    let cache_entries_purged = match &state.cache {
+        Some(cache) => cache.lock().await.purge_expired(),
+        None => 0,
+    };
+    HousekeepingReport {
+        finished_at: Utc::now().to_rfc3339(),
+        executions_pruned,
+        cache_entries_purged,
+    }
+}
+
+pub fn routes() -> Router<Arc<AppState>> {
+    Router::new().route("/admin/maintenance", get(maintenance_status))
+}
+
+async fn maintenance_status(State(state): State<Arc<AppState>>) -> Json<MaintenanceStatus> {
+    Json(state.maintenance.lock().await.status())
+}
@@ -0,0 +1,235 @@
+use axum::{
+    Router,
+    extract::{Path, State},
+    http::StatusCode,
+    response::Json,
+    routing::{get, put},
+};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
+use std::collections::HashSet;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use tokio_util::sync::CancellationToken;
+use uuid::Uuid;
+
+use crate::config::MemoryConfig;
+use crate::error::ApiError;
+use crate::model::{GenerationControl, LiveParams, PromptInput};
+use crate::server::AppState;
+
+/// A durable fact about the user, extracted from past exchanges.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Memory {
+    pub id: String,
+    pub text: String,
+    pub created_at: String,
+}
+
+#[derive(Deserialize)]
+struct UpdateMemoryRequest {
+    text: String,
+}
+
+// Memories persisted as a JSON array under the data dir
+pub struct MemoryStore {
+    path: PathBuf,
+    config: MemoryConfig,
+    memories: Vec<Memory>,
+}
+
+impl MemoryStore {
+    pub fn load(path: PathBuf, config: MemoryConfig) -> Result<Self, String> {
+        let memories = if path.exists() {
+            let data = std::fs::read_to_string(&path)
+                .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+            serde_json::from_str(&data)
+                .map_err(|e| format!("Invalid memories file {}: {}", path.display(), e))?
+        } else {
+            Vec::new()
+        };
+        Ok(Self {
+            path,
+            config,
+            memories,
+        })
+    }
+
+    fn save(&self) -> Result<(), String> {
+        if let Some(parent) = self.path.parent() {
+            std::fs::create_dir_all(parent).map_err(|e| e.to_string())?;
+        }
+        let data = serde_json::to_vec_pretty(&self.memories).map_err(|e| e.to_string())?;
+        std::fs::write(&self.path, data).map_err(|e| e.to_string())
+    }
+
+    /// Store new facts, skipping ones already remembered.
+    fn add(&mut self, facts: Vec<String>) -> Result<usize, String> {
+        let mut added = 0;
+        for text in facts {
+            if self
+                .memories
+                .iter()
+                .any(|m| m.text.eq_ignore_ascii_case(&text))
+            {
+                continue;
+            }
+            self.memories.push(Memory {
+                id: Uuid::new_v4().to_string(),
+                text,
+                created_at: Utc::now().to_rfc3339(),
+            });
+            added += 1;
+        }
+        if added > 0 {
+            self.save()?;
+        }
+        Ok(added)
+    }
+
+    /// Memories sharing the most words with `prompt`, best first.
+    pub fn relevant(&self, prompt: &str) -> Vec<&Memory> {
+        let prompt_words = words(prompt);
+        let mut scored: Vec<(usize, &Memory)> = self
+            .memories
+            .iter()
+            .map(|m| (words(&m.text).intersection(&prompt_words).count(), m))
+            .filter(|(score, _)| *score > 0)
+            .collect();
+        scored.sort_by_key(|(score, _)| Reverse(*score));
+        scored
+            .into_iter()
+            .take(self.config.max_injected)
+            .map(|(_, m)| m)
+            .collect()
+    }
+}
+
+fn words(text: &str) -> HashSet<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|w| w.len() > 3)
+        .map(str::to_lowercase)
+        .collect()
+}
+
+/// Attach memories relevant to the prompt; the template renders them after the system prompt.
+pub async fn inject(state: &AppState, input: &mut PromptInput) {
+    let Some(store) = &state.memories else {
+        return;
+    };
+    let store = store.lock().await;
+    input.memories = store
+        .relevant(&input.user)
+        .into_iter()
+        .map(|m| m.text.clone())
+        .collect();
+}
+
+/// Extract durable facts from a finished exchange without holding up the response.
+pub fn extract_in_background(state: Arc<AppState>, user: String, response: String) {
+    if state.memories.is_none() {
+        return;
+    }
+    tokio::spawn(async move {
+        let prompt = format!(
+            "From the exchange below, list durable facts about the user worth remembering in future conversations \
+             (preferences, personal details, ongoing projects). Write one short fact per line. \
+             If there are none, reply NONE.\n\nUser: {}\nAssistant: {}",
+            user, response
+        );
+        let control = GenerationControl::with_params(
+            CancellationToken::new(),
+            LiveParams {
+                temperature: 0.1,
+                max_tokens: 128,
+                ..Default::default()
+            },
+        );
+        // Queues behind any generation in progress
+        let output = {
+            let model = state.model.lock().await;
+            model.run_prompt(&PromptInput::text(prompt), &control).await
+        };
+        let facts: Vec<String> = match output {
+            Ok(output) => output
+                .lines()
+                .map(|line| {
+                    line.trim()
+                        .trim_start_matches(|c: char| {
+                            c.is_ascii_digit() || matches!(c, '-' | '*' | '•' | '.' | ')')
+                        })
+                        .trim()
+                        .to_string()
+                })
+                .filter(|fact| !fact.is_empty() && !fact.eq_ignore_ascii_case("none"))
+                .filter(|fact| fact.len() <= 200)
+                .collect(),
+            Err(e) => {
+                tracing::warn!("Memory extraction failed: {}", e);
+                return;
+            }
+        };
+        if let Some(store) = &state.memories
+            && let Err(e) = store.lock().await.add(facts)
+        {
+            tracing::error!("Failed to save memories: {}", e);
+        }
+    });
+}
+
+pub fn routes() -> Router<Arc<AppState>> {
+    Router::new()
+        .route("/memories", get(list_memories))
+        .route("/memories/:id", put(update_memory).delete(delete_memory))
+}
+
+fn store(state: &AppState) -> Result<&Arc<Mutex<MemoryStore>>, ApiError> {
+    state
+        .memories
+        .as_ref()
+        .ok_or_else(|| ApiError::not_found("Memory is not enabled"))
+}
+
+async fn list_memories(State(state): State<Arc<AppState>>) -> Result<Json<Vec<Memory>>, ApiError> {
+    let store = store(&state)?;
+    Ok(Json(store.lock().await.memories.clone()))
+}
+
+async fn update_memory(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+    Json(req): Json<UpdateMemoryRequest>,
+) -> Result<Json<Memory>, ApiError> {
+    let mut store = store(&state)?.lock().await;
+    let memory = store
+        .memories
+        .iter_mut()
+        .find(|m| m.id == id)
+        .ok_or_else(|| ApiError::not_found("Memory not found"))?;
+    memory.text = req.text;
+    let memory = memory.clone();
+    store.save().map_err(|e| {
+        tracing::error!("Failed to save memories: {}", e);
+        ApiError::internal(e)
+    })?;
+    Ok(Json(memory))
+}
+
+async fn delete_memory(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+) -> Result<StatusCode, ApiError> {
+    let mut store = store(&state)?.lock().await;
+    let before = store.memories.len();
+    store.memories.retain(|m| m.id != id);
+    if store.memories.len() == before {
+        return Err(ApiError::not_found("Memory not found"));
+    }
+    store.save().map_err(|e| {
+        tracing::error!("Failed to save memories: {}", e);
+        ApiError::internal(e)
+    })?;
+    Ok(StatusCode::OK)
+}
@@ -0,0 +1,96 @@
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::path::Path;
+use uuid::Uuid;
+
+/// A one-way upgrade of files under the data dir. Append new ones with the next id;
+/// never edit or reorder a migration once it has shipped.
+pub struct Migration {
+    pub id: u32,
+    pub description: &'static str,
+    apply: fn(&Path) -> Result<(), String>,
+}
+
+const MIGRATIONS: &[Migration] = &[Migration {
+    id: 1,
+    description: "Assign ids to personas saved without one",
+    apply: assign_persona_ids,
+}];
+
+#[derive(Serialize, Deserialize)]
+struct AppliedMigration {
+    id: u32,
+    description: String,
+    applied_at: String,
+}
+
+// Applied migrations are recorded in <data dir>/migrations.json
+fn load_applied(data_dir: &Path) -> Result<Vec<AppliedMigration>, String> {
+    let path = data_dir.join("migrations.json");
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+    let data = std::fs::read_to_string(&path)
+        .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+    serde_json::from_str(&data)
+        .map_err(|e| format!("Invalid migrations file {}: {}", path.display(), e))
+}
+
+fn save_applied(data_dir: &Path, applied: &[AppliedMigration]) -> Result<(), String> {
+    std::fs::create_dir_all(data_dir).map_err(|e| e.to_string())?;
+    let data = serde_json::to_vec_pretty(applied).map_err(|e| e.to_string())?;
+    std::fs::write(data_dir.join("migrations.json"), data).map_err(|e| e.to_string())
+}
+
+pub fn pending(data_dir: &Path) -> Result<Vec<&'static Migration>, String> {
+    let applied = load_applied(data_dir)?;
+    Ok(MIGRATIONS
+        .iter()
+        .filter(|m| !applied.iter().any(|a| a.id == m.id))
+        .collect())
+}
+
+/// Apply pending migrations in order, recording each as soon as it succeeds.
+/// Returns how many were applied.
+pub fn run(data_dir: &Path) -> Result<usize, String> {
+    let mut applied = load_applied(data_dir)?;
+    let pending = pending(data_dir)?;
+    for migration in &pending {
+        tracing::info!(
+            "Applying migration {}: {}",
+            migration.id,
+            migration.description
+        );
+        (migration.apply)(data_dir)
+            .map_err(|e| format!("Migration {} failed: {}", migration.id, e))?;
+        applied.push(AppliedMigration {
+            id: migration.id,
+            description: migration.description.to_string(),
+            applied_at: Utc::now().to_rfc3339(),
+        });
+        save_applied(data_dir, &applied)?;
+    }
+    Ok(pending.len())
+}
+
+// Personas without an id all loaded under "" and overwrote each other
+fn assign_persona_ids(data_dir: &Path) -> Result<(), String> {
+    let path = data_dir.join("personas.json");
+    if !path.exists() {
+        return Ok(());
+    }
+    let data = std::fs::read_to_string(&path).map_err(|e| e.to_string())?;
+    let mut personas: Vec<Value> = serde_json::from_str(&data).map_err(|e| e.to_string())?;
+    for persona in &mut personas {
+        let missing = persona
+            .get("id")
+            .and_then(Value::as_str)
+            .is_none_or(str::is_empty);
+        if missing && let Some(object) = persona.as_object_mut() {
+            object.insert("id".to_string(), Uuid::new_v4().to_string().into());
+        }
+    }
+    let data = serde_json::to_vec_pretty(&personas).map_err(|e| e.to_string())?;
+    std::fs::write(&path, data).map_err(|e| e.to_string())
+}
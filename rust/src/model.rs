@@ -1,100 +1,784 @@
-use llama_cpp_2::context::params::LlamaContextParams;
+use llama_cpp_2::context::params::{LlamaContextParams, LlamaPoolingType};
 use llama_cpp_2::llama_backend::LlamaBackend;
 use llama_cpp_2::llama_batch::LlamaBatch;
-use llama_cpp_2::model::{AddBos, LlamaModel, Special, params::LlamaModelParams};
+use llama_cpp_2::model::{AddBos, LlamaModel, params::LlamaModelParams};
+use llama_cpp_2::mtmd::{
+    MtmdBitmap, MtmdContext, MtmdContextParams, MtmdInputText, mtmd_default_marker,
+};
 use llama_cpp_2::sampling::LlamaSampler;
-use num_cpus;
 
+use serde::{Deserialize, Serialize};
 use std::num::NonZeroU32;
-use std::sync::Arc;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio_util::sync::CancellationToken;
+
+use crate::config::{ModelConfig, NumaMode};
+use crate::tools::{self, ToolDefinition};
+
+/// Sampling parameters the decode loop re-reads before every token.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LiveParams {
+    pub temperature: f32,
+    pub max_tokens: usize,
+    pub seed: u32,
+}
+
+impl Default for LiveParams {
+    fn default() -> Self {
+        Self {
+            temperature: 0.7,
+            max_tokens: 256,
+            seed: 42,
+        }
+    }
+}
+
+/// Partial set of sampling parameters; unset fields keep the current value.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SamplingProfile {
+    pub temperature: Option<f32>,
+    pub max_tokens: Option<usize>,
+    pub seed: Option<u32>,
+}
+
+impl SamplingProfile {
+    pub fn apply(&self, params: &mut LiveParams) {
+        if let Some(temperature) = self.temperature {
+            params.temperature = temperature;
+        }
+        if let Some(max_tokens) = self.max_tokens {
+            params.max_tokens = max_tokens;
+        }
+        if let Some(seed) = self.seed {
+            params.seed = seed;
+        }
+    }
+}
+
+pub const DEFAULT_SYSTEM_PROMPT: &str = "You are a helpful assistant.";
+
+/// Everything that goes into the rendered chat prompt.
+#[derive(Debug, Clone, Default)]
+pub struct PromptInput {
+    pub user: String,
+    /// Falls back to the configured `model.system_prompt` when unset
+    pub system: Option<String>,
+    pub images: Vec<Vec<u8>>,
+    /// Functions advertised to the model in the system prompt
+    pub tools: Vec<ToolDefinition>,
+    /// Remembered facts about the user, appended to the system prompt
+    pub memories: Vec<String>,
+    /// A client is waiting on the reply, so the prompt is evaluated in
+    /// `model.latency_batch` sized batches when that is set
+    pub interactive: bool,
+    /// Earlier steps of an agent run, replayed after the user's message
+    pub turns: Vec<Turn>,
+}
+
+/// One message of an agent run after the initial user prompt.
+#[derive(Debug, Clone)]
+pub enum Turn {
+    /// What the model answered, tool calls included
+    Assistant(String),
+    /// Output of a tool the model called
+    ToolResponse(String),
+}
+
+/// A rendered prompt and the named parts it was built from.
+struct RenderedPrompt {
+    text: String,
+    segments: Vec<(&'static str, String)>,
+}
+
+/// Token cost of one part of a prompt.
+#[derive(Debug, Clone, Serialize)]
+pub struct PromptSegment {
+    /// `system`, `tools`, `memories`, `user`, `history` or `template`
+    pub segment: &'static str,
+    pub tokens: usize,
+    pub chars: usize,
+}
+
+/// What the model is given for a prompt, for GET /execution/:id/context.
+#[derive(Debug, Clone, Serialize)]
+pub struct PromptContext {
+    pub rendered_prompt: String,
+    /// Text tokens only; image embeddings come on top
+    pub prompt_tokens: usize,
+    pub prompt_limit: usize,
+    pub context_size: usize,
+    pub images: usize,
+    pub segments: Vec<PromptSegment>,
+    pub fits: bool,
+    /// Prompts over the limit are refused with "Prompt too long", never shortened
+    pub truncation: &'static str,
+    /// Parts left out to fit; always empty under `reject`
+    pub trimmed: Vec<PromptSegment>,
+}
+
+impl PromptInput {
+    pub fn text(user: impl Into<String>) -> Self {
+        Self {
+            user: user.into(),
+            ..Default::default()
+        }
+    }
+}
+
+/// Why a generation stopped.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FinishReason {
+    /// The model ended its reply
+    #[default]
+    Stop,
+    /// `max_tokens` or the context window ran out first
+    Length,
+}
+
+/// Token counts and wall-clock timings captured from one generation.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct GenerationStats {
+    pub prompt_tokens: usize,
+    pub completion_tokens: usize,
+    pub prompt_eval_ms: f64,
+    pub generation_ms: f64,
+    pub finish_reason: FinishReason,
+}
+
+impl GenerationStats {
+    pub fn prompt_tokens_per_second(&self) -> f64 {
+        per_second(self.prompt_tokens, self.prompt_eval_ms)
+    }
+
+    pub fn generation_tokens_per_second(&self) -> f64 {
+        per_second(self.completion_tokens, self.generation_ms)
+    }
+}
+
+fn per_second(tokens: usize, ms: f64) -> f64 {
+    if ms > 0.0 {
+        tokens as f64 * 1000.0 / ms
+    } else {
+        0.0
+    }
+}
+
+/// Error returned by generation aborted through its cancellation token.
+pub const GENERATION_CANCELLED: &str = "Generation cancelled";
+
+/// Prefix of the error returned for prompts over `model.max_prompt_tokens`.
+pub const PROMPT_TOO_LONG: &str = "Prompt too long";
+
+/// Fixed prompt so benchmark numbers are comparable between runs and machines.
+pub const BENCHMARK_PROMPT: &str = "Write a detailed explanation of how a CPU cache hierarchy works, covering L1, L2 and L3 caches, cache lines, associativity and eviction policies.";
+
+/// Shared handle used to steer a generation while its decode loop is running.
+#[derive(Debug, Default)]
+pub struct GenerationControl {
+    paused: AtomicBool,
+    cancel: CancellationToken,
+    params: Mutex<LiveParams>,
+    // Totals over every generation run with this control, kept current by the decode loop
+    stats: Mutex<GenerationStats>,
+    // Text of the generation in progress, or of the last one, token by token
+    output: Mutex<String>,
+}
+
+impl GenerationControl {
+    pub fn new(cancel: CancellationToken) -> Self {
+        Self::with_params(cancel, LiveParams::default())
+    }
+
+    pub fn with_params(cancel: CancellationToken, params: LiveParams) -> Self {
+        Self {
+            paused: AtomicBool::new(false),
+            cancel,
+            params: Mutex::new(params),
+            stats: Mutex::new(GenerationStats::default()),
+            output: Mutex::new(String::new()),
+        }
+    }
+
+    pub fn params(&self) -> LiveParams {
+        *self.params.lock().unwrap()
+    }
+
+    pub fn update_params(&self, update: impl FnOnce(&mut LiveParams)) -> LiveParams {
+        let mut params = self.params.lock().unwrap();
+        update(&mut params);
+        *params
+    }
+
+    pub fn stats(&self) -> GenerationStats {
+        self.stats.lock().unwrap().clone()
+    }
+
+    pub fn record_stats(&self, update: impl FnOnce(&mut GenerationStats)) {
+        update(&mut self.stats.lock().unwrap());
+    }
+
+    /// What the current generation has produced so far, kept after it fails or is
+    /// cancelled.
+    pub fn partial_output(&self) -> String {
+        self.output.lock().unwrap().clone()
+    }
+
+    pub fn partial_len(&self) -> usize {
+        self.output.lock().unwrap().len()
+    }
+
+    pub fn set_partial_output(&self, text: String) {
+        *self.output.lock().unwrap() = text;
+    }
+
+    pub fn pause(&self) {
+        self.paused.store(true, Ordering::SeqCst);
+    }
+
+    pub fn resume(&self) {
+        self.paused.store(false, Ordering::SeqCst);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::SeqCst)
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancel.is_cancelled()
+    }
+
+    pub fn cancel_token(&self) -> CancellationToken {
+        self.cancel.clone()
+    }
+}
 
 pub struct Model {
     backend: Arc<LlamaBackend>,
+    config: ModelConfig,
+    // Vision projector; declared before `model` so it is dropped first
+    mtmd: Option<MtmdContext>,
     pub model: Option<Arc<LlamaModel>>,
+    path: Option<PathBuf>,
 }
 
 impl Model {
-    pub fn new() -> Result<Self, String> {
-        let backend = LlamaBackend::init().map_err(|e| format!("Backend init failed: {e:?}"))?;
+    pub fn new(config: ModelConfig) -> Result<Self, String> {
+        let backend = match config.numa {
+            NumaMode::Disabled => LlamaBackend::init(),
+            numa => LlamaBackend::init_numa(numa.into()),
+        }
+        .map_err(|e| format!("Backend init failed: {e:?}"))?;
 
         Ok(Self {
             backend: Arc::new(backend),
+            config,
+            mtmd: None,
             model: None,
+            path: None,
         })
     }
 
+    /// Create another model slot on the same llama backend, which can only be initialised once.
+    pub fn sibling(&self, config: ModelConfig) -> Self {
+        Self {
+            backend: Arc::clone(&self.backend),
+            config,
+            mtmd: None,
+            model: None,
+            path: None,
+        }
+    }
+
+    /// An unloaded model with the same backend and settings, to load replacement weights into.
+    pub fn standby(&self) -> Self {
+        self.sibling(self.config.clone())
+    }
+
     pub async fn load_model(&mut self, path: &str) -> Result<(), String> {
-        let params = LlamaModelParams::default();
+        if self.config.use_mlock && !llama_cpp_2::mlock_supported() {
+            tracing::warn!("use_mlock requested but mlock is not supported on this platform");
+        }
+        let mut params = LlamaModelParams::default()
+            .with_use_mmap(self.config.use_mmap)
+            .with_use_mlock(self.config.use_mlock);
+        if let Some(layers) = self.config.n_gpu_layers {
+            params = params.with_n_gpu_layers(layers);
+        }
 
         let model = LlamaModel::load_from_file(&self.backend, path, &params)
             .map_err(|e| format!("Model load failed: {e:?}"))?;
 
+        self.mtmd = match &self.config.mmproj_path {
+            Some(mmproj) => {
+                let params = MtmdContextParams {
+                    n_threads: num_cpus::get() as i32,
+                    ..Default::default()
+                };
+                let mtmd = MtmdContext::init_from_file(mmproj, &model, &params)
+                    .map_err(|e| format!("Projector load failed: {e:?}"))?;
+                Some(mtmd)
+            }
+            None => None,
+        };
         self.model = Some(Arc::new(model));
+        self.path = Some(PathBuf::from(path));
         Ok(())
     }
 
+    /// Drop the weights and projector; the backend stays for a later load.
+    pub fn unload(&mut self) {
+        self.mtmd = None;
+        self.model = None;
+        self.path = None;
+    }
+
     pub fn is_loaded(&self) -> bool {
         self.model.is_some()
     }
 
-    pub async fn run_prompt(&self, prompt: &str) -> Result<String, String> {
-        let model = self.model.as_ref().ok_or("Model not loaded")?;
+    pub fn supports_images(&self) -> bool {
+        self.mtmd.as_ref().is_some_and(|mtmd| mtmd.support_vision())
+    }
+
+    pub fn threads(&self) -> usize {
+        self.config.threads.unwrap_or_else(num_cpus::get)
+    }
+
+    pub fn gpu_layers(&self) -> Option<u32> {
+        self.config.n_gpu_layers
+    }
 
-        let threads = num_cpus::get();
+    /// Model name from the GGUF metadata, if the file declares one.
+    pub fn name(&self) -> Option<String> {
+        self.model
+            .as_ref()
+            .and_then(|model| model.meta_val_str("general.name").ok())
+    }
+
+    /// File the loaded weights came from.
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    /// Context window the model runs with, from `model.context_size`.
+    pub fn context_size(&self) -> u32 {
+        self.config.context_size
+    }
+
+    pub fn model_size(&self) -> u64 {
+        self.model.as_ref().map_or(0, |model| model.size())
+    }
+
+    fn context_params(&self, threads: usize) -> LlamaContextParams {
+        let rope = &self.config.rope;
 
-        let ctx_params = LlamaContextParams::default()
-            .with_n_ctx(NonZeroU32::new(2048))
+        let mut params = LlamaContextParams::default()
+            .with_n_ctx(NonZeroU32::new(self.config.context_size))
             .with_n_threads(threads as i32);
+        if let Some(scaling) = rope.scaling {
+            params = params.with_rope_scaling_type(scaling.into());
+        }
+        if let Some(base) = rope.freq_base {
+            params = params.with_rope_freq_base(base);
+        }
+        if let Some(scale) = rope.freq_scale {
+            params = params.with_rope_freq_scale(scale);
+        }
+        if let Some(factor) = rope.yarn_ext_factor {
+            params = params.with_yarn_ext_factor(factor);
+        }
+        if let Some(factor) = rope.yarn_attn_factor {
+            params = params.with_yarn_attn_factor(factor);
+        }
+        if let Some(beta) = rope.yarn_beta_fast {
+            params = params.with_yarn_beta_fast(beta);
+        }
+        if let Some(beta) = rope.yarn_beta_slow {
+            params = params.with_yarn_beta_slow(beta);
+        }
+        if let Some(ctx) = rope.yarn_orig_ctx {
+            params = params.with_yarn_orig_ctx(ctx);
+        }
+        params
+    }
 
+    fn pooled_context_params(&self, pooling: LlamaPoolingType) -> LlamaContextParams {
+        // Pooled outputs need the whole input in a single ubatch
+        let n_ctx = self.config.context_size;
+        self.context_params(self.threads())
+            .with_n_batch(n_ctx)
+            .with_n_ubatch(n_ctx)
+            .with_embeddings(true)
+            .with_pooling_type(pooling)
+    }
+
+    /// Mean-pooled, L2-normalised embedding of `text`.
+    pub async fn embed(&self, text: &str) -> Result<Vec<f32>, String> {
+        let model = self.model.as_ref().ok_or("Model not loaded")?;
         let mut ctx = model
-            .new_context(&self.backend, ctx_params)
+            .new_context(
+                &self.backend,
+                self.pooled_context_params(LlamaPoolingType::Mean),
+            )
             .map_err(|e| format!("Context creation failed: {e:?}"))?;
 
-        ctx.clear_kv_cache();
+        let mut tokens = model
+            .str_to_token(text, AddBos::Always)
+            .map_err(|e| format!("Tokenize failed: {e:?}"))?;
+        tokens.truncate(self.config.context_size as usize);
 
-        // ✅ Qwen2.5 uses ChatML format
-        let formatted_prompt = format!(
-            "<|im_start|>system\nYou are a helpful assistant.<|im_end|>\n<|im_start|>user\n{}<|im_end|>\n<|im_start|>assistant\n",
-            prompt
-        );
+        let mut batch = LlamaBatch::new(tokens.len(), 1);
+        batch
+            .add_sequence(&tokens, 0, true)
+            .map_err(|e| format!("Add sequence failed: {e:?}"))?;
+        ctx.decode(&mut batch)
+            .map_err(|e| format!("Eval failed: {e:?}"))?;
 
-        // Tokenize with BOS
-        let tokens = model
-            .str_to_token(&formatted_prompt, AddBos::Always)
-            .map_err(|e| format!("Tokenize failed: {e:?}"))?;
+        let embedding = ctx
+            .embeddings_seq_ith(0)
+            .map_err(|e| format!("Embedding failed: {e:?}"))?;
+        let norm = embedding
+            .iter()
+            .map(|v| v * v)
+            .sum::<f32>()
+            .sqrt()
+            .max(f32::EPSILON);
+        Ok(embedding.iter().map(|v| v / norm).collect())
+    }
+
+    /// Relevance of `document` to `query` from a cross-encoder reranker GGUF.
+    pub async fn rank(&self, query: &str, document: &str) -> Result<f32, String> {
+        let model = self.model.as_ref().ok_or("Model not loaded")?;
+        let mut ctx = model
+            .new_context(
+                &self.backend,
+                self.pooled_context_params(LlamaPoolingType::Rank),
+            )
+            .map_err(|e| format!("Context creation failed: {e:?}"))?;
 
-        println!("Prompt tokens: {}", tokens.len());
+        // [BOS] query [EOS] [SEP] document [EOS]
+        let mut tokens = model
+            .str_to_token(query, AddBos::Always)
+            .map_err(|e| format!("Tokenize failed: {e:?}"))?;
+        tokens.push(model.token_eos());
+        tokens.push(model.token_sep());
+        tokens.extend(
+            model
+                .str_to_token(document, AddBos::Never)
+                .map_err(|e| format!("Tokenize failed: {e:?}"))?,
+        );
+        tokens.push(model.token_eos());
+        tokens.truncate(self.config.context_size as usize);
 
-        // Evaluate prompt
-        let mut batch = LlamaBatch::new(2048, 1);
+        let mut batch = LlamaBatch::new(tokens.len(), 1);
         batch
             .add_sequence(&tokens, 0, true)
             .map_err(|e| format!("Add sequence failed: {e:?}"))?;
-
         ctx.decode(&mut batch)
             .map_err(|e| format!("Eval failed: {e:?}"))?;
 
-        // ✅ Better sampling parameters for Qwen2.5
-        let mut sampler = LlamaSampler::chain_simple(vec![
-            LlamaSampler::temp(0.7),
-            LlamaSampler::top_k(40),
-            LlamaSampler::top_p(0.9, 1),
-            LlamaSampler::min_p(0.05, 1),
-            LlamaSampler::dist(42),
-        ]);
+        let score = ctx
+            .embeddings_seq_ith(0)
+            .map_err(|e| format!("Rank failed: {e:?}"))?;
+        score
+            .first()
+            .copied()
+            .ok_or_else(|| "Reranker returned no score".to_string())
+    }
 
-        let mut output = String::new();
-        let mut pos = tokens.len() as i32;
-        let mut logits_index = (tokens.len() - 1) as i32;
+    pub async fn run_prompt(
+        &self,
+        input: &PromptInput,
+        control: &GenerationControl,
+    ) -> Result<String, String> {
+        self.run_prompt_with_stats(input, control)
+            .await
+            .map(|(output, _)| output)
+    }
+
+    pub async fn run_prompt_with_stats(
+        &self,
+        input: &PromptInput,
+        control: &GenerationControl,
+    ) -> Result<(String, GenerationStats), String> {
+        // The decode loop runs, and waits out pauses, without yielding, so the runtime
+        // hands this worker's other tasks to another thread meanwhile
+        tokio::task::block_in_place(|| self.generate(input, control, self.threads()))
+    }
 
-        let max_tokens = 256;
+    /// Run BENCHMARK_PROMPT with an explicit thread count.
+    pub async fn benchmark(
+        &self,
+        threads: usize,
+        control: &GenerationControl,
+    ) -> Result<GenerationStats, String> {
+        tokio::task::block_in_place(|| {
+            self.generate(&PromptInput::text(BENCHMARK_PROMPT), control, threads)
+        })
+        .map(|(_, stats)| stats)
+    }
+
+    /// Longest prompt accepted, in tokens.
+    fn prompt_limit(&self) -> usize {
+        let context = self.config.context_size as usize;
+        self.config
+            .max_prompt_tokens
+            .unwrap_or(context - context / 4)
+            .min(context - 1)
+    }
+
+    fn check_prompt_length(&self, tokens: usize) -> Result<(), String> {
+        let limit = self.prompt_limit();
+        if tokens > limit {
+            return Err(format!(
+                "{}: {} tokens, the limit is {}",
+                PROMPT_TOO_LONG, tokens, limit
+            ));
+        }
+        Ok(())
+    }
+
+    /// The prompt `generate` evaluates for `input`, with each part it was built from.
+    fn render(&self, input: &PromptInput) -> RenderedPrompt {
+        // Images are referenced by media markers ahead of the user's text
+        let markers: String = input
+            .images
+            .iter()
+            .map(|_| format!("{}\n", mtmd_default_marker()))
+            .collect();
+
+        let base = input
+            .system
+            .as_deref()
+            .unwrap_or(&self.config.system_prompt)
+            .to_string();
+        let mut system = if input.tools.is_empty() {
+            base.clone()
+        } else {
+            tools::system_prompt(&base, &input.tools)
+        };
+        let tools = system[base.len()..].to_string();
+        let mut memories = String::new();
+        if !input.memories.is_empty() {
+            memories.push_str("\n\nWhat you remember about the user:");
+            for memory in &input.memories {
+                memories.push_str("\n- ");
+                memories.push_str(memory);
+            }
+        }
+        system.push_str(&memories);
+
+        // Qwen2.5 returns tool output to the model in a user turn
+        let turns: String = input
+            .turns
+            .iter()
+            .map(|turn| match turn {
+                Turn::Assistant(content) => {
+                    format!("<|im_start|>assistant\n{}<|im_end|>\n", content)
+                }
+                Turn::ToolResponse(output) => format!(
+                    "<|im_start|>user\n<tool_response>\n{}\n</tool_response><|im_end|>\n",
+                    output
+                ),
+            })
+            .collect();
+
+        // ✅ Qwen2.5 uses ChatML format
+        let text = format!(
+            "<|im_start|>system\n{}<|im_end|>\n<|im_start|>user\n{}{}<|im_end|>\n{}<|im_start|>assistant\n",
+            system, markers, input.user, turns
+        );
+        RenderedPrompt {
+            text,
+            segments: vec![
+                ("system", base),
+                ("tools", tools),
+                ("memories", memories),
+                ("user", input.user.clone()),
+                ("history", turns),
+            ],
+        }
+    }
+
+    /// How `input` is rendered and what each part of it costs, without generating.
+    pub fn inspect_prompt(&self, input: &PromptInput) -> Result<PromptContext, String> {
+        let model = self.model.as_ref().ok_or("Model not loaded")?;
+        let count = |text: &str, bos: AddBos| {
+            model
+                .str_to_token(text, bos)
+                .map(|tokens| tokens.len())
+                .map_err(|e| format!("Tokenize failed: {e:?}"))
+        };
+        let rendered = self.render(input);
+        let prompt_tokens = count(&rendered.text, AddBos::Always)?;
+        let mut segments = Vec::new();
+        for (segment, text) in rendered.segments {
+            if text.is_empty() {
+                continue;
+            }
+            segments.push(PromptSegment {
+                segment,
+                tokens: count(&text, AddBos::Never)?,
+                chars: text.chars().count(),
+            });
+        }
+        // Role markers and separators of the chat template
+        let counted: usize = segments.iter().map(|s| s.tokens).sum();
+        segments.push(PromptSegment {
+            segment: "template",
+            tokens: prompt_tokens.saturating_sub(counted),
+            chars: 0,
+        });
+        let prompt_limit = self.prompt_limit();
+        Ok(PromptContext {
+            rendered_prompt: rendered.text,
+            prompt_tokens,
+            prompt_limit,
+            context_size: self.config.context_size as usize,
+            images: input.images.len(),
+            segments,
+            fits: prompt_tokens <= prompt_limit,
+            truncation: "reject",
+            trimmed: Vec::new(),
+        })
+    }
+
+    fn generate(
+        &self,
+        input: &PromptInput,
+        control: &GenerationControl,
+        threads: usize,
+    ) -> Result<(String, GenerationStats), String> {
+        let model = self.model.as_ref().ok_or("Model not loaded")?;
+        let images = &input.images;
+        if !images.is_empty() && !self.supports_images() {
+            return Err("Loaded model has no vision projector".to_string());
+        }
+
+        let n_ctx = self.config.context_size as usize;
+
+        let mut context_params = self.context_params(threads);
+        if input.interactive
+            && let Some(n_batch) = self.config.latency_batch
+        {
+            context_params = context_params.with_n_batch(n_batch).with_n_ubatch(n_batch);
+        }
+        let mut ctx = model
+            .new_context(&self.backend, context_params)
+            .map_err(|e| format!("Context creation failed: {e:?}"))?;
+
+        ctx.clear_kv_cache();
+        let mut stats = GenerationStats::default();
+        let prompt_start = Instant::now();
+
+        let formatted_prompt = self.render(input).text;
+
+        let (mut pos, mut logits_index) = match &self.mtmd {
+            Some(mtmd) if !images.is_empty() => {
+                let bitmaps = images
+                    .iter()
+                    .map(|data| MtmdBitmap::from_buffer(mtmd, data))
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(|e| format!("Image decode failed: {e:?}"))?;
+                let bitmap_refs: Vec<&MtmdBitmap> = bitmaps.iter().collect();
+                let chunks = mtmd
+                    .tokenize(
+                        MtmdInputText {
+                            text: formatted_prompt,
+                            add_special: true,
+                            parse_special: true,
+                        },
+                        &bitmap_refs,
+                    )
+                    .map_err(|e| format!("Tokenize failed: {e:?}"))?;
+
+                stats.prompt_tokens = chunks.total_tokens();
+                self.check_prompt_length(stats.prompt_tokens)?;
+                tracing::debug!(
+                    "Prompt tokens: {} ({} images)",
+                    stats.prompt_tokens,
+                    images.len()
+                );
+
+                // Text chunks are decoded directly, image chunks go through the vision encoder
+                let n_past = chunks
+                    .eval_chunks(mtmd, &ctx, 0, 0, ctx.n_batch() as i32, true)
+                    .map_err(|e| format!("Eval failed: {e:?}"))?;
+                (n_past, -1)
+            }
+            _ => {
+                // Tokenize with BOS
+                let tokens = model
+                    .str_to_token(&formatted_prompt, AddBos::Always)
+                    .map_err(|e| format!("Tokenize failed: {e:?}"))?;
+
+                stats.prompt_tokens = tokens.len();
+                tracing::debug!("Prompt tokens: {}", tokens.len());
+                self.check_prompt_length(tokens.len())?;
+
+                // Evaluate prompt, at most n_batch tokens per decode
+                let n_batch = ctx.n_batch() as usize;
+                let mut batch = LlamaBatch::new(n_batch, 1);
+                let mut last = 0;
+                for (i, chunk) in tokens.chunks(n_batch).enumerate() {
+                    batch.clear();
+                    for (j, token) in chunk.iter().enumerate() {
+                        let pos = i * n_batch + j;
+                        batch
+                            .add(*token, pos as i32, &[0], pos == tokens.len() - 1)
+                            .map_err(|e| format!("Add token failed: {e:?}"))?;
+                    }
+                    ctx.decode(&mut batch)
+                        .map_err(|e| format!("Eval failed: {e:?}"))?;
+                    last = chunk.len() - 1;
+                }
+
+                (tokens.len() as i32, last as i32)
+            }
+        };
+
+        stats.prompt_eval_ms = prompt_start.elapsed().as_secs_f64() * 1000.0;
+        control.record_stats(|totals| {
+            totals.prompt_tokens += stats.prompt_tokens;
+            totals.prompt_eval_ms += stats.prompt_eval_ms;
+        });
+        let generation_start = Instant::now();
+        let mut reported_ms = 0.0;
+
+        let mut params = control.params();
+        let mut sampler = build_sampler(params.temperature, params.seed);
+
+        let mut output = String::new();
         let mut generated = 0;
+        control.set_partial_output(String::new());
 
         // Get stop tokens
         let eos_token = model.token_eos();
+        let mut decoder = encoding_rs::UTF_8.new_decoder();
 
         loop {
-            if generated >= max_tokens {
+            // Hold here while paused; the context and KV cache stay alive
+            while control.is_paused() && !control.is_cancelled() {
+                std::thread::sleep(Duration::from_millis(50));
+            }
+            // Checked per token, as this loop never yields to the runtime
+            if control.is_cancelled() {
+                return Err(GENERATION_CANCELLED.to_string());
+            }
+
+            // Pick up parameters patched mid-generation
+            let latest = control.params();
+            if latest.temperature != params.temperature {
+                sampler = build_sampler(latest.temperature, latest.seed);
+            }
+            params = latest;
+
+            if generated >= params.max_tokens {
+                stats.finish_reason = FinishReason::Length;
                 break;
             }
 
@@ -107,7 +791,7 @@ impl Model {
 
             // ✅ Also check for <|im_end|> token (Qwen's stop token)
             let text = model
-                .token_to_str(token, Special::Tokenize)
+                .token_to_piece(token, &mut decoder, true, None)
                 .map_err(|e| format!("Decode failed: {e:?}"))?;
 
             // Stop if we hit the end marker
@@ -116,14 +800,16 @@ impl Model {
             }
 
             output.push_str(&text);
+            control.output.lock().unwrap().push_str(&text);
 
             // Check context limit
-            if pos >= 2047 {
+            if pos as usize >= n_ctx - 1 {
+                stats.finish_reason = FinishReason::Length;
                 break;
             }
 
             // Prepare next token
-            let mut batch = LlamaBatch::new(2048, 1);
+            let mut batch = LlamaBatch::new(n_ctx, 1);
             batch
                 .add(token, pos, &[0], true)
                 .map_err(|e| format!("Add token failed: {e:?}"))?;
@@ -134,8 +820,29 @@ impl Model {
 
             logits_index = 0;
             generated += 1;
+            let elapsed_ms = generation_start.elapsed().as_secs_f64() * 1000.0;
+            control.record_stats(|totals| {
+                totals.completion_tokens += 1;
+                totals.generation_ms += elapsed_ms - reported_ms;
+            });
+            reported_ms = elapsed_ms;
         }
 
-        Ok(output.trim().to_string())
+        stats.completion_tokens = generated;
+        stats.generation_ms = generation_start.elapsed().as_secs_f64() * 1000.0;
+        control.record_stats(|totals| totals.finish_reason = stats.finish_reason);
+
+        Ok((output.trim().to_string(), stats))
     }
 }
+
+// ✅ Better sampling parameters for Qwen2.5
+fn build_sampler(temperature: f32, seed: u32) -> LlamaSampler {
+    LlamaSampler::chain_simple(vec![
+        LlamaSampler::temp(temperature),
+        LlamaSampler::top_k(40),
+        LlamaSampler::top_p(0.9, 1),
+        LlamaSampler::min_p(0.05, 1),
+        LlamaSampler::dist(seed),
+    ])
+}
@@ -0,0 +1,146 @@
+use axum::{Router, extract::State, middleware, response::Json, routing::get};
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::UNIX_EPOCH;
+use tokio::sync::Mutex;
+
+use crate::config;
+use crate::error::ApiError;
+use crate::etag;
+use crate::gguf::{self, GgufInfo};
+use crate::model::Model;
+use crate::server::AppState;
+
+/// OpenAI-style `GET /v1/models` body.
+#[derive(Serialize)]
+struct ModelList {
+    object: &'static str,
+    data: Vec<ModelObject>,
+}
+
+#[derive(Serialize)]
+struct ModelObject {
+    /// File name without `.gguf`
+    id: String,
+    object: &'static str,
+    /// Modification time of the file, in Unix seconds
+    created: u64,
+    owned_by: &'static str,
+    path: String,
+    /// File size in bytes
+    size: u64,
+    /// `chat`, `embedding` or `reranker` when the model is loaded
+    #[serde(skip_serializing_if = "Option::is_none")]
+    loaded_as: Option<&'static str>,
+    /// Context window a loaded model runs with
+    #[serde(skip_serializing_if = "Option::is_none")]
+    context_size: Option<u32>,
+    #[serde(flatten)]
+    info: GgufInfo,
+    /// Why the header couldn't be read
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+pub fn routes() -> Router<Arc<AppState>> {
+    Router::new().route(
+        "/models",
+        get(list_models).layer(middleware::from_fn(etag::conditional_get)),
+    )
+}
+
+/// What each loaded model slot holds, as (role, file, context size).
+async fn loaded(state: &AppState) -> Vec<(&'static str, PathBuf, u32)> {
+    let slots: [(&'static str, Option<&Arc<Mutex<Model>>>); 3] = [
+        ("chat", Some(&state.model)),
+        ("embedding", state.embedder.as_ref()),
+        ("reranker", state.reranker.as_ref()),
+    ];
+    let mut loaded = Vec::new();
+    for (role, slot) in slots {
+        let Some(slot) = slot else {
+            continue;
+        };
+        let model = slot.lock().await;
+        if let Some(path) = model.path() {
+            loaded.push((role, path.to_path_buf(), model.context_size()));
+        }
+    }
+    loaded
+}
+
+/// Loaded models and every GGUF in the models directory, loaded ones first.
+async fn list_models(State(state): State<Arc<AppState>>) -> Result<Json<ModelList>, ApiError> {
+    let loaded = loaded(&state).await;
+    let data = tokio::task::spawn_blocking(move || describe_all(&loaded))
+        .await
+        .map_err(|e| ApiError::internal(e.to_string()))?;
+    Ok(Json(ModelList {
+        object: "list",
+        data,
+    }))
+}
+
+fn describe_all(loaded: &[(&'static str, PathBuf, u32)]) -> Vec<ModelObject> {
+    let mut local: Vec<PathBuf> = std::fs::read_dir(config::models_dir())
+        .map(|entries| {
+            entries
+                .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+                .filter(|path| path.extension().is_some_and(|ext| ext == "gguf"))
+                .collect()
+        })
+        .unwrap_or_default();
+    local.sort();
+
+    let same_file = |a: &Path, b: &Path| match (a.canonicalize(), b.canonicalize()) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => a == b,
+    };
+    let mut models: Vec<ModelObject> = loaded
+        .iter()
+        .map(|(role, path, context_size)| {
+            let mut model = describe(path);
+            model.loaded_as = Some(role);
+            model.context_size = Some(*context_size);
+            model
+        })
+        .collect();
+    models.extend(
+        local
+            .iter()
+            .filter(|path| !loaded.iter().any(|(_, l, _)| same_file(l, path)))
+            .map(|path| describe(path)),
+    );
+    models
+}
+
+fn describe(path: &Path) -> ModelObject {
+    let metadata = std::fs::metadata(path).ok();
+    let (info, error) = match gguf::read_info(path) {
+        Ok(info) => (info, None),
+        Err(e) => {
+            tracing::warn!("{}", e);
+            (GgufInfo::default(), Some(e))
+        }
+    };
+    ModelObject {
+        id: path
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned())
+            .unwrap_or_default(),
+        object: "model",
+        created: metadata
+            .as_ref()
+            .and_then(|m| m.modified().ok())
+            .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+            .map_or(0, |d| d.as_secs()),
+        owned_by: "local",
+        path: path.display().to_string(),
+        size: metadata.map_or(0, |m| m.len()),
+        loaded_as: None,
+        context_size: None,
+        info,
+        error,
+    }
+}
@@ -1,5 +1,7 @@
 use serde::Serialize;
-use sysinfo::System;
+use sysinfo::{System, get_current_pid};
+
+use crate::cache::CacheStats;
 
 #[derive(Serialize)]
 pub struct StatusResponse {
@@ -7,6 +9,11 @@ pub struct StatusResponse {
     pub cpu_usage: f32,
     pub memory_usage: u64,
     pub state: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cache: Option<CacheStats>,
+    /// Executions waiting for the model
+    pub queued: usize,
+    pub read_only: bool,
 }
 
 pub struct Monitor {
@@ -29,6 +36,18 @@ impl Monitor {
             cpu_usage,
             memory_usage,
             state: state.to_string(),
+            cache: None,
+            queued: 0,
+            read_only: false,
         }
     }
+
+    /// Resident memory of the daemon process in bytes.
+    pub fn process_memory(&mut self) -> u64 {
+        let Ok(pid) = get_current_pid() else {
+            return 0;
+        };
+        self.system.refresh_process(pid);
+        self.system.process(pid).map_or(0, |p| p.memory())
+    }
 }
@@ -0,0 +1,474 @@
+use axum::{
+    Extension, Router,
+    body::Body,
+    extract::State,
+    http::{StatusCode, header},
+    response::{IntoResponse, Json, Response},
+    routing::{get, post},
+};
+use chrono::{DateTime, Utc};
+use futures::{StreamExt, TryStreamExt, channel::mpsc};
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use std::convert::Infallible;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
+
+use crate::config;
+use crate::error::ApiError;
+use crate::limits;
+use crate::memories;
+use crate::model::{GenerationControl, GenerationStats, LiveParams, PROMPT_TOO_LONG, PromptInput};
+use crate::ratelimit::ClientId;
+use crate::security::SecurityEventKind;
+use crate::server::{self, AppState};
+use crate::tools::{self, ToolDefinition};
+use crate::usage::UsageRecord;
+
+// Ollama-compatible endpoints, so clients written against Ollama work unchanged
+
+const NDJSON: &str = "application/x-ndjson";
+// Minimum gap between progress lines while pulling a model
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(500);
+
+/// The subset of Ollama's `options` the decode loop understands; the rest are ignored.
+#[derive(Deserialize, Default)]
+struct Options {
+    temperature: Option<f32>,
+    /// Ollama uses -1 for "until the context is full"; non-positive values keep the default
+    num_predict: Option<i64>,
+    seed: Option<u32>,
+}
+
+impl Options {
+    fn params(&self) -> LiveParams {
+        let mut params = LiveParams::default();
+        if let Some(temperature) = self.temperature {
+            params.temperature = temperature;
+        }
+        if let Some(num_predict) = self.num_predict.filter(|n| *n > 0) {
+            params.max_tokens = num_predict as usize;
+        }
+        if let Some(seed) = self.seed {
+            params.seed = seed;
+        }
+        params
+    }
+}
+
+fn default_stream() -> bool {
+    true
+}
+
+#[derive(Deserialize)]
+struct GenerateRequest {
+    #[serde(default)]
+    model: String,
+    prompt: String,
+    system: Option<String>,
+    #[serde(default)]
+    images: Vec<String>,
+    #[serde(default)]
+    options: Options,
+    #[serde(default = "default_stream")]
+    stream: bool,
+}
+
+#[derive(Deserialize)]
+struct ChatMessage {
+    role: String,
+    #[serde(default)]
+    content: String,
+    #[serde(default)]
+    images: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct ChatRequest {
+    #[serde(default)]
+    model: String,
+    messages: Vec<ChatMessage>,
+    #[serde(default)]
+    tools: Vec<ToolDefinition>,
+    #[serde(default)]
+    options: Options,
+    #[serde(default = "default_stream")]
+    stream: bool,
+}
+
+#[derive(Deserialize)]
+struct PullRequest {
+    // Older clients send `name`
+    #[serde(alias = "name")]
+    model: String,
+    #[serde(default = "default_stream")]
+    stream: bool,
+}
+
+#[derive(Serialize)]
+struct ModelEntry {
+    name: String,
+    model: String,
+    modified_at: String,
+    size: u64,
+    details: ModelDetails,
+}
+
+#[derive(Serialize)]
+struct ModelDetails {
+    format: &'static str,
+}
+
+pub fn routes() -> Router<Arc<AppState>> {
+    Router::new()
+        .route("/api/generate", post(generate))
+        .route("/api/chat", post(chat))
+        .route("/api/tags", get(tags))
+        .route("/api/pull", post(pull))
+}
+
+/// Timing and token fields Ollama puts on the final chunk, durations in nanoseconds.
+fn final_fields(chunk: &mut Value, stats: &GenerationStats, total: Duration) {
+    let fields = json!({
+        "done": true,
+        "done_reason": stats.finish_reason,
+        "total_duration": total.as_nanos() as u64,
+        "load_duration": 0,
+        "prompt_eval_count": stats.prompt_tokens,
+        "prompt_eval_duration": (stats.prompt_eval_ms * 1e6) as u64,
+        "eval_count": stats.completion_tokens,
+        "eval_duration": (stats.generation_ms * 1e6) as u64,
+    });
+    if let (Some(chunk), Value::Object(fields)) = (chunk.as_object_mut(), fields) {
+        chunk.extend(fields);
+    }
+}
+
+/// One JSON object, or when streaming the content chunk followed by an empty final
+/// chunk. Generation isn't incremental, so the stream always has exactly two lines.
+fn reply(stream: bool, mut content: Value, empty: Value, done: impl Fn(&mut Value)) -> Response {
+    if !stream {
+        done(&mut content);
+        return Json(content).into_response();
+    }
+    let mut last = empty;
+    done(&mut last);
+    content["done"] = json!(false);
+    let body = format!("{}\n{}\n", content, last);
+    ([(header::CONTENT_TYPE, NDJSON)], body).into_response()
+}
+
+async fn run(
+    state: &Arc<AppState>,
+    client: &ClientId,
+    mut input: PromptInput,
+    params: LiveParams,
+) -> Result<(PromptInput, String, GenerationStats, Duration), Response> {
+    let started = Instant::now();
+    // Ollama clients wait on the reply
+    input.interactive = true;
+    memories::inject(state, &mut input).await;
+    if let Err(limited) = server::reserve_tokens(state, client, params.max_tokens).await {
+        return Err(limited.into_response());
+    }
+    let control = GenerationControl::with_params(CancellationToken::new(), params);
+    let limit = Duration::from_secs(state.timeouts.run_prompt_secs);
+    match server::run_foreground(state, &input, &control, started + limit).await {
+        Ok((output, stats)) => {
+            state
+                .usage
+                .record(UsageRecord::new("ollama", None, Some(&stats)))
+                .await;
+            memories::extract_in_background(Arc::clone(state), input.user.clone(), output.clone());
+            Ok((input, output, stats, started.elapsed()))
+        }
+        Err(_) if control.is_cancelled() && started.elapsed() >= limit => {
+            tracing::warn!("Prompt timed out after {:?}", limit);
+            Err(server::timed_out(limit))
+        }
+        Err(_) if control.is_cancelled() => {
+            tracing::info!("Prompt stopped");
+            Err(ApiError::cancelled().into_response())
+        }
+        Err(e) if e.starts_with(PROMPT_TOO_LONG) => {
+            tracing::warn!("Rejected prompt: {}", e);
+            state.security.lock().await.record(
+                SecurityEventKind::OversizedRequest,
+                Some(client.0.clone()),
+                e.clone(),
+            );
+            Err(limits::payload_too_large(e))
+        }
+        Err(e) => {
+            tracing::error!("Failed to run prompt: {:?}", e);
+            Err(ApiError::internal(e).into_response())
+        }
+    }
+}
+
+async fn generate(
+    State(state): State<Arc<AppState>>,
+    Extension(client): Extension<ClientId>,
+    Json(req): Json<GenerateRequest>,
+) -> Response {
+    let images = match server::decode_images(&req.images) {
+        Ok(images) => images,
+        Err(e) => return ApiError::bad_request(e).into_response(),
+    };
+    let input = PromptInput {
+        user: req.prompt,
+        system: req.system,
+        images,
+        ..Default::default()
+    };
+    let params = req.options.params();
+    let (_, output, stats, total) = match run(&state, &client, input, params).await {
+        Ok(result) => result,
+        Err(response) => return response,
+    };
+
+    let created_at = Utc::now().to_rfc3339();
+    let chunk = |response: &str| json!({"model": req.model, "created_at": created_at, "response": response});
+    reply(req.stream, chunk(&output), chunk(""), |last| {
+        final_fields(last, &stats, total)
+    })
+}
+
+/// Fold a chat transcript into one prompt: system messages become the system prompt,
+/// the last user message is the turn to answer and earlier turns are quoted before it.
+fn fold_messages(messages: Vec<ChatMessage>) -> Result<PromptInput, String> {
+    let last_user = messages
+        .iter()
+        .rposition(|message| message.role == "user")
+        .ok_or("messages must include a user message")?;
+    let mut system = Vec::new();
+    let mut history = Vec::new();
+    let mut input = PromptInput::default();
+    for (i, message) in messages.into_iter().enumerate() {
+        match message.role.as_str() {
+            "system" => system.push(message.content),
+            _ if i == last_user => {
+                input.user = message.content;
+                input.images = server::decode_images(&message.images)?;
+            }
+            role => history.push(format!("{}: {}", role, message.content)),
+        }
+    }
+    if !history.is_empty() {
+        input.user = format!(
+            "Conversation so far:\n{}\n\n{}",
+            history.join("\n"),
+            input.user
+        );
+    }
+    if !system.is_empty() {
+        input.system = Some(system.join("\n\n"));
+    }
+    Ok(input)
+}
+
+async fn chat(
+    State(state): State<Arc<AppState>>,
+    Extension(client): Extension<ClientId>,
+    Json(req): Json<ChatRequest>,
+) -> Response {
+    let mut input = match fold_messages(req.messages) {
+        Ok(input) => input,
+        Err(e) => return ApiError::bad_request(e).into_response(),
+    };
+    input.tools = req.tools;
+    let params = req.options.params();
+    let (input, output, stats, total) = match run(&state, &client, input, params).await {
+        Ok(result) => result,
+        Err(response) => return response,
+    };
+
+    let mut message = json!({"role": "assistant", "content": output});
+    if !input.tools.is_empty() {
+        let (content, calls) = tools::parse_tool_calls(&output);
+        message["content"] = json!(content);
+        if !calls.is_empty() {
+            // Ollama passes arguments as an object rather than an encoded string
+            let calls: Vec<Value> = calls
+                .into_iter()
+                .map(|call| {
+                    let arguments: Value =
+                        serde_json::from_str(&call.function.arguments).unwrap_or_default();
+                    json!({"function": {"name": call.function.name, "arguments": arguments}})
+                })
+                .collect();
+            message["tool_calls"] = json!(calls);
+        }
+    }
+    let created_at = Utc::now().to_rfc3339();
+    let chunk =
+        |message: Value| json!({"model": req.model, "created_at": created_at, "message": message});
+    reply(
+        req.stream,
+        chunk(message),
+        chunk(json!({"role": "assistant", "content": ""})),
+        |last| final_fields(last, &stats, total),
+    )
+}
+
+/// GGUF files in the models directory. Only the model kekahyde was started with is
+/// loaded; requests naming another model are served by it all the same.
+async fn tags() -> Response {
+    let mut models = Vec::new();
+    if let Ok(mut entries) = tokio::fs::read_dir(config::models_dir()).await {
+        while let Ok(Some(entry)) = entries.next_entry().await {
+            let path = entry.path();
+            if path.extension().is_none_or(|ext| ext != "gguf") {
+                continue;
+            }
+            let Ok(metadata) = entry.metadata().await else {
+                continue;
+            };
+            let name = path
+                .file_stem()
+                .map(|stem| stem.to_string_lossy().into_owned())
+                .unwrap_or_default();
+            let modified_at = metadata
+                .modified()
+                .map(|time| DateTime::<Utc>::from(time).to_rfc3339())
+                .unwrap_or_default();
+            models.push(ModelEntry {
+                model: name.clone(),
+                name,
+                modified_at,
+                size: metadata.len(),
+                details: ModelDetails { format: "gguf" },
+            });
+        }
+    }
+    models.sort_by(|a, b| a.name.cmp(&b.name));
+    Json(json!({"models": models})).into_response()
+}
+
+/// Download URL for a pull name. Ollama's registry isn't reachable from here, so models
+/// are named by their Hugging Face path (`hf.co/<owner>/<repo>/<file>.gguf`) or a URL.
+fn resolve_pull(name: &str) -> Result<(String, String), String> {
+    let url = if name.starts_with("https://") {
+        name.to_string()
+    } else {
+        let path = name
+            .strip_prefix("hf.co/")
+            .or_else(|| name.strip_prefix("huggingface.co/"))
+            .ok_or_else(|| {
+                format!(
+                    "Cannot pull {:?}: use hf.co/<owner>/<repo>/<file>.gguf or a URL",
+                    name
+                )
+            })?;
+        let (repo, file) = path
+            .rsplit_once('/')
+            .filter(|(repo, _)| repo.contains('/'))
+            .ok_or_else(|| format!("Cannot pull {:?}: missing <owner>/<repo>/<file>", name))?;
+        format!("https://huggingface.co/{}/resolve/main/{}", repo, file)
+    };
+    let file = url
+        .rsplit('/')
+        .next()
+        .filter(|file| file.ends_with(".gguf"))
+        .map(str::to_string)
+        .ok_or_else(|| format!("Cannot pull {:?}: not a .gguf file", name))?;
+    Ok((url, file))
+}
+
+/// Download `url` to `path` via a temporary file, reporting `(completed, total)`.
+async fn download(
+    url: &str,
+    path: &Path,
+    mut progress: impl FnMut(u64, u64),
+) -> Result<(), String> {
+    let response = reqwest::get(url)
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(|e| format!("Download failed: {}", e))?;
+    let total = response.content_length().unwrap_or(0);
+    if let Some(parent) = path.parent() {
+        tokio::fs::create_dir_all(parent)
+            .await
+            .map_err(|e| format!("Failed to create {}: {}", parent.display(), e))?;
+    }
+    let partial = path.with_extension("gguf.partial");
+    let mut file = tokio::fs::File::create(&partial)
+        .await
+        .map_err(|e| format!("Failed to create {}: {}", partial.display(), e))?;
+    let mut stream = response.bytes_stream();
+    let mut completed = 0;
+    while let Some(chunk) = stream
+        .try_next()
+        .await
+        .map_err(|e| format!("Download failed: {}", e))?
+    {
+        file.write_all(&chunk)
+            .await
+            .map_err(|e| format!("Failed to write {}: {}", partial.display(), e))?;
+        completed += chunk.len() as u64;
+        progress(completed, total);
+    }
+    file.flush()
+        .await
+        .map_err(|e| format!("Failed to write {}: {}", partial.display(), e))?;
+    tokio::fs::rename(&partial, path)
+        .await
+        .map_err(|e| format!("Failed to move {} into place: {}", path.display(), e))
+}
+
+async fn pull(Json(req): Json<PullRequest>) -> Response {
+    let (url, file) = match resolve_pull(&req.model) {
+        Ok(source) => source,
+        Err(e) => return ApiError::bad_request(e).into_response(),
+    };
+    let path = config::models_dir().join(&file);
+    let status = format!("pulling {}", file);
+
+    if !req.stream {
+        if !path.exists()
+            && let Err(e) = download(&url, &path, |_, _| {}).await
+        {
+            tracing::error!("{}", e);
+            return ApiError::status(StatusCode::BAD_GATEWAY, e).into_response();
+        }
+        return Json(json!({"status": "success"})).into_response();
+    }
+
+    let (tx, rx) = mpsc::unbounded::<String>();
+    tokio::spawn(async move {
+        let send = |line: Value| {
+            let _ = tx.unbounded_send(format!("{}\n", line));
+        };
+        if !path.exists() {
+            send(json!({"status": status}));
+            let mut last = Instant::now();
+            let result = download(&url, &path, |completed, total| {
+                if last.elapsed() >= PROGRESS_INTERVAL || completed == total {
+                    last = Instant::now();
+                    send(json!({
+                        "status": status,
+                        "digest": file,
+                        "total": total,
+                        "completed": completed,
+                    }));
+                }
+            })
+            .await;
+            if let Err(e) = result {
+                tracing::error!("{}", e);
+                send(json!({"error": e}));
+                return;
+            }
+        }
+        send(json!({"status": "success"}));
+    });
+    (
+        [(header::CONTENT_TYPE, NDJSON)],
+        Body::from_stream(rx.map(Ok::<_, Infallible>)),
+    )
+        .into_response()
+}
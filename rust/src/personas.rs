@@ -0,0 +1,212 @@
+use axum::{
+    Router,
+    extract::{Path, State},
+    http::StatusCode,
+    response::Json,
+    routing::{get, post},
+};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+use uuid::Uuid;
+
+use crate::error::ApiError;
+use crate::model::SamplingProfile;
+use crate::server::AppState;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Persona {
+    #[serde(default)]
+    pub id: String,
+    pub name: String,
+    pub system_prompt: String,
+    #[serde(default)]
+    pub sampling: SamplingProfile,
+    /// Voice identifier reserved for text-to-speech output
+    #[serde(default)]
+    pub voice: Option<String>,
+    /// Rewrite completions as plain sentences without markdown or emoji, for screen readers
+    #[serde(default)]
+    pub plain_output: bool,
+    /// When the persona was last written on any device, for sync
+    #[serde(default)]
+    pub updated_at: String,
+}
+
+// Personas persisted as a JSON array under the data dir
+pub struct PersonaStore {
+    path: PathBuf,
+    personas: HashMap<String, Persona>,
+}
+
+impl PersonaStore {
+    pub fn load(path: PathBuf) -> Result<Self, String> {
+        let personas = if path.exists() {
+            let data = std::fs::read_to_string(&path)
+                .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+            let list: Vec<Persona> = serde_json::from_str(&data)
+                .map_err(|e| format!("Invalid personas file {}: {}", path.display(), e))?;
+            list.into_iter().map(|p| (p.id.clone(), p)).collect()
+        } else {
+            HashMap::new()
+        };
+        Ok(Self { path, personas })
+    }
+
+    fn save(&self) -> Result<(), String> {
+        if let Some(parent) = self.path.parent() {
+            std::fs::create_dir_all(parent).map_err(|e| e.to_string())?;
+        }
+        let data = serde_json::to_vec_pretty(&self.list()).map_err(|e| e.to_string())?;
+        std::fs::write(&self.path, data).map_err(|e| e.to_string())
+    }
+
+    pub fn list(&self) -> Vec<Persona> {
+        let mut list: Vec<Persona> = self.personas.values().cloned().collect();
+        list.sort_by(|a, b| a.name.cmp(&b.name));
+        list
+    }
+
+    /// Look a persona up by id, falling back to a case-insensitive name match.
+    pub fn find(&self, key: &str) -> Option<&Persona> {
+        self.personas.get(key).or_else(|| {
+            self.personas
+                .values()
+                .find(|p| p.name.eq_ignore_ascii_case(key))
+        })
+    }
+
+    fn upsert(&mut self, mut persona: Persona) -> Result<Persona, String> {
+        if persona.id.is_empty() {
+            persona.id = Uuid::new_v4().to_string();
+        }
+        persona.updated_at = Utc::now().to_rfc3339();
+        self.personas.insert(persona.id.clone(), persona.clone());
+        self.save()?;
+        Ok(persona)
+    }
+
+    fn remove(&mut self, id: &str) -> Result<bool, String> {
+        let removed = self.personas.remove(id).is_some();
+        if removed {
+            self.save()?;
+        }
+        Ok(removed)
+    }
+
+    fn import(&mut self, personas: Vec<Persona>) -> Result<usize, String> {
+        let count = personas.len();
+        let now = Utc::now().to_rfc3339();
+        for mut persona in personas {
+            if persona.id.is_empty() {
+                persona.id = Uuid::new_v4().to_string();
+            }
+            persona.updated_at = now.clone();
+            self.personas.insert(persona.id.clone(), persona);
+        }
+        self.save()?;
+        Ok(count)
+    }
+
+    /// Take what a sync decided, keeping the winning versions' timestamps.
+    pub fn apply_sync(&mut self, upserts: Vec<Persona>, removals: &[String]) -> Result<(), String> {
+        if upserts.is_empty() && removals.is_empty() {
+            return Ok(());
+        }
+        for persona in upserts {
+            self.personas.insert(persona.id.clone(), persona);
+        }
+        for id in removals {
+            self.personas.remove(id);
+        }
+        self.save()
+    }
+}
+
+pub fn routes() -> Router<Arc<AppState>> {
+    Router::new()
+        .route("/personas", get(list_personas).post(create_persona))
+        .route("/personas/export", get(export_personas))
+        .route("/personas/import", post(import_personas))
+        .route(
+            "/personas/:id",
+            get(get_persona).put(update_persona).delete(delete_persona),
+        )
+}
+
+async fn list_personas(State(state): State<Arc<AppState>>) -> Json<Vec<Persona>> {
+    Json(state.personas.lock().await.list())
+}
+
+async fn get_persona(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+) -> Result<Json<Persona>, ApiError> {
+    let personas = state.personas.lock().await;
+    personas
+        .find(&id)
+        .cloned()
+        .map(Json)
+        .ok_or_else(|| ApiError::not_found("Persona not found"))
+}
+
+async fn create_persona(
+    State(state): State<Arc<AppState>>,
+    Json(mut persona): Json<Persona>,
+) -> Result<Json<Persona>, ApiError> {
+    persona.id = String::new();
+    let mut personas = state.personas.lock().await;
+    personas.upsert(persona).map(Json).map_err(|e| {
+        tracing::error!("Failed to save persona: {}", e);
+        ApiError::internal(e)
+    })
+}
+
+async fn update_persona(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+    Json(mut persona): Json<Persona>,
+) -> Result<Json<Persona>, ApiError> {
+    let mut personas = state.personas.lock().await;
+    if personas.find(&id).is_none() {
+        return Err(ApiError::not_found("Persona not found"));
+    }
+    persona.id = id;
+    personas.upsert(persona).map(Json).map_err(|e| {
+        tracing::error!("Failed to save persona: {}", e);
+        ApiError::internal(e)
+    })
+}
+
+async fn delete_persona(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+) -> Result<StatusCode, ApiError> {
+    let mut personas = state.personas.lock().await;
+    match personas.remove(&id) {
+        Ok(true) => Ok(StatusCode::OK),
+        Ok(false) => Err(ApiError::not_found("Persona not found")),
+        Err(e) => {
+            tracing::error!("Failed to delete persona: {}", e);
+            Err(ApiError::internal(e))
+        }
+    }
+}
+
+async fn export_personas(State(state): State<Arc<AppState>>) -> Json<Vec<Persona>> {
+    Json(state.personas.lock().await.list())
+}
+
+async fn import_personas(
+    State(state): State<Arc<AppState>>,
+    Json(list): Json<Vec<Persona>>,
+) -> Result<Json<serde_json::Value>, ApiError> {
+    let mut personas = state.personas.lock().await;
+    let imported = personas.import(list).map_err(|e| {
+        tracing::error!("Failed to import personas: {}", e);
+        ApiError::internal(e)
+    })?;
+    Ok(Json(serde_json::json!({ "imported": imported })))
+}
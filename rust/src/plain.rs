@@ -0,0 +1,132 @@
+/// Strip markdown and emoji from `text`. Headings and list items become sentences,
+/// links keep their text, table rows are read out cell by cell and code blocks are
+/// kept as-is without their fences.
+pub fn to_plain(text: &str) -> String {
+    let mut lines = Vec::new();
+    let mut in_code = false;
+    for line in text.lines() {
+        let trimmed = line.trim();
+        if trimmed.starts_with("```") || trimmed.starts_with("~~~") {
+            in_code = !in_code;
+            continue;
+        }
+        if in_code {
+            lines.push(line.to_string());
+            continue;
+        }
+        if is_rule(trimmed) || is_table_separator(trimmed) {
+            continue;
+        }
+
+        let (body, sentence) = block_body(trimmed);
+        let mut plain = strip_emoji(&strip_inline(&body));
+        plain = plain.split_whitespace().collect::<Vec<_>>().join(" ");
+        if plain.is_empty() {
+            // Keep paragraph breaks, but only one in a row
+            if lines.last().is_some_and(|l: &String| !l.is_empty()) {
+                lines.push(String::new());
+            }
+            continue;
+        }
+        if sentence && plain.ends_with(|c: char| c.is_alphanumeric() || c == ')') {
+            plain.push('.');
+        }
+        lines.push(plain);
+    }
+    while lines.last().is_some_and(|l| l.is_empty()) {
+        lines.pop();
+    }
+    lines.join("\n")
+}
+
+/// The text of a block-level line without its marker, and whether it should read as
+/// a sentence of its own.
+fn block_body(line: &str) -> (String, bool) {
+    let line = line.trim_start_matches('>').trim_start();
+    let heading = line.trim_start_matches('#');
+    if heading.len() < line.len() && (heading.is_empty() || heading.starts_with(' ')) {
+        return (heading.trim().to_string(), true);
+    }
+    for marker in ["- [ ] ", "- [x] ", "- ", "* ", "+ "] {
+        if let Some(item) = line.strip_prefix(marker) {
+            return (item.to_string(), true);
+        }
+    }
+    let digits = line.chars().take_while(char::is_ascii_digit).count();
+    if digits > 0
+        && let Some(item) = line[digits..]
+            .strip_prefix(". ")
+            .or_else(|| line[digits..].strip_prefix(") "))
+    {
+        return (item.to_string(), true);
+    }
+    if line.starts_with('|') {
+        let cells: Vec<&str> = line
+            .trim_matches('|')
+            .split('|')
+            .map(str::trim)
+            .filter(|cell| !cell.is_empty())
+            .collect();
+        return (cells.join(", "), true);
+    }
+    (line.to_string(), false)
+}
+
+fn is_rule(line: &str) -> bool {
+    line.len() >= 3
+        && ['-', '*', '_']
+            .iter()
+            .any(|c| line.chars().all(|l| l == *c || l == ' '))
+}
+
+fn is_table_separator(line: &str) -> bool {
+    line.starts_with('|') && line.chars().all(|c| matches!(c, '|' | '-' | ':' | ' '))
+}
+
+/// Drop emphasis and code markers, and reduce links and images to their text.
+fn strip_inline(text: &str) -> String {
+    let mut out = String::new();
+    let mut rest = text;
+    while let Some(c) = rest.chars().next() {
+        match c {
+            '*' | '`' => rest = &rest[1..],
+            '_' if rest.starts_with("__") => rest = &rest[2..],
+            '!' if rest.starts_with("![") => rest = &rest[1..],
+            '[' => match link(rest) {
+                Some((label, len)) => {
+                    out.push_str(label);
+                    rest = &rest[len..];
+                }
+                None => {
+                    out.push(c);
+                    rest = &rest[1..];
+                }
+            },
+            _ => {
+                out.push(c);
+                rest = &rest[c.len_utf8()..];
+            }
+        }
+    }
+    out
+}
+
+/// `[label](target)` at the start of `text`: the label and the length consumed.
+fn link(text: &str) -> Option<(&str, usize)> {
+    let close = text.find("](")?;
+    let end = text[close..].find(')')? + close;
+    Some((&text[1..close], end + 1))
+}
+
+fn strip_emoji(text: &str) -> String {
+    text.chars()
+        .filter(|c| {
+            !matches!(*c as u32,
+                0x1F000..=0x1FAFF // pictographs, emoticons, flags
+                | 0x2600..=0x27BF // symbols and dingbats
+                | 0x2B00..=0x2BFF // stars and arrows
+                | 0xFE0F | 0x200D // emoji presentation and joiner
+                | 0xE0020..=0xE007F)
+        })
+        .collect()
+}
@@ -0,0 +1,80 @@
+use chrono::Utc;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::path::{Path, PathBuf};
+
+use crate::config::ProvenanceMode;
+
+pub const PROVENANCE_HEADER: &str = "x-kekahyde-provenance";
+
+/// Machine-readable label for a piece of generated content.
+#[derive(Serialize, Debug, Clone)]
+pub struct Provenance {
+    pub generator: &'static str,
+    pub version: &'static str,
+    pub model: String,
+    pub model_sha256: String,
+    pub timestamp: String,
+}
+
+impl Provenance {
+    pub fn header_value(&self) -> String {
+        serde_json::to_string(self).unwrap_or_default()
+    }
+
+    pub fn footer(&self) -> String {
+        format!("\n\n---\nprovenance: {}", self.header_value())
+    }
+}
+
+// Model identity is fixed for the daemon's lifetime, so it's resolved once at startup
+pub struct ProvenanceStamp {
+    pub mode: ProvenanceMode,
+    model: String,
+    model_sha256: String,
+}
+
+impl ProvenanceStamp {
+    pub async fn new(mode: ProvenanceMode, model: String, path: PathBuf) -> Result<Self, String> {
+        let model_sha256 = tokio::task::spawn_blocking(move || sha256_file(&path))
+            .await
+            .map_err(|e| e.to_string())??;
+
+        Ok(Self {
+            mode,
+            model,
+            model_sha256,
+        })
+    }
+
+    pub fn model_sha256(&self) -> &str {
+        &self.model_sha256
+    }
+
+    pub fn header(&self) -> bool {
+        matches!(self.mode, ProvenanceMode::Header | ProvenanceMode::Both)
+    }
+
+    pub fn footer(&self) -> bool {
+        matches!(self.mode, ProvenanceMode::Footer | ProvenanceMode::Both)
+    }
+
+    pub fn stamp(&self) -> Provenance {
+        Provenance {
+            generator: "kekahyde",
+            version: env!("CARGO_PKG_VERSION"),
+            model: self.model.clone(),
+            model_sha256: self.model_sha256.clone(),
+            timestamp: Utc::now().to_rfc3339(),
+        }
+    }
+}
+
+/// Hex SHA-256 of the file at `path`; blocks while it is read.
+pub fn sha256_file(path: &Path) -> Result<String, String> {
+    let mut file = std::fs::File::open(path)
+        .map_err(|e| format!("Failed to open {}: {}", path.display(), e))?;
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut file, &mut hasher).map_err(|e| e.to_string())?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
@@ -0,0 +1,193 @@
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    http::{Method, StatusCode, header},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::auth;
+use crate::config::RateLimitConfig;
+use crate::error::{ApiError, ErrorCode};
+use crate::security::SecurityEventKind;
+use crate::server::AppState;
+
+const WINDOW: Duration = Duration::from_secs(60);
+
+/// Who a request is accounted to: its API key when it sends a configured one, otherwise
+/// its address.
+#[derive(Clone, Debug)]
+pub struct ClientId(pub String);
+
+/// 429 with the number of seconds until the client's budget frees up.
+pub struct RateLimited(pub Duration);
+
+impl IntoResponse for RateLimited {
+    fn into_response(self) -> Response {
+        let seconds = self.0.as_secs_f64().ceil().max(1.0) as u64;
+        (
+            [(header::RETRY_AFTER, seconds.to_string())],
+            ApiError::new(
+                StatusCode::TOO_MANY_REQUESTS,
+                ErrorCode::RateLimited,
+                format!("Rate limit exceeded, retry in {}s", seconds),
+            ),
+        )
+            .into_response()
+    }
+}
+
+#[derive(Default)]
+struct ClientWindow {
+    requests: VecDeque<Instant>,
+    tokens: VecDeque<(Instant, u64)>,
+}
+
+impl ClientWindow {
+    fn expire(&mut self, now: Instant) {
+        while self
+            .requests
+            .front()
+            .is_some_and(|t| now.duration_since(*t) >= WINDOW)
+        {
+            self.requests.pop_front();
+        }
+        while self
+            .tokens
+            .front()
+            .is_some_and(|(t, _)| now.duration_since(*t) >= WINDOW)
+        {
+            self.tokens.pop_front();
+        }
+    }
+
+    fn is_idle(&self) -> bool {
+        self.requests.is_empty() && self.tokens.is_empty()
+    }
+}
+
+// Sliding one-minute windows per client
+pub struct RateLimiter {
+    config: RateLimitConfig,
+    clients: HashMap<String, ClientWindow>,
+    // When windows with nothing left in them were last dropped
+    swept: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(config: RateLimitConfig) -> Self {
+        Self {
+            config,
+            clients: HashMap::new(),
+            swept: Instant::now(),
+        }
+    }
+
+    // Each address that ever sent a request has a window, so they're dropped once a
+    // minute after emptying
+    fn window(&mut self, client: &str, now: Instant) -> &mut ClientWindow {
+        if now.duration_since(self.swept) >= WINDOW {
+            self.clients.retain(|_, window| {
+                window.expire(now);
+                !window.is_idle()
+            });
+            self.swept = now;
+        }
+        let window = self.clients.entry(client.to_string()).or_default();
+        window.expire(now);
+        window
+    }
+
+    pub fn check_request(&mut self, client: &str) -> Result<(), RateLimited> {
+        let Some(limit) = self.config.requests_per_minute else {
+            return Ok(());
+        };
+        let now = Instant::now();
+        let window = self.window(client, now);
+        if window.requests.len() >= limit as usize {
+            // The oldest request leaving the window makes room for this one
+            let oldest = window.requests[window.requests.len() - limit as usize];
+            return Err(RateLimited(WINDOW - now.duration_since(oldest)));
+        }
+        window.requests.push_back(now);
+        Ok(())
+    }
+
+    /// Reserve `tokens` of generation budget for `client`.
+    pub fn reserve_tokens(&mut self, client: &str, tokens: u64) -> Result<(), RateLimited> {
+        let Some(limit) = self.config.tokens_per_minute else {
+            return Ok(());
+        };
+        if tokens > limit {
+            return Err(RateLimited(WINDOW));
+        }
+        let now = Instant::now();
+        let window = self.window(client, now);
+        let mut used: u64 = window.tokens.iter().map(|(_, n)| n).sum();
+        if used + tokens > limit {
+            // Find when enough earlier reservations will have expired
+            for (at, n) in &window.tokens {
+                used -= n;
+                if used + tokens <= limit {
+                    return Err(RateLimited(WINDOW - now.duration_since(*at)));
+                }
+            }
+            return Err(RateLimited(WINDOW));
+        }
+        window.tokens.push_back((now, tokens));
+        Ok(())
+    }
+}
+
+/// Tag every request with its ClientId and apply the request limit to mutating routes.
+pub async fn rate_limit(
+    State(state): State<Arc<AppState>>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let client = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(str::trim)
+        // Any other token would give the sender a fresh budget each time it changes it
+        .filter(|token| {
+            state
+                .api_keys
+                .iter()
+                .any(|key| auth::constant_time_eq(key, token))
+        })
+        // Hashed so keys don't end up in logs
+        .map(|key| format!("key:{:.12x}", Sha256::digest(key)))
+        .or_else(|| {
+            request
+                .extensions()
+                .get::<ConnectInfo<SocketAddr>>()
+                .map(|ConnectInfo(addr)| format!("ip:{}", addr.ip()))
+        })
+        .unwrap_or_else(|| "local".to_string());
+
+    if let Some(limiter) = &state.rate_limiter
+        && !matches!(
+            *request.method(),
+            Method::GET | Method::HEAD | Method::OPTIONS
+        )
+        && let Err(limited) = limiter.lock().await.check_request(&client)
+    {
+        tracing::warn!("Rate limited {}", client);
+        state.security.lock().await.record(
+            SecurityEventKind::RateLimited,
+            Some(client),
+            format!("Request limit hit on {}", request.uri().path()),
+        );
+        return limited.into_response();
+    }
+
+    request.extensions_mut().insert(ClientId(client));
+    next.run(request).await
+}
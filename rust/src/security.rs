@@ -0,0 +1,164 @@
+use axum::{
+    Router,
+    extract::{ConnectInfo, Query, Request, State, WebSocketUpgrade, ws::Message},
+    response::{IntoResponse, Json},
+    routing::get,
+};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::sync::broadcast;
+
+use crate::server::AppState;
+
+// Recent events kept for GET /security/events
+const RECENT_EVENTS: usize = 500;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum SecurityEventKind {
+    AuthFailure,
+    PolicyViolation,
+    OversizedRequest,
+    RateLimited,
+    IpDenied,
+    PeerProtocolError,
+}
+
+impl SecurityEventKind {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::AuthFailure => "auth_failure",
+            Self::PolicyViolation => "policy_violation",
+            Self::OversizedRequest => "oversized_request",
+            Self::RateLimited => "rate_limited",
+            Self::IpDenied => "ip_denied",
+            Self::PeerProtocolError => "peer_protocol_error",
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct SecurityEvent {
+    pub timestamp: String,
+    pub kind: SecurityEventKind,
+    /// Address or hashed API key the event is attributed to, when known
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client: Option<String>,
+    pub detail: String,
+}
+
+// Recent security events, per-kind counters since startup, and a live feed
+pub struct SecurityMonitor {
+    recent: VecDeque<SecurityEvent>,
+    counters: BTreeMap<SecurityEventKind, u64>,
+    tx: broadcast::Sender<SecurityEvent>,
+}
+
+impl SecurityMonitor {
+    pub fn new() -> Self {
+        let (tx, _) = broadcast::channel(100);
+        Self {
+            recent: VecDeque::new(),
+            counters: BTreeMap::new(),
+            tx,
+        }
+    }
+
+    pub fn record(
+        &mut self,
+        kind: SecurityEventKind,
+        client: Option<String>,
+        detail: impl Into<String>,
+    ) {
+        let event = SecurityEvent {
+            timestamp: Utc::now().to_rfc3339(),
+            kind,
+            client,
+            detail: detail.into(),
+        };
+        *self.counters.entry(kind).or_default() += 1;
+        if self.recent.len() == RECENT_EVENTS {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(event.clone());
+        let _ = self.tx.send(event);
+    }
+
+    /// Prometheus text exposition of the counters.
+    pub fn render_metrics(&self, out: &mut String) {
+        out.push_str("# HELP kekahyde_security_events_total Security events since startup.\n");
+        out.push_str("# TYPE kekahyde_security_events_total counter\n");
+        for kind in [
+            SecurityEventKind::AuthFailure,
+            SecurityEventKind::PolicyViolation,
+            SecurityEventKind::OversizedRequest,
+            SecurityEventKind::RateLimited,
+            SecurityEventKind::IpDenied,
+            SecurityEventKind::PeerProtocolError,
+        ] {
+            let _ = writeln!(
+                out,
+                "kekahyde_security_events_total{{kind=\"{}\"}} {}",
+                kind.as_str(),
+                self.counters.get(&kind).copied().unwrap_or(0)
+            );
+        }
+    }
+}
+
+/// Source address of `request`, in the same form as an address-based ClientId.
+pub fn client_address(request: &Request) -> Option<String> {
+    request
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| format!("ip:{}", addr.ip()))
+}
+
+#[derive(Deserialize)]
+struct EventsQuery {
+    kind: Option<SecurityEventKind>,
+}
+
+pub fn routes() -> Router<Arc<AppState>> {
+    Router::new()
+        .route("/security/events", get(list_events))
+        .route("/ws/security", get(events_ws))
+}
+
+async fn list_events(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<EventsQuery>,
+) -> Json<Vec<SecurityEvent>> {
+    let security = state.security.lock().await;
+    Json(
+        security
+            .recent
+            .iter()
+            .filter(|event| query.kind.is_none_or(|kind| event.kind == kind))
+            .cloned()
+            .collect(),
+    )
+}
+
+async fn events_ws(ws: WebSocketUpgrade, State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    let mut rx = state.security.lock().await.tx.subscribe();
+    ws.on_upgrade(move |mut socket| async move {
+        loop {
+            let event = match rx.recv().await {
+                Ok(event) => event,
+                Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => break,
+            };
+            let Ok(msg) = serde_json::to_string(&event) else {
+                continue;
+            };
+            if socket.send(Message::Text(msg)).await.is_err() {
+                break;
+            }
+        }
+    })
+}
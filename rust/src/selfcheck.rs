@@ -0,0 +1,295 @@
+use axum::{Router, extract::State, response::Json, routing::get};
+use chrono::{Local, NaiveTime, Utc};
+use serde::Serialize;
+use serde_json::json;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use sysinfo::Disks;
+use tokio::net::TcpStream;
+
+use crate::config::{self, SelfcheckConfig};
+use crate::error::ApiError;
+use crate::migrations;
+use crate::provenance;
+use crate::server::AppState;
+
+// A daily look at what degrades without failing any request: a filling disk, weights
+// changed on disk, peers gone quiet, migrations never applied, logs growing unbounded.
+
+const PEER_TIMEOUT: Duration = Duration::from_secs(3);
+// Files under the data dir that only ever grow
+const LOG_FILES: [&str; 2] = ["audit.log", "usage.log"];
+
+#[derive(Serialize, Clone)]
+pub struct SelfcheckReport {
+    pub started_at: String,
+    pub finished_at: String,
+    /// `ok` when every check passed, otherwise `degraded`
+    pub status: &'static str,
+    pub checks: Vec<Check>,
+    /// Hash of the model file, compared against on the next run
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub model_sha256: Option<String>,
+    #[serde(skip)]
+    model_path: Option<PathBuf>,
+}
+
+#[derive(Serialize, Clone)]
+pub struct Check {
+    /// `disk`, `model`, `peers`, `migrations` or `logs`
+    pub name: &'static str,
+    pub ok: bool,
+    pub detail: String,
+}
+
+impl Check {
+    fn new(name: &'static str, ok: bool, detail: String) -> Self {
+        Self { name, ok, detail }
+    }
+}
+
+pub fn parse_time(at: &str) -> Result<NaiveTime, String> {
+    NaiveTime::parse_from_str(at, "%H:%M")
+        .map_err(|_| format!("Invalid selfcheck time {:?}, expected HH:MM", at))
+}
+
+pub fn routes() -> Router<Arc<AppState>> {
+    Router::new().route("/admin/last-selfcheck", get(last_selfcheck))
+}
+
+async fn last_selfcheck(
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<SelfcheckReport>, ApiError> {
+    state
+        .last_selfcheck
+        .lock()
+        .await
+        .clone()
+        .map(Json)
+        .ok_or_else(|| ApiError::not_found("No self-check has run yet"))
+}
+
+/// Run the self-check every day at `selfcheck.at`, local time.
+pub fn spawn_scheduler(state: Arc<AppState>) {
+    let Some(config) = state.selfcheck.clone() else {
+        return;
+    };
+    let at = match parse_time(&config.at) {
+        Ok(at) => at,
+        Err(e) => {
+            tracing::error!("Self-check disabled: {}", e);
+            return;
+        }
+    };
+    tokio::spawn(async move {
+        loop {
+            let now = Local::now();
+            let next = [now.date_naive(), now.date_naive() + chrono::Days::new(1)]
+                .into_iter()
+                .filter_map(|date| date.and_time(at).and_local_timezone(Local).earliest())
+                .find(|start| *start > now);
+            let wait = next
+                .and_then(|next| (next - now).to_std().ok())
+                // A day without that local time (a DST gap) is skipped
+                .unwrap_or(Duration::from_secs(24 * 60 * 60));
+            tokio::select! {
+                _ = tokio::time::sleep(wait) => {}
+                _ = state.stop.cancelled() => return,
+            }
+            run(&state, &config).await;
+        }
+    });
+}
+
+async fn run(state: &AppState, config: &SelfcheckConfig) {
+    let started_at = Utc::now().to_rfc3339();
+    let data_dir = config::data_dir();
+    let previous = state.last_selfcheck.lock().await.clone();
+
+    let mut checks = vec![check_disk(&data_dir, config.min_free_disk_mb)];
+    let (model, model_path, model_sha256) = check_model(state, previous.as_ref()).await;
+    checks.push(model);
+    checks.push(check_peers(state).await);
+    checks.push(check_migrations(&data_dir));
+    checks.push(check_logs(&data_dir, config.max_log_mb));
+
+    let failed: Vec<&str> = checks.iter().filter(|c| !c.ok).map(|c| c.name).collect();
+    for check in checks.iter().filter(|c| !c.ok) {
+        tracing::warn!("Self-check {} failed: {}", check.name, check.detail);
+    }
+    let status = if failed.is_empty() { "ok" } else { "degraded" };
+    tracing::info!("Self-check finished: {}", status);
+    state
+        .audit
+        .record("selfcheck", json!({ "status": status, "failed": failed }))
+        .await;
+    *state.last_selfcheck.lock().await = Some(SelfcheckReport {
+        started_at,
+        finished_at: Utc::now().to_rfc3339(),
+        status,
+        checks,
+        model_sha256,
+        model_path,
+    });
+}
+
+/// Free space on the volume holding the data dir.
+fn check_disk(data_dir: &Path, min_free_mb: u64) -> Check {
+    let dir = data_dir
+        .canonicalize()
+        .unwrap_or_else(|_| data_dir.to_path_buf());
+    let disks = Disks::new_with_refreshed_list();
+    // The deepest mount point containing the directory is the one it lives on
+    let Some(disk) = disks
+        .list()
+        .iter()
+        .filter(|disk| dir.starts_with(disk.mount_point()))
+        .max_by_key(|disk| disk.mount_point().as_os_str().len())
+    else {
+        return Check::new(
+            "disk",
+            false,
+            format!("No volume found for {}", dir.display()),
+        );
+    };
+    let free_mb = disk.available_space() / (1024 * 1024);
+    Check::new(
+        "disk",
+        free_mb >= min_free_mb,
+        format!(
+            "{} MiB free on {} (minimum {} MiB)",
+            free_mb,
+            disk.mount_point().display(),
+            min_free_mb
+        ),
+    )
+}
+
+/// Hash the model file and compare it with the provenance stamp, or with the last run.
+async fn check_model(
+    state: &AppState,
+    previous: Option<&SelfcheckReport>,
+) -> (Check, Option<PathBuf>, Option<String>) {
+    let Some(path) = state.model.lock().await.path().map(Path::to_path_buf) else {
+        return (
+            Check::new("model", false, "No model is loaded".to_string()),
+            None,
+            None,
+        );
+    };
+    let hashed = {
+        let path = path.clone();
+        tokio::task::spawn_blocking(move || provenance::sha256_file(&path))
+            .await
+            .map_err(|e| e.to_string())
+            .and_then(|hash| hash)
+    };
+    let hash = match hashed {
+        Ok(hash) => hash,
+        Err(e) => return (Check::new("model", false, e), Some(path), None),
+    };
+    let expected = match &state.provenance {
+        Some(stamp) => Some(stamp.model_sha256().to_string()),
+        // A model upgrade since the last run starts a new reference
+        None => previous
+            .filter(|p| p.model_path.as_ref() == Some(&path))
+            .and_then(|p| p.model_sha256.clone()),
+    };
+    let check = match expected {
+        Some(expected) if expected != hash => Check::new(
+            "model",
+            false,
+            format!(
+                "{} changed on disk: sha256 {} instead of {}",
+                path.display(),
+                hash,
+                expected
+            ),
+        ),
+        Some(_) => Check::new("model", true, format!("{} is unchanged", path.display())),
+        None => Check::new(
+            "model",
+            true,
+            format!("Recorded the sha256 of {}", path.display()),
+        ),
+    };
+    (check, Some(path), Some(hash))
+}
+
+// A peer that no longer accepts connections would fail the next hybrid run
+async fn check_peers(state: &AppState) -> Check {
+    let peers = state.hybrid_executor.lock().await.peers.clone();
+    let mut stale = Vec::new();
+    for peer in &peers {
+        let reachable = tokio::time::timeout(PEER_TIMEOUT, TcpStream::connect(&peer.address))
+            .await
+            .is_ok_and(|connected| connected.is_ok());
+        if !reachable {
+            stale.push(peer.address.clone());
+        }
+    }
+    let detail = if stale.is_empty() {
+        format!("{} peers reachable", peers.len())
+    } else {
+        format!(
+            "{} of {} peers unreachable: {}",
+            stale.len(),
+            peers.len(),
+            stale.join(", ")
+        )
+    };
+    Check::new("peers", stale.is_empty(), detail)
+}
+
+fn check_migrations(data_dir: &Path) -> Check {
+    match migrations::pending(data_dir) {
+        Ok(pending) if pending.is_empty() => {
+            Check::new("migrations", true, "None pending".to_string())
+        }
+        Ok(pending) => Check::new(
+            "migrations",
+            false,
+            format!(
+                "{} pending until the next start: {}",
+                pending.len(),
+                pending
+                    .iter()
+                    .map(|m| m.description)
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+        ),
+        Err(e) => Check::new("migrations", false, e),
+    }
+}
+
+fn check_logs(data_dir: &Path, max_mb: u64) -> Check {
+    let sizes: Vec<(&str, u64)> = LOG_FILES
+        .iter()
+        .map(|name| {
+            let size = std::fs::metadata(data_dir.join(name))
+                .map(|m| m.len())
+                .unwrap_or(0);
+            (*name, size / (1024 * 1024))
+        })
+        .collect();
+    let oversized: Vec<String> = sizes
+        .iter()
+        .filter(|(_, mb)| *mb > max_mb)
+        .map(|(name, mb)| format!("{} is {} MiB", name, mb))
+        .collect();
+    if oversized.is_empty() {
+        let total: u64 = sizes.iter().map(|(_, mb)| mb).sum();
+        return Check::new(
+            "logs",
+            true,
+            format!("{} MiB of logs (limit {} MiB each)", total, max_mb),
+        );
+    }
+    Check::new(
+        "logs",
+        false,
+        format!("{} (limit {} MiB)", oversized.join(", "), max_mb),
+    )
+}
@@ -1,29 +1,82 @@
 use axum::{
-    Router,
-    extract::{Path, State, WebSocketUpgrade},
+    BoxError, Router,
+    error_handling::HandleErrorLayer,
+    extract::DefaultBodyLimit,
+    extract::{Extension, Path, Query, State, WebSocketUpgrade},
     http::StatusCode,
-    response::Json,
-    routing::{get, post},
+    http::{HeaderMap, HeaderName, Uri, header},
+    middleware,
+    response::{Html, IntoResponse, Json, Response},
+    routing::{get, patch, post},
 };
+use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
 use chrono::{DateTime, Utc};
 use futures::{SinkExt, StreamExt};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::path::PathBuf;
 use std::sync::Arc;
-use tokio::sync::{Mutex, broadcast};
+use std::time::Duration;
+use tokio::sync::{Mutex, broadcast, oneshot};
+use tokio::time::Instant;
 use tokio_util::sync::CancellationToken;
+use tower::ServiceBuilder;
 use tower_http::{
+    compression::CompressionLayer,
     cors::{Any, CorsLayer},
     services::ServeDir,
 };
 use uuid::Uuid;
 
+use crate::access_log;
+use crate::agent::{self, AgentOptions, AgentStep, Guardrails};
+use crate::approvals::{self, Approval, ApprovalKind, ApprovalQueue};
+use crate::audit::AuditLog;
+use crate::auth::{self, ReplayGuard};
+use crate::cache::ResponseCache;
+use crate::command_tools::{CommandTools, ToolResult};
+use crate::config::{
+    AgentConfig, Config, InterruptedExecutions, RetentionConfig, SelfcheckConfig, SharingConfig,
+    SyncConfig, TimeoutConfig, data_dir,
+};
+use crate::conversations::{self, ConversationStore};
+use crate::deprecation;
+use crate::error::{ApiError, ErrorCode};
+use crate::etag;
+use crate::history::{ExecutionHistory, StoredExecution};
 use crate::hybrid::HybridExecutor;
-use crate::model::Model;
+use crate::i18n;
+use crate::ipfilter::{self, IpFilter};
+use crate::limits;
+use crate::logging::{self, LogLevelHandle};
+use crate::maintenance::{self, Maintenance};
+use crate::memories::{self, MemoryStore};
+use crate::model::{
+    GenerationControl, GenerationStats, LiveParams, Model, PROMPT_TOO_LONG, PromptContext,
+    PromptInput, SamplingProfile,
+};
+use crate::models;
 use crate::monitor::{Monitor, StatusResponse};
+use crate::ollama;
+use crate::personas::{self, PersonaStore};
+use crate::plain;
+use crate::provenance::{PROVENANCE_HEADER, Provenance, ProvenanceStamp};
+use crate::ratelimit::{self, ClientId, RateLimited, RateLimiter};
+use crate::security::{self, SecurityEventKind, SecurityMonitor};
+use crate::selfcheck::{self, SelfcheckReport};
+use crate::sessions::{self, SessionStore};
+use crate::simulate;
+use crate::standby::{self, UpgradeStatus};
+use crate::sync;
+use crate::templates::{self, TemplateStore};
+use crate::tools::{self, ToolCall, ToolDefinition};
+use crate::transcript::Transcript;
+use crate::tts::Tts;
+use crate::usage::{self, UsageLog, UsageRecord};
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Policy {
     pub allow_networking: bool,
     pub allow_hybrid_compute: bool,
@@ -34,17 +87,215 @@ pub struct Policy {
 struct RunPromptRequest {
     prompt: String,
     policy: Policy,
+    /// Base64 encoded images (optionally as data URLs) for vision models
+    #[serde(default)]
+    images: Vec<String>,
+    /// Persona id or name supplying the system prompt and sampling defaults
+    persona: Option<String>,
+    /// Functions the model may call; the response becomes JSON with `tool_calls`
+    #[serde(default)]
+    tools: Vec<ToolDefinition>,
+    /// Configured command tools to offer as well; the daemon runs the ones the model
+    /// calls and returns their output as `tool_results`
+    #[serde(default)]
+    command_tools: Vec<String>,
+    /// Overrides the persona, session and configured system prompts
+    system_prompt: Option<String>,
+    /// Session id whose settings (see /sessions/:id) apply to this request
+    session: Option<String>,
+    /// Strip markdown and emoji from the response; defaults to the persona's setting
+    plain_output: Option<bool>,
 }
 
 #[derive(Deserialize)]
 struct StartExecutionRequest {
     prompt: String,
     policy: Policy,
+    #[serde(default)]
+    images: Vec<String>,
+    persona: Option<String>,
+    /// Generate this many candidates and return the one the model ranks best
+    #[serde(default = "default_best_of")]
+    best_of: usize,
+    #[serde(default)]
+    tools: Vec<ToolDefinition>,
+    #[serde(default)]
+    command_tools: Vec<String>,
+    /// Run as an agent: command tool output is fed back to the model until it answers
+    agent: Option<AgentOptions>,
+    system_prompt: Option<String>,
+    session: Option<String>,
+    plain_output: Option<bool>,
+    /// Hold the execution in PendingApproval until someone approves it
+    #[serde(default)]
+    require_approval: bool,
+    /// Run a failed execution again up to this many times
+    #[serde(default)]
+    max_retries: u32,
+    /// Wait before the first retry, doubled for each one after; `30s`, `500ms`, `2m`
+    backoff: Option<String>,
+    /// Fail the run with "timeout" once it has held the model this long
+    timeout_seconds: Option<u64>,
+    /// RFC 3339 time to queue the execution at
+    schedule_at: Option<String>,
+    /// Queue the execution after this long instead; `30s`, `500ms`, `2m`
+    delay: Option<String>,
+    /// Labels for finding the execution again with GET /executions?tag=
+    #[serde(default)]
+    tags: Vec<String>,
+    /// Caller's own JSON object, returned as is in the status
+    metadata: Option<serde_json::Map<String, Value>>,
+    /// `temperature`, `max_tokens` and `seed`, over the persona's and the defaults
+    #[serde(flatten)]
+    sampling: SamplingProfile,
+}
+
+fn default_best_of() -> usize {
+    1
+}
+
+const MAX_BEST_OF: usize = 8;
+const MAX_RETRIES: u32 = 10;
+// How long GET /execution/:id/context waits for the model to be free
+const CONTEXT_MODEL_WAIT: Duration = Duration::from_secs(5);
+// How often due schedules are looked for
+const SCHEDULE_POLL: Duration = Duration::from_secs(1);
+const DEFAULT_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_TAGS: usize = 16;
+const MAX_TAG_LEN: usize = 64;
+const MAX_METADATA_BYTES: usize = 8 * 1024;
+// Completed executions averaged for the speed estimates of /hybrid/simulate
+const RECENT_STATS_SAMPLES: usize = 20;
+// How often a running execution's partial result is broadcast and saved
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
+// Longest partial result a status carries
+const MAX_PARTIAL_RESULT_BYTES: usize = 8 * 1024;
+// `node` of executions generated on this machine
+const LOCAL_NODE: &str = "local";
+const IDEMPOTENCY_KEY_HEADER: &str = "idempotency-key";
+const MAX_IDEMPOTENCY_KEY_LEN: usize = 255;
+// Set on a start answered with the execution an earlier start created
+const IDEMPOTENT_REPLAYED_HEADER: &str = "idempotent-replayed";
+
+#[derive(Deserialize)]
+struct RerankRequest {
+    query: String,
+    documents: Vec<String>,
+    top_n: Option<usize>,
+    #[serde(default)]
+    return_documents: bool,
+}
+
+#[derive(Serialize)]
+struct RerankResult {
+    index: usize,
+    relevance_score: f32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    document: Option<String>,
+}
+
+#[derive(Serialize)]
+struct RerankResponse {
+    results: Vec<RerankResult>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum EmbeddingInput {
+    One(String),
+    Many(Vec<String>),
+}
+
+#[derive(Deserialize)]
+struct EmbeddingsRequest {
+    input: EmbeddingInput,
+}
+
+#[derive(Serialize)]
+struct Embedding {
+    object: &'static str,
+    index: usize,
+    embedding: Vec<f32>,
+}
+
+#[derive(Serialize)]
+struct EmbeddingsResponse {
+    object: &'static str,
+    data: Vec<Embedding>,
+    model: String,
+}
+
+#[derive(Deserialize)]
+struct BenchmarkRequest {
+    /// Thread counts to compare; defaults to the configured count
+    #[serde(default)]
+    threads: Vec<usize>,
+    max_tokens: Option<usize>,
+}
+
+#[derive(Serialize)]
+struct BenchmarkRun {
+    threads: usize,
+    prompt_tokens: usize,
+    prompt_tokens_per_second: f64,
+    completion_tokens: usize,
+    generation_tokens_per_second: f64,
+}
+
+#[derive(Serialize)]
+struct BenchmarkResponse {
+    runs: Vec<BenchmarkRun>,
+    gpu_layers: Option<u32>,
+    model_size_bytes: u64,
+    process_memory_bytes: u64,
+}
+
+const BENCHMARK_MAX_TOKENS: usize = 128;
+
+const DEFAULT_RECENT_EXECUTIONS: usize = 20;
+const DEFAULT_PAGE_SIZE: usize = 50;
+const MAX_PAGE_SIZE: usize = 500;
+// How long GET /execution/status/:id?wait=true blocks for a state change
+const DEFAULT_STATUS_WAIT: Duration = Duration::from_secs(30);
+const MAX_STATUS_WAIT: Duration = Duration::from_secs(300);
+
+/// GET /executions filters. `since` and `until` are RFC 3339 bounds on the start time.
+#[derive(Deserialize)]
+struct ListExecutionsQuery {
+    state: Option<String>,
+    since: Option<DateTime<Utc>>,
+    until: Option<DateTime<Utc>>,
+    /// Comma separated tags an execution must all carry
+    tag: Option<String>,
+    /// Comma separated `key:value` pairs its metadata must all hold
+    metadata: Option<String>,
+    #[serde(default)]
+    offset: usize,
+    limit: Option<usize>,
+}
+
+#[derive(Serialize)]
+struct ExecutionPage {
+    executions: Vec<ExecutionStatus>,
+    /// Matches across all pages
+    total: usize,
+    offset: usize,
+    limit: usize,
+}
+
+#[derive(Deserialize)]
+struct PatchExecutionRequest {
+    temperature: Option<f32>,
+    max_tokens: Option<usize>,
 }
 
 #[derive(Serialize)]
 struct StartExecutionResponse {
     id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    queue_position: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    scheduled_for: Option<String>,
 }
 
 #[derive(Serialize, Clone)]
@@ -52,14 +303,98 @@ pub(crate) struct ExecutionStatus {
     id: String,
     state: String,
     result: Option<String>,
+    /// Output generated so far while there is no result, including what a cancelled,
+    /// failed or interrupted run got to
+    #[serde(skip_serializing_if = "Option::is_none")]
+    partial_result: Option<String>,
+    /// `partial_result` holds only the first MAX_PARTIAL_RESULT_BYTES
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    partial_result_truncated: bool,
+    error: Option<String>,
+    start_time: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    finished_at: Option<String>,
+    /// Tokens of every prompt the run evaluated on the local model
+    #[serde(skip_serializing_if = "Option::is_none")]
+    prompt_tokens: Option<usize>,
+    /// Tokens generated so far, counted as they are decoded
+    #[serde(skip_serializing_if = "Option::is_none")]
+    completion_tokens: Option<usize>,
+    /// Generation speed, prompt evaluation excluded
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tokens_per_second: Option<f64>,
+    /// Milliseconds from starting to run until finishing, or until now
+    #[serde(skip_serializing_if = "Option::is_none")]
+    duration_ms: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    candidates: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    provenance: Option<Provenance>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tool_calls: Option<Vec<ToolCall>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tool_results: Option<Vec<ToolResult>>,
+    /// Trajectory of an agent run so far
+    #[serde(skip_serializing_if = "Option::is_none")]
+    steps: Option<Vec<AgentStep>>,
+    /// What the execution waits on in PendingApproval
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pending_approval: Option<Approval>,
+    /// Earlier runs that failed and were retried
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    attempts: Vec<ExecutionAttempt>,
+    /// Where the result was generated: `local` or a peer's address
+    #[serde(skip_serializing_if = "Option::is_none")]
+    node: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    scheduled_for: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    tags: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    metadata: Option<serde_json::Map<String, Value>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    queue_position: Option<usize>,
+}
+
+impl ExecutionStatus {
+    pub(crate) fn id(&self) -> &str {
+        &self.id
+    }
+}
+
+/// A failed run of an execution that was queued again.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) struct ExecutionAttempt {
+    attempt: u32,
     error: Option<String>,
+    failed_at: String,
+    retry_at: String,
+}
+
+/// An execution without its prompt or output, for support bundles.
+#[derive(Serialize)]
+struct ExecutionRecord {
+    id: String,
+    state: String,
     start_time: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    best_of: usize,
+    temperature: f32,
+    max_tokens: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    queue_position: Option<usize>,
 }
 
 #[derive(Debug, Clone, PartialEq)]
 enum ExecutionState {
+    /// Waiting for its `scheduled_for` time before it joins the queue
+    Scheduled,
     Queued,
     Running,
+    Paused,
+    /// Waiting for a human to approve the execution or an agent's tool calls
+    PendingApproval,
     Completed,
     Cancelled,
     Failed,
@@ -68,50 +403,732 @@ enum ExecutionState {
 #[derive(Debug, Clone)]
 struct Execution {
     id: String,
-    prompt: String,
+    input: PromptInput,
     _policy: Policy,
     state: ExecutionState,
     start_time: DateTime<Utc>,
+    // When the current attempt first ran
+    started_at: Option<DateTime<Utc>>,
+    finished_at: Option<DateTime<Utc>>,
     result: Option<String>,
     error: Option<String>,
     cancel_token: Option<CancellationToken>,
+    control: Arc<GenerationControl>,
+    // Params at submission, for the cache key of the finished result
+    params: LiveParams,
+    cache_key: Option<String>,
+    best_of: usize,
+    candidates: Option<Vec<String>>,
+    voice: Option<String>,
+    // Persona name, for usage reports
+    persona: Option<String>,
+    provenance: Option<Provenance>,
+    // Append the provenance footer to the reported result
+    footer: bool,
+    // Tools were offered, so completed output is split into text and tool calls
+    tools: bool,
+    tool_calls: Option<Vec<ToolCall>>,
+    // Command tools offered to the model, run before the execution completes
+    command_tools: Vec<String>,
+    tool_results: Option<Vec<ToolResult>>,
+    // Set when running in agent mode
+    agent: Option<Guardrails>,
+    steps: Vec<AgentStep>,
+    pending_approval: Option<Approval>,
+    // Completed output is rewritten by plain::to_plain
+    plain: bool,
+    max_retries: u32,
+    backoff: Duration,
+    attempts: Vec<ExecutionAttempt>,
+    // `local` or the peer that generated the result; unset for cached results
+    node: Option<String>,
+    // Set when a failed run was queued again, for spawn_execution to schedule it
+    retry_in: Option<Duration>,
+    timeout: Option<Duration>,
+    // The deadline passed; later reports from the aborted run are ignored
+    timed_out: bool,
+    scheduled_for: Option<DateTime<Utc>>,
+    tags: Vec<String>,
+    metadata: Option<serde_json::Map<String, Value>>,
+}
+
+/// Everything needed to create an execution, as resolved by the HTTP handler.
+struct ExecutionSpec {
+    input: PromptInput,
+    policy: Policy,
+    best_of: usize,
+    params: LiveParams,
+    cache_key: Option<String>,
+    voice: Option<String>,
+    persona: Option<String>,
+    tools: bool,
+    command_tools: Vec<String>,
+    agent: Option<Guardrails>,
+    plain: bool,
+    max_retries: u32,
+    backoff: Duration,
+    timeout: Option<Duration>,
+    scheduled_for: Option<DateTime<Utc>>,
+    tags: Vec<String>,
+    metadata: Option<serde_json::Map<String, Value>>,
+}
+
+impl Execution {
+    fn status(&self) -> ExecutionStatus {
+        // Peers and the cache report no token counts
+        let stats = Some(self.control.stats()).filter(|s| s.prompt_tokens > 0);
+        let mut partial = self.partial_result().unwrap_or_default();
+        let partial_result_truncated = partial.len() > MAX_PARTIAL_RESULT_BYTES;
+        if partial_result_truncated {
+            let mut end = MAX_PARTIAL_RESULT_BYTES;
+            while !partial.is_char_boundary(end) {
+                end -= 1;
+            }
+            partial.truncate(end);
+        }
+        ExecutionStatus {
+            id: self.id.clone(),
+            state: format!("{:?}", self.state),
+            result: match (&self.result, &self.provenance) {
+                (Some(result), Some(provenance)) if self.footer => {
+                    Some(format!("{}{}", result, provenance.footer()))
+                }
+                (result, _) => result.clone(),
+            },
+            partial_result: Some(partial).filter(|p| !p.is_empty()),
+            partial_result_truncated,
+            error: self.error.clone(),
+            start_time: self.start_time.to_rfc3339(),
+            finished_at: self.finished_at.map(|t| t.to_rfc3339()),
+            prompt_tokens: stats.as_ref().map(|s| s.prompt_tokens),
+            completion_tokens: stats.as_ref().map(|s| s.completion_tokens),
+            tokens_per_second: stats.as_ref().map(|s| s.generation_tokens_per_second()),
+            duration_ms: self.started_at.map(|started| {
+                (self.finished_at.unwrap_or_else(Utc::now) - started).num_milliseconds()
+            }),
+            candidates: self.candidates.clone(),
+            provenance: self.provenance.clone(),
+            tool_calls: self.tool_calls.clone(),
+            tool_results: self.tool_results.clone(),
+            steps: self.agent.as_ref().map(|_| self.steps.clone()),
+            pending_approval: self.pending_approval.clone(),
+            attempts: self.attempts.clone(),
+            node: self.node.clone(),
+            scheduled_for: self.scheduled_for.map(|at| at.to_rfc3339()),
+            tags: self.tags.clone(),
+            metadata: self.metadata.clone(),
+            queue_position: None,
+        }
+    }
+
+    /// The whole output generated so far, while there is no result.
+    fn partial_result(&self) -> Option<String> {
+        if self.result.is_some() {
+            return None;
+        }
+        Some(self.control.partial_output()).filter(|p| !p.is_empty())
+    }
+
+    /// Carries every tag in `tags` and every `key:value` pair in `metadata`.
+    fn labelled(&self, tags: Option<&str>, metadata: Option<&str>) -> bool {
+        let has_tag = |tag: &str| self.tags.iter().any(|t| t == tag);
+        let has_pair = |pair: &str| {
+            let (key, value) = pair.split_once(':').unwrap_or((pair, ""));
+            self.metadata
+                .as_ref()
+                .and_then(|metadata| metadata.get(key.trim()))
+                .is_some_and(|field| match field {
+                    Value::String(text) => text == value.trim(),
+                    // Numbers, booleans and null are compared as JSON
+                    other => serde_json::from_str::<Value>(value.trim()).is_ok_and(|v| v == *other),
+                })
+        };
+        let terms = |list: Option<&str>| {
+            list.map(|list| {
+                list.split(',')
+                    .map(str::trim)
+                    .filter(|t| !t.is_empty())
+                    .map(str::to_string)
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default()
+        };
+        terms(tags).iter().all(|tag| has_tag(tag))
+            && terms(metadata).iter().all(|pair| has_pair(pair))
+    }
+
+    fn is_finished(&self) -> bool {
+        matches!(
+            self.state,
+            ExecutionState::Completed | ExecutionState::Cancelled | ExecutionState::Failed
+        )
+    }
+
+    /// The execution as saved in the history database, with its result as reported.
+    fn stored(&self) -> StoredExecution {
+        StoredExecution {
+            id: self.id.clone(),
+            state: format!("{:?}", self.state),
+            prompt: self.input.user.clone(),
+            system_prompt: self.input.system.clone(),
+            persona: self.persona.clone(),
+            voice: self.voice.clone(),
+            policy: serde_json::to_value(&self._policy).unwrap_or_default(),
+            best_of: self.best_of,
+            temperature: self.params.temperature,
+            max_tokens: self.params.max_tokens,
+            seed: Some(self.params.seed),
+            result: self.status().result,
+            partial_result: self.partial_result(),
+            error: self.error.clone(),
+            candidates: self.candidates.clone(),
+            tool_calls: self
+                .tool_calls
+                .as_ref()
+                .and_then(|calls| serde_json::to_value(calls).ok()),
+            attempts: (!self.attempts.is_empty())
+                .then(|| serde_json::to_value(&self.attempts).ok())
+                .flatten(),
+            node: self.node.clone(),
+            tags: self.tags.clone(),
+            metadata: self.metadata.clone(),
+            start_time: self.start_time,
+            started_at: self.started_at,
+            finished_at: self.finished_at,
+            scheduled_for: self.scheduled_for,
+            stats: Some(self.control.stats()).filter(|s| s.prompt_tokens > 0),
+            // Images, tools, agent settings and output options aren't saved
+            replayable: self.input.images.is_empty()
+                && self.input.tools.is_empty()
+                && self.agent.is_none()
+                && !self.plain,
+        }
+    }
+
+    /// An execution saved by an earlier run of the daemon: finished, Running, Paused or
+    /// PendingApproval when that run died, or Scheduled.
+    fn restored(stored: StoredExecution) -> Option<Self> {
+        let state = match stored.state.as_str() {
+            "Running" => ExecutionState::Running,
+            "Paused" => ExecutionState::Paused,
+            "PendingApproval" => ExecutionState::PendingApproval,
+            "Scheduled" => ExecutionState::Scheduled,
+            "Completed" => ExecutionState::Completed,
+            "Cancelled" => ExecutionState::Cancelled,
+            "Failed" => ExecutionState::Failed,
+            _ => return None,
+        };
+        let params = LiveParams {
+            temperature: stored.temperature,
+            max_tokens: stored.max_tokens,
+            seed: stored.seed.unwrap_or(LiveParams::default().seed),
+        };
+        let control = Arc::new(GenerationControl::with_params(
+            CancellationToken::new(),
+            params,
+        ));
+        if let Some(stats) = stored.stats {
+            control.record_stats(|totals| *totals = stats);
+        }
+        if let Some(partial) = stored.partial_result {
+            control.set_partial_output(partial);
+        }
+        Some(Self {
+            id: stored.id,
+            input: PromptInput {
+                user: stored.prompt,
+                system: stored.system_prompt,
+                ..Default::default()
+            },
+            _policy: serde_json::from_value(stored.policy).ok()?,
+            state,
+            start_time: stored.start_time,
+            started_at: stored.started_at,
+            finished_at: stored.finished_at,
+            result: stored.result,
+            error: stored.error,
+            cancel_token: None,
+            control,
+            params,
+            cache_key: None,
+            best_of: stored.best_of,
+            candidates: stored.candidates,
+            voice: stored.voice,
+            persona: stored.persona,
+            provenance: None,
+            footer: false,
+            tools: false,
+            tool_calls: stored
+                .tool_calls
+                .and_then(|calls| serde_json::from_value(calls).ok()),
+            command_tools: Vec::new(),
+            tool_results: None,
+            agent: None,
+            steps: Vec::new(),
+            pending_approval: None,
+            plain: false,
+            max_retries: 0,
+            backoff: DEFAULT_BACKOFF,
+            attempts: stored
+                .attempts
+                .and_then(|attempts| serde_json::from_value(attempts).ok())
+                .unwrap_or_default(),
+            node: stored.node,
+            retry_in: None,
+            timeout: None,
+            timed_out: false,
+            scheduled_for: stored.scheduled_for,
+            tags: stored.tags,
+            metadata: stored.metadata,
+        })
+    }
 }
 
 pub struct ExecutionManager {
     executions: HashMap<String, Execution>,
-    current: Option<String>,
+    // Executions holding one of the `slots`, from being dispatched until they finish
+    running: HashSet<String>,
+    slots: usize,
+    // Admitted executions waiting for the model, oldest first
+    queue: VecDeque<String>,
+    queue_depth: usize,
+    // Maintenance holds queued executions back
+    paused: bool,
     status_tx: broadcast::Sender<ExecutionStatus>,
+    provenance: Option<Arc<ProvenanceStamp>>,
+    // What executions in PendingApproval wait on
+    approvals: ApprovalQueue,
+    // Finished executions are saved here when `persist_executions` is on
+    history: Option<ExecutionHistory>,
+    retention: RetentionConfig,
+    // Executions started with an Idempotency-Key, by client and key
+    idempotency_keys: HashMap<(String, String), IdempotentStart>,
+}
+
+// What a start with an Idempotency-Key created, and a hash of the prompt it sent
+struct IdempotentStart {
+    id: String,
+    prompt_hash: u64,
 }
 
 impl ExecutionManager {
-    pub fn new() -> Self {
+    pub fn new(
+        provenance: Option<Arc<ProvenanceStamp>>,
+        queue_depth: usize,
+        slots: usize,
+        retention: RetentionConfig,
+    ) -> Self {
         let (status_tx, _) = broadcast::channel(100);
         Self {
             executions: HashMap::new(),
-            current: None,
+            running: HashSet::new(),
+            slots: slots.max(1),
+            queue: VecDeque::new(),
+            queue_depth,
+            paused: false,
             status_tx,
+            provenance,
+            approvals: ApprovalQueue::new(),
+            history: None,
+            retention,
+            idempotency_keys: HashMap::new(),
+        }
+    }
+
+    /// Take back the executions an earlier run saved and save new ones to `history`.
+    /// Returns the interrupted executions queued again, oldest first, for `requeue`.
+    pub fn restore(
+        &mut self,
+        history: ExecutionHistory,
+        stored: Vec<StoredExecution>,
+        interrupted: InterruptedExecutions,
+    ) -> Vec<String> {
+        let mut requeued = Vec::new();
+        for stored in stored {
+            let id = stored.id.clone();
+            let replayable = stored.replayable;
+            let Some(mut execution) = Execution::restored(stored) else {
+                tracing::warn!("Skipping unreadable saved execution {}", id);
+                continue;
+            };
+            // Cut short mid-run, whether generating, paused or waiting on a tool approval
+            if matches!(
+                execution.state,
+                ExecutionState::Running | ExecutionState::Paused | ExecutionState::PendingApproval
+            ) {
+                if interrupted == InterruptedExecutions::Requeue && replayable {
+                    execution.state = ExecutionState::Queued;
+                    execution.cancel_token = Some(execution.control.cancel_token());
+                    execution.footer = self.provenance.as_ref().is_some_and(|p| p.footer());
+                    execution.started_at = None;
+                    requeued.push((execution.start_time, id.clone()));
+                } else {
+                    execution.state = ExecutionState::Failed;
+                    execution.error = Some("interrupted".to_string());
+                    execution.finished_at = Some(Utc::now());
+                    history.save(execution.stored());
+                }
+                tracing::info!(
+                    "Execution {} was interrupted by the last shutdown and is now {:?}",
+                    id,
+                    execution.state
+                );
+            }
+            // Still waiting for its time; the schedule runner admits it once due
+            if execution.state == ExecutionState::Scheduled {
+                if replayable {
+                    execution.cancel_token = Some(execution.control.cancel_token());
+                    execution.footer = self.provenance.as_ref().is_some_and(|p| p.footer());
+                } else {
+                    execution.state = ExecutionState::Cancelled;
+                    execution.error = Some("interrupted".to_string());
+                    execution.finished_at = Some(Utc::now());
+                    history.save(execution.stored());
+                }
+            }
+            self.executions.insert(id, execution);
+        }
+        self.history = Some(history);
+        requeued.sort();
+        requeued.into_iter().map(|(_, id)| id).collect()
+    }
+
+    /// The finished executions, oldest first, as saved in the history.
+    fn export(&self) -> Vec<StoredExecution> {
+        let mut finished: Vec<&Execution> = self
+            .executions
+            .values()
+            .filter(|e| e.is_finished())
+            .collect();
+        finished.sort_by_key(|e| e.start_time);
+        finished.into_iter().map(Execution::stored).collect()
+    }
+
+    /// Take in exported executions; returns how many were added, and the ids of those
+    /// skipped because they are already here, unfinished or unreadable.
+    fn import(&mut self, stored: Vec<StoredExecution>) -> (usize, Vec<String>) {
+        let mut imported = 0;
+        let mut skipped = Vec::new();
+        for stored in stored {
+            let id = stored.id.clone();
+            // Ids become part of paths
+            if Uuid::parse_str(&id).is_err() || self.executions.contains_key(&id) {
+                skipped.push(id);
+                continue;
+            }
+            let Some(execution) = Execution::restored(stored).filter(|e| e.is_finished()) else {
+                skipped.push(id);
+                continue;
+            };
+            if let Some(history) = &self.history {
+                history.save(execution.stored());
+            }
+            self.executions.insert(id, execution);
+            imported += 1;
         }
+        (imported, skipped)
     }
 
-    fn start_execution(&mut self, prompt: String, policy: Policy) -> Result<String, String> {
-        if self.current.is_some() {
-            return Err("Another execution is already running".to_string());
+    fn record_finished(&mut self, id: &str) {
+        let Some(execution) = self.executions.get_mut(id) else {
+            return;
+        };
+        execution.finished_at = Some(Utc::now());
+        if let Some(history) = &self.history {
+            history.save(execution.stored());
         }
+    }
+
+    /// Record a new execution in the Queued state; `admit` decides when it runs.
+    fn create_execution(&mut self, spec: ExecutionSpec) -> String {
         let id = Uuid::new_v4().to_string();
         let cancel_token = CancellationToken::new();
+        let control = Arc::new(GenerationControl::with_params(
+            cancel_token.clone(),
+            spec.params,
+        ));
         let execution = Execution {
             id: id.clone(),
-            prompt,
-            _policy: policy,
+            input: spec.input,
+            _policy: spec.policy,
             state: ExecutionState::Queued,
             start_time: Utc::now(),
+            started_at: None,
+            finished_at: None,
             result: None,
             error: None,
             cancel_token: Some(cancel_token),
+            control,
+            params: spec.params,
+            cache_key: spec.cache_key,
+            best_of: spec.best_of,
+            candidates: None,
+            voice: spec.voice,
+            persona: spec.persona,
+            provenance: None,
+            footer: self.provenance.as_ref().is_some_and(|p| p.footer()),
+            tools: spec.tools,
+            tool_calls: None,
+            command_tools: spec.command_tools,
+            tool_results: None,
+            agent: spec.agent,
+            steps: Vec::new(),
+            pending_approval: None,
+            plain: spec.plain,
+            max_retries: spec.max_retries,
+            backoff: spec.backoff,
+            attempts: Vec::new(),
+            node: None,
+            retry_in: None,
+            timeout: spec.timeout,
+            timed_out: false,
+            scheduled_for: spec.scheduled_for,
+            tags: spec.tags,
+            metadata: spec.metadata,
         };
         self.executions.insert(id.clone(), execution);
-        self.current = Some(id.clone());
-        Ok(id)
+        id
+    }
+
+    /// Take a free slot (`Ok(true)`) or join the back of the queue (`Ok(false)`).
+    /// A full queue drops the execution.
+    fn admit(&mut self, id: &str) -> Result<bool, String> {
+        if self.running.len() < self.slots && self.queue.is_empty() && !self.paused {
+            self.running.insert(id.to_string());
+            return Ok(true);
+        }
+        if self.queue.len() >= self.queue_depth {
+            self.executions.remove(id);
+            // A retry with the same key may try again
+            self.idempotency_keys.retain(|_, start| start.id != id);
+            return Err("Execution queue is full".to_string());
+        }
+        self.queue.push_back(id.to_string());
+        Ok(false)
+    }
+
+    /// Hand a free slot to the oldest queued execution, if any.
+    fn next_queued(&mut self) -> Option<String> {
+        if self.running.len() >= self.slots || self.paused {
+            return None;
+        }
+        while let Some(id) = self.queue.pop_front() {
+            if self
+                .executions
+                .get(&id)
+                .is_some_and(|e| e.state == ExecutionState::Queued)
+            {
+                self.running.insert(id.clone());
+                self.broadcast_queue();
+                return Some(id);
+            }
+        }
+        None
+    }
+
+    // Cancelling broadcasts the final state, so subscribers hear about it before exit
+    fn cancel_queued(&mut self) {
+        let mut queued: Vec<String> = self.queue.iter().cloned().collect();
+        queued.extend(
+            self.executions
+                .values()
+                .filter(|e| e.state == ExecutionState::Scheduled)
+                .map(|e| e.id.clone()),
+        );
+        for id in queued {
+            let _ = self.cancel_execution(&id);
+        }
+    }
+
+    /// The response to a repeated start: the execution the first start with `key`
+    /// created, as long as it is still known and was started with the same prompt.
+    fn replay_start(
+        &self,
+        key: &(String, String),
+        prompt_hash: u64,
+    ) -> Result<Option<Response>, ApiError> {
+        let Some(start) = self.idempotency_keys.get(key) else {
+            return Ok(None);
+        };
+        if start.prompt_hash != prompt_hash {
+            return Err(ApiError::new(
+                StatusCode::UNPROCESSABLE_ENTITY,
+                ErrorCode::InvalidRequest,
+                "This Idempotency-Key was already used with a different prompt",
+            ));
+        }
+        let Some(execution) = self.executions.get(&start.id) else {
+            return Ok(None);
+        };
+        let body = StartExecutionResponse {
+            id: execution.id.clone(),
+            queue_position: self.queue_position(&execution.id),
+            scheduled_for: execution.scheduled_for.map(|at| at.to_rfc3339()),
+        };
+        Ok(Some(
+            ([(IDEMPOTENT_REPLAYED_HEADER, "true")], Json(body)).into_response(),
+        ))
+    }
+
+    fn remember_start(&mut self, key: (String, String), prompt_hash: u64, id: &str) {
+        self.idempotency_keys.insert(
+            key,
+            IdempotentStart {
+                id: id.to_string(),
+                prompt_hash,
+            },
+        );
+    }
+
+    fn queue_position(&self, id: &str) -> Option<usize> {
+        self.queue.iter().position(|q| q == id).map(|i| i + 1)
+    }
+
+    pub fn queue_len(&self) -> usize {
+        self.queue.len()
+    }
+
+    /// Stop (or restart) handing the model to queued executions. The running one is unaffected.
+    pub fn set_paused(&mut self, paused: bool) {
+        self.paused = paused;
+    }
+
+    /// Forget finished executions past the retention: those that finished more than
+    /// `max_age_hours` ago, then the oldest beyond `max_count`. Returns their ids.
+    pub fn prune_finished(&mut self) -> Vec<String> {
+        let mut finished: Vec<(DateTime<Utc>, String)> = self
+            .executions
+            .values()
+            .filter(|e| e.is_finished())
+            .map(|e| (e.finished_at.unwrap_or(e.start_time), e.id.clone()))
+            .collect();
+        finished.sort();
+        let mut expired = Vec::new();
+        if self.retention.max_age_hours > 0 {
+            let cutoff = Utc::now() - chrono::Duration::hours(self.retention.max_age_hours as i64);
+            let stale = finished.partition_point(|(at, _)| *at < cutoff);
+            expired.extend(finished.drain(..stale).map(|(_, id)| id));
+        }
+        if self.retention.max_count > 0 && finished.len() > self.retention.max_count {
+            let excess = finished.len() - self.retention.max_count;
+            expired.extend(finished.drain(..excess).map(|(_, id)| id));
+        }
+        self.forget(&expired);
+        expired
+    }
+
+    /// Forget one finished execution.
+    fn delete_execution(&mut self, id: &str) -> Result<(), String> {
+        let execution = self.executions.get(id).ok_or("Execution not found")?;
+        if !execution.is_finished() {
+            return Err("Execution has not finished; cancel it first".to_string());
+        }
+        self.forget(&[id.to_string()]);
+        Ok(())
+    }
+
+    fn forget(&mut self, ids: &[String]) {
+        for id in ids {
+            self.executions.remove(id);
+        }
+        self.idempotency_keys
+            .retain(|_, start| !ids.contains(&start.id));
+        if let Some(history) = &self.history {
+            history.delete(ids.to_vec());
+        }
+    }
+
+    /// Executions matching `filter`, newest first.
+    fn matching(&self, filter: impl Fn(&Execution) -> bool) -> Vec<&Execution> {
+        let mut executions: Vec<&Execution> =
+            self.executions.values().filter(|e| filter(e)).collect();
+        executions.sort_by_key(|e| std::cmp::Reverse(e.start_time));
+        executions
+    }
+
+    fn list(&self, query: &ListExecutionsQuery, limit: usize) -> ExecutionPage {
+        let matching = self.matching(|e| {
+            query
+                .state
+                .as_ref()
+                .is_none_or(|state| format!("{:?}", e.state).eq_ignore_ascii_case(state))
+                && query.since.is_none_or(|since| e.start_time >= since)
+                && query.until.is_none_or(|until| e.start_time < until)
+                && e.labelled(query.tag.as_deref(), query.metadata.as_deref())
+        });
+        ExecutionPage {
+            total: matching.len(),
+            executions: matching
+                .into_iter()
+                .skip(query.offset)
+                .take(limit)
+                .map(|e| ExecutionStatus {
+                    queue_position: self.queue_position(&e.id),
+                    ..e.status()
+                })
+                .collect(),
+            offset: query.offset,
+            limit,
+        }
+    }
+
+    /// The `limit` most recently started executions, newest first.
+    fn recent(&self, limit: usize) -> Vec<ExecutionRecord> {
+        self.matching(|_| true)
+            .into_iter()
+            .take(limit)
+            .map(|e| ExecutionRecord {
+                id: e.id.clone(),
+                state: format!("{:?}", e.state),
+                start_time: e.start_time.to_rfc3339(),
+                error: e.error.clone(),
+                best_of: e.best_of,
+                temperature: e.params.temperature,
+                max_tokens: e.params.max_tokens,
+                queue_position: self.queue_position(&e.id),
+            })
+            .collect()
+    }
+
+    /// Summed generation stats of the latest completed local runs, with how many there
+    /// were, for speed estimates.
+    pub fn recent_local_stats(&self) -> Option<(GenerationStats, usize)> {
+        let mut total = GenerationStats::default();
+        let mut samples = 0;
+        for execution in self
+            .matching(|e| e.state == ExecutionState::Completed)
+            .into_iter()
+            .take(RECENT_STATS_SAMPLES)
+        {
+            // Offloaded prompts leave no stats behind
+            let stats = execution.control.stats();
+            if stats.prompt_eval_ms <= 0.0 || stats.generation_ms <= 0.0 {
+                continue;
+            }
+            total.prompt_tokens += stats.prompt_tokens;
+            total.completion_tokens += stats.completion_tokens;
+            total.prompt_eval_ms += stats.prompt_eval_ms;
+            total.generation_ms += stats.generation_ms;
+            samples += 1;
+        }
+        (samples > 0).then_some((total, samples))
+    }
+
+    fn status(&self, id: &str) -> Option<ExecutionStatus> {
+        let execution = self.executions.get(id)?;
+        Some(ExecutionStatus {
+            queue_position: self.queue_position(id),
+            ..execution.status()
+        })
+    }
+
+    // Everyone behind a dispatched execution moved up one place
+    fn broadcast_queue(&self) {
+        for id in &self.queue {
+            if let Some(status) = self.status(id) {
+                let _ = self.status_tx.send(status);
+            }
+        }
     }
 
     fn get_execution(&self, id: &str) -> Option<&Execution> {
@@ -120,284 +1137,2737 @@ impl ExecutionManager {
 
     fn cancel_execution(&mut self, id: &str) -> Result<(), String> {
         if let Some(execution) = self.executions.get_mut(id) {
-            if execution.state == ExecutionState::Running {
+            if matches!(
+                execution.state,
+                ExecutionState::Running | ExecutionState::Paused | ExecutionState::PendingApproval
+            ) {
                 if let Some(token) = &execution.cancel_token {
                     token.cancel();
                 }
                 execution.state = ExecutionState::Cancelled;
-                self.current = None;
+                execution.pending_approval = None;
+                self.approvals.withdraw(id);
+                // An execution awaiting approval never took a slot
+                self.running.remove(id);
+                let _ = self.status_tx.send(execution.status());
+                self.record_finished(id);
+                Ok(())
+            } else if matches!(
+                execution.state,
+                ExecutionState::Queued | ExecutionState::Scheduled
+            ) {
+                execution.state = ExecutionState::Cancelled;
+                let _ = self.status_tx.send(execution.status());
+                // It may have been dispatched but not yet started
+                self.running.remove(id);
+                self.queue.retain(|q| q != id);
+                self.broadcast_queue();
+                self.record_finished(id);
                 Ok(())
             } else {
-                Err("Execution is not running".to_string())
+                Err("Execution has already finished".to_string())
             }
         } else {
             Err("Execution not found".to_string())
         }
     }
 
-    pub fn subscribe(&self) -> broadcast::Receiver<ExecutionStatus> {
-        self.status_tx.subscribe()
+    /// Cancel every execution that hasn't started, and with `running` the one holding
+    /// the model and any paused or waiting on a tool approval too. Returns their ids.
+    fn cancel_all(&mut self, running: bool) -> Vec<String> {
+        let mut ids: Vec<(DateTime<Utc>, String)> = self
+            .executions
+            .values()
+            .filter(|e| match e.state {
+                ExecutionState::Scheduled | ExecutionState::Queued => true,
+                // Awaiting approval of the execution itself, so it never ran
+                ExecutionState::PendingApproval if e.started_at.is_none() => true,
+                ExecutionState::Running
+                | ExecutionState::Paused
+                | ExecutionState::PendingApproval => running,
+                _ => false,
+            })
+            .map(|e| (e.start_time, e.id.clone()))
+            .collect();
+        ids.sort();
+        ids.into_iter()
+            .map(|(_, id)| id)
+            .filter(|id| self.cancel_execution(id).is_ok())
+            .collect()
     }
 
-    fn update_execution(
+    fn pause_execution(&mut self, id: &str) -> Result<(), String> {
+        let execution = self.executions.get(id).ok_or("Execution not found")?;
+        if execution.state != ExecutionState::Running {
+            return Err("Execution is not running".to_string());
+        }
+        execution.control.pause();
+        self.update_execution(id, ExecutionState::Paused, None, None);
+        Ok(())
+    }
+
+    fn resume_execution(&mut self, id: &str) -> Result<(), String> {
+        let execution = self.executions.get(id).ok_or("Execution not found")?;
+        if execution.state != ExecutionState::Paused {
+            return Err("Execution is not paused".to_string());
+        }
+        execution.control.resume();
+        self.update_execution(id, ExecutionState::Running, None, None);
+        Ok(())
+    }
+
+    fn patch_execution(
         &mut self,
         id: &str,
-        state: ExecutionState,
-        result: Option<String>,
-        error: Option<String>,
-    ) {
-        if let Some(execution) = self.executions.get_mut(id) {
-            execution.state = state.clone();
-            execution.result = result.clone();
-            execution.error = error.clone();
-            if !matches!(state, ExecutionState::Running) {
-                self.current = None;
-            }
-            // Send status update
-            let status = ExecutionStatus {
-                id: execution.id.clone(),
-                state: format!("{:?}", execution.state),
-                result: execution.result.clone(),
-                error: execution.error.clone(),
-                start_time: execution.start_time.to_rfc3339(),
-            };
-            let _ = self.status_tx.send(status);
+        patch: &PatchExecutionRequest,
+    ) -> Result<LiveParams, String> {
+        let execution = self.executions.get(id).ok_or("Execution not found")?;
+        if !matches!(
+            execution.state,
+            ExecutionState::Running | ExecutionState::Paused
+        ) {
+            return Err("Execution is not running".to_string());
         }
+        if let Some(node) = execution.node.as_deref().filter(|node| *node != LOCAL_NODE) {
+            return Err(format!("Execution is running on peer {}", node));
+        }
+        Ok(execution.control.update_params(|params| {
+            if let Some(temperature) = patch.temperature {
+                params.temperature = temperature;
+            }
+            if let Some(max_tokens) = patch.max_tokens {
+                params.max_tokens = max_tokens;
+            }
+        }))
     }
-}
 
-pub struct AppState {
-    pub model: Arc<Mutex<Model>>,
-    pub monitor: Arc<Mutex<Monitor>>,
-    pub state: Arc<Mutex<String>>,
-    pub execution_manager: Arc<Mutex<ExecutionManager>>,
-    pub hybrid_executor: Arc<Mutex<HybridExecutor>>,
-}
+    /// Generate `n` candidates with different seeds, then ask the model to pick the best one.
+    /// Returns the winner together with every candidate.
+    async fn run_best_of(
+        model: &Model,
+        input: &PromptInput,
+        n: usize,
+        control: &GenerationControl,
+    ) -> Result<(String, Vec<String>), String> {
+        let base_seed = control.params().seed;
+        let mut candidates = Vec::with_capacity(n);
+        for i in 0..n {
+            control.update_params(|params| params.seed = base_seed.wrapping_add(i as u32));
+            candidates.push(model.run_prompt(input, control).await?);
+            if control.is_cancelled() {
+                return Err("Execution cancelled".to_string());
+            }
+        }
 
-fn enforce_policy(policy: Policy) -> Result<Policy, String> {
-    // Reject privilege escalation - networking and telemetry are disabled by build configuration
-    if policy.allow_networking {
-        return Err("Networking is disabled by build configuration".into());
+        let listing: String = candidates
+            .iter()
+            .enumerate()
+            .map(|(i, c)| format!("Answer {}:\n{}\n\n", i + 1, c))
+            .collect();
+        let ranking_prompt = format!(
+            "Question:\n{}\n\n{}Which answer is the most accurate and helpful? Reply with the answer number only.",
+            input.user, listing
+        );
+        // Keep the judge near-greedy and short
+        let judge = GenerationControl::new(control.cancel_token());
+        judge.update_params(|params| {
+            params.temperature = 0.1;
+            params.max_tokens = 8;
+        });
+        let verdict = model
+            .run_prompt(&PromptInput::text(ranking_prompt), &judge)
+            .await?;
+        let winner = verdict
+            .split(|c: char| !c.is_ascii_digit())
+            .find_map(|d| d.parse::<usize>().ok())
+            .filter(|i| (1..=n).contains(i))
+            .unwrap_or(1);
+
+        Ok((candidates[winner - 1].clone(), candidates))
     }
-    if policy.allow_telemetry {
-        return Err("Telemetry is disabled by build configuration".into());
+
+    fn set_tool_output(&mut self, id: &str, calls: Vec<ToolCall>, results: Vec<ToolResult>) {
+        if let Some(execution) = self.executions.get_mut(id) {
+            execution.tool_calls = Some(calls);
+            execution.tool_results = Some(results);
+        }
     }
-    // Allow hybrid compute as per policy
-    Ok(Policy {
-        allow_networking: false,
-        allow_hybrid_compute: policy.allow_hybrid_compute,
-        allow_telemetry: false,
-    })
-}
 
-pub fn create_router(state: Arc<AppState>) -> Router {
-    let cors = CorsLayer::new()
-        .allow_origin(Any)
-        .allow_methods(Any)
-        .allow_headers(Any);
+    /// Move execution `id` to PendingApproval until `answer_approval` is called for the
+    /// approval it opens. `calls` are the tool calls a `ToolCalls` approval is for.
+    pub fn await_approval(
+        &mut self,
+        id: &str,
+        kind: ApprovalKind,
+        calls: Vec<ToolCall>,
+    ) -> oneshot::Receiver<bool> {
+        let prompt = self
+            .executions
+            .get(id)
+            .filter(|_| kind == ApprovalKind::Execution)
+            .map(|e| e.input.user.clone());
+        let (approval, rx) = self.approvals.request(id, kind, prompt.as_deref(), calls);
+        if let Some(execution) = self.executions.get_mut(id) {
+            execution.pending_approval = Some(approval);
+        }
+        self.update_execution(id, ExecutionState::PendingApproval, None, None);
+        rx
+    }
 
-    Router::new()
-        .route("/run_prompt", post(run_prompt))
-        .route("/stop", post(stop))
-        .route("/status", get(status))
-        .route("/execution/start", post(start_execution))
-        .route("/execution/cancel/:id", post(cancel_execution))
-        .route("/execution/status/:id", get(execution_status))
-        .route("/ws/execution/:id", get(execution_ws))
-        .layer(cors)
-        .with_state(state)
-}
+    /// Resolve an open approval. An agent run goes back to Running; an execution goes
+    /// back to Queued for admit_when_approved to admit or cancel.
+    pub fn answer_approval(
+        &mut self,
+        approval_id: &str,
+        approved: bool,
+    ) -> Result<Approval, String> {
+        let approval = self.approvals.answer(approval_id, approved)?;
+        if let Some(execution) = self.executions.get_mut(&approval.execution_id) {
+            execution.pending_approval = None;
+        }
+        let state = match approval.kind {
+            ApprovalKind::Execution => ExecutionState::Queued,
+            ApprovalKind::ToolCalls => ExecutionState::Running,
+        };
+        self.update_execution(&approval.execution_id, state, None, None);
+        Ok(approval)
+    }
 
-async fn run_prompt(
-    State(state): State<Arc<AppState>>,
-    Json(req): Json<RunPromptRequest>,
-) -> Result<axum::response::Response<String>, StatusCode> {
-    let _enforced_policy = enforce_policy(req.policy).map_err(|e| {
-        tracing::error!("Policy enforcement failed: {}", e);
-        StatusCode::BAD_REQUEST
-    })?;
+    pub fn approvals(&self) -> &ApprovalQueue {
+        &self.approvals
+    }
 
-    tracing::info!("Running prompt: {}", req.prompt);
-    let model = state.model.lock().await;
-    *state.state.lock().await = "running".to_string();
-    match model.run_prompt(&req.prompt).await {
-        Ok(response) => {
-            *state.state.lock().await = "idle".to_string();
-            tracing::info!("Prompt executed successfully");
-            Ok(axum::response::Response::builder()
-                .header("content-type", "text/plain")
-                .body(response)
-                .unwrap())
+    /// Abort run `attempt` of `id` and fail it with "timeout" if it is still going.
+    fn time_out(&mut self, id: &str, attempt: usize) {
+        let Some(execution) = self.executions.get_mut(id) else {
+            return;
+        };
+        if execution.attempts.len() != attempt
+            || !matches!(
+                execution.state,
+                ExecutionState::Running | ExecutionState::Paused | ExecutionState::PendingApproval
+            )
+        {
+            return;
         }
-        Err(e) => {
-            *state.state.lock().await = "idle".to_string();
-            tracing::error!("Failed to run prompt: {:?}", e);
-            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        if let Some(token) = &execution.cancel_token {
+            token.cancel();
         }
+        execution.timed_out = true;
+        tracing::warn!("Execution {} timed out", id);
+        self.update_execution(
+            id,
+            ExecutionState::Failed,
+            None,
+            Some("timeout".to_string()),
+        );
     }
+
+    /// Hold `id` back as Scheduled if its time hasn't come by `now`.
+    fn defer(&mut self, id: &str, now: DateTime<Utc>) -> bool {
+        let Some(execution) = self.executions.get_mut(id) else {
+            return false;
+        };
+        if execution.state != ExecutionState::Queued
+            || execution.scheduled_for.is_none_or(|at| at <= now)
+        {
+            return false;
+        }
+        execution.state = ExecutionState::Scheduled;
+        let _ = self.status_tx.send(execution.status());
+        // Saved, so it still runs after a restart
+        if let Some(history) = &self.history {
+            history.save(execution.stored());
+        }
+        true
+    }
+
+    /// Move Scheduled executions whose time has come by `now` back to Queued, returning
+    /// them soonest first.
+    fn take_due(&mut self, now: DateTime<Utc>) -> Vec<String> {
+        let mut due: Vec<&mut Execution> = self
+            .executions
+            .values_mut()
+            .filter(|e| {
+                e.state == ExecutionState::Scheduled && e.scheduled_for.is_none_or(|at| at <= now)
+            })
+            .collect();
+        due.sort_by_key(|e| e.scheduled_for);
+        due.into_iter()
+            .map(|e| {
+                e.state = ExecutionState::Queued;
+                e.id.clone()
+            })
+            .collect()
+    }
+
+    /// Executions waiting for their time, soonest first.
+    fn schedules(&self) -> Vec<ExecutionStatus> {
+        let mut scheduled = self.matching(|e| e.state == ExecutionState::Scheduled);
+        scheduled.sort_by_key(|e| e.scheduled_for);
+        scheduled.into_iter().map(Execution::status).collect()
+    }
+
+    /// How long until a failed run of `id` that was queued again may be admitted.
+    fn take_retry(&mut self, id: &str) -> Option<Duration> {
+        self.executions.get_mut(id)?.retry_in.take()
+    }
+
+    /// Record a finished agent step and tell subscribers.
+    pub fn push_step(&mut self, id: &str, step: AgentStep) {
+        if let Some(execution) = self.executions.get_mut(id) {
+            execution.steps.push(step);
+            let _ = self.status_tx.send(execution.status());
+        }
+    }
+
+    /// Broadcast and save `id`'s partial result if it grew past `reported` bytes. Returns
+    /// its length, or `None` once `attempt` is over.
+    fn report_progress(&mut self, id: &str, attempt: usize, reported: usize) -> Option<usize> {
+        let execution = self.executions.get(id)?;
+        if execution.attempts.len() != attempt
+            || !matches!(
+                execution.state,
+                ExecutionState::Running | ExecutionState::Paused
+            )
+        {
+            return None;
+        }
+        let len = execution.control.partial_len();
+        if len != reported {
+            let _ = self.status_tx.send(execution.status());
+            if let Some(history) = &self.history {
+                history.save(execution.stored());
+            }
+        }
+        Some(len)
+    }
+
+    fn set_node(&mut self, id: &str, node: String) {
+        if let Some(execution) = self.executions.get_mut(id) {
+            execution.node = Some(node);
+        }
+    }
+
+    fn set_candidates(&mut self, id: &str, candidates: Vec<String>) {
+        if let Some(execution) = self.executions.get_mut(id) {
+            execution.candidates = Some(candidates);
+        }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<ExecutionStatus> {
+        self.status_tx.subscribe()
+    }
+
+    fn update_execution(
+        &mut self,
+        id: &str,
+        state: ExecutionState,
+        result: Option<String>,
+        error: Option<String>,
+    ) {
+        if let Some(execution) = self.executions.get_mut(id) {
+            // An aborted run surfaces as an error from the model; keep it Cancelled
+            if execution.state == ExecutionState::Cancelled && state != ExecutionState::Cancelled {
+                return;
+            }
+            if execution.timed_out && execution.state == ExecutionState::Failed {
+                return;
+            }
+            // A failed run with retries left goes back to Queued instead
+            if state == ExecutionState::Failed
+                && execution.state == ExecutionState::Running
+                && !execution.timed_out
+                && (execution.attempts.len() as u32) < execution.max_retries
+            {
+                let attempt = execution.attempts.len() as u32 + 1;
+                let delay = execution.backoff.saturating_mul(1 << (attempt - 1).min(16));
+                let now = Utc::now();
+                execution.attempts.push(ExecutionAttempt {
+                    attempt,
+                    error,
+                    failed_at: now.to_rfc3339(),
+                    retry_at: (now + chrono::Duration::from_std(delay).unwrap_or_default())
+                        .to_rfc3339(),
+                });
+                execution.state = ExecutionState::Queued;
+                execution.retry_in = Some(delay);
+                execution.provenance = None;
+                self.running.remove(id);
+                let _ = self.status_tx.send(execution.status());
+                return;
+            }
+            // Each attempt reports its own counts and duration
+            let starting =
+                state == ExecutionState::Running && execution.state == ExecutionState::Queued;
+            if starting {
+                execution.started_at = Some(Utc::now());
+                execution
+                    .control
+                    .record_stats(|totals| *totals = GenerationStats::default());
+                execution.control.set_partial_output(String::new());
+                execution.node = None;
+            }
+            execution.state = state.clone();
+            // Saved as Running, so a crash leaves a row for the next start to resolve
+            if starting && let Some(history) = &self.history {
+                history.save(execution.stored());
+            }
+            execution.result = result.clone();
+            execution.error = error.clone();
+            if state == ExecutionState::Completed {
+                execution.provenance = self.provenance.as_ref().map(|p| p.stamp());
+                // complete_execution splits the output itself when it runs tools
+                if execution.tools
+                    && execution.tool_calls.is_none()
+                    && let Some(output) = &result
+                {
+                    let (content, calls) = tools::parse_tool_calls(output);
+                    execution.result = Some(content);
+                    execution.tool_calls = Some(calls);
+                }
+                if execution.plain {
+                    execution.result = execution.result.as_deref().map(plain::to_plain);
+                }
+            }
+            if !matches!(
+                state,
+                ExecutionState::Running | ExecutionState::Paused | ExecutionState::PendingApproval
+            ) {
+                execution.pending_approval = None;
+                self.approvals.withdraw(id);
+                self.running.remove(id);
+            }
+            // Send status update
+            let _ = self.status_tx.send(execution.status());
+            if matches!(
+                state,
+                ExecutionState::Completed | ExecutionState::Cancelled | ExecutionState::Failed
+            ) {
+                self.record_finished(id);
+            }
+        }
+    }
+}
+
+pub struct AppState {
+    pub model: Arc<Mutex<Model>>,
+    pub reranker: Option<Arc<Mutex<Model>>>,
+    /// Dedicated embedding model; the chat model is used when unset
+    pub embedder: Option<Arc<Mutex<Model>>>,
+    pub monitor: Arc<Mutex<Monitor>>,
+    pub state: Arc<Mutex<String>>,
+    pub execution_manager: Arc<Mutex<ExecutionManager>>,
+    pub hybrid_executor: Arc<Mutex<HybridExecutor>>,
+    pub personas: Arc<Mutex<PersonaStore>>,
+    pub templates: Arc<Mutex<TemplateStore>>,
+    pub sessions: Arc<Mutex<SessionStore>>,
+    pub conversations: Arc<Mutex<ConversationStore>>,
+    /// Present when `memory` is enabled in the config
+    pub memories: Option<Arc<Mutex<MemoryStore>>>,
+    pub tts: Option<Arc<Tts>>,
+    pub cache: Option<Arc<Mutex<ResponseCache>>>,
+    pub provenance: Option<Arc<ProvenanceStamp>>,
+    /// Cancellation token of the /run_prompt request in flight, for /stop
+    pub prompt_cancel: Arc<Mutex<Option<CancellationToken>>>,
+    pub api_keys: Vec<String>,
+    /// Set when mutating requests must also be signed
+    pub replay_guard: Option<Arc<Mutex<ReplayGuard>>>,
+    pub ip_filter: Option<IpFilter>,
+    pub audit: Arc<AuditLog>,
+    pub usage: Arc<UsageLog>,
+    pub security: Arc<Mutex<SecurityMonitor>>,
+    /// Cancelled to stop serving, on shutdown or for POST /admin/restart
+    pub stop: CancellationToken,
+    pub log_level: LogLevelHandle,
+    pub read_only: bool,
+    pub timeouts: TimeoutConfig,
+    pub max_body_bytes: usize,
+    pub frontend_dir: PathBuf,
+    /// Index into i18n::LOCALES of the configured `locale`
+    pub locale: Option<usize>,
+    pub maintenance: Arc<Mutex<Maintenance>>,
+    pub rate_limiter: Option<Arc<Mutex<RateLimiter>>>,
+    pub command_tools: Arc<CommandTools>,
+    pub agent: AgentConfig,
+    /// Hold every /execution/start in PendingApproval until it is approved
+    pub approve_executions: bool,
+    pub sharing: SharingConfig,
+    pub sync: Option<SyncConfig>,
+    /// Progress of the last POST /admin/model/upgrade
+    pub model_upgrade: Arc<Mutex<UpgradeStatus>>,
+    pub selfcheck: Option<SelfcheckConfig>,
+    pub last_selfcheck: Arc<Mutex<Option<SelfcheckReport>>>,
+}
+
+impl AppState {
+    fn embedding_model(&self) -> &Arc<Mutex<Model>> {
+        self.embedder.as_ref().unwrap_or(&self.model)
+    }
+
+    /// Abort the in-flight /run_prompt request and the running executions.
+    async fn stop_generation(&self) {
+        // The decode loop checks these tokens before every token
+        if let Some(token) = self.prompt_cancel.lock().await.take() {
+            token.cancel();
+        }
+        let mut manager = self.execution_manager.lock().await;
+        let running: Vec<String> = manager.running.iter().cloned().collect();
+        for id in running {
+            let _ = manager.cancel_execution(&id);
+        }
+        drop(manager);
+        *self.state.lock().await = "idle".to_string();
+    }
+
+    /// Shutdown: drop queued executions, give in-flight generations up to `grace`
+    /// to finish, then cancel whatever is still running.
+    pub async fn drain(&self, grace: Duration) {
+        self.execution_manager.lock().await.cancel_queued();
+        let deadline = Instant::now() + grace;
+        while Instant::now() < deadline {
+            let idle = self.execution_manager.lock().await.running.is_empty()
+                && self.prompt_cancel.lock().await.is_none();
+            if idle {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(200)).await;
+        }
+        tracing::warn!("Generation still running after {:?}, cancelling", grace);
+        self.stop_generation().await;
+    }
+}
+
+/// Output settings a persona carries beyond its prompt and sampling.
+#[derive(Default)]
+struct PersonaOutput {
+    name: Option<String>,
+    voice: Option<String>,
+    plain: bool,
+}
+
+/// Apply the selected persona, if any, to the prompt and its sampling parameters.
+/// Returns the persona's voice for text-to-speech and its plain-output setting.
+async fn apply_persona(
+    state: &AppState,
+    persona: Option<&str>,
+    input: &mut PromptInput,
+    params: &mut LiveParams,
+) -> Result<PersonaOutput, String> {
+    let Some(key) = persona else {
+        return Ok(PersonaOutput::default());
+    };
+    let personas = state.personas.lock().await;
+    let persona = personas
+        .find(key)
+        .ok_or_else(|| format!("Unknown persona: {}", key))?;
+    input.system = Some(persona.system_prompt.clone());
+    persona.sampling.apply(params);
+    Ok(PersonaOutput {
+        name: Some(persona.name.clone()),
+        voice: persona.voice.clone(),
+        plain: persona.plain_output,
+    })
+}
+
+/// Resolve the system prompt: the request's own, then the persona's, then the session's.
+/// Left unset, the model uses the configured `model.system_prompt`.
+async fn apply_system_prompt(
+    state: &AppState,
+    system_prompt: Option<String>,
+    session: Option<&str>,
+    input: &mut PromptInput,
+) {
+    if system_prompt.is_some() {
+        input.system = system_prompt;
+        return;
+    }
+    if input.system.is_some() {
+        return;
+    }
+    if let Some(id) = session {
+        let sessions = state.sessions.lock().await;
+        input.system = sessions.get(id).and_then(|s| s.system_prompt.clone());
+    }
+}
+
+async fn record_policy_violation(state: &AppState, client: &ClientId, detail: String) {
+    state.security.lock().await.record(
+        SecurityEventKind::PolicyViolation,
+        Some(client.0.clone()),
+        detail,
+    );
+}
+
+fn enforce_policy(policy: Policy) -> Result<Policy, String> {
+    // Reject privilege escalation - networking and telemetry are disabled by build configuration
+    if policy.allow_networking {
+        return Err("Networking is disabled by build configuration".into());
+    }
+    if policy.allow_telemetry {
+        return Err("Telemetry is disabled by build configuration".into());
+    }
+    // Allow hybrid compute as per policy
+    Ok(Policy {
+        allow_networking: false,
+        allow_hybrid_compute: policy.allow_hybrid_compute,
+        allow_telemetry: false,
+    })
+}
+
+pub(crate) fn decode_images(images: &[String]) -> Result<Vec<Vec<u8>>, String> {
+    images
+        .iter()
+        .map(|image| {
+            // Accept both bare base64 and data:image/...;base64,<data> URLs
+            let data = image.split_once(',').map_or(image.as_str(), |(_, d)| d);
+            BASE64
+                .decode(data.trim())
+                .map_err(|e| format!("Invalid base64 image: {}", e))
+        })
+        .collect()
+}
+
+pub(crate) fn timed_out(after: Duration) -> Response {
+    ApiError::new(
+        StatusCode::GATEWAY_TIMEOUT,
+        ErrorCode::Timeout,
+        format!("Request timed out after {}s", after.as_secs()),
+    )
+    .into_response()
+}
+
+pub fn create_router(state: Arc<AppState>) -> Router {
+    let cors = CorsLayer::new()
+        .allow_origin(Any)
+        .allow_methods(Any)
+        .allow_headers(Any)
+        .expose_headers([
+            header::ETAG,
+            HeaderName::from_static(IDEMPOTENT_REPLAYED_HEADER),
+        ]);
+
+    // Answer 504 once a route runs past its configured limit
+    let timeout = |secs: u64| {
+        let after = Duration::from_secs(secs);
+        ServiceBuilder::new()
+            .layer(HandleErrorLayer::new(move |_: BoxError| async move {
+                timed_out(after)
+            }))
+            .timeout(after)
+    };
+    let limits = &state.timeouts;
+    let sensitive_read =
+        middleware::from_fn_with_state(Arc::clone(&state), auth::require_api_key_on_reads);
+
+    // Served under /v1; the old unprefixed paths keep working but are marked deprecated
+    let api = Router::new()
+        .route(
+            "/run_prompt",
+            post(run_prompt).layer(timeout(limits.run_prompt_secs)),
+        )
+        .route("/stop", post(stop))
+        .route("/admin/restart", post(restart))
+        .route(
+            "/admin/executions",
+            get(recent_executions).layer(middleware::from_fn(etag::conditional_get)),
+        )
+        .route("/admin/executions/cancel", post(cancel_all_executions))
+        .route(
+            "/status",
+            get(status).layer(middleware::from_fn(etag::conditional_get)),
+        )
+        .route(
+            "/benchmark",
+            post(benchmark).layer(timeout(limits.benchmark_secs)),
+        )
+        .route(
+            "/executions",
+            get(list_executions).layer(middleware::from_fn(etag::conditional_get)),
+        )
+        .route(
+            "/executions/export",
+            get(export_executions).layer(sensitive_read.clone()),
+        )
+        .route("/executions/import", post(import_executions))
+        .route("/execution/start", post(start_execution))
+        .route("/execution/start_from_template", post(start_from_template))
+        .route("/schedules", get(list_schedules))
+        .route("/execution/cancel/:id", post(cancel_execution))
+        .route("/execution/status/:id", get(execution_status))
+        .route(
+            "/execution/:id",
+            patch(patch_execution).delete(delete_execution),
+        )
+        .route("/execution/:id/pause", post(pause_execution))
+        .route("/execution/:id/resume", post(resume_execution))
+        .route(
+            "/execution/:id/audio",
+            get(execution_audio).layer(timeout(limits.audio_secs)),
+        )
+        .route(
+            "/execution/:id/transcript",
+            get(execution_transcript).layer(sensitive_read.clone()),
+        )
+        .route(
+            "/execution/:id/context",
+            get(execution_context).layer(sensitive_read.clone()),
+        )
+        .route("/execution/:id/approval", post(approve_execution))
+        .route("/ws/execution/:id", get(execution_ws))
+        .merge(models::routes())
+        .merge(approvals::routes())
+        .merge(personas::routes())
+        .merge(templates::routes())
+        .merge(sessions::routes())
+        .merge(sync::routes())
+        .merge(conversations::routes().route_layer(sensitive_read))
+        .merge(memories::routes())
+        .merge(maintenance::routes())
+        .merge(selfcheck::routes())
+        .merge(simulate::routes())
+        .merge(standby::routes())
+        .merge(security::routes())
+        .merge(logging::routes())
+        .merge(usage::routes());
+
+    let router = Router::new()
+        .route("/healthz", get(healthz))
+        .route("/metrics", get(metrics))
+        .route(
+            "/v1/rerank",
+            post(rerank).layer(timeout(limits.embeddings_secs)),
+        )
+        .route(
+            "/v1/embeddings",
+            post(embeddings).layer(timeout(limits.embeddings_secs)),
+        )
+        // Ollama's paths are fixed by the clients that speak it
+        .merge(ollama::routes())
+        .nest(deprecation::API_PREFIX, api.clone())
+        .merge(api.layer(middleware::from_fn(deprecation::mark_deprecated)));
+    // Anything else is the web UI, or a page explaining where it should be
+    let router = if state.frontend_dir.is_dir() {
+        router.fallback_service(ServeDir::new(&state.frontend_dir))
+    } else {
+        tracing::warn!(
+            "Frontend directory {} not found, serving a landing page instead",
+            state.frontend_dir.display()
+        );
+        router.fallback(landing_page)
+    };
+
+    router
+        .layer(DefaultBodyLimit::max(state.max_body_bytes))
+        .layer(middleware::from_fn_with_state(
+            Arc::clone(&state),
+            limits::limit_body,
+        ))
+        .layer(middleware::from_fn_with_state(
+            Arc::clone(&state),
+            auth::enforce_read_only,
+        ))
+        .layer(middleware::from_fn_with_state(
+            Arc::clone(&state),
+            ratelimit::rate_limit,
+        ))
+        .layer(middleware::from_fn_with_state(
+            Arc::clone(&state),
+            auth::require_api_key,
+        ))
+        .layer(cors)
+        .layer(middleware::from_fn_with_state(
+            Arc::clone(&state),
+            ipfilter::filter_ip,
+        ))
+        .layer(middleware::from_fn(access_log::trace_request))
+        .layer(middleware::from_fn_with_state(
+            Arc::clone(&state),
+            i18n::localize,
+        ))
+        // Outermost, so finished bodies are compressed; tiny, image and event-stream
+        // bodies pass through
+        .layer(CompressionLayer::new())
+        .with_state(state)
+}
+
+/// How finish_output post-processes a completion.
+#[derive(Clone, Copy)]
+struct OutputMode<'a> {
+    /// Tools were offered, so tool calls are split out of the text
+    tools: bool,
+    /// Rewrite the text without markdown or emoji
+    plain: bool,
+    /// Command tools offered to the model, run when it calls them
+    run_tools: &'a [String],
+}
+
+/// The post-processed parts of a completion.
+struct FinishedOutput {
+    builder: axum::http::response::Builder,
+    content: String,
+    tool_calls: Vec<ToolCall>,
+    tool_results: Vec<ToolResult>,
+    provenance: Option<Provenance>,
+}
+
+/// Split a completion into text and tool calls, run the command tools it called,
+/// rewrite the text as `mode` asks, and label it with provenance metadata when enabled.
+async fn finish_output(state: &AppState, response: String, mode: OutputMode<'_>) -> FinishedOutput {
+    let mut builder = axum::response::Response::builder();
+    let (mut content, tool_calls) = if mode.tools {
+        tools::parse_tool_calls(&response)
+    } else {
+        (response, Vec::new())
+    };
+    let tool_results = state
+        .command_tools
+        .run_all(&tool_calls, mode.run_tools, &state.audit)
+        .await;
+    if mode.plain {
+        content = plain::to_plain(&content);
+    }
+    let mut provenance = None;
+    if let Some(stamp) = &state.provenance {
+        let stamped = stamp.stamp();
+        if stamp.header() {
+            builder = builder.header(PROVENANCE_HEADER, stamped.header_value());
+        }
+        if stamp.footer() {
+            content.push_str(&stamped.footer());
+        }
+        provenance = Some(stamped);
+    }
+    FinishedOutput {
+        builder,
+        content,
+        tool_calls,
+        tool_results,
+        provenance,
+    }
+}
+
+/// Completion as plain text, or as JSON with `tool_calls` (and the `tool_results` of
+/// command tools) when tools were offered.
+async fn text_response(
+    state: &AppState,
+    response: String,
+    mode: OutputMode<'_>,
+) -> axum::response::Response<String> {
+    let FinishedOutput {
+        builder,
+        content,
+        tool_calls,
+        tool_results,
+        ..
+    } = finish_output(state, response, mode).await;
+    if mode.tools {
+        let mut body = serde_json::json!({ "content": content, "tool_calls": tool_calls });
+        if !mode.run_tools.is_empty() {
+            body["tool_results"] = serde_json::json!(tool_results);
+        }
+        builder
+            .header("content-type", "application/json")
+            .body(body.to_string())
+            .unwrap()
+    } else {
+        builder
+            .header("content-type", "text/plain")
+            .body(content)
+            .unwrap()
+    }
+}
+
+/// Completion and generation metadata, the JSON form of /run_prompt's response.
+#[derive(Serialize)]
+struct RunPromptResponse {
+    output: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tool_calls: Option<Vec<ToolCall>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tool_results: Option<Vec<ToolResult>>,
+    /// Token counts, timings and finish_reason; absent for cached responses
+    #[serde(flatten)]
+    stats: Option<GenerationStats>,
+    total_ms: f64,
+    seed: u32,
+    cached: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    provenance: Option<Provenance>,
+}
+
+async fn json_response(
+    state: &AppState,
+    response: String,
+    mode: OutputMode<'_>,
+    stats: Option<GenerationStats>,
+    seed: u32,
+    started: Instant,
+) -> axum::response::Response<String> {
+    let FinishedOutput {
+        builder,
+        content: output,
+        tool_calls,
+        tool_results,
+        provenance,
+    } = finish_output(state, response, mode).await;
+    let body = RunPromptResponse {
+        output,
+        tool_calls: mode.tools.then_some(tool_calls),
+        tool_results: (!mode.run_tools.is_empty()).then_some(tool_results),
+        cached: stats.is_none(),
+        stats,
+        total_ms: started.elapsed().as_secs_f64() * 1000.0,
+        seed,
+        provenance,
+    };
+    builder
+        .header("content-type", "application/json")
+        .body(serde_json::to_string(&body).unwrap())
+        .unwrap()
+}
+
+#[derive(Deserialize)]
+struct RunPromptQuery {
+    /// `text` or `json`; defaults to what the Accept header asks for
+    format: Option<String>,
+}
+
+/// Whether the client lists application/json among the types it accepts.
+fn accepts_json(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(header::ACCEPT)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|range| {
+            let mut parts = range.split(';');
+            parts.next().is_some_and(|t| t.trim() == "application/json")
+                && !parts.any(|p| matches!(p.trim(), "q=0" | "q=0.0"))
+        })
+}
+
+/// Charge a generation's token budget to the client when rate limiting is enabled.
+pub(crate) async fn reserve_tokens(
+    state: &AppState,
+    client: &ClientId,
+    tokens: usize,
+) -> Result<(), RateLimited> {
+    match &state.rate_limiter {
+        Some(limiter) => limiter
+            .lock()
+            .await
+            .reserve_tokens(&client.0, tokens as u64),
+        None => Ok(()),
+    }
+}
+
+async fn cached_response(
+    cache: &Option<Arc<Mutex<ResponseCache>>>,
+    key: Option<&str>,
+) -> Option<String> {
+    let (cache, key) = (cache.as_ref()?, key?);
+    cache.lock().await.get(key)
+}
+
+async fn store_response(
+    cache: &Option<Arc<Mutex<ResponseCache>>>,
+    key: Option<String>,
+    response: &str,
+) {
+    if let (Some(cache), Some(key)) = (cache, key) {
+        cache.lock().await.insert(key, response.to_string());
+    }
+}
+
+async fn run_prompt(
+    State(state): State<Arc<AppState>>,
+    Extension(client): Extension<ClientId>,
+    headers: HeaderMap,
+    Query(query): Query<RunPromptQuery>,
+    Json(req): Json<RunPromptRequest>,
+) -> Result<Response, ApiError> {
+    let started = Instant::now();
+    let json = match query.format.as_deref() {
+        None => accepts_json(&headers),
+        Some("json") => true,
+        Some("text") => false,
+        Some(format) => {
+            tracing::error!("Unknown response format: {}", format);
+            return Err(ApiError::bad_request(format!(
+                "Unknown format {:?}, expected json or text",
+                format
+            )));
+        }
+    };
+    let _enforced_policy = match enforce_policy(req.policy) {
+        Ok(policy) => policy,
+        Err(e) => {
+            tracing::error!("Policy enforcement failed: {}", e);
+            record_policy_violation(&state, &client, e.clone()).await;
+            return Err(ApiError::new(
+                StatusCode::BAD_REQUEST,
+                ErrorCode::PolicyViolation,
+                e,
+            ));
+        }
+    };
+    let images = decode_images(&req.images).map_err(|e| {
+        tracing::error!("Rejected images: {}", e);
+        ApiError::bad_request(e)
+    })?;
+    let mut tools = req.tools;
+    tools.extend(
+        state
+            .command_tools
+            .definitions(&req.command_tools)
+            .map_err(ApiError::bad_request)?,
+    );
+
+    let mut input = PromptInput {
+        user: req.prompt,
+        images,
+        tools,
+        interactive: true,
+        ..Default::default()
+    };
+    let mut params = LiveParams::default();
+    let persona = apply_persona(&state, req.persona.as_deref(), &mut input, &mut params)
+        .await
+        .map_err(|e| {
+            tracing::error!("{}", e);
+            ApiError::bad_request(e)
+        })?;
+    let plain = req.plain_output.unwrap_or(persona.plain);
+    apply_system_prompt(
+        &state,
+        req.system_prompt,
+        req.session.as_deref(),
+        &mut input,
+    )
+    .await;
+    memories::inject(&state, &mut input).await;
+    // Command tools see the current state of the machine, so their runs aren't cached
+    let cache_key = state
+        .cache
+        .as_ref()
+        .filter(|_| req.command_tools.is_empty())
+        .map(|_| ResponseCache::key(&input, &params));
+    let mode = OutputMode {
+        tools: !input.tools.is_empty(),
+        plain,
+        run_tools: &req.command_tools,
+    };
+    if let Some(response) = cached_response(&state.cache, cache_key.as_deref()).await {
+        tracing::info!("Serving cached response");
+        return Ok(if json {
+            json_response(&state, response, mode, None, params.seed, started)
+                .await
+                .into_response()
+        } else {
+            text_response(&state, response, mode).await.into_response()
+        });
+    }
+    if let Err(limited) = reserve_tokens(&state, &client, params.max_tokens).await {
+        return Ok(limited.into_response());
+    }
+    let control = GenerationControl::with_params(CancellationToken::new(), params);
+    let limit = Duration::from_secs(state.timeouts.run_prompt_secs);
+    let result = run_foreground(&state, &input, &control, started + limit).await;
+    match result {
+        Ok((response, stats)) => {
+            tracing::info!("Prompt executed successfully");
+            state
+                .usage
+                .record(UsageRecord::new("run_prompt", persona.name, Some(&stats)))
+                .await;
+            store_response(&state.cache, cache_key, &response).await;
+            memories::extract_in_background(
+                Arc::clone(&state),
+                input.user.clone(),
+                response.clone(),
+            );
+            let seed = control.params().seed;
+            Ok(if json {
+                json_response(&state, response, mode, Some(stats), seed, started)
+                    .await
+                    .into_response()
+            } else {
+                text_response(&state, response, mode).await.into_response()
+            })
+        }
+        Err(_) if control.is_cancelled() && started.elapsed() >= limit => {
+            tracing::warn!("Prompt timed out after {:?}", limit);
+            Ok(timed_out(limit))
+        }
+        Err(_) if control.is_cancelled() => {
+            tracing::info!("Prompt stopped");
+            Err(ApiError::cancelled())
+        }
+        Err(e) if e.starts_with(PROMPT_TOO_LONG) => {
+            tracing::warn!("Rejected prompt: {}", e);
+            state.security.lock().await.record(
+                SecurityEventKind::OversizedRequest,
+                Some(client.0.clone()),
+                e.clone(),
+            );
+            Ok(limits::payload_too_large(e))
+        }
+        Err(e) => {
+            tracing::error!("Failed to run prompt: {:?}", e);
+            Err(ApiError::internal(e))
+        }
+    }
+}
+
+/// Aborts its timer when dropped.
+struct AbortOnDrop(tokio::task::JoinHandle<()>);
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+/// Cancel `token` once `deadline` passes, as long as the returned guard lives. Decoding,
+/// scoring and embedding block their task until they finish, so a route timeout never
+/// gets to interrupt them; they stop on the token instead.
+fn cancel_at(deadline: Instant, token: CancellationToken) -> AbortOnDrop {
+    AbortOnDrop(tokio::spawn(async move {
+        tokio::time::sleep_until(deadline).await;
+        token.cancel();
+    }))
+}
+
+/// Run `input` on the main model for a request waiting on the answer. The generation
+/// can be stopped with POST /stop and is cancelled once `deadline` passes.
+pub(crate) async fn run_foreground(
+    state: &AppState,
+    input: &PromptInput,
+    control: &GenerationControl,
+    deadline: Instant,
+) -> Result<(String, GenerationStats), String> {
+    let _timer = cancel_at(deadline, control.cancel_token());
+
+    tracing::info!("Running prompt: {}", input.user);
+    let model = state.model.lock().await;
+    *state.state.lock().await = "running".to_string();
+    *state.prompt_cancel.lock().await = Some(control.cancel_token());
+    let result = model.run_prompt_with_stats(input, control).await;
+    *state.prompt_cancel.lock().await = None;
+    *state.state.lock().await = "idle".to_string();
+    result
+}
+
+async fn metrics(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    let mut out = String::new();
+    state.security.lock().await.render_metrics(&mut out);
+    ([(header::CONTENT_TYPE, "text/plain; version=0.0.4")], out)
+}
+
+/// Shown at `/` when `frontend_dir` doesn't exist.
+async fn landing_page(State(state): State<Arc<AppState>>, uri: Uri) -> Response {
+    if uri.path() != "/" {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+    let dir = state
+        .frontend_dir
+        .display()
+        .to_string()
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;");
+    Html(format!(
+        r#"<!doctype html>
+<html lang="en">
+<head><meta charset="utf-8"><title>kekahyde</title></head>
+<body>
+<h1>kekahyde {version} is running</h1>
+<p>The web UI wasn't found at <code>{dir}</code>. Build it with <code>pnpm build</code> in
+<code>frontend/</code>, or point <code>frontend_dir</code> in the config at the built
+<code>out</code> directory, then restart the daemon.</p>
+<p>The API is available meanwhile: <a href="/healthz">/healthz</a>,
+<a href="{prefix}/status">{prefix}/status</a>.</p>
+</body>
+</html>
+"#,
+        version = env!("CARGO_PKG_VERSION"),
+        prefix = deprecation::API_PREFIX,
+    ))
+    .into_response()
+}
+
+async fn healthz() -> &'static str {
+    "ok"
+}
+
+async fn stop(State(state): State<Arc<AppState>>) -> StatusCode {
+    state.stop_generation().await;
+    StatusCode::OK
+}
+
+/// Drain, unload the models, re-read the config and bind the listeners again, all
+/// in-process. Refused while the config on disk wouldn't start.
+async fn restart(State(state): State<Arc<AppState>>) -> Response {
+    let checked = Config::load().and_then(|config| {
+        if let Some(filter) = &config.ip_filter {
+            IpFilter::new(filter)?;
+        }
+        if let Some(locale) = &config.locale
+            && i18n::locale_index(locale).is_none()
+        {
+            return Err(format!("Unsupported locale {:?}", locale));
+        }
+        CommandTools::new(
+            config.command_tools,
+            config.tool_sandbox,
+            data_dir().join("tools"),
+        )?;
+        if let Some(selfcheck) = &config.selfcheck {
+            selfcheck::parse_time(&selfcheck.at)?;
+        }
+        Maintenance::new(config.maintenance).map(|_| ())
+    });
+    if let Err(e) = checked {
+        tracing::error!("Not restarting: {}", e);
+        return ApiError::bad_request(e).into_response();
+    }
+    tracing::info!("Restart requested");
+    state.audit.record("restart", serde_json::json!({})).await;
+    state.stop.cancel();
+    (
+        StatusCode::ACCEPTED,
+        Json(serde_json::json!({"status": "restarting"})),
+    )
+        .into_response()
+}
+
+#[derive(Deserialize)]
+struct ExportExecutionsQuery {
+    /// `json` (the default) or `ndjson`
+    format: Option<String>,
+}
+
+/// Every finished execution, oldest first, in the form the history database keeps.
+async fn export_executions(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<ExportExecutionsQuery>,
+) -> Result<Response, ApiError> {
+    let ndjson = match query.format.as_deref() {
+        None | Some("json") => false,
+        Some("ndjson") => true,
+        Some(format) => {
+            return Err(ApiError::bad_request(format!(
+                "Unknown format {:?}, expected json or ndjson",
+                format
+            )));
+        }
+    };
+    let executions = state.execution_manager.lock().await.export();
+    let (content_type, extension, body) = if ndjson {
+        let mut body = String::new();
+        for execution in &executions {
+            body.push_str(&serde_json::to_string(execution).unwrap_or_default());
+            body.push('\n');
+        }
+        ("application/x-ndjson", "ndjson", body)
+    } else {
+        (
+            "application/json",
+            "json",
+            serde_json::to_string_pretty(&executions).unwrap_or_default(),
+        )
+    };
+    Ok((
+        [
+            (header::CONTENT_TYPE, content_type.to_string()),
+            (
+                header::CONTENT_DISPOSITION,
+                format!(
+                    "attachment; filename=\"executions-{}.{}\"",
+                    Utc::now().format("%Y%m%d"),
+                    extension
+                ),
+            ),
+        ],
+        body,
+    )
+        .into_response())
+}
+
+/// Add the executions of an export, as a JSON array or NDJSON, to this daemon's.
+async fn import_executions(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    body: String,
+) -> Result<Json<Value>, ApiError> {
+    let ndjson = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("application/x-ndjson"));
+    let executions: Vec<StoredExecution> = if ndjson {
+        body.lines()
+            .enumerate()
+            .filter(|(_, line)| !line.trim().is_empty())
+            .map(|(i, line)| {
+                serde_json::from_str(line)
+                    .map_err(|e| ApiError::bad_request(format!("Line {}: {}", i + 1, e)))
+            })
+            .collect::<Result<_, _>>()?
+    } else {
+        serde_json::from_str(&body).map_err(|e| ApiError::bad_request(e.to_string()))?
+    };
+    let (imported, skipped) = state.execution_manager.lock().await.import(executions);
+    state
+        .audit
+        .record(
+            "executions_imported",
+            serde_json::json!({ "imported": imported, "skipped": skipped.len() }),
+        )
+        .await;
+    Ok(Json(
+        serde_json::json!({ "imported": imported, "skipped": skipped }),
+    ))
+}
+
+async fn list_executions(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<ListExecutionsQuery>,
+) -> Result<Json<ExecutionPage>, ApiError> {
+    const STATES: [&str; 8] = [
+        "scheduled",
+        "queued",
+        "running",
+        "paused",
+        "pendingapproval",
+        "completed",
+        "cancelled",
+        "failed",
+    ];
+    if let Some(filter) = &query.state
+        && !STATES.iter().any(|s| s.eq_ignore_ascii_case(filter))
+    {
+        return Err(ApiError::bad_request(format!(
+            "Unknown state {:?}, expected one of {}",
+            filter,
+            STATES.join(", ")
+        )));
+    }
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_PAGE_SIZE)
+        .clamp(1, MAX_PAGE_SIZE);
+    Ok(Json(
+        state.execution_manager.lock().await.list(&query, limit),
+    ))
+}
+
+async fn list_schedules(State(state): State<Arc<AppState>>) -> Json<Vec<ExecutionStatus>> {
+    Json(state.execution_manager.lock().await.schedules())
+}
+
+#[derive(Deserialize)]
+struct RecentQuery {
+    limit: Option<usize>,
+}
+
+async fn recent_executions(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<RecentQuery>,
+) -> Json<Vec<ExecutionRecord>> {
+    let limit = query.limit.unwrap_or(DEFAULT_RECENT_EXECUTIONS);
+    Json(state.execution_manager.lock().await.recent(limit))
+}
+
+async fn status(State(state): State<Arc<AppState>>) -> Json<StatusResponse> {
+    tracing::debug!("Status requested");
+    let mut monitor = state.monitor.lock().await;
+    let model_loaded = state.model.lock().await.is_loaded();
+    let current_state = state.state.lock().await.clone();
+    let mut status = monitor.get_status(model_loaded, &current_state);
+    if let Some(cache) = &state.cache {
+        status.cache = Some(cache.lock().await.stats());
+    }
+    status.queued = state.execution_manager.lock().await.queue_len();
+    status.read_only = state.read_only;
+    Json(status)
+}
+
+// Puts the daemon back to idle when a benchmark is dropped mid-run, e.g. by the client
+// hanging up
+struct Benchmarking(Arc<AppState>);
+
+impl Drop for Benchmarking {
+    fn drop(&mut self) {
+        let state = Arc::clone(&self.0);
+        tokio::spawn(async move {
+            let mut current = state.state.lock().await;
+            if *current == "benchmarking" {
+                *current = "idle".to_string();
+            }
+        });
+    }
+}
+
+async fn benchmark(
+    State(state): State<Arc<AppState>>,
+    Json(req): Json<BenchmarkRequest>,
+) -> Result<Response, ApiError> {
+    let started = Instant::now();
+    let limit = Duration::from_secs(state.timeouts.benchmark_secs);
+    let model = state.model.lock().await;
+    let thread_counts = if req.threads.is_empty() {
+        vec![model.threads()]
+    } else {
+        req.threads
+    };
+    if thread_counts.contains(&0) {
+        return Err(ApiError::bad_request("Thread counts must be at least 1"));
+    }
+
+    *state.state.lock().await = "benchmarking".to_string();
+    let _benchmarking = Benchmarking(Arc::clone(&state));
+    let cancel = CancellationToken::new();
+    let _timer = cancel_at(started + limit, cancel.clone());
+    let mut runs = Vec::with_capacity(thread_counts.len());
+    for threads in thread_counts {
+        let control = GenerationControl::with_params(
+            cancel.clone(),
+            LiveParams {
+                max_tokens: req.max_tokens.unwrap_or(BENCHMARK_MAX_TOKENS),
+                ..Default::default()
+            },
+        );
+        let result = model.benchmark(threads, &control).await;
+        let stats = match result {
+            Ok(stats) => stats,
+            Err(_) if cancel.is_cancelled() => {
+                *state.state.lock().await = "idle".to_string();
+                tracing::warn!("Benchmark timed out after {:?}", limit);
+                return Ok(timed_out(limit));
+            }
+            Err(e) => {
+                *state.state.lock().await = "idle".to_string();
+                tracing::error!("Benchmark failed: {}", e);
+                return Err(ApiError::internal(e));
+            }
+        };
+        tracing::info!(
+            "Benchmark with {} threads: {:.1} prompt tok/s, {:.1} gen tok/s",
+            threads,
+            stats.prompt_tokens_per_second(),
+            stats.generation_tokens_per_second()
+        );
+        runs.push(BenchmarkRun {
+            threads,
+            prompt_tokens: stats.prompt_tokens,
+            prompt_tokens_per_second: stats.prompt_tokens_per_second(),
+            completion_tokens: stats.completion_tokens,
+            generation_tokens_per_second: stats.generation_tokens_per_second(),
+        });
+    }
+    *state.state.lock().await = "idle".to_string();
+
+    Ok(Json(BenchmarkResponse {
+        runs,
+        gpu_layers: model.gpu_layers(),
+        model_size_bytes: model.model_size(),
+        process_memory_bytes: state.monitor.lock().await.process_memory(),
+    })
+    .into_response())
+}
+
+async fn rerank(
+    State(state): State<Arc<AppState>>,
+    Json(req): Json<RerankRequest>,
+) -> Result<Response, ApiError> {
+    let limit = Duration::from_secs(state.timeouts.embeddings_secs);
+    // Checked between documents
+    let deadline = CancellationToken::new();
+    let _timer = cancel_at(Instant::now() + limit, deadline.clone());
+    let scores = match &state.reranker {
+        // A cross-encoder scores each (query, document) pair directly
+        Some(reranker) => {
+            let reranker = reranker.lock().await;
+            let mut scores = Vec::with_capacity(req.documents.len());
+            for document in &req.documents {
+                if deadline.is_cancelled() {
+                    return Ok(timed_out(limit));
+                }
+                scores.push(reranker.rank(&req.query, document).await);
+            }
+            scores.into_iter().collect::<Result<Vec<_>, _>>()
+        }
+        // Otherwise fall back to cosine similarity of embeddings
+        None => {
+            let model = state.embedding_model().lock().await;
+            let scores = async {
+                let query = model.embed(&req.query).await?;
+                let mut scores = Vec::with_capacity(req.documents.len());
+                for document in &req.documents {
+                    if deadline.is_cancelled() {
+                        return Ok(None);
+                    }
+                    let embedding = model.embed(document).await?;
+                    scores.push(query.iter().zip(&embedding).map(|(a, b)| a * b).sum());
+                }
+                Ok::<_, String>(Some(scores))
+            }
+            .await;
+            match scores {
+                Ok(Some(scores)) => Ok(scores),
+                Ok(None) => return Ok(timed_out(limit)),
+                Err(e) => Err(e),
+            }
+        }
+    }
+    .map_err(|e| {
+        tracing::error!("Rerank failed: {}", e);
+        ApiError::internal(e)
+    })?;
+
+    let mut results: Vec<RerankResult> = scores
+        .into_iter()
+        .enumerate()
+        .map(|(index, relevance_score)| RerankResult {
+            index,
+            relevance_score,
+            document: req.return_documents.then(|| req.documents[index].clone()),
+        })
+        .collect();
+    results.sort_by(|a, b| b.relevance_score.total_cmp(&a.relevance_score));
+    if let Some(top_n) = req.top_n {
+        results.truncate(top_n);
+    }
+
+    Ok(Json(RerankResponse { results }).into_response())
+}
+
+async fn embeddings(
+    State(state): State<Arc<AppState>>,
+    Json(req): Json<EmbeddingsRequest>,
+) -> Result<Response, ApiError> {
+    let limit = Duration::from_secs(state.timeouts.embeddings_secs);
+    // Checked between inputs
+    let deadline = CancellationToken::new();
+    let _timer = cancel_at(Instant::now() + limit, deadline.clone());
+    let inputs = match req.input {
+        EmbeddingInput::One(text) => vec![text],
+        EmbeddingInput::Many(texts) => texts,
+    };
+    let model = state.embedding_model().lock().await;
+    let mut data = Vec::with_capacity(inputs.len());
+    for (index, text) in inputs.iter().enumerate() {
+        if deadline.is_cancelled() {
+            return Ok(timed_out(limit));
+        }
+        let embedding = model.embed(text).await.map_err(|e| {
+            tracing::error!("Embedding failed: {}", e);
+            ApiError::internal(e)
+        })?;
+        data.push(Embedding {
+            object: "embedding",
+            index,
+            embedding,
+        });
+    }
+
+    Ok(Json(EmbeddingsResponse {
+        object: "list",
+        data,
+        model: model.name().unwrap_or_else(|| "kekahyde".to_string()),
+    })
+    .into_response())
+}
+
+/// The request's Idempotency-Key, if it sent one.
+fn idempotency_key(headers: &HeaderMap) -> Result<Option<String>, ApiError> {
+    let Some(value) = headers.get(IDEMPOTENCY_KEY_HEADER) else {
+        return Ok(None);
+    };
+    match value.to_str() {
+        Ok(key) if !key.is_empty() && key.len() <= MAX_IDEMPOTENCY_KEY_LEN => {
+            Ok(Some(key.to_string()))
+        }
+        _ => Err(ApiError::bad_request(format!(
+            "Idempotency-Key must be 1 to {} visible ASCII characters",
+            MAX_IDEMPOTENCY_KEY_LEN
+        ))),
+    }
+}
+
+fn check_sampling(sampling: &SamplingProfile) -> Result<(), String> {
+    if sampling
+        .temperature
+        .is_some_and(|t| !(0.0..=5.0).contains(&t))
+    {
+        return Err("temperature must be between 0.0 and 5.0".to_string());
+    }
+    if sampling.max_tokens == Some(0) {
+        return Err("max_tokens must be at least 1".to_string());
+    }
+    Ok(())
+}
+
+// Tags are matched in list filters, where commas separate them
+fn check_labels(
+    tags: &[String],
+    metadata: Option<&serde_json::Map<String, Value>>,
+) -> Result<(), String> {
+    if tags.len() > MAX_TAGS {
+        return Err(format!("At most {} tags are allowed", MAX_TAGS));
+    }
+    if let Some(tag) = tags
+        .iter()
+        .find(|t| t.trim().is_empty() || t.len() > MAX_TAG_LEN || t.contains(','))
+    {
+        return Err(format!(
+            "Invalid tag {:?}: tags are 1 to {} characters without commas",
+            tag, MAX_TAG_LEN
+        ));
+    }
+    if metadata.is_some_and(|m| Value::Object(m.clone()).to_string().len() > MAX_METADATA_BYTES) {
+        return Err(format!(
+            "metadata must be at most {} bytes of JSON",
+            MAX_METADATA_BYTES
+        ));
+    }
+    Ok(())
+}
+
+async fn start_execution(
+    State(state): State<Arc<AppState>>,
+    Extension(client): Extension<ClientId>,
+    headers: HeaderMap,
+    Json(req): Json<StartExecutionRequest>,
+) -> Result<Response, ApiError> {
+    // A retry with the key of an earlier start gets that start's execution back
+    let idempotency = match idempotency_key(&headers)? {
+        Some(key) => {
+            let mut hasher = DefaultHasher::new();
+            req.prompt.hash(&mut hasher);
+            Some(((client.0.clone(), key), hasher.finish()))
+        }
+        None => None,
+    };
+    if let Some((key, prompt_hash)) = &idempotency
+        && let Some(replayed) = state
+            .execution_manager
+            .lock()
+            .await
+            .replay_start(key, *prompt_hash)?
+    {
+        return Ok(replayed);
+    }
+    let _enforced_policy = match enforce_policy(req.policy.clone()) {
+        Ok(policy) => policy,
+        Err(e) => {
+            record_policy_violation(&state, &client, e.clone()).await;
+            return Err(ApiError::new(
+                StatusCode::BAD_REQUEST,
+                ErrorCode::PolicyViolation,
+                e,
+            ));
+        }
+    };
+    let images = decode_images(&req.images).map_err(ApiError::bad_request)?;
+    if !(1..=MAX_BEST_OF).contains(&req.best_of) {
+        return Err(ApiError::bad_request(format!(
+            "best_of must be between 1 and {}",
+            MAX_BEST_OF
+        )));
+    }
+    check_sampling(&req.sampling).map_err(ApiError::bad_request)?;
+    let scheduled_for = match (&req.schedule_at, &req.delay) {
+        (Some(_), Some(_)) => {
+            return Err(ApiError::bad_request(
+                "Give either schedule_at or delay, not both",
+            ));
+        }
+        (Some(at), None) => Some(
+            DateTime::parse_from_rfc3339(at)
+                .map_err(|_| ApiError::bad_request(format!("Invalid schedule_at {:?}", at)))?
+                .with_timezone(&Utc),
+        ),
+        (None, Some(delay)) => {
+            let delay = parse_duration(delay)
+                .and_then(|d| chrono::Duration::from_std(d).ok())
+                .ok_or_else(|| ApiError::bad_request(format!("Invalid delay {:?}", delay)))?;
+            Some(Utc::now() + delay)
+        }
+        (None, None) => None,
+    }
+    // A time already passed runs now
+    .filter(|at| *at > Utc::now());
+    if req.timeout_seconds == Some(0) {
+        return Err(ApiError::bad_request("timeout_seconds must be positive"));
+    }
+    if req.max_retries > MAX_RETRIES {
+        return Err(ApiError::bad_request(format!(
+            "max_retries must be at most {}",
+            MAX_RETRIES
+        )));
+    }
+    check_labels(&req.tags, req.metadata.as_ref()).map_err(ApiError::bad_request)?;
+    let backoff = match req.backoff.as_deref() {
+        None => DEFAULT_BACKOFF,
+        Some(backoff) => parse_duration(backoff)
+            .ok_or_else(|| ApiError::bad_request(format!("Invalid backoff {:?}", backoff)))?,
+    };
+    let agent = match &req.agent {
+        Some(_) if req.command_tools.is_empty() => {
+            return Err(ApiError::bad_request(
+                "Agent mode needs at least one command tool",
+            ));
+        }
+        Some(_) if req.best_of > 1 => {
+            return Err(ApiError::bad_request(
+                "Agent mode can't be combined with best_of",
+            ));
+        }
+        Some(options) => {
+            let offered: Vec<&str> = req
+                .tools
+                .iter()
+                .map(|tool| tool.function.name.as_str())
+                .chain(req.command_tools.iter().map(String::as_str))
+                .collect();
+            let guardrails = Guardrails::resolve(options, &state.agent, &offered).map_err(|e| {
+                ApiError::new(StatusCode::BAD_REQUEST, ErrorCode::PolicyViolation, e)
+            })?;
+            Some(guardrails)
+        }
+        None => None,
+    };
+    let mut tools = req.tools;
+    tools.extend(
+        state
+            .command_tools
+            .definitions(&req.command_tools)
+            .map_err(ApiError::bad_request)?,
+    );
+
+    let mut input = PromptInput {
+        user: req.prompt,
+        images,
+        tools,
+        ..Default::default()
+    };
+    let mut params = LiveParams::default();
+    let persona = apply_persona(&state, req.persona.as_deref(), &mut input, &mut params)
+        .await
+        .map_err(ApiError::bad_request)?;
+    req.sampling.apply(&mut params);
+    apply_system_prompt(
+        &state,
+        req.system_prompt,
+        req.session.as_deref(),
+        &mut input,
+    )
+    .await;
+    memories::inject(&state, &mut input).await;
+
+    // Best-of runs pick among fresh samples and command tools see the current state of
+    // the machine, so both bypass the cache
+    let cache_key = state
+        .cache
+        .as_ref()
+        .filter(|_| req.best_of == 1 && req.command_tools.is_empty())
+        .map(|_| ResponseCache::key(&input, &params));
+    // A scheduled run is wanted fresh at its time; its result is still cached
+    let cached = match scheduled_for {
+        Some(_) => None,
+        None => cached_response(&state.cache, cache_key.as_deref()).await,
+    };
+    if cached.is_none()
+        && let Err(limited) = reserve_tokens(&state, &client, params.max_tokens * req.best_of).await
+    {
+        return Ok(limited.into_response());
+    }
+
+    let tools_offered = !input.tools.is_empty();
+    let mut manager = state.execution_manager.lock().await;
+    // A concurrent retry may have started it while this one was being prepared
+    if let Some((key, prompt_hash)) = &idempotency
+        && let Some(replayed) = manager.replay_start(key, *prompt_hash)?
+    {
+        return Ok(replayed);
+    }
+    let id = manager.create_execution(ExecutionSpec {
+        input,
+        policy: _enforced_policy,
+        best_of: req.best_of,
+        params,
+        cache_key,
+        voice: persona.voice,
+        persona: persona.name,
+        tools: tools_offered,
+        command_tools: req.command_tools,
+        agent,
+        plain: req.plain_output.unwrap_or(persona.plain),
+        max_retries: req.max_retries,
+        backoff,
+        timeout: req.timeout_seconds.map(Duration::from_secs),
+        scheduled_for,
+        tags: req.tags,
+        metadata: req.metadata,
+    });
+    if let Some((key, prompt_hash)) = idempotency {
+        manager.remember_start(key, prompt_hash, &id);
+    }
+    if cached.is_some() {
+        manager.update_execution(&id, ExecutionState::Completed, cached, None);
+        return Ok(Json(StartExecutionResponse {
+            id,
+            queue_position: None,
+            scheduled_for: None,
+        })
+        .into_response());
+    }
+    if req.require_approval || state.approve_executions {
+        let answer = manager.await_approval(&id, ApprovalKind::Execution, Vec::new());
+        drop(manager);
+        tokio::spawn(admit_when_approved(Arc::clone(&state), id.clone(), answer));
+        return Ok(Json(StartExecutionResponse {
+            id,
+            queue_position: None,
+            scheduled_for: scheduled_for.map(|at| at.to_rfc3339()),
+        })
+        .into_response());
+    }
+    if manager.defer(&id, Utc::now()) {
+        return Ok(Json(StartExecutionResponse {
+            id,
+            queue_position: None,
+            scheduled_for: scheduled_for.map(|at| at.to_rfc3339()),
+        })
+        .into_response());
+    }
+    match manager.admit(&id) {
+        Ok(true) => {
+            drop(manager); // release lock
+            spawn_execution(Arc::clone(&state), id.clone());
+            Ok(Json(StartExecutionResponse {
+                id,
+                queue_position: None,
+                scheduled_for: None,
+            })
+            .into_response())
+        }
+        Ok(false) => {
+            let queue_position = manager.queue_position(&id);
+            Ok(Json(StartExecutionResponse {
+                id,
+                queue_position,
+                scheduled_for: None,
+            })
+            .into_response())
+        }
+        Err(_) => Err(ApiError::new(
+            StatusCode::SERVICE_UNAVAILABLE,
+            ErrorCode::QueueFull,
+            "Execution queue is full",
+        )),
+    }
+}
+
+/// POST /execution/start with the prompt of a saved template, its placeholders filled
+/// from `variables`. Every other field is taken as is.
+async fn start_from_template(
+    State(state): State<Arc<AppState>>,
+    Extension(client): Extension<ClientId>,
+    headers: HeaderMap,
+    Json(mut body): Json<serde_json::Map<String, Value>>,
+) -> Result<Response, ApiError> {
+    let Some(Value::String(key)) = body.remove("template") else {
+        return Err(ApiError::bad_request(
+            "template must be the id or name of a saved template",
+        ));
+    };
+    let variables = match body.remove("variables") {
+        None => serde_json::Map::new(),
+        Some(Value::Object(variables)) => variables,
+        Some(_) => return Err(ApiError::bad_request("variables must be an object")),
+    };
+    if body.contains_key("prompt") {
+        return Err(ApiError::bad_request("The prompt comes from the template"));
+    }
+    let template = state
+        .templates
+        .lock()
+        .await
+        .find(&key)
+        .cloned()
+        .ok_or_else(|| ApiError::not_found("Template not found"))?;
+    let (prompt, system_prompt) = template.render(&variables).map_err(ApiError::bad_request)?;
+    body.insert("prompt".to_string(), Value::String(prompt));
+    // A system prompt in the request still wins
+    if let Some(system_prompt) = system_prompt
+        && !body.contains_key("system_prompt")
+    {
+        body.insert("system_prompt".to_string(), Value::String(system_prompt));
+    }
+    let req: StartExecutionRequest = serde_json::from_value(Value::Object(body))
+        .map_err(|e| ApiError::bad_request(e.to_string()))?;
+    start_execution(State(state), Extension(client), headers, Json(req)).await
+}
+
+/// Admit execution `id` once its approval is granted; a denial cancels it.
+async fn admit_when_approved(state: Arc<AppState>, id: String, answer: oneshot::Receiver<bool>) {
+    // A dropped sender means the execution was cancelled while it waited
+    let Ok(approved) = answer.await else {
+        return;
+    };
+    if !approved {
+        state.execution_manager.lock().await.update_execution(
+            &id,
+            ExecutionState::Cancelled,
+            None,
+            Some("Denied by the operator".to_string()),
+        );
+        return;
+    }
+    admit_queued(state, id).await;
+}
+
+/// Report the output of attempt `attempt` of `id` as it is generated, until it ends.
+async fn report_progress(state: Arc<AppState>, id: String, attempt: usize) {
+    let mut reported = 0;
+    loop {
+        tokio::time::sleep(PROGRESS_INTERVAL).await;
+        let progress = state
+            .execution_manager
+            .lock()
+            .await
+            .report_progress(&id, attempt, reported);
+        match progress {
+            Some(len) => reported = len,
+            None => return,
+        }
+    }
+}
+
+/// Time out run `attempt` of `id` once it has had `timeout`.
+async fn deadline(state: Arc<AppState>, id: String, attempt: usize, timeout: Duration) {
+    tokio::time::sleep(timeout).await;
+    state.execution_manager.lock().await.time_out(&id, attempt);
+}
+
+/// Queue scheduled executions as their time comes, until the daemon stops.
+pub fn spawn_schedule_runner(state: Arc<AppState>) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(SCHEDULE_POLL);
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {}
+                _ = state.stop.cancelled() => return,
+            }
+            let due = state.execution_manager.lock().await.take_due(Utc::now());
+            for id in due {
+                tracing::info!("Scheduled execution {} is due", id);
+                admit_queued(Arc::clone(&state), id).await;
+            }
+        }
+    });
 }
 
-async fn stop(State(state): State<Arc<AppState>>) -> StatusCode {
-    // For cancellation, need to implement
-    *state.state.lock().await = "idle".to_string();
-    StatusCode::OK
+/// Queue a failed execution again once `delay` has passed.
+async fn retry_after(state: Arc<AppState>, id: String, delay: Duration) {
+    tokio::time::sleep(delay).await;
+    admit_queued(state, id).await;
 }
 
-async fn status(State(state): State<Arc<AppState>>) -> Json<StatusResponse> {
-    tracing::debug!("Status requested");
-    let mut monitor = state.monitor.lock().await;
-    let model_loaded = state.model.lock().await.is_loaded();
-    let current_state = state.state.lock().await.clone();
-    let status = monitor.get_status(model_loaded, &current_state);
-    Json(status)
+/// Put executions interrupted by the last shutdown back in line, as `restore` left them.
+pub fn requeue(state: &Arc<AppState>, ids: Vec<String>) {
+    let state = Arc::clone(state);
+    tokio::spawn(async move {
+        for id in ids {
+            admit_queued(Arc::clone(&state), id).await;
+        }
+    });
 }
 
-async fn start_execution(
-    State(state): State<Arc<AppState>>,
-    Json(req): Json<StartExecutionRequest>,
-) -> Result<Json<StartExecutionResponse>, StatusCode> {
-    let _enforced_policy =
-        enforce_policy(req.policy.clone()).map_err(|_| StatusCode::BAD_REQUEST)?;
-
+/// Admit `id`, which waited outside the queue; it may have been cancelled meanwhile.
+async fn admit_queued(state: Arc<AppState>, id: String) {
     let mut manager = state.execution_manager.lock().await;
-    match manager.start_execution(req.prompt, _enforced_policy) {
-        Ok(id) => {
-            // Spawn the execution task
-            let execution_manager_clone = Arc::clone(&state.execution_manager);
-            let model_clone = Arc::clone(&state.model);
-            let hybrid_clone = Arc::clone(&state.hybrid_executor);
-            let execution = manager.executions.get(&id).unwrap().clone();
-            let id_clone = id.clone();
-            drop(manager); // release lock
+    // Approved ahead of its time
+    if manager.defer(&id, Utc::now()) {
+        return;
+    }
+    let Some(execution) = manager
+        .get_execution(&id)
+        .filter(|e| e.state == ExecutionState::Queued)
+        .cloned()
+    else {
+        return;
+    };
+    match manager.admit(&id) {
+        Ok(true) => {
+            drop(manager);
+            spawn_execution(Arc::clone(&state), id);
+        }
+        Ok(false) => {
+            // Report its place in line
+            if let Some(status) = manager.status(&id) {
+                let _ = manager.status_tx.send(status);
+            }
+        }
+        Err(e) => {
+            // admit forgets executions it can't queue; this one was already reported
+            manager.executions.insert(id.clone(), execution);
+            manager.update_execution(&id, ExecutionState::Failed, None, Some(e));
+        }
+    }
+}
 
-            tokio::spawn(async move {
-                let mut mgr = execution_manager_clone.lock().await;
-                mgr.update_execution(&id_clone, ExecutionState::Running, None, None);
-                drop(mgr);
+/// Run `id` in the background, then pass the model on to the next queued execution.
+fn spawn_execution(state: Arc<AppState>, id: String) {
+    tokio::spawn(async move {
+        run_execution(&state, &id).await;
+        let retry = state.execution_manager.lock().await.take_retry(&id);
+        if let Some(delay) = retry {
+            tokio::spawn(retry_after(Arc::clone(&state), id.clone(), delay));
+        }
+        dispatch_next(&state).await;
+    });
+}
 
-                let enforced_policy = execution._policy.clone();
-                let use_hybrid = {
-                    let hybrid = hybrid_clone.lock().await;
-                    hybrid.should_use_hybrid(enforced_policy.allow_hybrid_compute)
-                        && !hybrid.peers.is_empty()
-                };
+/// Start queued executions until every slot is taken.
+pub(crate) async fn dispatch_next(state: &Arc<AppState>) {
+    loop {
+        let next = state.execution_manager.lock().await.next_queued();
+        let Some(id) = next else {
+            return;
+        };
+        spawn_execution(Arc::clone(state), id);
+    }
+}
+
+async fn run_execution(state: &Arc<AppState>, id: &str) {
+    let Some(execution) = state
+        .execution_manager
+        .lock()
+        .await
+        .get_execution(id)
+        .cloned()
+        // Cancelled while it waited
+        .filter(|e| e.state == ExecutionState::Queued)
+    else {
+        return;
+    };
+
+    let mut mgr = state.execution_manager.lock().await;
+    mgr.update_execution(id, ExecutionState::Running, None, None);
+    drop(mgr);
+    tokio::spawn(report_progress(
+        Arc::clone(state),
+        id.to_string(),
+        execution.attempts.len(),
+    ));
+    if let Some(timeout) = execution.timeout {
+        tokio::spawn(deadline(
+            Arc::clone(state),
+            id.to_string(),
+            execution.attempts.len(),
+            timeout,
+        ));
+    }
+
+    if execution.best_of > 1 {
+        // Fan-out runs locally so every candidate shares one model
+        let model = state.model.lock().await;
+        let cancel_token = execution.cancel_token.as_ref().unwrap().clone();
+        let result = tokio::select! {
+            res = ExecutionManager::run_best_of(
+                &model,
+                &execution.input,
+                execution.best_of,
+                &execution.control,
+            ) => res,
+            _ = cancel_token.cancelled() => {
+                let mut mgr = state.execution_manager.lock().await;
+                mgr.update_execution(id, ExecutionState::Cancelled, None, None);
+                return;
+            }
+        };
+
+        match result {
+            Ok((winner, candidates)) => {
+                state
+                    .usage
+                    .record(UsageRecord::new(
+                        "execution",
+                        execution.persona.clone(),
+                        None,
+                    ))
+                    .await;
+                memories::extract_in_background(
+                    Arc::clone(state),
+                    execution.input.user.clone(),
+                    winner.clone(),
+                );
+                state
+                    .execution_manager
+                    .lock()
+                    .await
+                    .set_candidates(id, candidates);
+                state
+                    .execution_manager
+                    .lock()
+                    .await
+                    .set_node(id, LOCAL_NODE.to_string());
+                complete_execution(state, &execution, winner).await;
+            }
+            Err(e) => state.execution_manager.lock().await.update_execution(
+                id,
+                ExecutionState::Failed,
+                None,
+                Some(e),
+            ),
+        }
+        return;
+    }
+
+    if let Some(guardrails) = &execution.agent {
+        run_agent_execution(state, &execution, guardrails).await;
+        return;
+    }
+
+    let enforced_policy = execution._policy.clone();
+    let use_hybrid = {
+        let hybrid = state.hybrid_executor.lock().await;
+        hybrid.should_use_hybrid(enforced_policy.allow_hybrid_compute)
+            && HybridExecutor::local_only(
+                !execution.input.images.is_empty(),
+                execution.input.system.is_some(),
+                !execution.input.tools.is_empty(),
+                !execution.input.memories.is_empty(),
+                execution.params != LiveParams::default(),
+            )
+            .is_none()
+    };
+
+    if use_hybrid {
+        // Distributed execution: offload to the selected peer, then the next healthy ones
+        let (peers, sessions, timeout) = {
+            let hybrid = state.hybrid_executor.lock().await;
+            (
+                hybrid.failover_order(),
+                Arc::clone(&hybrid.sessions),
+                hybrid.peer_timeout,
+            )
+        };
 
-                if use_hybrid {
-                    // Distributed execution: offload to peer
-                    let peer = {
-                        let hybrid = hybrid_clone.lock().await;
-                        hybrid.peers.first().cloned().unwrap() // Safe since we checked !is_empty
-                    };
-
-                    let result = {
-                        let hybrid = hybrid_clone.lock().await;
-                        hybrid
-                            .run_distributed_inference(
-                                &*model_clone.lock().await,
-                                &execution.prompt,
-                                &peer,
-                            )
-                            .await
-                    };
-
-                    let result = match result {
-                        Ok(output) => Ok(output),
-                        Err(e) => {
-                            tracing::warn!(
-                                "Distributed execution failed, falling back to local: {}",
-                                e
-                            );
-                            // Fallback to local on failure
-                            let model = model_clone.lock().await;
-                            let cancel_token = execution.cancel_token.as_ref().unwrap().clone();
-                            tokio::select! {
-                                res = model.run_prompt(&execution.prompt) => res,
-                                _ = cancel_token.cancelled() => {
-                                    let mut mgr = execution_manager_clone.lock().await;
-                                    mgr.update_execution(&id_clone, ExecutionState::Cancelled, None, None);
-                                    return;
-                                }
-                            }
-                        }
-                    };
-
-                    let mut mgr = execution_manager_clone.lock().await;
-                    match result {
-                        Ok(r) => mgr.update_execution(
-                            &id_clone,
-                            ExecutionState::Completed,
-                            Some(r),
-                            None,
-                        ),
-                        Err(e) => {
-                            mgr.update_execution(&id_clone, ExecutionState::Failed, None, Some(e))
-                        }
+        let cancel_token = execution.cancel_token.as_ref().unwrap().clone();
+        let mut offloaded = None;
+        for peer in &peers {
+            // Shown while it runs there, and keeps PATCH from changing sampling the peer
+            // won't see
+            state
+                .execution_manager
+                .lock()
+                .await
+                .set_node(id, peer.address.clone());
+            // Sent over the peer's open session; neither the model nor the executor is held.
+            // A cancelled or timed-out execution stops waiting on the peer
+            let offload = tokio::select! {
+                res = sessions.offload(&execution.input.user, peer, timeout) => res,
+                _ = cancel_token.cancelled() => {
+                    let mut mgr = state.execution_manager.lock().await;
+                    mgr.update_execution(id, ExecutionState::Cancelled, None, None);
+                    return;
+                }
+            };
+            match offload {
+                Ok(result) => {
+                    offloaded = Some((peer.address.clone(), result.output));
+                    break;
+                }
+                Err(e) => {
+                    tracing::warn!("Offload to {} failed: {}", peer.address, e);
+                    // An unreachable, silent or failing peer is an outage; anything else is
+                    // suspect
+                    if !["Connect failed", "Timed out", "Generation failed"]
+                        .iter()
+                        .any(|outage| e.starts_with(outage))
+                    {
+                        let kind = if e.starts_with("Handshake failed")
+                            || e.starts_with("Result signature")
+                        {
+                            SecurityEventKind::AuthFailure
+                        } else {
+                            SecurityEventKind::PeerProtocolError
+                        };
+                        state.security.lock().await.record(
+                            kind,
+                            Some(format!("peer:{}", peer.address)),
+                            e,
+                        );
                     }
-                } else {
-                    // Local execution
-                    let model = model_clone.lock().await;
-                    let cancel_token = execution.cancel_token.as_ref().unwrap().clone();
-
-                    let result = tokio::select! {
-                        res = model.run_prompt(&execution.prompt) => res,
-                        _ = cancel_token.cancelled() => {
-                            let mut mgr = execution_manager_clone.lock().await;
-                            mgr.update_execution(&id_clone, ExecutionState::Cancelled, None, None);
-                            return;
-                        }
-                    };
-
-                    let mut mgr = execution_manager_clone.lock().await;
-                    match result {
-                        Ok(r) => mgr.update_execution(
-                            &id_clone,
-                            ExecutionState::Completed,
-                            Some(r),
-                            None,
-                        ),
-                        Err(e) => {
-                            mgr.update_execution(&id_clone, ExecutionState::Failed, None, Some(e))
-                        }
+                }
+            }
+        }
+
+        let result = match offloaded {
+            Some((node, output)) => {
+                state
+                    .usage
+                    .record(UsageRecord {
+                        offloaded: true,
+                        ..UsageRecord::new("execution", execution.persona.clone(), None)
+                    })
+                    .await;
+                state.execution_manager.lock().await.set_node(id, node);
+                Ok(output)
+            }
+            None => {
+                tracing::warn!(
+                    "Distributed execution failed on {} peers, falling back to local",
+                    peers.len()
+                );
+                // Fallback to local on failure
+                state
+                    .execution_manager
+                    .lock()
+                    .await
+                    .set_node(id, LOCAL_NODE.to_string());
+                let model = state.model.lock().await;
+                let result = tokio::select! {
+                    res = model.run_prompt_with_stats(&execution.input, &execution.control) => {
+                        record_execution_usage(state, &execution, res).await
                     }
+                    _ = cancel_token.cancelled() => {
+                        let mut mgr = state.execution_manager.lock().await;
+                        mgr.update_execution(id, ExecutionState::Cancelled, None, None);
+                        return;
+                    }
+                };
+                if result.is_ok() {
+                    state
+                        .execution_manager
+                        .lock()
+                        .await
+                        .set_node(id, LOCAL_NODE.to_string());
                 }
-            });
+                result
+            }
+        };
+
+        // Patched parameters no longer match the key this run was cached under
+        if let Ok(r) = &result
+            && execution.control.params() == execution.params
+        {
+            store_response(&state.cache, execution.cache_key.clone(), r).await;
+        }
+        if let Ok(r) = &result {
+            memories::extract_in_background(
+                Arc::clone(state),
+                execution.input.user.clone(),
+                r.clone(),
+            );
+        }
+
+        match result {
+            Ok(r) => complete_execution(state, &execution, r).await,
+            Err(e) => state.execution_manager.lock().await.update_execution(
+                id,
+                ExecutionState::Failed,
+                None,
+                Some(e),
+            ),
+        }
+    } else {
+        // Local execution
+        let model = state.model.lock().await;
+        let cancel_token = execution.cancel_token.as_ref().unwrap().clone();
+
+        let result = tokio::select! {
+            res = model.run_prompt_with_stats(&execution.input, &execution.control) => {
+                record_execution_usage(state, &execution, res).await
+            }
+            _ = cancel_token.cancelled() => {
+                let mut mgr = state.execution_manager.lock().await;
+                mgr.update_execution(id, ExecutionState::Cancelled, None, None);
+                return;
+            }
+        };
+
+        // Patched parameters no longer match the key this run was cached under
+        if let Ok(r) = &result
+            && execution.control.params() == execution.params
+        {
+            store_response(&state.cache, execution.cache_key.clone(), r).await;
+        }
+        if let Ok(r) = &result {
+            state
+                .execution_manager
+                .lock()
+                .await
+                .set_node(id, LOCAL_NODE.to_string());
+            memories::extract_in_background(
+                Arc::clone(state),
+                execution.input.user.clone(),
+                r.clone(),
+            );
+        }
+
+        match result {
+            Ok(r) => complete_execution(state, &execution, r).await,
+            Err(e) => state.execution_manager.lock().await.update_execution(
+                id,
+                ExecutionState::Failed,
+                None,
+                Some(e),
+            ),
+        }
+    }
+}
 
-            Ok(Json(StartExecutionResponse { id }))
+/// Run `execution` in agent mode on the local model.
+async fn run_agent_execution(
+    state: &Arc<AppState>,
+    execution: &Execution,
+    guardrails: &Guardrails,
+) {
+    let id = &execution.id;
+    let cancel_token = execution.cancel_token.as_ref().unwrap().clone();
+    let result = tokio::select! {
+        res = agent::run(
+            state,
+            id,
+            &execution.input,
+            &execution.control,
+            &execution.command_tools,
+            guardrails,
+        ) => res,
+        _ = cancel_token.cancelled() => {
+            let mut mgr = state.execution_manager.lock().await;
+            mgr.update_execution(id, ExecutionState::Cancelled, None, None);
+            return;
         }
-        Err(_) => Err(StatusCode::CONFLICT), // Another execution running
+    };
+
+    match result {
+        Ok((last, stats)) => {
+            state
+                .usage
+                .record(UsageRecord::new(
+                    "execution",
+                    execution.persona.clone(),
+                    Some(&stats),
+                ))
+                .await;
+            memories::extract_in_background(
+                Arc::clone(state),
+                execution.input.user.clone(),
+                last.content.clone(),
+            );
+            let mut mgr = state.execution_manager.lock().await;
+            mgr.set_tool_output(id, last.tool_calls, last.tool_results);
+            mgr.set_node(id, LOCAL_NODE.to_string());
+            mgr.update_execution(id, ExecutionState::Completed, Some(last.content), None);
+        }
+        Err(e) => state.execution_manager.lock().await.update_execution(
+            id,
+            ExecutionState::Failed,
+            None,
+            Some(e),
+        ),
+    }
+}
+
+/// Mark `execution` Completed with `output`. Command tools the model called run first,
+/// so the Completed status already carries their results.
+async fn complete_execution(state: &AppState, execution: &Execution, output: String) {
+    if !execution.command_tools.is_empty() {
+        let (content, calls) = tools::parse_tool_calls(&output);
+        let results = state
+            .command_tools
+            .run_all(&calls, &execution.command_tools, &state.audit)
+            .await;
+        let mut mgr = state.execution_manager.lock().await;
+        mgr.set_tool_output(&execution.id, calls, results);
+        mgr.update_execution(
+            &execution.id,
+            ExecutionState::Completed,
+            Some(content),
+            None,
+        );
+        return;
     }
+    state.execution_manager.lock().await.update_execution(
+        &execution.id,
+        ExecutionState::Completed,
+        Some(output),
+        None,
+    );
+}
+
+/// Log a locally generated execution result for usage reports.
+async fn record_execution_usage(
+    state: &AppState,
+    execution: &Execution,
+    result: Result<(String, GenerationStats), String>,
+) -> Result<String, String> {
+    let (output, stats) = result?;
+    state
+        .usage
+        .record(UsageRecord::new(
+            "execution",
+            execution.persona.clone(),
+            Some(&stats),
+        ))
+        .await;
+    Ok(output)
 }
 
 async fn cancel_execution(
     State(state): State<Arc<AppState>>,
     Path(id): Path<String>,
-) -> StatusCode {
+) -> Result<StatusCode, ApiError> {
     let mut manager = state.execution_manager.lock().await;
     match manager.cancel_execution(&id) {
-        Ok(_) => StatusCode::OK,
-        Err(_) => StatusCode::NOT_FOUND,
+        Ok(_) => Ok(StatusCode::OK),
+        Err(e) if manager.get_execution(&id).is_some() => Err(ApiError::conflict(e)),
+        Err(e) => Err(ApiError::not_found(e)),
     }
 }
 
-async fn execution_status(
+#[derive(Deserialize)]
+struct CancelAllQuery {
+    /// Also cancel the running execution and any paused or awaiting a tool approval
+    #[serde(default)]
+    running: bool,
+}
+
+#[derive(Serialize)]
+struct CancelAllResponse {
+    cancelled: Vec<String>,
+}
+
+async fn cancel_all_executions(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<CancelAllQuery>,
+) -> Json<CancelAllResponse> {
+    let cancelled = state
+        .execution_manager
+        .lock()
+        .await
+        .cancel_all(query.running);
+    tracing::info!("Cancelled {} executions", cancelled.len());
+    state
+        .audit
+        .record(
+            "executions_cancelled",
+            serde_json::json!({ "count": cancelled.len(), "running": query.running }),
+        )
+        .await;
+    Json(CancelAllResponse { cancelled })
+}
+
+async fn delete_execution(
     State(state): State<Arc<AppState>>,
     Path(id): Path<String>,
-) -> Json<Value> {
-    let manager = state.execution_manager.lock().await;
-    if let Some(execution) = manager.get_execution(&id) {
-        Json(
-            serde_json::to_value(ExecutionStatus {
-                id: execution.id.clone(),
-                state: format!("{:?}", execution.state),
-                result: execution.result.clone(),
-                error: execution.error.clone(),
-                start_time: execution.start_time.to_rfc3339(),
-            })
-            .unwrap(),
+) -> Result<StatusCode, ApiError> {
+    {
+        let mut manager = state.execution_manager.lock().await;
+        match manager.delete_execution(&id) {
+            Ok(()) => {}
+            Err(e) if manager.get_execution(&id).is_some() => return Err(ApiError::conflict(e)),
+            Err(_) => return Err(ApiError::not_found("Execution not found")),
+        }
+    }
+    if let Some(tts) = &state.tts {
+        tts.remove_audio(&id).await;
+    }
+    Ok(StatusCode::OK)
+}
+
+async fn pause_execution(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+) -> Result<StatusCode, ApiError> {
+    let mut manager = state.execution_manager.lock().await;
+    match manager.pause_execution(&id) {
+        Ok(_) => Ok(StatusCode::OK),
+        Err(e) if manager.get_execution(&id).is_some() => Err(ApiError::conflict(e)),
+        Err(_) => Err(ApiError::not_found("Execution not found")),
+    }
+}
+
+async fn resume_execution(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+) -> Result<StatusCode, ApiError> {
+    let mut manager = state.execution_manager.lock().await;
+    match manager.resume_execution(&id) {
+        Ok(_) => Ok(StatusCode::OK),
+        Err(e) if manager.get_execution(&id).is_some() => Err(ApiError::conflict(e)),
+        Err(_) => Err(ApiError::not_found("Execution not found")),
+    }
+}
+
+async fn execution_audio(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+) -> Result<Response, ApiError> {
+    let Some(tts) = state.tts.clone() else {
+        return Err(ApiError::unavailable("Text-to-speech is not configured"));
+    };
+
+    let (text, voice) = {
+        let manager = state.execution_manager.lock().await;
+        let execution = manager
+            .get_execution(&id)
+            .ok_or_else(|| ApiError::not_found("Execution not found"))?;
+        match (&execution.state, &execution.result) {
+            (ExecutionState::Completed, Some(result)) => (result.clone(), execution.voice.clone()),
+            _ => return Err(ApiError::conflict("Execution has not completed")),
+        }
+    };
+
+    let path = tts
+        .synthesize(&id, &text, voice.as_deref())
+        .await
+        .map_err(|e| {
+            tracing::error!("Text-to-speech failed for {}: {}", id, e);
+            ApiError::internal(e)
+        })?;
+    let audio = tokio::fs::read(&path).await.map_err(|e| {
+        tracing::error!("Failed to read {}: {}", path.display(), e);
+        ApiError::internal(e.to_string())
+    })?;
+    Ok(([(header::CONTENT_TYPE, "audio/wav")], audio).into_response())
+}
+
+#[derive(Deserialize)]
+struct ApprovalRequest {
+    approved: bool,
+}
+
+/// Answer an execution's open approval by execution id; same as /approvals/:id.
+async fn approve_execution(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+    Json(req): Json<ApprovalRequest>,
+) -> Result<StatusCode, ApiError> {
+    let approval = {
+        let manager = state.execution_manager.lock().await;
+        match manager.approvals().for_execution(&id) {
+            Some(approval) => approval.id.clone(),
+            None if manager.get_execution(&id).is_some() => {
+                return Err(ApiError::conflict("Execution is not waiting for approval"));
+            }
+            None => return Err(ApiError::not_found("Execution not found")),
+        }
+    };
+    approvals::answer(&state, &approval, req.approved).await
+}
+
+#[derive(Deserialize)]
+struct TranscriptQuery {
+    /// `markdown` (the default) or `json`
+    format: Option<String>,
+}
+
+/// The prompt an execution gives the model, with the token cost of each part.
+async fn execution_context(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+) -> Result<Json<PromptContext>, ApiError> {
+    let input = state
+        .execution_manager
+        .lock()
+        .await
+        .get_execution(&id)
+        .map(|e| e.input.clone())
+        .ok_or_else(|| ApiError::not_found("Execution not found"))?;
+    // Tokenizing needs the chat model, which a running generation holds
+    let model = tokio::time::timeout(CONTEXT_MODEL_WAIT, state.model.lock())
+        .await
+        .map_err(|_| ApiError::unavailable("The model is busy generating; try again shortly"))?;
+    model
+        .inspect_prompt(&input)
+        .map(Json)
+        .map_err(ApiError::internal)
+}
+
+async fn execution_transcript(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+    Query(query): Query<TranscriptQuery>,
+) -> Result<Response, ApiError> {
+    let markdown = match query.format.as_deref() {
+        None | Some("markdown") => true,
+        Some("json") => false,
+        Some(format) => {
+            return Err(ApiError::bad_request(format!(
+                "Unknown format {:?}, expected markdown or json",
+                format
+            )));
+        }
+    };
+    let transcript = {
+        let manager = state.execution_manager.lock().await;
+        let execution = manager
+            .get_execution(&id)
+            .ok_or_else(|| ApiError::not_found("Execution not found"))?;
+        let status = execution.status();
+        Transcript {
+            id: status.id,
+            state: status.state,
+            start_time: status.start_time,
+            persona: execution.persona.clone(),
+            system_prompt: execution.input.system.clone(),
+            prompt: execution.input.user.clone(),
+            steps: execution.steps.clone(),
+            output: status.result,
+            partial_output: execution.partial_result(),
+            tool_calls: status.tool_calls,
+            tool_results: status.tool_results,
+            candidates: status.candidates,
+            error: status.error,
+        }
+    };
+    let (content_type, extension, body) = if markdown {
+        (
+            "text/markdown; charset=utf-8",
+            "md",
+            transcript.to_markdown(),
         )
     } else {
-        Json(serde_json::json!({"error": "Execution not found"}))
+        (
+            "application/json",
+            "json",
+            serde_json::to_string_pretty(&transcript).unwrap_or_default(),
+        )
+    };
+    Ok((
+        [
+            (header::CONTENT_TYPE, content_type.to_string()),
+            (
+                header::CONTENT_DISPOSITION,
+                format!("attachment; filename=\"execution-{}.{}\"", id, extension),
+            ),
+        ],
+        body,
+    )
+        .into_response())
+}
+
+async fn patch_execution(
+    State(state): State<Arc<AppState>>,
+    Extension(client): Extension<ClientId>,
+    Path(id): Path<String>,
+    Json(req): Json<PatchExecutionRequest>,
+) -> Result<Response, ApiError> {
+    if req.temperature.is_some_and(|t| !(0.0..=5.0).contains(&t)) {
+        return Err(ApiError::bad_request(
+            "temperature must be between 0.0 and 5.0",
+        ));
+    }
+    if req.max_tokens == Some(0) {
+        return Err(ApiError::bad_request("max_tokens must be at least 1"));
+    }
+    let mut manager = state.execution_manager.lock().await;
+    // Raising max_tokens is charged like the budget reserved at admission
+    let increase = manager
+        .get_execution(&id)
+        .filter(|e| {
+            matches!(e.state, ExecutionState::Running | ExecutionState::Paused)
+                && e.node.as_deref().is_none_or(|node| node == LOCAL_NODE)
+        })
+        .zip(req.max_tokens)
+        .map(|(e, max_tokens)| max_tokens.saturating_sub(e.control.params().max_tokens) * e.best_of)
+        .unwrap_or(0);
+    if increase > 0
+        && let Err(limited) = reserve_tokens(&state, &client, increase).await
+    {
+        return Ok(limited.into_response());
+    }
+    match manager.patch_execution(&id, &req) {
+        Ok(params) => Ok(Json(serde_json::json!({
+            "temperature": params.temperature,
+            "max_tokens": params.max_tokens,
+        }))
+        .into_response()),
+        Err(e) if manager.get_execution(&id).is_some() => Err(ApiError::conflict(e)),
+        Err(_) => Err(ApiError::not_found("Execution not found")),
+    }
+}
+
+#[derive(Deserialize)]
+struct StatusQuery {
+    /// Block until the execution leaves its current state
+    #[serde(default)]
+    wait: bool,
+    timeout: Option<String>,
+}
+
+/// `30s`, `500ms`, `2m` or bare seconds.
+fn parse_duration(value: &str) -> Option<Duration> {
+    let value = value.trim();
+    let digits = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    let amount: u64 = value[..digits].parse().ok()?;
+    match &value[digits..] {
+        "" | "s" => Some(Duration::from_secs(amount)),
+        "ms" => Some(Duration::from_millis(amount)),
+        "m" => Some(Duration::from_secs(amount.checked_mul(60)?)),
+        _ => None,
+    }
+}
+
+async fn execution_status(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+    Query(query): Query<StatusQuery>,
+) -> Response {
+    let wait = match query.timeout.as_deref().map(parse_duration) {
+        None => DEFAULT_STATUS_WAIT,
+        Some(Some(wait)) => wait.min(MAX_STATUS_WAIT),
+        Some(None) => {
+            return ApiError::bad_request(format!(
+                "Invalid timeout {:?}, expected e.g. 30s or 500ms",
+                query.timeout.unwrap_or_default()
+            ))
+            .into_response();
+        }
+    };
+    // Subscribe before reading the status so a change in between isn't missed
+    let (status, mut rx) = {
+        let manager = state.execution_manager.lock().await;
+        (manager.status(&id), manager.subscribe())
+    };
+    let Some(status) = status else {
+        return ApiError::not_found("Execution not found").into_response();
+    };
+    if !query.wait || matches!(status.state.as_str(), "Completed" | "Cancelled" | "Failed") {
+        return Json(status).into_response();
     }
+
+    let changed = tokio::time::timeout(wait, async {
+        loop {
+            match rx.recv().await {
+                Ok(update) if update.id == id && update.state != status.state => {
+                    return Some(update);
+                }
+                Ok(_) => {}
+                // Updates were dropped; the current status tells whether one was ours
+                Err(broadcast::error::RecvError::Lagged(_)) => {
+                    let current = state.execution_manager.lock().await.status(&id)?;
+                    if current.state != status.state {
+                        return Some(current);
+                    }
+                }
+                Err(broadcast::error::RecvError::Closed) => return None,
+            }
+        }
+    })
+    .await;
+    // On timeout the unchanged status is returned
+    Json(changed.ok().flatten().unwrap_or(status)).into_response()
 }
 
 async fn execution_ws(
@@ -413,19 +3883,10 @@ async fn handle_socket(mut socket: axum::extract::ws::WebSocket, state: Arc<AppS
 
     // Send initial status
     let manager = state.execution_manager.lock().await;
-    if let Some(execution) = manager.get_execution(&id) {
-        let status = ExecutionStatus {
-            id: execution.id.clone(),
-            state: format!("{:?}", execution.state),
-            result: execution.result.clone(),
-            error: execution.error.clone(),
-            start_time: execution.start_time.to_rfc3339(),
-        };
-        if let Ok(msg) = serde_json::to_string(&status) {
-            let _ = sender
-                .send(axum::extract::ws::Message::Text(msg.into()))
-                .await;
-        }
+    if let Some(status) = manager.status(&id)
+        && let Ok(msg) = serde_json::to_string(&status)
+    {
+        let _ = sender.send(axum::extract::ws::Message::Text(msg)).await;
     }
     drop(manager);
 
@@ -455,3 +3916,458 @@ async fn handle_socket(mut socket: axum::extract::ws::WebSocket, state: Arc<AppS
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn manager(slots: usize, queue_depth: usize) -> ExecutionManager {
+        ExecutionManager::new(None, queue_depth, slots, RetentionConfig::default())
+    }
+
+    fn spec() -> ExecutionSpec {
+        ExecutionSpec {
+            input: PromptInput::text("Hello"),
+            policy: Policy {
+                allow_networking: false,
+                allow_hybrid_compute: false,
+                allow_telemetry: false,
+            },
+            best_of: 1,
+            params: LiveParams::default(),
+            cache_key: None,
+            voice: None,
+            persona: None,
+            tools: false,
+            command_tools: Vec::new(),
+            agent: None,
+            plain: false,
+            max_retries: 0,
+            backoff: DEFAULT_BACKOFF,
+            timeout: None,
+            scheduled_for: None,
+            tags: Vec::new(),
+            metadata: None,
+        }
+    }
+
+    fn state(manager: &ExecutionManager, id: &str) -> ExecutionState {
+        manager.get_execution(id).unwrap().state.clone()
+    }
+
+    fn finish(manager: &mut ExecutionManager, id: &str) {
+        manager.update_execution(id, ExecutionState::Running, None, None);
+        manager.update_execution(id, ExecutionState::Completed, Some("done".into()), None);
+    }
+
+    #[test]
+    fn queued_executions_run_in_arrival_order() {
+        let mut manager = manager(1, 4);
+        let ids: Vec<String> = (0..3).map(|_| manager.create_execution(spec())).collect();
+        assert_eq!(manager.admit(&ids[0]), Ok(true));
+        assert_eq!(manager.admit(&ids[1]), Ok(false));
+        assert_eq!(manager.admit(&ids[2]), Ok(false));
+        assert_eq!(manager.next_queued(), None);
+
+        finish(&mut manager, &ids[0]);
+        // A newcomer waits behind the queue even though a slot is free
+        let late = manager.create_execution(spec());
+        assert_eq!(manager.admit(&late), Ok(false));
+        assert_eq!(manager.queue_position(&late), Some(3));
+
+        for id in [&ids[1], &ids[2], &late] {
+            assert_eq!(manager.next_queued().as_ref(), Some(id));
+            assert_eq!(manager.next_queued(), None);
+            finish(&mut manager, id);
+        }
+        assert_eq!(manager.next_queued(), None);
+    }
+
+    #[test]
+    fn full_queue_drops_the_execution() {
+        let mut manager = manager(1, 1);
+        let (a, b, c) = (
+            manager.create_execution(spec()),
+            manager.create_execution(spec()),
+            manager.create_execution(spec()),
+        );
+        assert_eq!(manager.admit(&a), Ok(true));
+        assert_eq!(manager.admit(&b), Ok(false));
+        assert!(manager.admit(&c).is_err());
+        assert!(manager.get_execution(&c).is_none());
+    }
+
+    #[test]
+    fn next_queued_skips_cancelled_executions() {
+        let mut manager = manager(1, 4);
+        let (a, b, c) = (
+            manager.create_execution(spec()),
+            manager.create_execution(spec()),
+            manager.create_execution(spec()),
+        );
+        manager.admit(&a).unwrap();
+        manager.admit(&b).unwrap();
+        manager.admit(&c).unwrap();
+        manager.cancel_execution(&b).unwrap();
+        finish(&mut manager, &a);
+        assert_eq!(manager.next_queued(), Some(c));
+    }
+
+    #[test]
+    fn failed_run_with_retries_left_is_queued_again() {
+        let mut manager = manager(1, 4);
+        let id = manager.create_execution(ExecutionSpec {
+            max_retries: 1,
+            backoff: Duration::from_secs(2),
+            ..spec()
+        });
+        assert_eq!(manager.admit(&id), Ok(true));
+        manager.update_execution(&id, ExecutionState::Running, None, None);
+        manager.update_execution(&id, ExecutionState::Failed, None, Some("boom".into()));
+        assert_eq!(state(&manager, &id), ExecutionState::Queued);
+        assert_eq!(manager.take_retry(&id), Some(Duration::from_secs(2)));
+        assert_eq!(manager.take_retry(&id), None);
+        // The failed attempt gave its slot back
+        assert_eq!(manager.admit(&id), Ok(true));
+
+        manager.update_execution(&id, ExecutionState::Running, None, None);
+        manager.update_execution(&id, ExecutionState::Failed, None, Some("boom".into()));
+        assert_eq!(state(&manager, &id), ExecutionState::Failed);
+        assert_eq!(manager.get_execution(&id).unwrap().attempts.len(), 1);
+    }
+
+    #[test]
+    fn scheduled_executions_wait_for_their_time() {
+        let mut manager = manager(1, 4);
+        let start = Utc::now();
+        let at = |secs| Some(start + chrono::Duration::seconds(secs));
+        let later = manager.create_execution(ExecutionSpec {
+            scheduled_for: at(60),
+            ..spec()
+        });
+        let sooner = manager.create_execution(ExecutionSpec {
+            scheduled_for: at(30),
+            ..spec()
+        });
+        let due = manager.create_execution(ExecutionSpec {
+            scheduled_for: at(0),
+            ..spec()
+        });
+        assert!(manager.defer(&later, start));
+        assert!(manager.defer(&sooner, start));
+        assert!(!manager.defer(&due, start));
+        assert_eq!(state(&manager, &later), ExecutionState::Scheduled);
+        assert!(manager.take_due(at(29).unwrap()).is_empty());
+
+        assert_eq!(
+            manager.take_due(at(90).unwrap()),
+            vec![sooner.clone(), later.clone()]
+        );
+        assert_eq!(state(&manager, &later), ExecutionState::Queued);
+        assert_eq!(manager.admit(&sooner), Ok(true));
+        assert_eq!(manager.admit(&later), Ok(false));
+    }
+
+    #[test]
+    fn restore_resolves_executions_paused_by_the_last_shutdown() {
+        let path = std::env::temp_dir().join(format!("kekahyde-test-{}.sqlite3", Uuid::new_v4()));
+        let mut earlier = manager(1, 4);
+        let id = earlier.create_execution(spec());
+        let mut paused = earlier.get_execution(&id).unwrap().stored();
+        paused.state = "Paused".to_string();
+
+        for (interrupted, expected) in [
+            (InterruptedExecutions::Requeue, ExecutionState::Queued),
+            (InterruptedExecutions::Fail, ExecutionState::Failed),
+        ] {
+            let (history, _) = ExecutionHistory::open(&path).unwrap();
+            let mut manager = manager(1, 4);
+            let requeued = manager.restore(history, vec![paused.clone()], interrupted);
+            assert_eq!(state(&manager, &id), expected);
+            assert_eq!(
+                requeued.len(),
+                usize::from(expected == ExecutionState::Queued)
+            );
+            if expected == ExecutionState::Failed {
+                let error = manager.get_execution(&id).unwrap().error.as_deref();
+                assert_eq!(error, Some("interrupted"));
+            }
+        }
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn slots_run_executions_side_by_side() {
+        let mut manager = manager(2, 4);
+        let ids: Vec<String> = (0..3).map(|_| manager.create_execution(spec())).collect();
+        assert_eq!(manager.admit(&ids[0]), Ok(true));
+        assert_eq!(manager.admit(&ids[1]), Ok(true));
+        assert_eq!(manager.admit(&ids[2]), Ok(false));
+
+        manager.set_paused(true);
+        finish(&mut manager, &ids[0]);
+        assert_eq!(manager.next_queued(), None);
+        manager.set_paused(false);
+        assert_eq!(manager.next_queued(), Some(ids[2].clone()));
+        assert_eq!(manager.next_queued(), None);
+    }
+
+    /// One scripted arrival: when it comes in, how long each run takes, how many of its
+    /// runs fail, and how far ahead it is scheduled.
+    #[derive(Clone, Copy, Default)]
+    struct Arrival {
+        at: i64,
+        run_secs: i64,
+        failures: u32,
+        scheduled_in: Option<i64>,
+    }
+
+    /// Drives an ExecutionManager on a virtual clock, one second per step, the way
+    /// admit_queued, spawn_execution, retry_after and the schedule runner do.
+    struct Simulation {
+        manager: ExecutionManager,
+        slots: usize,
+        clock: DateTime<Utc>,
+        // Each run in progress and when it ends
+        running: Vec<(String, DateTime<Utc>)>,
+        failures: HashMap<String, u32>,
+        run_secs: HashMap<String, i64>,
+        retries: Vec<(DateTime<Utc>, String)>,
+        arrived: Vec<String>,
+        starts: Vec<(DateTime<Utc>, String)>,
+        dropped: Vec<String>,
+    }
+
+    impl Simulation {
+        fn new(slots: usize, queue_depth: usize) -> Self {
+            Self {
+                manager: manager(slots, queue_depth),
+                slots,
+                clock: DateTime::from_timestamp(1_700_000_000, 0).unwrap(),
+                running: Vec::new(),
+                failures: HashMap::new(),
+                run_secs: HashMap::new(),
+                retries: Vec::new(),
+                arrived: Vec::new(),
+                starts: Vec::new(),
+                dropped: Vec::new(),
+            }
+        }
+
+        fn run(&mut self, arrivals: &[Arrival], steps: i64) {
+            let epoch = self.clock;
+            for step in 0..steps {
+                self.clock = epoch + chrono::Duration::seconds(step);
+                let now = self.clock;
+                self.finish_runs();
+                let retries = std::mem::take(&mut self.retries);
+                let (due, waiting): (Vec<_>, Vec<_>) =
+                    retries.into_iter().partition(|(at, _)| *at <= now);
+                self.retries = waiting;
+                for (_, id) in due {
+                    self.admit_queued(id);
+                }
+                for arrival in arrivals.iter().filter(|a| a.at == step) {
+                    let id = self.manager.create_execution(ExecutionSpec {
+                        max_retries: arrival.failures,
+                        backoff: Duration::from_secs(5),
+                        scheduled_for: arrival
+                            .scheduled_in
+                            .map(|secs| now + chrono::Duration::seconds(secs)),
+                        ..spec()
+                    });
+                    self.failures.insert(id.clone(), arrival.failures);
+                    self.run_secs.insert(id.clone(), arrival.run_secs);
+                    self.arrived.push(id.clone());
+                    self.admit_queued(id);
+                }
+                for id in self.manager.take_due(now) {
+                    self.admit_queued(id);
+                }
+                while let Some(id) = self.manager.next_queued() {
+                    self.start(id);
+                }
+                assert!(self.running.len() <= self.slots, "more runs than slots");
+            }
+        }
+
+        fn admit_queued(&mut self, id: String) {
+            if self.manager.defer(&id, self.clock)
+                || self.manager.get_execution(&id).unwrap().state != ExecutionState::Queued
+            {
+                return;
+            }
+            match self.manager.admit(&id) {
+                Ok(true) => self.start(id),
+                Ok(false) => {}
+                Err(_) => self.dropped.push(id),
+            }
+        }
+
+        fn start(&mut self, id: String) {
+            let execution = self.manager.get_execution(&id).unwrap();
+            if let Some(at) = execution.scheduled_for {
+                assert!(at <= self.clock, "{} started before its time", id);
+            }
+            self.manager
+                .update_execution(&id, ExecutionState::Running, None, None);
+            let end = self.clock + chrono::Duration::seconds(self.run_secs[&id]);
+            self.starts.push((self.clock, id.clone()));
+            self.running.push((id, end));
+        }
+
+        fn finish_runs(&mut self) {
+            let now = self.clock;
+            let (ended, running): (Vec<_>, Vec<_>) = std::mem::take(&mut self.running)
+                .into_iter()
+                .partition(|(_, end)| *end <= now);
+            self.running = running;
+            for (id, _) in ended {
+                let failures = self.failures.get_mut(&id).unwrap();
+                if *failures > 0 {
+                    *failures -= 1;
+                    self.manager.update_execution(
+                        &id,
+                        ExecutionState::Failed,
+                        None,
+                        Some("boom".into()),
+                    );
+                } else {
+                    self.manager.update_execution(
+                        &id,
+                        ExecutionState::Completed,
+                        Some("done".into()),
+                        None,
+                    );
+                }
+                if let Some(delay) = self.manager.take_retry(&id) {
+                    let delay = chrono::Duration::from_std(delay).unwrap();
+                    self.retries.push((now + delay, id));
+                }
+            }
+        }
+
+        /// The order executions first started in.
+        fn first_starts(&self) -> Vec<String> {
+            let mut order = Vec::new();
+            for (_, id) in &self.starts {
+                if !order.contains(id) {
+                    order.push(id.clone());
+                }
+            }
+            order
+        }
+
+        fn assert_all_completed(&self) {
+            for id in &self.arrived {
+                assert_eq!(
+                    self.manager.get_execution(id).unwrap().state,
+                    ExecutionState::Completed,
+                    "{} starved",
+                    id
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn simulated_burst_runs_first_come_first_served() {
+        let mut simulation = Simulation::new(1, 16);
+        // A burst, then a steady trickle that must not overtake it
+        let arrivals: Vec<Arrival> = (0..8)
+            .map(|i| Arrival {
+                at: i / 4,
+                run_secs: 3,
+                ..Default::default()
+            })
+            .chain((0..4).map(|i| Arrival {
+                at: 2 + i,
+                run_secs: 1,
+                ..Default::default()
+            }))
+            .collect();
+        simulation.run(&arrivals, 60);
+        simulation.assert_all_completed();
+        assert_eq!(simulation.first_starts(), simulation.arrived);
+        assert!(simulation.dropped.is_empty());
+    }
+
+    #[test]
+    fn simulated_retries_and_schedules_rejoin_the_back_of_the_queue() {
+        let mut simulation = Simulation::new(2, 16);
+        let arrivals = [
+            Arrival {
+                at: 0,
+                run_secs: 2,
+                failures: 2,
+                ..Default::default()
+            },
+            Arrival {
+                at: 0,
+                run_secs: 10,
+                scheduled_in: Some(20),
+                ..Default::default()
+            },
+            Arrival {
+                at: 1,
+                run_secs: 4,
+                ..Default::default()
+            },
+            Arrival {
+                at: 1,
+                run_secs: 4,
+                ..Default::default()
+            },
+            Arrival {
+                at: 3,
+                run_secs: 4,
+                ..Default::default()
+            },
+        ];
+        simulation.run(&arrivals, 120);
+        simulation.assert_all_completed();
+        let flaky = &simulation.arrived[0];
+        let runs: Vec<_> = simulation
+            .starts
+            .iter()
+            .filter(|(_, id)| id == flaky)
+            .collect();
+        assert_eq!(runs.len(), 3);
+        // Each retry waits out its backoff, doubling from 5s, before it is admitted
+        assert!(runs[1].0 - runs[0].0 >= chrono::Duration::seconds(2 + 5));
+        assert!(runs[2].0 - runs[1].0 >= chrono::Duration::seconds(2 + 10));
+        let scheduled = &simulation.arrived[1];
+        let (started, _) = simulation
+            .starts
+            .iter()
+            .find(|(_, id)| id == scheduled)
+            .unwrap();
+        assert_eq!(
+            *started - simulation.starts[0].0,
+            chrono::Duration::seconds(20)
+        );
+        // Plain arrivals keep their order around the flaky and scheduled ones
+        let plain: Vec<_> = simulation
+            .first_starts()
+            .into_iter()
+            .filter(|id| id != scheduled && id != flaky)
+            .collect();
+        assert_eq!(plain, simulation.arrived[2..]);
+    }
+
+    #[test]
+    fn simulated_overload_drops_only_what_the_queue_cannot_hold() {
+        let mut simulation = Simulation::new(1, 2);
+        let arrivals: Vec<Arrival> = (0..6)
+            .map(|_| Arrival {
+                at: 0,
+                run_secs: 2,
+                ..Default::default()
+            })
+            .collect();
+        simulation.run(&arrivals, 20);
+        // One runs, two wait, the rest are refused
+        assert_eq!(simulation.dropped, simulation.arrived[3..]);
+        assert_eq!(simulation.first_starts(), simulation.arrived[..3]);
+    }
+}
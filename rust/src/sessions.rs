@@ -0,0 +1,76 @@
+use axum::{
+    Router,
+    extract::{Path, State},
+    http::StatusCode,
+    response::Json,
+    routing::get,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::error::ApiError;
+use crate::server::AppState;
+
+/// Client-chosen session settings that apply to every request tagged with its id.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Session {
+    #[serde(default)]
+    pub system_prompt: Option<String>,
+}
+
+// Sessions live for the daemon's lifetime only
+#[derive(Default)]
+pub struct SessionStore {
+    sessions: HashMap<String, Session>,
+}
+
+impl SessionStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get(&self, id: &str) -> Option<&Session> {
+        self.sessions.get(id)
+    }
+}
+
+pub fn routes() -> Router<Arc<AppState>> {
+    Router::new().route(
+        "/sessions/:id",
+        get(get_session).put(put_session).delete(delete_session),
+    )
+}
+
+async fn get_session(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+) -> Result<Json<Session>, ApiError> {
+    let sessions = state.sessions.lock().await;
+    sessions
+        .get(&id)
+        .cloned()
+        .map(Json)
+        .ok_or_else(|| ApiError::not_found("Session not found"))
+}
+
+async fn put_session(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+    Json(session): Json<Session>,
+) -> Json<Session> {
+    let mut sessions = state.sessions.lock().await;
+    sessions.sessions.insert(id, session.clone());
+    Json(session)
+}
+
+async fn delete_session(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+) -> Result<StatusCode, ApiError> {
+    let mut sessions = state.sessions.lock().await;
+    match sessions.sessions.remove(&id) {
+        Some(_) => Ok(StatusCode::OK),
+        None => Err(ApiError::not_found("Session not found")),
+    }
+}
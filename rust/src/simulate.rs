@@ -0,0 +1,267 @@
+use axum::{Router, extract::State, response::Json, routing::post};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::net::TcpStream;
+
+use crate::config::PeerSelection;
+use crate::error::ApiError;
+use crate::hybrid::{HybridExecutor, Peer};
+use crate::model::LiveParams;
+use crate::server::AppState;
+
+// Dry runs of the offload decision an execution would get, with latency estimates from
+// the speeds recent local executions reached. Peers are probed with a TCP connect; no
+// prompt is sent anywhere.
+
+const PROBE_TIMEOUT: Duration = Duration::from_secs(3);
+
+#[derive(Deserialize)]
+struct SimulateRequest {
+    /// Rendered prompt length to plan for
+    prompt_tokens: usize,
+    /// Tokens to generate; defaults to the execution default
+    max_tokens: Option<usize>,
+    #[serde(default = "default_true")]
+    allow_hybrid_compute: bool,
+    /// What the prompt carries besides the user's text; any of it keeps it local
+    #[serde(default)]
+    images: usize,
+    #[serde(default)]
+    system_prompt: bool,
+    #[serde(default)]
+    tools: bool,
+    #[serde(default)]
+    memories: bool,
+    /// Peers to plan with instead of the configured ones
+    peers: Option<Vec<SimulatedPeer>>,
+    /// This machine's speeds, instead of those measured on recent executions
+    local: Option<Speed>,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+#[derive(Deserialize)]
+struct SimulatedPeer {
+    address: String,
+    /// The peer's speeds; assumed to match this machine's when unset
+    #[serde(flatten)]
+    speed: Option<Speed>,
+}
+
+#[derive(Deserialize, Clone, Copy)]
+struct Speed {
+    prompt_tokens_per_second: f64,
+    tokens_per_second: f64,
+}
+
+#[derive(Serialize)]
+struct SimulateResponse {
+    /// `local` or `peer`
+    route: &'static str,
+    /// `local` or the chosen peer's address
+    target: String,
+    reason: String,
+    /// Prompts always go whole to one side; the peer protocol has no partial offload
+    split: bool,
+    local_speed: Option<LocalSpeed>,
+    options: Vec<RouteOption>,
+}
+
+#[derive(Serialize)]
+struct LocalSpeed {
+    prompt_tokens_per_second: f64,
+    tokens_per_second: f64,
+    /// `request`, or `executions` when measured on recent ones
+    source: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    samples: Option<usize>,
+}
+
+#[derive(Serialize)]
+struct RouteOption {
+    target: String,
+    /// The policy could route the prompt here
+    eligible: bool,
+    /// The policy would route it here
+    chosen: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reachable: Option<bool>,
+    /// Round trip of the TCP connect probe
+    #[serde(skip_serializing_if = "Option::is_none")]
+    connect_ms: Option<f64>,
+    /// Unset when no speed is known to estimate with
+    estimated_ms: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    note: Option<String>,
+}
+
+pub fn routes() -> Router<Arc<AppState>> {
+    Router::new().route("/hybrid/simulate", post(simulate))
+}
+
+async fn simulate(
+    State(state): State<Arc<AppState>>,
+    Json(req): Json<SimulateRequest>,
+) -> Result<Json<SimulateResponse>, ApiError> {
+    let max_tokens = req.max_tokens.unwrap_or(LiveParams::default().max_tokens);
+    let local_speed = match req.local {
+        Some(speed) => Some(LocalSpeed {
+            prompt_tokens_per_second: speed.prompt_tokens_per_second,
+            tokens_per_second: speed.tokens_per_second,
+            source: "request",
+            samples: None,
+        }),
+        None => state
+            .execution_manager
+            .lock()
+            .await
+            .recent_local_stats()
+            .map(|(stats, samples)| LocalSpeed {
+                prompt_tokens_per_second: stats.prompt_tokens_per_second(),
+                tokens_per_second: stats.generation_tokens_per_second(),
+                source: "executions",
+                samples: Some(samples),
+            }),
+    };
+    let local = local_speed.as_ref().map(|s| Speed {
+        prompt_tokens_per_second: s.prompt_tokens_per_second,
+        tokens_per_second: s.tokens_per_second,
+    });
+    if [req.local]
+        .iter()
+        .flatten()
+        .chain(req.peers.iter().flatten().filter_map(|p| p.speed.as_ref()))
+        .any(|s| s.prompt_tokens_per_second <= 0.0 || s.tokens_per_second <= 0.0)
+    {
+        return Err(ApiError::bad_request("Speeds must be positive"));
+    }
+    let estimate = |speed: Option<Speed>| {
+        speed.map(|s| {
+            (req.prompt_tokens as f64 / s.prompt_tokens_per_second
+                + max_tokens as f64 / s.tokens_per_second)
+                * 1000.0
+        })
+    };
+
+    let hybrid = state.hybrid_executor.lock().await;
+    let peers: Vec<(Peer, Option<Speed>)> = match req.peers {
+        Some(peers) => peers
+            .into_iter()
+            .map(|p| {
+                (
+                    Peer {
+                        id: p.address.clone(),
+                        address: p.address,
+                    },
+                    p.speed,
+                )
+            })
+            .collect(),
+        None => hybrid
+            .peers
+            .iter()
+            .cloned()
+            .map(|peer| (peer, None))
+            .collect(),
+    };
+    // Without moving round-robin on, so the next execution gets the same peer
+    let selected = hybrid.choose(
+        &peers
+            .iter()
+            .map(|(peer, _)| peer.clone())
+            .collect::<Vec<_>>(),
+        false,
+    );
+    let selection = hybrid.selection;
+    drop(hybrid);
+    let sampling = req
+        .max_tokens
+        .is_some_and(|n| n != LiveParams::default().max_tokens);
+    let blocker = HybridExecutor::local_only(
+        req.images > 0,
+        req.system_prompt,
+        req.tools,
+        req.memories,
+        sampling,
+    );
+    let chosen_peer = selected.filter(|_| req.allow_hybrid_compute && blocker.is_none());
+    let offload = chosen_peer.is_some();
+    let reason = match (blocker, selected) {
+        _ if !req.allow_hybrid_compute => "The policy doesn't allow hybrid compute".to_string(),
+        (Some(blocker), _) => format!("Prompts with {} stay local", blocker),
+        (None, None) => "No peers are configured".to_string(),
+        (None, Some(index)) => format!(
+            "Offloaded to {}, the {}",
+            peers[index].0.address,
+            match selection {
+                PeerSelection::First => "first peer",
+                PeerSelection::RoundRobin => "next peer in turn",
+                PeerSelection::LeastLoaded => "peer with the fewest offloads in flight",
+                PeerSelection::LowestLatency => "peer that has answered fastest",
+            }
+        ),
+    };
+
+    let local_estimate = estimate(local);
+    let mut options = vec![RouteOption {
+        target: "local".to_string(),
+        eligible: true,
+        chosen: !offload,
+        reachable: None,
+        connect_ms: None,
+        estimated_ms: local_estimate,
+        note: local
+            .is_none()
+            .then(|| "No speeds known yet; run an execution or send `local`".to_string()),
+    }];
+    for (i, (peer, speed)) in peers.iter().enumerate() {
+        let started = Instant::now();
+        let reachable = tokio::time::timeout(PROBE_TIMEOUT, TcpStream::connect(&peer.address))
+            .await
+            .is_ok_and(|connected| connected.is_ok());
+        let connect_ms = started.elapsed().as_secs_f64() * 1000.0;
+        let chosen = chosen_peer == Some(i);
+        let (estimated_ms, note) = if reachable {
+            // Connecting, then one round trip for the prompt and its result
+            let work = estimate(speed.or(local));
+            (
+                work.map(|ms| ms + 2.0 * connect_ms),
+                speed
+                    .is_none()
+                    .then(|| "Assumed to match this machine's speed".to_string()),
+            )
+        } else {
+            (
+                local_estimate.map(|ms| ms + connect_ms),
+                chosen.then(|| {
+                    "Unreachable, so the run would fail over to the next peer, then local"
+                        .to_string()
+                }),
+            )
+        };
+        options.push(RouteOption {
+            target: peer.address.clone(),
+            eligible: req.allow_hybrid_compute && blocker.is_none(),
+            chosen,
+            reachable: Some(reachable),
+            connect_ms: Some(connect_ms),
+            estimated_ms,
+            note,
+        });
+    }
+
+    Ok(Json(SimulateResponse {
+        route: if offload { "peer" } else { "local" },
+        target: match chosen_peer {
+            Some(index) => peers[index].0.address.clone(),
+            None => "local".to_string(),
+        },
+        reason,
+        split: false,
+        local_speed,
+        options,
+    }))
+}
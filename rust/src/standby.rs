@@ -0,0 +1,151 @@
+use axum::{
+    Router,
+    extract::State,
+    http::StatusCode,
+    response::{IntoResponse, Json, Response},
+    routing::post,
+};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use crate::config;
+use crate::error::ApiError;
+use crate::server::AppState;
+
+// Replaces the chat model without downtime: the new weights load beside the old ones
+// while they keep serving, then take over the slot as soon as the generation holding
+// it finishes. The old weights are dropped with the swap.
+
+#[derive(Serialize, Clone)]
+pub struct UpgradeStatus {
+    /// `idle`, `loading`, `switching`, `done` or `failed`
+    pub state: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub started_at: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub finished_at: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl UpgradeStatus {
+    pub fn new() -> Self {
+        Self {
+            state: "idle",
+            path: None,
+            started_at: None,
+            finished_at: None,
+            error: None,
+        }
+    }
+
+    fn in_progress(&self) -> bool {
+        matches!(self.state, "loading" | "switching")
+    }
+}
+
+#[derive(Deserialize)]
+struct UpgradeRequest {
+    /// GGUF to switch to: a path, or a file name in the models directory
+    path: String,
+}
+
+pub fn routes() -> Router<Arc<AppState>> {
+    Router::new().route(
+        "/admin/model/upgrade",
+        post(start_upgrade).get(upgrade_status),
+    )
+}
+
+async fn upgrade_status(State(state): State<Arc<AppState>>) -> Json<UpgradeStatus> {
+    Json(state.model_upgrade.lock().await.clone())
+}
+
+async fn start_upgrade(
+    State(state): State<Arc<AppState>>,
+    Json(req): Json<UpgradeRequest>,
+) -> Result<Response, ApiError> {
+    if state.provenance.is_some() {
+        return Err(ApiError::conflict(
+            "Provenance stamps the model hashed at startup; restart with the new MODEL_PATH instead",
+        ));
+    }
+    let path = resolve(&req.path)
+        .ok_or_else(|| ApiError::bad_request(format!("Model file {:?} not found", req.path)))?;
+    let path = path.to_string_lossy().into_owned();
+
+    let mut upgrade = state.model_upgrade.lock().await;
+    if upgrade.in_progress() {
+        return Err(ApiError::conflict("A model upgrade is already in progress"));
+    }
+    *upgrade = UpgradeStatus {
+        state: "loading",
+        path: Some(path.clone()),
+        started_at: Some(Utc::now().to_rfc3339()),
+        finished_at: None,
+        error: None,
+    };
+    let status = upgrade.clone();
+    drop(upgrade);
+
+    state
+        .audit
+        .record("model_upgrade", serde_json::json!({ "path": path }))
+        .await;
+    tokio::spawn(run_upgrade(Arc::clone(&state), path));
+    Ok((StatusCode::ACCEPTED, Json(status)).into_response())
+}
+
+fn resolve(path: &str) -> Option<PathBuf> {
+    let direct = PathBuf::from(path);
+    if direct.is_file() {
+        return Some(direct);
+    }
+    let local = config::models_dir().join(path);
+    if local.is_file() {
+        return Some(local);
+    }
+    let local = local.with_extension("gguf");
+    local.is_file().then_some(local)
+}
+
+async fn run_upgrade(state: Arc<AppState>, path: String) {
+    tracing::info!("Loading {} beside the current model", path);
+    // Held only between generations, to copy the backend and settings
+    let mut standby = state.model.lock().await.standby();
+    let loaded = {
+        let path = path.clone();
+        tokio::task::spawn_blocking(move || {
+            futures::executor::block_on(standby.load_model(&path)).map(|_| standby)
+        })
+        .await
+        .map_err(|e| e.to_string())
+        .and_then(|loaded| loaded)
+    };
+    let standby = match loaded {
+        Ok(standby) => standby,
+        Err(e) => {
+            tracing::error!("Model upgrade failed, keeping the current model: {}", e);
+            finish(&state, "failed", Some(e)).await;
+            return;
+        }
+    };
+
+    state.model_upgrade.lock().await.state = "switching";
+    // Waits for the generation in flight; everything after it runs on the new model
+    let old = std::mem::replace(&mut *state.model.lock().await, standby);
+    drop(old);
+    tracing::info!("Switched the chat model to {}", path);
+    finish(&state, "done", None).await;
+}
+
+async fn finish(state: &AppState, outcome: &'static str, error: Option<String>) {
+    let mut upgrade = state.model_upgrade.lock().await;
+    upgrade.state = outcome;
+    upgrade.finished_at = Some(Utc::now().to_rfc3339());
+    upgrade.error = error;
+}
@@ -0,0 +1,335 @@
+use axum::{
+    Router,
+    body::{Body, to_bytes},
+    extract::ConnectInfo,
+    http::{Method, Request, header},
+};
+use serde::Deserialize;
+use serde_json::{Value, json};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::sync::{broadcast, mpsc};
+use tower::ServiceExt;
+use uuid::Uuid;
+
+use crate::auth::{self, SIGNATURE_HEADER, TIMESTAMP_HEADER};
+use crate::deprecation::API_PREFIX;
+use crate::server::AppState;
+
+// JSON-RPC 2.0 on stdin/stdout with LSP's Content-Length framing, for editor plugins
+// that spawn the daemon as a child process instead of finding a free port. Every call
+// goes through the HTTP router, so it shares the queue, limits and audit log of the
+// HTTP API.
+
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const INTERNAL_ERROR: i64 = -32603;
+// The HTTP API refused the call; `data` carries its status and body
+const API_ERROR: i64 = -32000;
+
+#[derive(Deserialize)]
+struct RpcRequest {
+    /// Unset for notifications, which get no reply
+    id: Option<Value>,
+    method: String,
+    #[serde(default)]
+    params: Value,
+}
+
+#[derive(Deserialize)]
+struct ExecutionParams {
+    id: String,
+}
+
+struct RpcError {
+    code: i64,
+    message: String,
+    data: Option<Value>,
+}
+
+impl RpcError {
+    fn new(code: i64, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+            data: None,
+        }
+    }
+
+    fn to_value(&self) -> Value {
+        let mut error = json!({ "code": self.code, "message": self.message });
+        if let Some(data) = &self.data {
+            error["data"] = data.clone();
+        }
+        error
+    }
+}
+
+/// Answer JSON-RPC calls on stdin until it closes or the daemon stops.
+pub async fn serve(app: Router, state: Arc<AppState>) {
+    let (tx, mut rx) = mpsc::unbounded_channel::<Value>();
+    tokio::spawn(async move {
+        let mut stdout = tokio::io::stdout();
+        while let Some(message) = rx.recv().await {
+            let body = message.to_string();
+            let frame = format!("Content-Length: {}\r\n\r\n{}", body.len(), body);
+            if stdout.write_all(frame.as_bytes()).await.is_err() || stdout.flush().await.is_err() {
+                break;
+            }
+        }
+    });
+
+    tracing::info!("Serving JSON-RPC on stdio");
+    let mut stdin = BufReader::new(tokio::io::stdin());
+    loop {
+        let message = tokio::select! {
+            message = read_message(&mut stdin, state.max_body_bytes) => message,
+            _ = state.stop.cancelled() => return,
+        };
+        let message = match message {
+            Ok(Some(message)) => message,
+            // The editor closed the pipe
+            Ok(None) => return,
+            Err(e) => {
+                // The framing is lost, so nothing after this can be read either
+                tracing::error!("Unreadable stdio message: {}", e);
+                let _ = tx.send(reply(Value::Null, Err(RpcError::new(PARSE_ERROR, e))));
+                return;
+            }
+        };
+        let request: RpcRequest = match serde_json::from_slice(&message) {
+            Ok(request) => request,
+            Err(e) => {
+                let code = if serde_json::from_slice::<Value>(&message).is_ok() {
+                    INVALID_REQUEST
+                } else {
+                    PARSE_ERROR
+                };
+                let _ = tx.send(reply(Value::Null, Err(RpcError::new(code, e.to_string()))));
+                continue;
+            }
+        };
+
+        // Calls run side by side, so a `cancel` isn't stuck behind a `stream`
+        let (app, state, tx) = (app.clone(), Arc::clone(&state), tx.clone());
+        tokio::spawn(async move {
+            let outcome = call(&app, &state, &tx, &request.method, request.params).await;
+            if let Some(id) = request.id {
+                let _ = tx.send(reply(id, outcome));
+            }
+        });
+    }
+}
+
+/// One framed message, or `None` at the end of input.
+async fn read_message<R: AsyncRead + Unpin>(
+    reader: &mut BufReader<R>,
+    max_bytes: usize,
+) -> Result<Option<Vec<u8>>, String> {
+    let mut length = None;
+    loop {
+        let mut line = String::new();
+        if reader
+            .read_line(&mut line)
+            .await
+            .map_err(|e| e.to_string())?
+            == 0
+        {
+            return Ok(None);
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            // Blank lines between messages are tolerated
+            if length.is_some() {
+                break;
+            }
+            continue;
+        }
+        if let Some((name, value)) = line.split_once(':')
+            && name.trim().eq_ignore_ascii_case("content-length")
+        {
+            length = Some(
+                value
+                    .trim()
+                    .parse::<usize>()
+                    .map_err(|_| format!("Invalid Content-Length {:?}", value.trim()))?,
+            );
+        }
+    }
+    let length = length.unwrap_or_default();
+    if length > max_bytes {
+        return Err(format!("Message of {} bytes is over the limit", length));
+    }
+    let mut body = vec![0u8; length];
+    reader
+        .read_exact(&mut body)
+        .await
+        .map_err(|e| e.to_string())?;
+    Ok(Some(body))
+}
+
+fn reply(id: Value, outcome: Result<Value, RpcError>) -> Value {
+    match outcome {
+        Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+        Err(error) => json!({ "jsonrpc": "2.0", "id": id, "error": error.to_value() }),
+    }
+}
+
+async fn call(
+    app: &Router,
+    state: &AppState,
+    tx: &mpsc::UnboundedSender<Value>,
+    method: &str,
+    params: Value,
+) -> Result<Value, RpcError> {
+    match method {
+        // Same body as POST /execution/start
+        "run" => api(app, state, Method::POST, "/execution/start", Some(params)).await,
+        "status" => {
+            let id = execution_id(params)?;
+            api(
+                app,
+                state,
+                Method::GET,
+                &format!("/execution/status/{}", id),
+                None,
+            )
+            .await
+        }
+        "cancel" => {
+            let id = execution_id(params)?;
+            api(
+                app,
+                state,
+                Method::POST,
+                &format!("/execution/cancel/{}", id),
+                None,
+            )
+            .await
+        }
+        "stream" => {
+            let id = execution_id(params)?;
+            stream(app, state, tx, &id).await
+        }
+        other => Err(RpcError::new(
+            METHOD_NOT_FOUND,
+            format!("Unknown method {:?}", other),
+        )),
+    }
+}
+
+fn execution_id(params: Value) -> Result<String, RpcError> {
+    let params: ExecutionParams =
+        serde_json::from_value(params).map_err(|e| RpcError::new(INVALID_PARAMS, e.to_string()))?;
+    // It becomes part of a path
+    Uuid::parse_str(&params.id)
+        .map(|_| params.id.clone())
+        .map_err(|_| {
+            RpcError::new(
+                INVALID_PARAMS,
+                format!("Invalid execution id {:?}", params.id),
+            )
+        })
+}
+
+/// Push an `execution/status` notification for every change to `id`, then answer with
+/// its final status once it has finished.
+async fn stream(
+    app: &Router,
+    state: &AppState,
+    tx: &mpsc::UnboundedSender<Value>,
+    id: &str,
+) -> Result<Value, RpcError> {
+    // Subscribed before reading the status, so no change falls in between
+    let mut rx = state.execution_manager.lock().await.subscribe();
+    let path = format!("/execution/status/{}", id);
+    let mut status = api(app, state, Method::GET, &path, None).await?;
+    loop {
+        let _ = tx.send(json!({
+            "jsonrpc": "2.0",
+            "method": "execution/status",
+            "params": status,
+        }));
+        if matches!(
+            status["state"].as_str(),
+            Some("Completed" | "Cancelled" | "Failed")
+        ) {
+            return Ok(status);
+        }
+        status = loop {
+            match rx.recv().await {
+                Ok(update) if update.id() == id => {
+                    break serde_json::to_value(&update)
+                        .map_err(|e| RpcError::new(INTERNAL_ERROR, e.to_string()))?;
+                }
+                Ok(_) => {}
+                // Missed updates; the current status covers them
+                Err(broadcast::error::RecvError::Lagged(_)) => {
+                    break api(app, state, Method::GET, &path, None).await?;
+                }
+                Err(broadcast::error::RecvError::Closed) => {
+                    return Err(RpcError::new(INTERNAL_ERROR, "The daemon is stopping"));
+                }
+            }
+        };
+    }
+}
+
+/// Call the HTTP API at `path` (under /v1) as a local client.
+async fn api(
+    app: &Router,
+    state: &AppState,
+    method: Method,
+    path: &str,
+    body: Option<Value>,
+) -> Result<Value, RpcError> {
+    let target = format!("{}{}", API_PREFIX, path);
+    let body = body.map(|body| body.to_string()).unwrap_or_default();
+    let mut builder = Request::builder()
+        .method(method.clone())
+        .uri(&target)
+        .extension(ConnectInfo(SocketAddr::from(([127, 0, 0, 1], 0))));
+    if !body.is_empty() {
+        builder = builder.header(header::CONTENT_TYPE, "application/json");
+    }
+    // The process that spawned the daemon holds its config, and with it the keys
+    if let Some(key) = state.api_keys.first() {
+        let (timestamp, signature) = auth::sign(key, method.as_str(), &target, body.as_bytes());
+        builder = builder
+            .header(header::AUTHORIZATION, format!("Bearer {}", key))
+            .header(TIMESTAMP_HEADER, timestamp)
+            .header(SIGNATURE_HEADER, signature);
+    }
+    let request = builder
+        .body(Body::from(body))
+        .map_err(|e| RpcError::new(INTERNAL_ERROR, e.to_string()))?;
+    let response = match app.clone().oneshot(request).await {
+        Ok(response) => response,
+        Err(never) => match never {},
+    };
+
+    let status = response.status();
+    let bytes = to_bytes(response.into_body(), usize::MAX)
+        .await
+        .map_err(|e| RpcError::new(INTERNAL_ERROR, e.to_string()))?;
+    let value = if bytes.is_empty() {
+        Value::Null
+    } else {
+        serde_json::from_slice(&bytes)
+            .unwrap_or_else(|_| Value::String(String::from_utf8_lossy(&bytes).into_owned()))
+    };
+    if status.is_success() {
+        return Ok(value);
+    }
+    Err(RpcError {
+        code: API_ERROR,
+        message: value["error"]
+            .as_str()
+            .map(str::to_string)
+            .unwrap_or_else(|| status.to_string()),
+        data: Some(json!({ "status": status.as_u16(), "body": value })),
+    })
+}
@@ -0,0 +1,309 @@
+use chrono::{Datelike, Local, Timelike, Utc};
+use serde_json::Value;
+use std::fs::File;
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::Path;
+use std::time::Duration;
+
+use crate::config::{self, Config};
+use crate::deprecation::API_PREFIX;
+use crate::migrations;
+
+// Only the end of the audit log goes into a bundle
+const AUDIT_TAIL_BYTES: u64 = 1024 * 1024;
+// How long to wait on the running daemon for its status and executions
+const DAEMON_TIMEOUT: Duration = Duration::from_secs(5);
+const REDACTED: &str = "<redacted>";
+
+/// Write a zip of what a bug report needs to `out`: version info, the config with
+/// secrets redacted, a checks report, the tail of the audit log, and the running
+/// daemon's status and last `executions` execution records when it is reachable.
+/// Returns the number of files written.
+pub async fn bundle(data_dir: &Path, out: &Path, executions: usize) -> Result<usize, String> {
+    let mut files: Vec<(&str, Vec<u8>)> = Vec::new();
+    let mut checks = Vec::new();
+
+    files.push((
+        "version.txt",
+        format!(
+            "kekahyde {}\nos: {}\narch: {}\ncreated_at: {}\n",
+            env!("CARGO_PKG_VERSION"),
+            std::env::consts::OS,
+            std::env::consts::ARCH,
+            Utc::now().to_rfc3339()
+        )
+        .into_bytes(),
+    ));
+
+    let config_path = config::config_path();
+    if let Ok(data) = std::fs::read_to_string(&config_path) {
+        let redacted = match serde_json::from_str::<Value>(&data) {
+            Ok(mut value) => {
+                redact(&mut value);
+                serde_json::to_vec_pretty(&value).unwrap_or_default()
+            }
+            // Unparseable, and possibly holding keys: leave it out entirely
+            Err(_) => Vec::new(),
+        };
+        if !redacted.is_empty() {
+            files.push(("config.json", redacted));
+        }
+    }
+    let config = match Config::load() {
+        Ok(config) => {
+            checks.push(format!("config: ok ({})", config_path.display()));
+            Some(config)
+        }
+        Err(e) => {
+            checks.push(format!("config: {}", e));
+            None
+        }
+    };
+
+    match migrations::pending(data_dir) {
+        Ok(pending) if pending.is_empty() => checks.push("migrations: none pending".to_string()),
+        Ok(pending) => checks.extend(
+            pending
+                .iter()
+                .map(|m| format!("migrations: {} pending ({})", m.id, m.description)),
+        ),
+        Err(e) => checks.push(format!("migrations: {}", e)),
+    }
+
+    match std::fs::read_dir(data_dir.join("models")) {
+        Ok(entries) => {
+            for entry in entries.flatten() {
+                let size = entry.metadata().map(|m| m.len()).unwrap_or(0);
+                checks.push(format!(
+                    "model: {} ({} bytes)",
+                    entry.file_name().to_string_lossy(),
+                    size
+                ));
+            }
+        }
+        Err(e) => checks.push(format!("models: {}", e)),
+    }
+
+    if let Some(tail) = read_tail(&data_dir.join("audit.log"), AUDIT_TAIL_BYTES) {
+        files.push(("audit.log", tail));
+    }
+
+    if let Some(config) = &config {
+        match fetch_daemon(config, executions).await {
+            Ok((status, recent)) => {
+                checks.push("daemon: reachable".to_string());
+                files.push(("status.json", status));
+                files.push(("executions.json", recent));
+            }
+            Err(e) => checks.push(format!("daemon: {}", e)),
+        }
+    }
+
+    checks.push(String::new());
+    files.push(("checks.txt", checks.join("\n").into_bytes()));
+
+    let mut zip = ZipWriter::create(out)?;
+    for (name, data) in &files {
+        zip.add(name, data)?;
+    }
+    zip.finish()?;
+    Ok(files.len())
+}
+
+/// Replace API keys and anything that looks like a secret. `hybrid.peer_keys` stays, as
+/// it only holds public keys.
+fn redact(value: &mut Value) {
+    match value {
+        Value::Object(map) => {
+            for (key, value) in map.iter_mut() {
+                let key = key.to_ascii_lowercase();
+                if key == "api_keys" {
+                    if let Value::Array(keys) = value {
+                        keys.fill(Value::from(REDACTED));
+                    }
+                } else if key.ends_with("key")
+                    || ["secret", "password", "token"]
+                        .iter()
+                        .any(|word| key.contains(word))
+                {
+                    *value = Value::from(REDACTED);
+                } else {
+                    redact(value);
+                }
+            }
+        }
+        Value::Array(items) => items.iter_mut().for_each(redact),
+        _ => {}
+    }
+}
+
+fn read_tail(path: &Path, max: u64) -> Option<Vec<u8>> {
+    let mut file = File::open(path).ok()?;
+    let len = file.metadata().ok()?.len();
+    file.seek(SeekFrom::Start(len.saturating_sub(max))).ok()?;
+    let mut data = Vec::new();
+    file.read_to_end(&mut data).ok()?;
+    Some(data)
+}
+
+/// GET /v1/status and /v1/admin/executions from the daemon `config` describes.
+async fn fetch_daemon(config: &Config, executions: usize) -> Result<(Vec<u8>, Vec<u8>), String> {
+    let mut addr: SocketAddr = config
+        .listen
+        .parse()
+        .map_err(|_| format!("cannot parse listen address {:?}", config.listen))?;
+    if addr.ip().is_unspecified() {
+        addr.set_ip(IpAddr::V4(Ipv4Addr::LOCALHOST));
+    }
+    let scheme = if config.tls.is_some() {
+        "https"
+    } else {
+        "http"
+    };
+    // Local daemons commonly run with a self-signed certificate
+    let client = reqwest::Client::builder()
+        .timeout(DAEMON_TIMEOUT)
+        .danger_accept_invalid_certs(true)
+        .build()
+        .map_err(|e| e.to_string())?;
+    let get = |path: String| {
+        let request = client.get(format!("{}://{}{}", scheme, addr, path));
+        async move {
+            let response = request
+                .send()
+                .await
+                .and_then(|response| response.error_for_status())
+                .map_err(|e| format!("not reachable at {}: {}", addr, e))?;
+            response
+                .bytes()
+                .await
+                .map(|body| body.to_vec())
+                .map_err(|e| e.to_string())
+        }
+    };
+    let status = get(format!("{}/status", API_PREFIX)).await?;
+    let recent = get(format!(
+        "{}/admin/executions?limit={}",
+        API_PREFIX, executions
+    ))
+    .await?;
+    Ok((status, recent))
+}
+
+// Minimal zip writer: entries are stored uncompressed, which every unzip tool reads
+struct ZipWriter {
+    out: BufWriter<File>,
+    entries: Vec<ZipEntry>,
+    offset: u32,
+    // MS-DOS time and date, as zip headers record them
+    time: u16,
+    date: u16,
+}
+
+struct ZipEntry {
+    name: String,
+    crc: u32,
+    size: u32,
+    offset: u32,
+}
+
+impl ZipWriter {
+    fn create(path: &Path) -> Result<Self, String> {
+        let file = File::create(path)
+            .map_err(|e| format!("Failed to create {}: {}", path.display(), e))?;
+        let now = Local::now();
+        Ok(Self {
+            out: BufWriter::new(file),
+            entries: Vec::new(),
+            offset: 0,
+            time: ((now.hour() << 11) | (now.minute() << 5) | (now.second() / 2)) as u16,
+            date: (((now.year().max(1980) - 1980) as u32) << 9 | (now.month() << 5) | now.day())
+                as u16,
+        })
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> Result<(), String> {
+        self.out
+            .write_all(bytes)
+            .map_err(|e| format!("Failed to write bundle: {}", e))?;
+        self.offset += bytes.len() as u32;
+        Ok(())
+    }
+
+    fn add(&mut self, name: &str, data: &[u8]) -> Result<(), String> {
+        let entry = ZipEntry {
+            name: name.to_string(),
+            crc: crc32(data),
+            size: data.len() as u32,
+            offset: self.offset,
+        };
+        let mut header = Vec::new();
+        header.extend(0x04034b50u32.to_le_bytes());
+        header.extend(20u16.to_le_bytes()); // version needed
+        header.extend(0u16.to_le_bytes()); // flags
+        header.extend(0u16.to_le_bytes()); // stored
+        header.extend(self.time.to_le_bytes());
+        header.extend(self.date.to_le_bytes());
+        header.extend(entry.crc.to_le_bytes());
+        header.extend(entry.size.to_le_bytes());
+        header.extend(entry.size.to_le_bytes());
+        header.extend((name.len() as u16).to_le_bytes());
+        header.extend(0u16.to_le_bytes()); // extra field length
+        header.extend(name.as_bytes());
+        self.write(&header)?;
+        self.write(data)?;
+        self.entries.push(entry);
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<(), String> {
+        let directory_offset = self.offset;
+        let mut directory = Vec::new();
+        for entry in &self.entries {
+            directory.extend(0x02014b50u32.to_le_bytes());
+            directory.extend(20u16.to_le_bytes()); // version made by
+            directory.extend(20u16.to_le_bytes()); // version needed
+            directory.extend(0u16.to_le_bytes()); // flags
+            directory.extend(0u16.to_le_bytes()); // stored
+            directory.extend(self.time.to_le_bytes());
+            directory.extend(self.date.to_le_bytes());
+            directory.extend(entry.crc.to_le_bytes());
+            directory.extend(entry.size.to_le_bytes());
+            directory.extend(entry.size.to_le_bytes());
+            directory.extend((entry.name.len() as u16).to_le_bytes());
+            directory.extend([0u8; 12]); // extra, comment, disk, attributes
+            directory.extend(entry.offset.to_le_bytes());
+            directory.extend(entry.name.as_bytes());
+        }
+        let count = self.entries.len() as u16;
+        let mut end = Vec::new();
+        end.extend(0x06054b50u32.to_le_bytes());
+        end.extend([0u8; 4]); // disk numbers
+        end.extend(count.to_le_bytes());
+        end.extend(count.to_le_bytes());
+        end.extend((directory.len() as u32).to_le_bytes());
+        end.extend(directory_offset.to_le_bytes());
+        end.extend(0u16.to_le_bytes()); // comment length
+        self.write(&directory)?;
+        self.write(&end)?;
+        self.out
+            .flush()
+            .map_err(|e| format!("Failed to write bundle: {}", e))
+    }
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB88320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
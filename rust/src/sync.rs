@@ -0,0 +1,230 @@
+use axum::{Router, extract::State, response::Json, routing::post};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::config::{self, SyncConfig};
+use crate::error::ApiError;
+use crate::personas::Persona;
+use crate::server::AppState;
+
+// Personas synced through a shared folder (a mounted WebDAV share, Syncthing, a NAS)
+// that every device reads and writes. Each side is compared with what the last sync
+// agreed on, so an edit on one device is taken as is and edits on both are a
+// conflict; the newer write wins.
+
+const SYNC_FILE: &str = "kekahyde-sync.json";
+
+/// A persona as last written by any device; `persona` is unset once it was deleted.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct Entry {
+    updated_at: String,
+    device: String,
+    persona: Option<Persona>,
+}
+
+/// Contents of the shared folder's sync file.
+#[derive(Serialize, Deserialize, Default)]
+struct SharedState {
+    personas: HashMap<String, Entry>,
+}
+
+/// What the last sync left on this device, under the data dir.
+#[derive(Serialize, Deserialize, Default)]
+struct LocalState {
+    /// Version of each persona both sides agreed on
+    personas: HashMap<String, Base>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct Base {
+    updated_at: String,
+    deleted: bool,
+}
+
+#[derive(Serialize, Default)]
+pub struct SyncReport {
+    /// Names of personas sent to the shared folder
+    pub pushed: Vec<String>,
+    /// Names of personas taken from it
+    pub pulled: Vec<String>,
+    pub conflicts: Vec<SyncConflict>,
+}
+
+/// A persona changed on both sides since the last sync.
+#[derive(Serialize)]
+pub struct SyncConflict {
+    pub id: String,
+    pub name: String,
+    pub local_updated_at: String,
+    pub remote_updated_at: String,
+    pub remote_device: String,
+    /// `local` or `remote`, whichever was written last
+    pub winner: &'static str,
+}
+
+pub fn routes() -> Router<Arc<AppState>> {
+    Router::new().route("/sync", post(sync_now))
+}
+
+async fn sync_now(State(state): State<Arc<AppState>>) -> Result<Json<SyncReport>, ApiError> {
+    let config = state
+        .sync
+        .as_ref()
+        .ok_or_else(|| ApiError::bad_request("Sync is not configured"))?;
+    run(&state, config).await.map(Json).map_err(|e| {
+        tracing::error!("Sync failed: {}", e);
+        ApiError::internal(e)
+    })
+}
+
+/// Sync every `sync.interval_secs`; a failed round is logged and retried next time.
+pub fn spawn_scheduler(state: Arc<AppState>) {
+    let Some(config) = state.sync.clone().filter(|c| c.interval_secs > 0) else {
+        return;
+    };
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(config.interval_secs));
+        loop {
+            interval.tick().await;
+            if let Err(e) = run(&state, &config).await {
+                tracing::warn!("Scheduled sync failed: {}", e);
+            }
+        }
+    });
+}
+
+pub async fn run(state: &AppState, config: &SyncConfig) -> Result<SyncReport, String> {
+    // Held throughout, so no persona edit lands between reading and writing
+    let mut personas = state.personas.lock().await;
+    let shared_path = Path::new(&config.folder).join(SYNC_FILE);
+    let local_path = config::data_dir().join("sync.json");
+    let mut shared: SharedState = read_json(&shared_path)?;
+    let mut local: LocalState = read_json(&local_path)?;
+
+    let now = Utc::now().to_rfc3339();
+    let current: HashMap<String, Persona> = personas
+        .list()
+        .into_iter()
+        .map(|p| (p.id.clone(), p))
+        .collect();
+    let ids: BTreeSet<String> = current
+        .keys()
+        .chain(shared.personas.keys())
+        .chain(local.personas.keys())
+        .cloned()
+        .collect();
+
+    let mut report = SyncReport::default();
+    let mut upserts = Vec::new();
+    let mut removals = Vec::new();
+    for id in ids {
+        let base = local.personas.get(&id);
+        let mine = match (current.get(&id), base) {
+            (Some(persona), _) => Some(Entry {
+                updated_at: persona.updated_at.clone(),
+                device: config.device_name.clone(),
+                persona: Some(persona.clone()),
+            }),
+            // Deleted here since the last sync
+            (None, Some(base)) if !base.deleted => Some(Entry {
+                updated_at: now.clone(),
+                device: config.device_name.clone(),
+                persona: None,
+            }),
+            (None, Some(base)) => Some(Entry {
+                updated_at: base.updated_at.clone(),
+                device: config.device_name.clone(),
+                persona: None,
+            }),
+            (None, None) => None,
+        };
+        let theirs = shared.personas.get(&id).cloned();
+        let stamp = |entry: &Option<Entry>| entry.as_ref().map(|e| e.updated_at.clone());
+        let base_stamp = base.map(|b| b.updated_at.clone());
+        let mine_changed = stamp(&mine) != base_stamp;
+        let theirs_changed = stamp(&theirs) != base_stamp;
+
+        let take_mine = match (mine_changed, theirs_changed) {
+            (false, false) => continue,
+            (true, false) => true,
+            (false, true) => false,
+            (true, true) if stamp(&mine) == stamp(&theirs) => continue,
+            (true, true) => match (&mine, &theirs) {
+                (Some(m), Some(t)) => {
+                    let take_mine = m.updated_at > t.updated_at;
+                    report.conflicts.push(SyncConflict {
+                        id: id.clone(),
+                        name: name_of(m).or(name_of(t)).unwrap_or_default(),
+                        local_updated_at: m.updated_at.clone(),
+                        remote_updated_at: t.updated_at.clone(),
+                        remote_device: t.device.clone(),
+                        winner: if take_mine { "local" } else { "remote" },
+                    });
+                    take_mine
+                }
+                // The shared file lost an entry this device still has
+                _ => mine.is_some(),
+            },
+        };
+        let Some(winner) = (if take_mine { mine } else { theirs }) else {
+            continue;
+        };
+        let name = name_of(&winner).or_else(|| current.get(&id).map(|p| p.name.clone()));
+        if take_mine {
+            report.pushed.push(name.unwrap_or_else(|| id.clone()));
+        } else {
+            report.pulled.push(name.unwrap_or_else(|| id.clone()));
+            match &winner.persona {
+                Some(persona) => upserts.push(persona.clone()),
+                None if current.contains_key(&id) => removals.push(id.clone()),
+                None => {}
+            }
+        }
+        local.personas.insert(
+            id.clone(),
+            Base {
+                updated_at: winner.updated_at.clone(),
+                deleted: winner.persona.is_none(),
+            },
+        );
+        shared.personas.insert(id, winner);
+    }
+
+    write_json(&shared_path, &shared)?;
+    personas.apply_sync(upserts, &removals)?;
+    write_json(&local_path, &local)?;
+    tracing::info!(
+        "Synced personas: {} pushed, {} pulled, {} conflicts",
+        report.pushed.len(),
+        report.pulled.len(),
+        report.conflicts.len()
+    );
+    Ok(report)
+}
+
+fn name_of(entry: &Entry) -> Option<String> {
+    entry.persona.as_ref().map(|p| p.name.clone())
+}
+
+fn read_json<T: for<'de> Deserialize<'de> + Default>(path: &Path) -> Result<T, String> {
+    if !path.exists() {
+        return Ok(T::default());
+    }
+    let data = std::fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+    serde_json::from_str(&data).map_err(|e| format!("Invalid sync file {}: {}", path.display(), e))
+}
+
+// Written beside the target and renamed over it, so another device never reads half
+fn write_json<T: Serialize>(path: &Path, value: &T) -> Result<(), String> {
+    let data = serde_json::to_vec_pretty(value).map_err(|e| e.to_string())?;
+    let mut partial = PathBuf::from(path);
+    partial.set_extension("json.partial");
+    std::fs::write(&partial, data)
+        .and_then(|_| std::fs::rename(&partial, path))
+        .map_err(|e| format!("Failed to write {}: {}", path.display(), e))
+}
@@ -0,0 +1,285 @@
+use axum::{
+    Router,
+    extract::{Path, State},
+    http::StatusCode,
+    response::Json,
+    routing::get,
+};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::collections::{BTreeMap, HashMap};
+use std::path::PathBuf;
+use std::sync::Arc;
+use uuid::Uuid;
+
+use crate::error::ApiError;
+use crate::server::AppState;
+
+/// A reusable prompt with `{name}` placeholders, filled in by
+/// POST /execution/start_from_template.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Template {
+    #[serde(default)]
+    pub id: String,
+    pub name: String,
+    pub prompt: String,
+    /// May use the same placeholders as the prompt
+    #[serde(default)]
+    pub system_prompt: Option<String>,
+    /// Values for placeholders a request leaves out
+    #[serde(default)]
+    pub defaults: BTreeMap<String, String>,
+    /// Placeholders in the prompt and system prompt, in order of first use
+    #[serde(default)]
+    pub variables: Vec<String>,
+    #[serde(default)]
+    pub updated_at: String,
+}
+
+impl Template {
+    /// The prompt and system prompt with every placeholder replaced.
+    pub fn render(
+        &self,
+        variables: &Map<String, Value>,
+    ) -> Result<(String, Option<String>), String> {
+        let unknown: Vec<&str> = variables
+            .keys()
+            .filter(|name| !self.variables.contains(name))
+            .map(String::as_str)
+            .collect();
+        if !unknown.is_empty() {
+            return Err(format!(
+                "Template {} has no variables {}",
+                self.name,
+                unknown.join(", ")
+            ));
+        }
+        let mut values = HashMap::new();
+        let mut missing = Vec::new();
+        for name in &self.variables {
+            let value = match variables.get(name) {
+                Some(Value::String(s)) => s.clone(),
+                Some(value @ (Value::Number(_) | Value::Bool(_))) => value.to_string(),
+                Some(_) => return Err(format!("Variable {} must be a string or number", name)),
+                None => match self.defaults.get(name) {
+                    Some(value) => value.clone(),
+                    None => {
+                        missing.push(name.as_str());
+                        continue;
+                    }
+                },
+            };
+            values.insert(name.as_str(), value);
+        }
+        if !missing.is_empty() {
+            return Err(format!("Missing variables {}", missing.join(", ")));
+        }
+        let fill = |text: &str| {
+            let mut out = String::with_capacity(text.len());
+            for piece in parse(text) {
+                match piece {
+                    Piece::Text(text) => out.push_str(text),
+                    Piece::Variable(name) => out.push_str(&values[name]),
+                }
+            }
+            out
+        };
+        Ok((fill(&self.prompt), self.system_prompt.as_deref().map(fill)))
+    }
+}
+
+enum Piece<'a> {
+    Text(&'a str),
+    Variable(&'a str),
+}
+
+/// Split `text` at its placeholders. Only `{name}` with letters, digits and `_` is a
+/// placeholder, so JSON in a prompt passes through; `{{` and `}}` are literal braces.
+fn parse(text: &str) -> Vec<Piece<'_>> {
+    let mut pieces = Vec::new();
+    let mut rest = text;
+    while let Some(start) = rest.find(['{', '}']) {
+        pieces.push(Piece::Text(&rest[..start]));
+        let tail = &rest[start..];
+        if tail.starts_with("{{") || tail.starts_with("}}") {
+            pieces.push(Piece::Text(&tail[..1]));
+            rest = &tail[2..];
+            continue;
+        }
+        let name_len = tail[1..]
+            .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+            .unwrap_or(tail.len() - 1);
+        if tail.starts_with('{') && name_len > 0 && tail[1 + name_len..].starts_with('}') {
+            pieces.push(Piece::Variable(&tail[1..1 + name_len]));
+            rest = &tail[name_len + 2..];
+        } else {
+            pieces.push(Piece::Text(&tail[..1]));
+            rest = &tail[1..];
+        }
+    }
+    pieces.push(Piece::Text(rest));
+    pieces
+}
+
+// Templates persisted as a JSON array under the data dir
+pub struct TemplateStore {
+    path: PathBuf,
+    templates: HashMap<String, Template>,
+}
+
+impl TemplateStore {
+    pub fn load(path: PathBuf) -> Result<Self, String> {
+        let templates = if path.exists() {
+            let data = std::fs::read_to_string(&path)
+                .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+            let list: Vec<Template> = serde_json::from_str(&data)
+                .map_err(|e| format!("Invalid templates file {}: {}", path.display(), e))?;
+            list.into_iter().map(|t| (t.id.clone(), t)).collect()
+        } else {
+            HashMap::new()
+        };
+        Ok(Self { path, templates })
+    }
+
+    fn save(&self) -> Result<(), String> {
+        if let Some(parent) = self.path.parent() {
+            std::fs::create_dir_all(parent).map_err(|e| e.to_string())?;
+        }
+        let data = serde_json::to_vec_pretty(&self.list()).map_err(|e| e.to_string())?;
+        std::fs::write(&self.path, data).map_err(|e| e.to_string())
+    }
+
+    pub fn list(&self) -> Vec<Template> {
+        let mut list: Vec<Template> = self.templates.values().cloned().collect();
+        list.sort_by(|a, b| a.name.cmp(&b.name));
+        list
+    }
+
+    /// Look a template up by id, falling back to a case-insensitive name match.
+    pub fn find(&self, key: &str) -> Option<&Template> {
+        self.templates.get(key).or_else(|| {
+            self.templates
+                .values()
+                .find(|t| t.name.eq_ignore_ascii_case(key))
+        })
+    }
+
+    fn upsert(&mut self, mut template: Template) -> Result<Template, ApiError> {
+        if template.name.trim().is_empty() {
+            return Err(ApiError::bad_request("Template name is required"));
+        }
+        // Requests pick templates by name
+        if self
+            .templates
+            .values()
+            .any(|t| t.id != template.id && t.name.eq_ignore_ascii_case(&template.name))
+        {
+            return Err(ApiError::conflict(format!(
+                "A template named {:?} already exists",
+                template.name
+            )));
+        }
+        template.variables = Vec::new();
+        let texts = std::iter::once(&template.prompt).chain(template.system_prompt.as_ref());
+        for piece in texts.flat_map(|text| parse(text)) {
+            if let Piece::Variable(name) = piece
+                && !template.variables.iter().any(|v| v == name)
+            {
+                template.variables.push(name.to_string());
+            }
+        }
+        if let Some(name) = template
+            .defaults
+            .keys()
+            .find(|name| !template.variables.contains(name))
+        {
+            return Err(ApiError::bad_request(format!(
+                "Default for {} matches no placeholder",
+                name
+            )));
+        }
+        if template.id.is_empty() {
+            template.id = Uuid::new_v4().to_string();
+        }
+        template.updated_at = Utc::now().to_rfc3339();
+        self.templates.insert(template.id.clone(), template.clone());
+        self.save().map_err(|e| {
+            tracing::error!("Failed to save template: {}", e);
+            ApiError::internal(e)
+        })?;
+        Ok(template)
+    }
+
+    fn remove(&mut self, id: &str) -> Result<bool, String> {
+        let removed = self.templates.remove(id).is_some();
+        if removed {
+            self.save()?;
+        }
+        Ok(removed)
+    }
+}
+
+pub fn routes() -> Router<Arc<AppState>> {
+    Router::new()
+        .route("/templates", get(list_templates).post(create_template))
+        .route(
+            "/templates/:id",
+            get(get_template)
+                .put(update_template)
+                .delete(delete_template),
+        )
+}
+
+async fn list_templates(State(state): State<Arc<AppState>>) -> Json<Vec<Template>> {
+    Json(state.templates.lock().await.list())
+}
+
+async fn get_template(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+) -> Result<Json<Template>, ApiError> {
+    let templates = state.templates.lock().await;
+    templates
+        .find(&id)
+        .cloned()
+        .map(Json)
+        .ok_or_else(|| ApiError::not_found("Template not found"))
+}
+
+async fn create_template(
+    State(state): State<Arc<AppState>>,
+    Json(mut template): Json<Template>,
+) -> Result<Json<Template>, ApiError> {
+    template.id = String::new();
+    let mut templates = state.templates.lock().await;
+    templates.upsert(template).map(Json)
+}
+
+async fn update_template(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+    Json(mut template): Json<Template>,
+) -> Result<Json<Template>, ApiError> {
+    let mut templates = state.templates.lock().await;
+    let Some(existing) = templates.find(&id) else {
+        return Err(ApiError::not_found("Template not found"));
+    };
+    template.id = existing.id.clone();
+    templates.upsert(template).map(Json)
+}
+
+async fn delete_template(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<String>,
+) -> Result<StatusCode, ApiError> {
+    let mut templates = state.templates.lock().await;
+    match templates.remove(&id) {
+        Ok(true) => Ok(StatusCode::OK),
+        Ok(false) => Err(ApiError::not_found("Template not found")),
+        Err(e) => {
+            tracing::error!("Failed to delete template: {}", e);
+            Err(ApiError::internal(e))
+        }
+    }
+}
@@ -0,0 +1,106 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use uuid::Uuid;
+
+// OpenAI-compatible function calling on top of Qwen's <tool_call> prompt format
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ToolDefinition {
+    #[serde(rename = "type", default = "function_type")]
+    pub kind: String,
+    pub function: FunctionDefinition,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FunctionDefinition {
+    pub name: String,
+    #[serde(default)]
+    pub description: String,
+    /// JSON Schema of the arguments object
+    #[serde(default)]
+    pub parameters: Value,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ToolCall {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub function: FunctionCall,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FunctionCall {
+    pub name: String,
+    /// Arguments as a JSON-encoded string, as in the OpenAI API
+    pub arguments: String,
+}
+
+fn function_type() -> String {
+    "function".to_string()
+}
+
+/// Append the tool signatures and calling convention to a system prompt.
+pub fn system_prompt(base: &str, tools: &[ToolDefinition]) -> String {
+    let signatures: Vec<String> = tools
+        .iter()
+        .map(|tool| serde_json::to_string(tool).unwrap_or_default())
+        .collect();
+    format!(
+        "{}\n\n# Tools\n\nYou may call one or more functions to assist with the user query.\n\n\
+         You are provided with function signatures within <tools></tools> XML tags:\n<tools>\n{}\n</tools>\n\n\
+         For each function call, return a json object with function name and arguments within <tool_call></tool_call> XML tags:\n\
+         <tool_call>\n{{\"name\": <function-name>, \"arguments\": <args-json-object>}}\n</tool_call>",
+        base,
+        signatures.join("\n")
+    )
+}
+
+/// Split a completion into its text content and any tool calls it contains.
+/// Calls that don't parse as JSON are left in the content.
+pub fn parse_tool_calls(output: &str) -> (String, Vec<ToolCall>) {
+    const OPEN: &str = "<tool_call>";
+    const CLOSE: &str = "</tool_call>";
+
+    let mut content = String::new();
+    let mut calls = Vec::new();
+    let mut rest = output;
+    while let Some(start) = rest.find(OPEN) {
+        content.push_str(&rest[..start]);
+        let body = &rest[start + OPEN.len()..];
+        // Generation may stop before the closing tag
+        let (inner, next) = match body.find(CLOSE) {
+            Some(end) => (&body[..end], &body[end + CLOSE.len()..]),
+            None => (body, ""),
+        };
+        match to_call(inner) {
+            Some(call) => calls.push(call),
+            None => content.push_str(&rest[start..rest.len() - next.len()]),
+        }
+        rest = next;
+    }
+    content.push_str(rest);
+
+    // Small models sometimes answer with the bare JSON object
+    if calls.is_empty()
+        && let Some(call) = to_call(&content)
+    {
+        return (String::new(), vec![call]);
+    }
+    (content.trim().to_string(), calls)
+}
+
+fn to_call(json: &str) -> Option<ToolCall> {
+    let value: Value = serde_json::from_str(json.trim()).ok()?;
+    let name = value.get("name")?.as_str()?.to_string();
+    let arguments = match value.get("arguments") {
+        Some(Value::String(encoded)) => encoded.clone(),
+        Some(args) => args.to_string(),
+        None => "{}".to_string(),
+    };
+    Some(ToolCall {
+        id: format!("call_{}", Uuid::new_v4().simple()),
+        kind: function_type(),
+        function: FunctionCall { name, arguments },
+    })
+}
@@ -0,0 +1,103 @@
+use serde::Serialize;
+use std::fmt::Write;
+
+use crate::agent::AgentStep;
+use crate::command_tools::ToolResult;
+use crate::tools::ToolCall;
+
+/// An execution's prompt and everything it produced, as downloaded from
+/// GET /execution/:id/transcript.
+#[derive(Serialize)]
+pub struct Transcript {
+    pub id: String,
+    pub state: String,
+    pub start_time: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub persona: Option<String>,
+    /// Unset when the configured `model.system_prompt` applied
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub system_prompt: Option<String>,
+    pub prompt: String,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub steps: Vec<AgentStep>,
+    pub output: Option<String>,
+    /// Everything generated before the run ended without an output, untruncated
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub partial_output: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tool_calls: Option<Vec<ToolCall>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tool_results: Option<Vec<ToolResult>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub candidates: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl Transcript {
+    pub fn to_markdown(&self) -> String {
+        let mut out = String::new();
+        let _ = writeln!(out, "# Execution {}\n", self.id);
+        let _ = writeln!(out, "- State: {}", self.state);
+        let _ = writeln!(out, "- Started: {}", self.start_time);
+        if let Some(persona) = &self.persona {
+            let _ = writeln!(out, "- Persona: {}", persona);
+        }
+        if let Some(system) = &self.system_prompt {
+            let _ = write!(out, "\n## System prompt\n\n{}\n", system);
+        }
+        let _ = write!(out, "\n## Prompt\n\n{}\n", self.prompt);
+        for step in &self.steps {
+            let _ = write!(out, "\n## Step {}\n\n", step.step);
+            if !step.content.is_empty() {
+                let _ = writeln!(out, "{}\n", step.content);
+            }
+            tool_section(&mut out, &step.tool_calls, &step.tool_results);
+        }
+        if let Some(output) = &self.output {
+            let _ = write!(out, "\n## Output\n\n{}\n", output);
+        }
+        if let Some(partial) = &self.partial_output {
+            let _ = write!(out, "\n## Partial output\n\n{}\n", partial);
+        }
+        if let Some(calls) = &self.tool_calls {
+            out.push('\n');
+            tool_section(
+                &mut out,
+                calls,
+                self.tool_results.as_deref().unwrap_or_default(),
+            );
+        }
+        if let Some(candidates) = &self.candidates {
+            for (i, candidate) in candidates.iter().enumerate() {
+                let _ = write!(out, "\n## Candidate {}\n\n{}\n", i + 1, candidate);
+            }
+        }
+        if let Some(error) = &self.error {
+            let _ = write!(out, "\n## Error\n\n{}\n", error);
+        }
+        out
+    }
+}
+
+/// Each call with its arguments, followed by the tool's output when it was run.
+fn tool_section(out: &mut String, calls: &[ToolCall], results: &[ToolResult]) {
+    for call in calls {
+        let _ = writeln!(
+            out,
+            "**Tool call** `{}` with `{}`\n",
+            call.function.name, call.function.arguments
+        );
+        let Some(result) = results.iter().find(|r| r.tool_call_id == call.id) else {
+            continue;
+        };
+        match &result.error {
+            Some(error) => {
+                let _ = writeln!(out, "Failed: {}\n", error);
+            }
+            None => {
+                let _ = writeln!(out, "```\n{}\n```\n", result.output.trim_end());
+            }
+        }
+    }
+}
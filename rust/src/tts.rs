@@ -0,0 +1,110 @@
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+
+use crate::config::{TtsConfig, data_dir};
+
+// Text-to-speech stage backed by a local piper binary and .onnx voices
+pub struct Tts {
+    config: TtsConfig,
+    voices_dir: PathBuf,
+    audio_dir: PathBuf,
+}
+
+impl Tts {
+    pub fn new(config: TtsConfig) -> Self {
+        let data = data_dir();
+        Self {
+            config,
+            voices_dir: data.join("voices"),
+            audio_dir: data.join("audio"),
+        }
+    }
+
+    fn voice_path(&self, voice: &str) -> Result<PathBuf, String> {
+        // Voices come from personas, so keep them from escaping the voices dir
+        if voice.is_empty()
+            || !voice
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+            || voice.contains("..")
+        {
+            return Err(format!("Invalid voice name: {}", voice));
+        }
+        Ok(self.voices_dir.join(format!("{}.onnx", voice)))
+    }
+
+    /// Fetch the default voice (and its piper config) if it isn't installed yet.
+    pub async fn ensure_default_voice(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let Some(url) = &self.config.voice_url else {
+            return Ok(());
+        };
+        let path = self.voice_path(&self.config.voice)?;
+        crate::download_model(url, &path).await?;
+        let config_path = PathBuf::from(format!("{}.json", path.display()));
+        crate::download_model(&format!("{}.json", url), &config_path).await
+    }
+
+    /// Render `text` to a WAV file for execution `id`, reusing a previous render if present.
+    pub async fn synthesize(
+        &self,
+        id: &str,
+        text: &str,
+        voice: Option<&str>,
+    ) -> Result<PathBuf, String> {
+        let output = self.audio_dir.join(format!("{}.wav", id));
+        if output.exists() {
+            return Ok(output);
+        }
+        let voice_path = self.voice_path(voice.unwrap_or(&self.config.voice))?;
+        if !voice_path.exists() {
+            return Err(format!("Voice not installed: {}", voice_path.display()));
+        }
+        tokio::fs::create_dir_all(&self.audio_dir)
+            .await
+            .map_err(|e| e.to_string())?;
+
+        self.run_piper(&voice_path, &output, text).await?;
+        Ok(output)
+    }
+
+    /// Delete the cached render for execution `id`, if any.
+    pub async fn remove_audio(&self, id: &str) {
+        let _ = tokio::fs::remove_file(self.audio_dir.join(format!("{}.wav", id))).await;
+    }
+
+    async fn run_piper(&self, voice: &Path, output: &Path, text: &str) -> Result<(), String> {
+        let mut child = Command::new(&self.config.piper_path)
+            .arg("--model")
+            .arg(voice)
+            .arg("--output_file")
+            .arg(output)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| format!("Failed to start piper: {}", e))?;
+
+        if let Some(mut stdin) = child.stdin.take() {
+            stdin
+                .write_all(text.as_bytes())
+                .await
+                .map_err(|e| format!("Failed to send text to piper: {}", e))?;
+        }
+
+        let result = child
+            .wait_with_output()
+            .await
+            .map_err(|e| format!("piper failed: {}", e))?;
+        if !result.status.success() {
+            let _ = tokio::fs::remove_file(output).await;
+            return Err(format!(
+                "piper exited with {}: {}",
+                result.status,
+                String::from_utf8_lossy(&result.stderr).trim()
+            ));
+        }
+        Ok(())
+    }
+}
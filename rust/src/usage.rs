@@ -0,0 +1,301 @@
+use axum::{
+    Router,
+    extract::{Query, State},
+    http::header,
+    response::{IntoResponse, Json, Response},
+    routing::get,
+};
+use chrono::{DateTime, Datelike, Duration, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::io::AsyncWriteExt;
+
+use crate::error::ApiError;
+use crate::model::GenerationStats;
+use crate::server::AppState;
+
+// Periods reported when the request doesn't say
+const DEFAULT_DAYS: usize = 7;
+const DEFAULT_WEEKS: usize = 4;
+const MAX_PERIODS: usize = 366;
+const TOP_PERSONAS: usize = 5;
+
+/// One finished generation, as appended to usage.log.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct UsageRecord {
+    pub timestamp: DateTime<Utc>,
+    /// `run_prompt`, `execution` or `ollama`
+    pub source: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub persona: Option<String>,
+    /// Unknown for best-of runs and output generated by a peer
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub prompt_tokens: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub completion_tokens: Option<usize>,
+    /// Generated by a peer instead of this machine
+    #[serde(default)]
+    pub offloaded: bool,
+}
+
+impl UsageRecord {
+    pub fn new(source: &str, persona: Option<String>, stats: Option<&GenerationStats>) -> Self {
+        Self {
+            timestamp: Utc::now(),
+            source: source.to_string(),
+            persona,
+            prompt_tokens: stats.map(|s| s.prompt_tokens),
+            completion_tokens: stats.map(|s| s.completion_tokens),
+            offloaded: false,
+        }
+    }
+}
+
+// Finished generations, appended as one JSON object per line
+pub struct UsageLog {
+    path: PathBuf,
+}
+
+impl UsageLog {
+    pub fn new(path: PathBuf) -> Self {
+        Self { path }
+    }
+
+    /// Append `record`. Failures are logged, never surfaced to the caller.
+    pub async fn record(&self, record: UsageRecord) {
+        let mut line = serde_json::to_string(&record).unwrap_or_default();
+        line.push('\n');
+        if let Err(e) = self.append(line.as_bytes()).await {
+            tracing::error!("Failed to write usage log {}: {}", self.path.display(), e);
+        }
+    }
+
+    async fn append(&self, line: &[u8]) -> std::io::Result<()> {
+        if let Some(parent) = self.path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        let mut file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .await?;
+        file.write_all(line).await
+    }
+
+    /// Every record since `since`. Unreadable lines are skipped.
+    async fn since(&self, since: DateTime<Utc>) -> Result<Vec<UsageRecord>, String> {
+        let data = match tokio::fs::read_to_string(&self.path).await {
+            Ok(data) => data,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(format!("Failed to read {}: {}", self.path.display(), e)),
+        };
+        Ok(data
+            .lines()
+            .filter_map(|line| serde_json::from_str::<UsageRecord>(line).ok())
+            .filter(|record| record.timestamp >= since)
+            .collect())
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum Period {
+    #[default]
+    Daily,
+    /// Monday to Sunday
+    Weekly,
+}
+
+impl Period {
+    fn days(self) -> i64 {
+        match self {
+            Self::Daily => 1,
+            Self::Weekly => 7,
+        }
+    }
+
+    /// First day of the period `day` falls in.
+    fn start(self, day: NaiveDate) -> NaiveDate {
+        match self {
+            Self::Daily => day,
+            Self::Weekly => day - Duration::days(day.weekday().num_days_from_monday() as i64),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct ReportQuery {
+    #[serde(default)]
+    period: Period,
+    /// Periods to report, counting back from the current one
+    count: Option<usize>,
+    /// `json` or `markdown`
+    format: Option<String>,
+}
+
+#[derive(Serialize)]
+struct UsageReport {
+    /// First and last UTC day of the period
+    start: NaiveDate,
+    end: NaiveDate,
+    prompts: usize,
+    prompt_tokens: usize,
+    completion_tokens: usize,
+    offloaded: usize,
+    /// Fraction of prompts a peer generated
+    offload_share: f64,
+    by_source: BTreeMap<String, usize>,
+    top_personas: Vec<PersonaUsage>,
+}
+
+#[derive(Serialize)]
+struct PersonaUsage {
+    persona: String,
+    prompts: usize,
+}
+
+pub fn routes() -> Router<Arc<AppState>> {
+    Router::new().route("/reports", get(reports))
+}
+
+fn top_personas<'a>(records: impl Iterator<Item = &'a UsageRecord>) -> Vec<PersonaUsage> {
+    let mut counts: HashMap<&str, usize> = HashMap::new();
+    for persona in records.filter_map(|r| r.persona.as_deref()) {
+        *counts.entry(persona).or_default() += 1;
+    }
+    let mut top: Vec<PersonaUsage> = counts
+        .into_iter()
+        .map(|(persona, prompts)| PersonaUsage {
+            persona: persona.to_string(),
+            prompts,
+        })
+        .collect();
+    top.sort_by(|a, b| b.prompts.cmp(&a.prompts).then(a.persona.cmp(&b.persona)));
+    top.truncate(TOP_PERSONAS);
+    top
+}
+
+fn report(start: NaiveDate, end: NaiveDate, records: &[&UsageRecord]) -> UsageReport {
+    let offloaded = records.iter().filter(|r| r.offloaded).count();
+    let mut by_source = BTreeMap::new();
+    for record in records {
+        *by_source.entry(record.source.clone()).or_default() += 1;
+    }
+    UsageReport {
+        start,
+        end,
+        prompts: records.len(),
+        prompt_tokens: records.iter().filter_map(|r| r.prompt_tokens).sum(),
+        completion_tokens: records.iter().filter_map(|r| r.completion_tokens).sum(),
+        offloaded,
+        offload_share: if records.is_empty() {
+            0.0
+        } else {
+            offloaded as f64 / records.len() as f64
+        },
+        by_source,
+        top_personas: top_personas(records.iter().copied()),
+    }
+}
+
+/// A Markdown digest of `reports`, newest period first.
+fn digest(period: Period, reports: &[UsageReport], records: &[UsageRecord]) -> String {
+    let mut out = String::new();
+    let title = match period {
+        Period::Daily => "Daily usage",
+        Period::Weekly => "Weekly usage",
+    };
+    if let (Some(newest), Some(oldest)) = (reports.first(), reports.last()) {
+        let _ = writeln!(out, "# {}: {} to {}\n", title, oldest.start, newest.end);
+    }
+    out.push_str("| Period | Prompts | Prompt tokens | Generated tokens | Peer offload |\n");
+    out.push_str("|---|---:|---:|---:|---:|\n");
+    for r in reports {
+        let range = if r.start == r.end {
+            r.start.to_string()
+        } else {
+            format!("{} to {}", r.start, r.end)
+        };
+        let _ = writeln!(
+            out,
+            "| {} | {} | {} | {} | {:.0}% |",
+            range,
+            r.prompts,
+            r.prompt_tokens,
+            r.completion_tokens,
+            r.offload_share * 100.0
+        );
+    }
+
+    let prompts: usize = reports.iter().map(|r| r.prompts).sum();
+    let generated: usize = reports.iter().map(|r| r.completion_tokens).sum();
+    let _ = writeln!(
+        out,
+        "\n{} prompts in total, {} tokens generated.",
+        prompts, generated
+    );
+    let top = top_personas(records.iter());
+    if !top.is_empty() {
+        out.push_str("\n## Top personas\n\n");
+        for persona in top {
+            let _ = writeln!(out, "- {}: {} prompts", persona.persona, persona.prompts);
+        }
+    }
+    out
+}
+
+async fn reports(
+    State(state): State<Arc<AppState>>,
+    Query(query): Query<ReportQuery>,
+) -> Result<Response, ApiError> {
+    let markdown = match query.format.as_deref() {
+        None | Some("json") => false,
+        Some("markdown") => true,
+        Some(format) => {
+            return Err(ApiError::bad_request(format!(
+                "Unknown format {:?}, expected json or markdown",
+                format
+            )));
+        }
+    };
+    let period = query.period;
+    let count = query
+        .count
+        .unwrap_or(match period {
+            Period::Daily => DEFAULT_DAYS,
+            Period::Weekly => DEFAULT_WEEKS,
+        })
+        .clamp(1, MAX_PERIODS);
+
+    let current = period.start(Utc::now().date_naive());
+    let first = current - Duration::days(period.days() * (count as i64 - 1));
+    let Some(since) = first.and_hms_opt(0, 0, 0).map(|t| t.and_utc()) else {
+        return Err(ApiError::bad_request("Report range is out of bounds"));
+    };
+    let records = state.usage.since(since).await.map_err(ApiError::internal)?;
+
+    let reports: Vec<UsageReport> = (0..count)
+        .map(|i| {
+            let start = current - Duration::days(period.days() * i as i64);
+            let end = start + Duration::days(period.days() - 1);
+            let in_period: Vec<&UsageRecord> = records
+                .iter()
+                .filter(|r| period.start(r.timestamp.date_naive()) == start)
+                .collect();
+            report(start, end, &in_period)
+        })
+        .collect();
+
+    Ok(if markdown {
+        (
+            [(header::CONTENT_TYPE, "text/markdown; charset=utf-8")],
+            digest(period, &reports, &records),
+        )
+            .into_response()
+    } else {
+        Json(reports).into_response()
+    })
+}